@@ -5,11 +5,75 @@ use egui::{vec2, Pos2, Rect};
 use gl::types::{GLfloat, GLsizei};
 use my_tracer::world::camera;
 use my_tracer::world::math::Math;
-use my_tracer::{graphics::window::Window, world::scene::Scene};
+use my_tracer::world::tiles::{RenderMode, Tile, TileOrder};
+use my_tracer::{graphics::window::Window, world::scene::{ExampleScene, Scene}};
 use my_tracer::graphics::gl_wrapper::*;
 use glfw::{Action, Key, WindowEvent};
 
+// Renders the scene without a window and saves it, denoised if --denoise is given. --aovs also saves the
+// undenoised image, every AOV and the light passes as layers of an EXR file, --lpe (repeatable) adds a light path expression pass, filled by the path tracers.
+// --buckets renders tile by tile with n samples each instead of progressively, a frame per batch of tiles, so --max-spp then limits the batches.
+// --crop only renders part of the image, --scene picks one of the example scenes. The options are
+// --headless <output.png> [--scene <name>] [--threshold <relative error>] [--max-spp <n>] [--seed <n>] [--sampler <name>] [--integrator <name>] [--denoise] [--aovs <output.exr>] [--lpe <name>=<expression>]
+// [--tile-size <pixels>] [--tile-order <name>] [--buckets <n>] [--crop <x0>,<y0>,<x1>,<y1>]
+fn render_headless(args: &[String]) {
+    let option = |name: &str| args.iter().position(|a| a == name).and_then(|i| args.get(i + 1));
+    let output = option("--headless").expect("--headless needs an output path");
+    let threshold: f32 = option("--threshold").map_or(0.02, |v| v.parse().expect("invalid --threshold"));
+    let max_spp: u32 = option("--max-spp").map_or(4096, |v| v.parse().expect("invalid --max-spp"));
+
+    let (width, height) = (1080, 720);
+    let mut scene = Scene::new(width, height, "src/textures/qwantani_dusk_1_4k.hdr");
+    let example = option("--scene").map_or(ExampleScene::Spheres, |v| *ExampleScene::ALL.iter().find(|e| e.name() == v).expect("unknown scene"));
+    scene.build_example(example);
+    scene.set_adaptive_threshold(threshold);
+    scene.set_denoiser(args.iter().any(|a| a == "--denoise"));
+    scene.set_record_aovs(option("--aovs").is_some());
+    if let Some(seed) = option("--seed") { scene.set_seed(seed.parse().expect("invalid --seed")); }
+    if let Some(sampler) = option("--sampler") { assert!(scene.set_sampler(sampler), "unknown sampler {}", sampler); }
+    if let Some(integrator) = option("--integrator") { assert!(scene.set_integrator(integrator), "unknown integrator {}", integrator); }
+    for pass in args.windows(2).filter(|w| w[0] == "--lpe").map(|w| &w[1]) {
+        let (name, expression) = pass.split_once('=').expect("--lpe needs <name>=<expression>");
+        if let Err(e) = scene.add_light_pass(name, expression) { panic!("invalid light pass {}: {}", name, e); }
+    }
+
+    let tile_size: u32 = option("--tile-size").map_or(32, |v| v.parse().expect("invalid --tile-size"));
+    let order = option("--tile-order").map_or(TileOrder::Spiral, |v| *TileOrder::ALL.iter().find(|o| o.name() == v).expect("unknown tile order"));
+    let buckets: Option<u32> = option("--buckets").map(|v| v.parse().expect("invalid --buckets"));
+    let mode = if buckets.is_some() { RenderMode::Bucket } else { RenderMode::Progressive };
+    scene.set_tiles(mode, order, tile_size, buckets.unwrap_or(64));
+    if let Some(crop) = option("--crop") {
+        let c: Vec<u32> = crop.split(',').map(|v| v.parse().expect("invalid --crop")).collect();
+        assert!(c.len() == 4, "--crop needs <x0>,<y0>,<x1>,<y1>");
+        scene.set_crop(Some(Tile::new(c[0], c[1], c[2], c[3])));
+    }
+
+    let mut pixels: Vec<Vector3<f32>> = vec![Vector3::zero(); (width * height) as usize];
+    let mut pixels_rgb8 = vec![0; (width * height) as usize];
+
+    let start_time = Instant::now();
+    let frames = scene.render(&mut pixels, &mut pixels_rgb8, max_spp);
+    println!("Rendered {} frames in {:.1}s", frames, start_time.elapsed().as_secs_f32());
+
+    // Pixels are stored as BGR
+    let image = image::RgbImage::from_fn(width, height, |x, y| {
+        let color = Math::rgb8_to_rgbf32(pixels_rgb8[(y * width + x) as usize]);
+        image::Rgb([(color.z * 255.0) as u8, (color.y * 255.0) as u8, (color.x * 255.0) as u8])
+    });
+    image.save(output).expect("failed to save the render");
+
+    if let Some(path) = option("--aovs") {
+        scene.save_aovs(path, &pixels).expect("failed to save the AOVs");
+    }
+}
+
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.iter().any(|a| a == "--headless") {
+        render_headless(&args);
+        return;
+    }
+
     let mut window = Window::new(1080, 720, "Hello World");
     window.init_gl();
 
@@ -111,6 +175,7 @@ fn main() {
         let end_elapsed = start_time.elapsed();
         egui::Window::new("Egui with GLFW").show(&egui_ctx, |ui| {
             ui.label(format!("Elapsed: {}", 1.0 / (end_elapsed - start_elapsed).as_secs_f32()));
+            scene.ui(ui);
         });
 
         let egui::FullOutput {
@@ -0,0 +1,151 @@
+use cgmath::*;
+use rayon::prelude::*;
+
+use super::{reprojection::{HistoryTaps, Reprojection}, tiles::Tile};
+
+// Darker pixels measure their error against this intensity per channel, so black regions can still converge
+const MIN_LUMINANCE: f32 = 0.05;
+
+// Adaptive sampling. Even and odd samples of every pixel go to two half buffers, the difference between their means
+// estimates the error of the pixel. Noisy pixels take more samples per frame, pixels below the threshold stop.
+pub struct AdaptiveSampling {
+    pub enabled: bool,
+    // Relative error (standard error over the mean) at which a pixel stops
+    pub threshold: f32,
+    // Samples every pixel takes before its error is trusted
+    pub min_samples: u32,
+    // Most samples a noisy pixel takes in a single frame
+    pub max_samples_per_frame: u32,
+    width: usize,
+    height: usize,
+    halves: [Vec<Vector3<f32>>; 2],
+    counts: Vec<u32>,
+    errors: Vec<f32>,
+}
+
+impl AdaptiveSampling {
+    pub fn new(width: u32, height: u32) -> AdaptiveSampling {
+        let size = (width * height) as usize;
+        AdaptiveSampling {
+            enabled: false,
+            threshold: 0.02,
+            min_samples: 32,
+            max_samples_per_frame: 4,
+            width: width as usize,
+            height: height as usize,
+            halves: [vec![Vector3::zero(); size], vec![Vector3::zero(); size]],
+            counts: vec![0; size],
+            errors: vec![f32::MAX; size],
+        }
+    }
+
+    pub fn reset(&mut self) {
+        for half in self.halves.iter_mut() {
+            half.fill(Vector3::zero());
+        }
+        self.counts.fill(0);
+        self.errors.fill(f32::MAX);
+    }
+
+    // Samples pixel idx has taken so far, also the index of its next sample
+    pub fn sample_count(&self, idx: usize) -> u32 {
+        self.counts[idx]
+    }
+
+    pub fn sample_counts(&self) -> &[u32] {
+        &self.counts
+    }
+
+    pub fn error(&self, idx: usize) -> f32 {
+        self.errors[idx]
+    }
+
+    // Samples pixel idx takes this frame, enough to reach the threshold if the error keeps falling with the square root of the samples
+    pub fn samples_this_frame(&self, idx: usize) -> u32 {
+        let count = self.counts[idx];
+        if !self.enabled || count < self.min_samples { return 1; }
+
+        let error = self.errors[idx];
+        if error <= self.threshold { return 0; }
+
+        let needed = count as f32 * ((error / self.threshold).powi(2) - 1.0);
+        (needed.ceil() as u32).clamp(1, self.max_samples_per_frame)
+    }
+
+    // Adds the even and odd samples a pixel took this frame
+    pub fn add(&mut self, idx: usize, halves: [Vector3<f32>; 2], count: u32) {
+        self.halves[0][idx] += halves[0];
+        self.halves[1][idx] += halves[1];
+        self.counts[idx] += count;
+    }
+
+    // Moves the samples to the pixels that see the same surfaces after the camera moved, every pixel keeps at most max_history of them.
+    // The halves are resampled as means, so even and odd samples stay apart.
+    pub fn reproject(&mut self, taps: &[HistoryTaps], max_history: u32) {
+        let counts: Vec<f32> = self.counts.iter().map(|&c| c as f32).collect();
+        let even: Vec<Vector3<f32>> = self.halves[0].iter().zip(&self.counts).map(|(sum, &c)| sum / c.div_ceil(2).max(1) as f32).collect();
+        let odd: Vec<Vector3<f32>> = self.halves[1].iter().zip(&self.counts).map(|(sum, &c)| sum / (c / 2).max(1) as f32).collect();
+
+        let reprojected: Vec<(u32, [Vector3<f32>; 2])> = taps.par_iter().map(|taps| {
+            let count = match Reprojection::resample(taps, &counts) {
+                Some(c) => (c.round() as u32).min(max_history),
+                None => return (0, [Vector3::zero(); 2])
+            };
+            let (even, odd) = (Reprojection::resample(taps, &even).unwrap(), Reprojection::resample(taps, &odd).unwrap());
+            (count, [even * count.div_ceil(2) as f32, odd * (count / 2) as f32])
+        }).collect();
+
+        for (i, (count, halves)) in reprojected.into_iter().enumerate() {
+            self.counts[i] = count;
+            self.halves[0][i] = halves[0];
+            self.halves[1][i] = halves[1];
+        }
+        self.update_errors();
+    }
+
+    // Estimates the error of every pixel. A single pixel's estimate is noisy itself, so it takes the largest one of its 3x3 neighbourhood.
+    pub fn update_errors(&mut self) {
+        let raw: Vec<f32> = (0..self.counts.len()).into_par_iter().map(|i| self.pixel_error(i)).collect();
+
+        let (width, height) = (self.width as i32, self.height as i32);
+        self.errors.par_iter_mut().enumerate().for_each(|(i, error)| {
+            let (x, y) = (i as i32 % width, i as i32 / width);
+            let mut largest: f32 = 0.0;
+            for ny in (y - 1).max(0)..(y + 2).min(height) {
+                for nx in (x - 1).max(0)..(x + 2).min(width) {
+                    largest = largest.max(raw[(ny * width + nx) as usize]);
+                }
+            }
+            *error = largest;
+        });
+    }
+
+    // Fraction of the pixels in region below the threshold
+    pub fn converged_fraction(&self, region: &Tile) -> f32 {
+        let converged = region.pixels(self.width as u32).filter(|&i| self.pixel_converged(i)).count();
+        converged as f32 / region.pixel_count().max(1) as f32
+    }
+
+    // True if every pixel in region is below the threshold, pixels outside of it aren't rendered
+    pub fn converged(&self, region: &Tile) -> bool {
+        region.pixels(self.width as u32).all(|i| self.pixel_converged(i))
+    }
+
+    fn pixel_converged(&self, idx: usize) -> bool {
+        self.counts[idx] >= self.min_samples && self.errors[idx] <= self.threshold
+    }
+
+    // The halves have independent means with twice the variance of the full mean, so their difference is twice its standard error
+    fn pixel_error(&self, idx: usize) -> f32 {
+        let count = self.counts[idx];
+        if count < 2 { return f32::MAX; }
+
+        let even = self.halves[0][idx] / count.div_ceil(2) as f32;
+        let odd = self.halves[1][idx] / (count / 2) as f32;
+        let mean = (self.halves[0][idx] + self.halves[1][idx]) / count as f32;
+
+        // Summed over the channels, since colored noise can leave the luminance unchanged
+        let difference = (even - odd).map(f32::abs);
+        0.5 * (difference.x + difference.y + difference.z) / f32::max(mean.x + mean.y + mean.z, 3.0 * MIN_LUMINANCE)
+    }
+}
@@ -0,0 +1,356 @@
+use std::ops::{Add, Div, Mul};
+use cgmath::*;
+use rayon::prelude::*;
+
+use super::{lpe::LightPass, math::Math, reprojection::{HistoryTaps, Reprojection}};
+
+// Buffers written next to the beauty image, for compositing and to guide the denoiser
+#[derive(Copy, Clone, PartialEq)]
+pub enum Aov {
+    Beauty,
+    Albedo,
+    Normal,
+    Depth,
+    Position,
+    ObjectId,
+    MaterialId,
+    DirectDiffuse,
+    IndirectDiffuse,
+    DirectSpecular,
+    IndirectSpecular,
+    Emission,
+    SampleCount,
+    // Light group or light path expression pass, by its index in the passes of the scene
+    LightPass(usize),
+}
+
+impl Aov {
+    pub const ALL: [Aov; 13] = [
+        Aov::Beauty, Aov::Albedo, Aov::Normal, Aov::Depth, Aov::Position, Aov::ObjectId, Aov::MaterialId,
+        Aov::DirectDiffuse, Aov::IndirectDiffuse, Aov::DirectSpecular, Aov::IndirectSpecular, Aov::Emission, Aov::SampleCount,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Aov::Beauty => "Beauty",
+            Aov::Albedo => "Albedo",
+            Aov::Normal => "Shading normal",
+            Aov::Depth => "Depth",
+            Aov::Position => "Position",
+            Aov::ObjectId => "Object ID",
+            Aov::MaterialId => "Material ID",
+            Aov::DirectDiffuse => "Direct diffuse",
+            Aov::IndirectDiffuse => "Indirect diffuse",
+            Aov::DirectSpecular => "Direct specular",
+            Aov::IndirectSpecular => "Indirect specular",
+            Aov::Emission => "Emission",
+            Aov::SampleCount => "Sample count",
+            Aov::LightPass(_) => "Light pass",
+        }
+    }
+
+    // True for the passes that split up the lighting, light passes included, the integrator has to fill them
+    pub fn is_lighting(&self) -> bool {
+        matches!(self, Aov::DirectDiffuse | Aov::IndirectDiffuse | Aov::DirectSpecular | Aov::IndirectSpecular | Aov::Emission | Aov::LightPass(_))
+    }
+
+    // Name of the layer in exported EXR files
+    fn layer(&self) -> &'static str {
+        match self {
+            Aov::Beauty => "beauty",
+            Aov::Albedo => "albedo",
+            Aov::Normal => "normal",
+            Aov::Depth => "depth",
+            Aov::Position => "position",
+            Aov::ObjectId => "object_id",
+            Aov::MaterialId => "material_id",
+            Aov::DirectDiffuse => "direct_diffuse",
+            Aov::IndirectDiffuse => "indirect_diffuse",
+            Aov::DirectSpecular => "direct_specular",
+            Aov::IndirectSpecular => "indirect_specular",
+            Aov::Emission => "emission",
+            Aov::SampleCount => "sample_count",
+            Aov::LightPass(_) => "light_pass",
+        }
+    }
+}
+
+// Light of a path split by how it reached the camera. Direct light scattered at the first surface only, diffuse and specular
+// tell which lobes of that first surface scattered it. Together they add up to the beauty image.
+#[derive(Copy, Clone)]
+pub struct LightingAovs {
+    pub direct_diffuse: Vector3<f32>,
+    pub indirect_diffuse: Vector3<f32>,
+    pub direct_specular: Vector3<f32>,
+    pub indirect_specular: Vector3<f32>,
+    // Emitters and the sky seen by the camera
+    pub emission: Vector3<f32>,
+}
+
+impl LightingAovs {
+    pub fn zero() -> LightingAovs {
+        LightingAovs {
+            direct_diffuse: Vector3::zero(),
+            indirect_diffuse: Vector3::zero(),
+            direct_specular: Vector3::zero(),
+            indirect_specular: Vector3::zero(),
+            emission: Vector3::zero(),
+        }
+    }
+
+    // Adds light that scattered at least once, diffuse_share is the part of it (per channel) the first surface scattered diffusely
+    pub fn add_scattered(&mut self, light: Vector3<f32>, diffuse_share: Vector3<f32>, direct: bool) {
+        let diffuse = light.mul_element_wise(diffuse_share);
+        let specular = light - diffuse;
+        if direct {
+            self.direct_diffuse += diffuse;
+            self.direct_specular += specular;
+        } else {
+            self.indirect_diffuse += diffuse;
+            self.indirect_specular += specular;
+        }
+    }
+
+    fn map(&self, f: impl Fn(Vector3<f32>) -> Vector3<f32>) -> LightingAovs {
+        LightingAovs {
+            direct_diffuse: f(self.direct_diffuse),
+            indirect_diffuse: f(self.indirect_diffuse),
+            direct_specular: f(self.direct_specular),
+            indirect_specular: f(self.indirect_specular),
+            emission: f(self.emission),
+        }
+    }
+
+    fn pass(&self, aov: Aov) -> Vector3<f32> {
+        match aov {
+            Aov::DirectDiffuse => self.direct_diffuse,
+            Aov::IndirectDiffuse => self.indirect_diffuse,
+            Aov::DirectSpecular => self.direct_specular,
+            Aov::IndirectSpecular => self.indirect_specular,
+            Aov::Emission => self.emission,
+            _ => Vector3::zero(),
+        }
+    }
+}
+
+impl Add for LightingAovs {
+    type Output = LightingAovs;
+
+    fn add(self, other: LightingAovs) -> LightingAovs {
+        LightingAovs {
+            direct_diffuse: self.direct_diffuse + other.direct_diffuse,
+            indirect_diffuse: self.indirect_diffuse + other.indirect_diffuse,
+            direct_specular: self.direct_specular + other.direct_specular,
+            indirect_specular: self.indirect_specular + other.indirect_specular,
+            emission: self.emission + other.emission,
+        }
+    }
+}
+
+impl Mul<f32> for LightingAovs {
+    type Output = LightingAovs;
+
+    fn mul(self, s: f32) -> LightingAovs {
+        self.map(|v| v * s)
+    }
+}
+
+impl Div<f32> for LightingAovs {
+    type Output = LightingAovs;
+
+    fn div(self, s: f32) -> LightingAovs {
+        self.map(|v| v / s)
+    }
+}
+
+// What a single camera sample sees, the first surface along the primary ray and the lighting passes
+#[derive(Copy, Clone)]
+pub struct AovSample {
+    // White for the sky and emitters, so dividing it out leaves their color
+    pub albedo: Vector3<f32>,
+    // Shading normal facing the camera, zero for the sky
+    pub normal: Vector3<f32>,
+    // Distance along the primary ray, zero for the sky
+    pub depth: f32,
+    pub position: Vector3<f32>,
+    // Primitive index and material id, -1 for the sky. Sums keep the ids of their first sample, None if they have none.
+    pub ids: Option<(i32, i32)>,
+    pub lighting: LightingAovs,
+}
+
+impl AovSample {
+    pub fn zero() -> AovSample {
+        AovSample {
+            albedo: Vector3::zero(),
+            normal: Vector3::zero(),
+            depth: 0.0,
+            position: Vector3::zero(),
+            ids: None,
+            lighting: LightingAovs::zero(),
+        }
+    }
+
+    pub fn add(&mut self, other: AovSample) {
+        self.albedo += other.albedo;
+        self.normal += other.normal;
+        self.depth += other.depth;
+        self.position += other.position;
+        self.ids = self.ids.or(other.ids);
+        self.lighting = self.lighting + other.lighting;
+    }
+}
+
+// AOVs of every pixel averaged over its samples like the beauty pixels, colors are stored as BGR to match them.
+// Ids can't be averaged, they come from the first sample of the pixel.
+pub struct AovBuffers {
+    pub albedo: Vec<Vector3<f32>>,
+    pub normal: Vec<Vector3<f32>>,
+    pub depth: Vec<f32>,
+    pub position: Vec<Vector3<f32>>,
+    pub object_id: Vec<i32>,
+    pub material_id: Vec<i32>,
+    pub lighting: Vec<LightingAovs>,
+    // One buffer per light pass
+    pub light_passes: Vec<Vec<Vector3<f32>>>,
+    // Recorded even when neither the view, the denoiser nor reprojection needs them, to save them
+    pub enabled: bool,
+}
+
+impl AovBuffers {
+    pub fn new(size: usize) -> AovBuffers {
+        AovBuffers {
+            albedo: vec![Vector3::zero(); size],
+            normal: vec![Vector3::zero(); size],
+            depth: vec![0.0; size],
+            position: vec![Vector3::zero(); size],
+            object_id: vec![-1; size],
+            material_id: vec![-1; size],
+            lighting: vec![LightingAovs::zero(); size],
+            light_passes: Vec::new(),
+            enabled: false,
+        }
+    }
+
+    // Makes room for count light passes, all of them black
+    pub fn set_light_pass_count(&mut self, count: usize) {
+        let size = self.depth.len();
+        self.light_passes = vec![vec![Vector3::zero(); size]; count];
+    }
+
+    // Adds the sum of count new samples, and what they added to every light pass, to the average of the previous ones
+    pub fn add(&mut self, idx: usize, sum: AovSample, passes: &[Vector3<f32>], count: u32, previous: u32) {
+        let (previous_f, total) = (previous as f32, (previous + count) as f32);
+        self.albedo[idx] = (AovBuffers::bgr(sum.albedo) + self.albedo[idx] * previous_f) / total;
+        self.normal[idx] = (sum.normal + self.normal[idx] * previous_f) / total;
+        self.depth[idx] = (sum.depth + self.depth[idx] * previous_f) / total;
+        self.position[idx] = (sum.position + self.position[idx] * previous_f) / total;
+        self.lighting[idx] = (sum.lighting.map(AovBuffers::bgr) + self.lighting[idx] * previous_f) / total;
+        for (buffer, pass) in self.light_passes.iter_mut().zip(passes) {
+            buffer[idx] = (AovBuffers::bgr(*pass) + buffer[idx] * previous_f) / total;
+        }
+        if let (0, Some((object, material))) = (previous, sum.ids) {
+            self.object_id[idx] = object;
+            self.material_id[idx] = material;
+        }
+    }
+
+    // Moves the passes to the camera the taps were found for. The surfaces seen through the new pixel centers replace
+    // the geometry, so it stays sharp, the lighting is resampled like the beauty pixels.
+    pub fn reproject(&mut self, taps: &[HistoryTaps], surfaces: &[AovSample]) {
+        self.lighting = taps.par_iter().map(|t| Reprojection::resample(t, &self.lighting).unwrap_or(LightingAovs::zero())).collect();
+        for buffer in self.light_passes.iter_mut() {
+            *buffer = taps.par_iter().map(|t| Reprojection::resample(t, buffer).unwrap_or(Vector3::zero())).collect();
+        }
+        for (idx, surface) in surfaces.iter().enumerate() {
+            self.albedo[idx] = AovBuffers::bgr(surface.albedo);
+            self.normal[idx] = surface.normal;
+            self.depth[idx] = surface.depth;
+            self.position[idx] = surface.position;
+            let (object, material) = surface.ids.unwrap_or((-1, -1));
+            self.object_id[idx] = object;
+            self.material_id[idx] = material;
+        }
+    }
+
+    // Colors (as BGR) to look at an AOV. Depth and sample count are scaled by their largest value, positions repeat every unit
+    // and ids get a random color each.
+    pub fn display(&self, aov: Aov, beauty: &[Vector3<f32>], sample_counts: &[u32]) -> Vec<Vector3<f32>> {
+        let gray = |v: f32| vec3(v, v, v);
+        let id_color = |id: i32| {
+            if id < 0 { return Vector3::zero(); }
+            let hash = Math::wang_hash(id as u32 + 1);
+            vec3((hash & 0xFF) as f32, ((hash >> 8) & 0xFF) as f32, ((hash >> 16) & 0xFF) as f32) / 255.0
+        };
+
+        match aov {
+            Aov::Beauty => beauty.to_vec(),
+            Aov::Albedo => self.albedo.clone(),
+            Aov::Normal => self.normal.par_iter().map(|n| AovBuffers::bgr(n * 0.5 + vec3(0.5, 0.5, 0.5))).collect(),
+            Aov::Depth => {
+                let largest = self.depth.iter().fold(0.0, |a: f32, &b| a.max(b)).max(f32::EPSILON);
+                self.depth.par_iter().map(|&d| gray(d / largest)).collect()
+            }
+            Aov::Position => self.position.par_iter().map(|p| AovBuffers::bgr(p.map(|c| c - c.floor()))).collect(),
+            Aov::ObjectId => self.object_id.par_iter().map(|&id| id_color(id)).collect(),
+            Aov::MaterialId => self.material_id.par_iter().map(|&id| id_color(id)).collect(),
+            Aov::SampleCount => {
+                let largest = sample_counts.iter().copied().max().unwrap_or(0).max(1) as f32;
+                sample_counts.par_iter().map(|&c| gray(c as f32 / largest)).collect()
+            }
+            Aov::LightPass(idx) => self.light_passes[idx].clone(),
+            _ => self.lighting.par_iter().map(|l| l.pass(aov)).collect(),
+        }
+    }
+
+    // Writes the beauty image, every AOV and the light passes as layers of a multi-part EXR file.
+    // The lighting AOVs and the light passes are left out unless lighting is set, when the integrator didn't fill them.
+    pub fn save_exr(&self, path: &str, width: usize, height: usize, beauty: &[Vector3<f32>], sample_counts: &[u32], passes: &[LightPass], lighting: bool) -> exr::error::Result<()> {
+        use exr::prelude::*;
+
+        // Colors are turned back into RGB
+        let rgb = |values: Vec<Vector3<f32>>| -> SmallVec<[AnyChannel<FlatSamples>; 4]> {
+            SmallVec::from_vec(vec![
+                AnyChannel::new("R", FlatSamples::F32(values.iter().map(|v| v.z).collect())),
+                AnyChannel::new("G", FlatSamples::F32(values.iter().map(|v| v.y).collect())),
+                AnyChannel::new("B", FlatSamples::F32(values.iter().map(|v| v.x).collect())),
+            ])
+        };
+        let xyz = |values: &[Vector3<f32>]| -> SmallVec<[AnyChannel<FlatSamples>; 4]> {
+            SmallVec::from_vec(vec![
+                AnyChannel::new("X", FlatSamples::F32(values.iter().map(|v| v.x).collect())),
+                AnyChannel::new("Y", FlatSamples::F32(values.iter().map(|v| v.y).collect())),
+                AnyChannel::new("Z", FlatSamples::F32(values.iter().map(|v| v.z).collect())),
+            ])
+        };
+        let single = |name: &str, values: Vec<f32>| -> SmallVec<[AnyChannel<FlatSamples>; 4]> {
+            SmallVec::from_vec(vec![AnyChannel::new(name, FlatSamples::F32(values))])
+        };
+
+        let mut layers: Vec<Layer<AnyChannels<FlatSamples>>> = Aov::ALL.iter().filter(|aov| lighting || !aov.is_lighting()).map(|&aov| {
+            let channels = match aov {
+                Aov::Beauty => rgb(beauty.to_vec()),
+                Aov::Albedo => rgb(self.albedo.clone()),
+                Aov::Normal => xyz(&self.normal),
+                Aov::Depth => single("Z", self.depth.clone()),
+                Aov::Position => xyz(&self.position),
+                Aov::ObjectId => single("id", self.object_id.iter().map(|&id| id as f32).collect()),
+                Aov::MaterialId => single("id", self.material_id.iter().map(|&id| id as f32).collect()),
+                Aov::SampleCount => single("Y", sample_counts.iter().map(|&c| c as f32).collect()),
+                _ => rgb(self.lighting.iter().map(|l| l.pass(aov)).collect()),
+            };
+            Layer::new((width, height), LayerAttributes::named(aov.layer()), Encoding::FAST_LOSSLESS, AnyChannels::sort(channels))
+        }).collect();
+        if lighting {
+            for (pass, buffer) in passes.iter().zip(&self.light_passes) {
+                let channels = rgb(buffer.clone());
+                layers.push(Layer::new((width, height), LayerAttributes::named(pass.layer().as_str()), Encoding::FAST_LOSSLESS, AnyChannels::sort(channels)));
+            }
+        }
+
+        Image::from_layers(ImageAttributes::new(IntegerBounds::from_dimensions((width, height))), layers).write().to_file(path)
+    }
+
+    fn bgr(v: Vector3<f32>) -> Vector3<f32> {
+        vec3(v.z, v.y, v.x)
+    }
+}
@@ -5,6 +5,7 @@ use crate::graphics::window::{get_mouse_delta, key_held};
 
 use super::ray::Ray;
 
+#[derive(Copy, Clone)]
 pub struct Camera{
     pub position: Vector3<f32>,
     pub top_left: Vector3<f32>, 
@@ -41,6 +42,53 @@ impl Camera{
         Ray::new(self.position, (p - self.position).normalize(), f32::max_value())
     }
 
+    pub fn ahead(&self) -> Vector3<f32> {
+        let center = (self.top_right + self.bottom_left) * 0.5;
+        (center - self.position).normalize()
+    }
+
+    // Area of the image plane at distance 1 from the camera
+    pub fn image_plane_area(&self) -> f32 {
+        let width = (self.top_right - self.top_left).magnitude();
+        let height = (self.bottom_left - self.top_left).magnitude();
+        let dist = ((self.top_right + self.bottom_left) * 0.5 - self.position).magnitude();
+        width * height / (dist * dist)
+    }
+
+    // Angle covered by a single pixel when the image is height pixels tall
+    pub fn pixel_spread_angle(&self, height: u32) -> f32 {
+        let plane_height = (self.bottom_left - self.top_left).magnitude();
+        let dist = ((self.top_right + self.bottom_left) * 0.5 - self.position).magnitude();
+        plane_height / (dist * height as f32)
+    }
+
+    // Solid angle pdf of a primary ray going in direction dir
+    pub fn direction_pdf(&self, dir: Vector3<f32>) -> f32 {
+        let cos_theta = dir.dot(self.ahead());
+        if cos_theta <= 0.0 { return 0.0; }
+        1.0 / (self.image_plane_area() * cos_theta * cos_theta * cos_theta)
+    }
+
+    // Inverse of calculate_primary_ray, returns the screen coordinates in [0, 1) of the point p
+    pub fn project(&self, p: Vector3<f32>) -> Option<(f32, f32)> {
+        let ahead = self.ahead();
+        let dir = p - self.position;
+        let depth = dir.dot(ahead);
+        if depth <= 0.0 { return None; }
+
+        let center = (self.top_right + self.bottom_left) * 0.5;
+        let plane_dist = (center - self.position).dot(ahead);
+        let on_plane = self.position + dir * (plane_dist / depth) - self.top_left;
+
+        let horizontal = self.top_right - self.top_left;
+        let vertical = self.bottom_left - self.top_left;
+        let x = on_plane.dot(horizontal) / horizontal.magnitude2();
+        let y = on_plane.dot(vertical) / vertical.magnitude2();
+
+        if x < 0.0 || x >= 1.0 || y < 0.0 || y >= 1.0 { return None; }
+        Some((x, y))
+    }
+
     pub fn update(&mut self, delta_time: f32, aspect: f32) -> bool {
         let mut changed = false;
 
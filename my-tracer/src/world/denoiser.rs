@@ -0,0 +1,89 @@
+use cgmath::*;
+use rayon::prelude::*;
+
+use super::aov::AovBuffers;
+
+// B3 spline, the 5 taps of the a-trous kernel in each direction
+const KERNEL: [f32; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+// Edge avoiding a-trous wavelet filter (Dammertz et al. 2010). Every iteration blurs with a 5x5 kernel whose taps are twice
+// as far apart as in the previous one, and neighbours with a different color, normal or depth get less weight.
+// The filter works on the lighting with the albedo divided out, so textures stay sharp. The albedo, normal and depth AOVs guide it.
+pub struct Denoiser {
+    pub enabled: bool,
+    // The interactive view is denoised every this many frames
+    pub interval: u32,
+    pub iterations: u32,
+    // Allowed color difference at the first iteration, halved with every iteration
+    pub sigma_color: f32,
+    // Exponent on the cosine between two normals
+    pub sigma_normal: f32,
+    // Allowed depth difference, relative to the depth and per pixel of tap distance
+    pub sigma_depth: f32,
+}
+
+impl Denoiser {
+    pub fn new() -> Denoiser {
+        Denoiser {
+            enabled: false,
+            interval: 8,
+            iterations: 5,
+            sigma_color: 1.0,
+            sigma_normal: 64.0,
+            sigma_depth: 0.02,
+        }
+    }
+
+    pub fn denoise(&self, color: &[Vector3<f32>], aovs: &AovBuffers, width: usize, height: usize) -> Vec<Vector3<f32>> {
+        let albedo: Vec<Vector3<f32>> = aovs.albedo.par_iter().map(|a| a.map(|c| f32::max(c, 0.01))).collect();
+        let mut lighting: Vec<Vector3<f32>> = color.par_iter().zip(&albedo).map(|(c, a)| c.div_element_wise(*a)).collect();
+
+        for iteration in 0..self.iterations {
+            let step = 1 << iteration;
+            let sigma_color = self.sigma_color / step as f32;
+
+            lighting = (0..width * height).into_par_iter().map(|p| {
+                let (x, y) = ((p % width) as i32, (p / width) as i32);
+                let (color_p, normal_p, depth_p) = (Denoiser::compress(lighting[p]), aovs.normal[p], aovs.depth[p]);
+
+                // The center tap always has full weight
+                let mut weight_sum = KERNEL[2] * KERNEL[2];
+                let mut sum = lighting[p] * weight_sum;
+                for dy in -2..=2 {
+                    for dx in -2..=2 {
+                        if dx == 0 && dy == 0 { continue; }
+                        let (qx, qy) = (x + dx * step, y + dy * step);
+                        if qx < 0 || qy < 0 || qx >= width as i32 || qy >= height as i32 { continue; }
+                        let q = qy as usize * width + qx as usize;
+
+                        let difference = Denoiser::compress(lighting[q]) - color_p;
+                        let w_color = f32::exp(-difference.dot(difference) / (sigma_color * sigma_color));
+                        let w_normal = if normal_p == Vector3::zero() && aovs.normal[q] == Vector3::zero() { 1.0 } else {
+                            f32::max(normal_p.dot(aovs.normal[q]), 0.0).powf(self.sigma_normal)
+                        };
+                        let distance = (dx * dx + dy * dy) as f32;
+                        let w_depth = f32::exp(-f32::abs(depth_p - aovs.depth[q]) / (self.sigma_depth * depth_p * step as f32 * distance.sqrt() + 1e-4));
+
+                        let weight = KERNEL[(dx + 2) as usize] * KERNEL[(dy + 2) as usize] * w_color * w_normal * w_depth;
+                        sum += lighting[q] * weight;
+                        weight_sum += weight;
+                    }
+                }
+                sum / weight_sum
+            }).collect();
+        }
+
+        lighting.par_iter().zip(&albedo).map(|(l, a)| l.mul_element_wise(*a)).collect()
+    }
+
+    // Colors are compared after a Reinhard curve, so bright lights don't dominate the differences
+    fn compress(color: Vector3<f32>) -> Vector3<f32> {
+        color.map(|c| c / (1.0 + c))
+    }
+}
+
+impl Default for Denoiser {
+    fn default() -> Self {
+        Denoiser::new()
+    }
+}
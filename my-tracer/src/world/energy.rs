@@ -0,0 +1,137 @@
+use core::f32;
+use cgmath::*;
+
+use super::{material::Material, principled::Principled};
+
+// Resolution of the albedo tables over the cosine of wo and the roughness
+const TABLE_SIZE: usize = 32;
+// Stratified samples per table entry
+const TABLE_SAMPLES: usize = 64;
+
+lazy_static::lazy_static! {
+    // GGX with separable Smith shadowing, used by the glossy material
+    static ref SEPARABLE_ALBEDO: AlbedoTable = AlbedoTable::generate(|wo, wi, alpha| {
+        Material::smith_g1(wo.z, alpha) * Material::smith_g1(wi.z, alpha)
+    });
+    // GGX with height correlated Smith shadowing, used by the principled BSDF
+    static ref CORRELATED_ALBEDO: AlbedoTable = AlbedoTable::generate(|wo, wi, alpha| {
+        Principled::smith_g(wo, wi, alpha, alpha)
+    });
+}
+
+// Directional albedo E(cos) of a GGX lobe without fresnel, and its cosine weighted average over the hemisphere, per roughness.
+// Generated on first use by integrating the single scattering BRDF.
+struct AlbedoTable {
+    albedo: Vec<f32>,
+    average: Vec<f32>,
+}
+
+impl AlbedoTable {
+    fn generate(g: impl Fn(Vector3<f32>, Vector3<f32>, f32) -> f32) -> AlbedoTable {
+        let mut albedo = vec![0.0; TABLE_SIZE * TABLE_SIZE];
+        let mut average = vec![0.0; TABLE_SIZE];
+
+        for r in 0..TABLE_SIZE {
+            let roughness = r as f32 / (TABLE_SIZE - 1) as f32;
+            let alpha = Material::alpha(roughness);
+
+            for c in 0..TABLE_SIZE {
+                let cos_o = f32::max(c as f32 / (TABLE_SIZE - 1) as f32, 1e-3);
+                let wo = vec3(f32::sqrt(1.0 - cos_o * cos_o), 0.0, cos_o);
+
+                // Sampling h proportional to D(h) * cos(theta_h) leaves G * (wo.h) / (cos_o * cos_h) per sample
+                let mut sum = 0.0;
+                for i in 0..TABLE_SAMPLES {
+                    for j in 0..TABLE_SAMPLES {
+                        let r1 = (i as f32 + 0.5) / TABLE_SAMPLES as f32;
+                        let r2 = (j as f32 + 0.5) / TABLE_SAMPLES as f32;
+                        let cos_theta = f32::sqrt((1.0 - r1) / (1.0 + (alpha * alpha - 1.0) * r1));
+                        let sin_theta = f32::sqrt(f32::max(0.0, 1.0 - cos_theta * cos_theta));
+                        let phi = 2.0 * f32::consts::PI * r2;
+                        let h = vec3(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta);
+
+                        let wo_dot_h = wo.dot(h);
+                        let wi = h * (2.0 * wo_dot_h) - wo;
+                        if wi.z <= 0.0 || wo_dot_h <= 0.0 { continue; }
+                        sum += g(wo, wi, alpha) * wo_dot_h / (cos_o * cos_theta);
+                    }
+                }
+                albedo[r * TABLE_SIZE + c] = f32::min(sum / (TABLE_SAMPLES * TABLE_SAMPLES) as f32, 1.0);
+            }
+
+            // E_avg = 2 * integral of E(cos) * cos over [0, 1], trapezoidal rule
+            let mut sum = 0.0;
+            for c in 0..TABLE_SIZE - 1 {
+                let (c0, c1) = (c as f32 / (TABLE_SIZE - 1) as f32, (c + 1) as f32 / (TABLE_SIZE - 1) as f32);
+                sum += 0.5 * (albedo[r * TABLE_SIZE + c] * c0 + albedo[r * TABLE_SIZE + c + 1] * c1) * (c1 - c0);
+            }
+            average[r] = f32::min(2.0 * sum, 1.0);
+        }
+
+        AlbedoTable {
+            albedo: albedo,
+            average: average,
+        }
+    }
+
+    // Bilinear lookup of E(cos, roughness)
+    fn albedo(&self, cos: f32, roughness: f32) -> f32 {
+        let x = cos.clamp(0.0, 1.0) * (TABLE_SIZE - 1) as f32;
+        let y = roughness.clamp(0.0, 1.0) * (TABLE_SIZE - 1) as f32;
+        let (x0, y0) = ((x as usize).min(TABLE_SIZE - 2), (y as usize).min(TABLE_SIZE - 2));
+        let (tx, ty) = (x - x0 as f32, y - y0 as f32);
+
+        let at = |x: usize, y: usize| self.albedo[y * TABLE_SIZE + x];
+        let top = at(x0, y0) * (1.0 - tx) + at(x0 + 1, y0) * tx;
+        let bottom = at(x0, y0 + 1) * (1.0 - tx) + at(x0 + 1, y0 + 1) * tx;
+        top * (1.0 - ty) + bottom * ty
+    }
+
+    fn average(&self, roughness: f32) -> f32 {
+        let y = roughness.clamp(0.0, 1.0) * (TABLE_SIZE - 1) as f32;
+        let y0 = (y as usize).min(TABLE_SIZE - 2);
+        let ty = y - y0 as f32;
+        self.average[y0] * (1.0 - ty) + self.average[y0 + 1] * ty
+    }
+
+    // Kulla-Conty lobe adding the energy single scattering loses to light bouncing between microfacets,
+    // tinted by the average fresnel reflectance, since every extra bounce reflects only part of the light.
+    // Anisotropic lobes pass the roughness seen from each direction.
+    fn compensation(&self, fresnel_average: Vector3<f32>, roughness_o: f32, roughness_i: f32, cos_o: f32, cos_i: f32) -> Vector3<f32> {
+        let average = self.average(f32::sqrt(roughness_o * roughness_i));
+        if average >= 1.0 { return Vector3::zero(); }
+
+        let lost = (1.0 - self.albedo(cos_o, roughness_o)) * (1.0 - self.albedo(cos_i, roughness_i));
+        let white = lost / (f32::consts::PI * (1.0 - average));
+
+        let tint = fresnel_average.map(|f| f * f * average / (1.0 - f * (1.0 - average)));
+        tint * white
+    }
+}
+
+// Energy compensation for the microfacet lobes, roughness is the artist roughness (alpha is its square)
+pub struct EnergyCompensation;
+
+impl EnergyCompensation {
+    pub fn separable(fresnel_average: Vector3<f32>, roughness: f32, cos_o: f32, cos_i: f32) -> Vector3<f32> {
+        SEPARABLE_ALBEDO.compensation(fresnel_average, roughness, roughness, cos_o, cos_i)
+    }
+
+    pub fn correlated(fresnel_average: Vector3<f32>, roughness_o: f32, roughness_i: f32, cos_o: f32, cos_i: f32) -> Vector3<f32> {
+        CORRELATED_ALBEDO.compensation(fresnel_average, roughness_o, roughness_i, cos_o, cos_i)
+    }
+
+    // Fraction of the light single scattering loses for light arriving at cos, the share the compensation lobe adds back
+    pub fn separable_loss(roughness: f32, cos: f32) -> f32 {
+        1.0 - SEPARABLE_ALBEDO.albedo(cos, roughness)
+    }
+
+    pub fn correlated_loss(roughness: f32, cos: f32) -> f32 {
+        1.0 - CORRELATED_ALBEDO.albedo(cos, roughness)
+    }
+
+    // Average of Schlick's approximation over the hemisphere, weighted by the cosine
+    pub fn schlick_average(f0: Vector3<f32>) -> Vector3<f32> {
+        f0 + (vec3(1.0, 1.0, 1.0) - f0) / 21.0
+    }
+}
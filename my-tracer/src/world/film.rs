@@ -0,0 +1,37 @@
+use std::sync::atomic::{AtomicI64, Ordering};
+use cgmath::*;
+
+// Fractional bits of the fixed point splats
+const SPLAT_FRACTION_BITS: i32 = 32;
+
+// Per pixel color that many threads can add to at once, used for contributions that don't land on the pixel being traced.
+// Colors are summed in fixed point, integer addition gives the same result in any order so renders don't depend on the threads.
+pub struct SplatBuffer {
+    data: Vec<AtomicI64>,
+}
+
+impl SplatBuffer {
+    pub fn new(size: usize) -> SplatBuffer {
+        SplatBuffer {
+            data: (0..size * 3).map(|_| AtomicI64::new(0)).collect(),
+        }
+    }
+
+    pub fn add(&self, idx: usize, color: Vector3<f32>) {
+        for i in 0..3 {
+            let fixed = (color[i] as f64 * f64::powi(2.0, SPLAT_FRACTION_BITS)) as i64;
+            self.data[idx * 3 + i].fetch_add(fixed, Ordering::Relaxed);
+        }
+    }
+
+    pub fn get(&self, idx: usize) -> Vector3<f32> {
+        let channel = |i: usize| (self.data[idx * 3 + i].load(Ordering::Relaxed) as f64 * f64::powi(2.0, -SPLAT_FRACTION_BITS)) as f32;
+        vec3(channel(0), channel(1), channel(2))
+    }
+
+    pub fn clear(&mut self) {
+        for cell in self.data.iter_mut() {
+            *cell.get_mut() = 0;
+        }
+    }
+}
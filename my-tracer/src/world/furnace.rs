@@ -0,0 +1,94 @@
+use core::f32;
+use cgmath::*;
+
+use super::{layered::Coat, material::Material, math::Math, principled::Principled};
+
+// Angles of incidence the albedo is checked at, as the cosine towards the normal
+pub const FURNACE_COSINES: [f32; 3] = [1.0, 0.5, 0.1];
+const FURNACE_SAMPLES: usize = 1 << 16;
+
+// A BSDF with white albedo under a uniform white sky. Lossless ones should reflect exactly what arrives,
+// the others may absorb some light but never reflect more than arrives.
+pub struct FurnaceCase {
+    pub name: &'static str,
+    pub material: Material,
+    pub lossless: bool,
+}
+
+pub struct FurnaceResult {
+    pub name: &'static str,
+    // Luminance of the albedo at each of FURNACE_COSINES
+    pub albedo: [f32; 3],
+    pub passed: bool,
+}
+
+// White furnace test. Every BSDF is lit by a white sky, so a surface that conserves energy disappears against it.
+pub struct Furnace;
+
+impl Furnace {
+    pub fn cases() -> Vec<FurnaceCase> {
+        let case = |name, material, lossless| FurnaceCase { name: name, material: material, lossless: lossless };
+        vec![
+            case("Diffuse", Material::Diffuse, true),
+            case("Glossy 0.2", Material::Glossy { roughness: 0.2 }, true),
+            case("Glossy 0.6", Material::Glossy { roughness: 0.6 }, true),
+            case("Glossy 1.0", Material::Glossy { roughness: 1.0 }, true),
+            case("Mirror", Material::Mirror, true),
+            case("Dielectric", Material::Dielectric { ior: 1.5, dispersion: 0.0 }, true),
+            case("Subsurface", Material::Subsurface { mean_free_path: vec3(0.1, 0.1, 0.1) }, true),
+            case("Principled dielectric", Material::Principled(Principled::new()), false),
+            case("Principled metal 0.3", Material::Principled(Principled { metallic: 1.0, roughness: 0.3, ..Principled::new() }), true),
+            case("Principled metal 1.0", Material::Principled(Principled { metallic: 1.0, roughness: 1.0, ..Principled::new() }), true),
+            // The energy compensation of anisotropic lobes is approximate
+            case("Principled anisotropic", Material::Principled(Principled { metallic: 1.0, roughness: 0.5, anisotropic: 0.8, ..Principled::new() }), false),
+            case("Principled clearcoat", Material::Principled(Principled { clearcoat: 1.0, ..Principled::new() }), false),
+            case("Principled glass", Material::Principled(Principled { roughness: 0.3, transmission: 1.0, ..Principled::new() }), false),
+            // Coats lose the light that is reflected back down inside them
+            case("Coated diffuse", Material::coated(Material::Diffuse, Coat::new(1.5, 0.1)), false),
+            case("Coated glossy", Material::coated(Material::Glossy { roughness: 0.5 }, Coat::new(1.5, 0.3)), false),
+            case("Thin film mirror", Material::coated(Material::Mirror, Coat::new(1.5, 0.05).with_film(400.0, 1.33)), false),
+        ]
+    }
+
+    // Checks every case, allowing the given relative error for the Monte Carlo estimate
+    pub fn verify(tolerance: f32) -> Vec<FurnaceResult> {
+        Furnace::cases().iter().map(|case| {
+            let mut albedo = [0.0; 3];
+            let mut passed = true;
+            for (i, cos) in FURNACE_COSINES.iter().enumerate() {
+                albedo[i] = Math::luminance(Furnace::albedo(case.material, *cos));
+                passed &= albedo[i] <= 1.0 + tolerance;
+                passed &= !case.lossless || albedo[i] >= 1.0 - tolerance;
+            }
+            FurnaceResult { name: case.name, albedo: albedo, passed: passed }
+        }).collect()
+    }
+
+    // Fraction of the light arriving from every direction that the material sends towards wo, estimated by sampling the BSDF
+    pub fn albedo(material: Material, cos_o: f32) -> Vector3<f32> {
+        let normal = vec3(0.0, 0.0, 1.0);
+        let wo = vec3(f32::sqrt(1.0 - cos_o * cos_o), 0.0, cos_o);
+        let white = vec3(1.0, 1.0, 1.0);
+
+        let mut sampler: u32 = 0x9E3779B9;
+        let mut sum = Vector3::zero();
+        for _ in 0..FURNACE_SAMPLES {
+            if let Some(sample) = material.sample(white, normal, wo, true, &mut sampler) {
+                sum += sample.f * (f32::abs(normal.dot(sample.wi)) / sample.pdf);
+            }
+        }
+        sum / FURNACE_SAMPLES as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_bsdf_passes_the_furnace() {
+        for result in Furnace::verify(0.02) {
+            assert!(result.passed, "{} reflects {:?}", result.name, result.albedo);
+        }
+    }
+}
@@ -0,0 +1,647 @@
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use cgmath::*;
+use flate2::read::ZlibDecoder;
+
+use super::medium::DensityGrid;
+
+// Loads density grids for heterogeneous volumes. Two formats are understood:
+//
+// Dense grids (.vol), the grid volume format of Mitsuba. All numbers are little endian:
+//   3 bytes    "VOL"
+//   u8         version, 3
+//   i32        encoding, 1 for f32 values
+//   i32 x 3    resolution in x, y and z
+//   i32        channels, only the first one is used as density
+//   f32 x 6    bounding box, min x, y, z followed by max x, y, z
+//   f32 ...    the values, ((z * res_y + y) * res_x + x) * channels + channel
+//
+// OpenVDB (.vdb), limited to what simulation caches usually contain: files written by OpenVDB 3 or newer
+// holding a float grid with the standard 5-4-3 tree, stored uncompressed or zip compressed, in full or half precision.
+// Blosc compression is not supported. The sparse tree is turned into a dense grid over the bounding box of its leaves,
+// tiles at the root level are left out. The grid is placed by the index to world transform stored in the file.
+pub struct GridLoader;
+
+impl GridLoader {
+    // Picks the format from the file extension, for .vdb files the first float grid is used
+    pub fn load(path: &str) -> io::Result<DensityGrid> {
+        if path.to_lowercase().ends_with(".vdb") {
+            GridLoader::load_vdb(path, None)
+        } else {
+            GridLoader::load_vol(path)
+        }
+    }
+
+    pub fn load_vol(path: &str) -> io::Result<DensityGrid> {
+        let mut reader = BufReader::new(File::open(path)?);
+
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic[0..3] != b"VOL" || magic[3] != 3 {
+            return Err(invalid_data("not a version 3 .vol file"));
+        }
+
+        let encoding = read_i32(&mut reader)?;
+        if encoding != 1 {
+            return Err(invalid_data("only .vol files with f32 values are supported"));
+        }
+
+        let nx = read_i32(&mut reader)?;
+        let ny = read_i32(&mut reader)?;
+        let nz = read_i32(&mut reader)?;
+        let channels = read_i32(&mut reader)?;
+        if nx <= 0 || ny <= 0 || nz <= 0 || channels <= 0 {
+            return Err(invalid_data("invalid .vol resolution"));
+        }
+
+        let mut bounds = [0.0f32; 6];
+        for b in bounds.iter_mut() {
+            *b = read_f32(&mut reader)?;
+        }
+
+        let count = nx as usize * ny as usize * nz as usize;
+        let mut data = Vec::with_capacity(count);
+        for _ in 0..count {
+            data.push(read_f32(&mut reader)?);
+            for _ in 1..channels {
+                read_f32(&mut reader)?;
+            }
+        }
+
+        Ok(DensityGrid::new(nx as usize, ny as usize, nz as usize, data, vec3(bounds[0], bounds[1], bounds[2]), vec3(bounds[3], bounds[4], bounds[5])))
+    }
+
+    // Reads the float grid with the given name, or the first float grid in the file
+    pub fn load_vdb(path: &str, grid_name: Option<&str>) -> io::Result<DensityGrid> {
+        let mut reader = VdbReader::new(BufReader::new(File::open(path)?));
+        reader.read_grid(grid_name)
+    }
+}
+
+const VDB_MAGIC: i64 = 0x56444220;
+// File version that moved the compression flags into every grid, older files are not supported
+const VDB_NODE_MASK_COMPRESSION: u32 = 222;
+
+const VDB_COMPRESS_ZIP: u32 = 0x1;
+const VDB_COMPRESS_ACTIVE_MASK: u32 = 0x2;
+const VDB_COMPRESS_BLOSC: u32 = 0x4;
+
+// log2 of the width of the leaf, lower and upper internal nodes in voxels
+const LEAF_LOG2: u32 = 3;
+const LOWER_LOG2: u32 = 4;
+const UPPER_LOG2: u32 = 5;
+
+struct Leaf {
+    origin: Vector3<i32>,
+    values: Vec<f32>,
+}
+
+// Active tile of an internal node, a cube of size voxels with a single value
+struct Tile {
+    origin: Vector3<i32>,
+    size: i32,
+    value: f32,
+}
+
+struct VdbReader<R: Read + Seek> {
+    reader: R,
+    compression: u32,
+    half_float: bool,
+    background: f32,
+    leaves: Vec<Leaf>,
+    tiles: Vec<Tile>,
+}
+
+impl<R: Read + Seek> VdbReader<R> {
+    fn new(reader: R) -> VdbReader<R> {
+        VdbReader {
+            reader: reader,
+            compression: 0,
+            half_float: false,
+            background: 0.0,
+            leaves: Vec::new(),
+            tiles: Vec::new(),
+        }
+    }
+
+    fn read_grid(&mut self, grid_name: Option<&str>) -> io::Result<DensityGrid> {
+        // Header
+        if read_i64(&mut self.reader)? != VDB_MAGIC {
+            return Err(invalid_data("not an OpenVDB file"));
+        }
+        let version = read_u32(&mut self.reader)?;
+        if version < VDB_NODE_MASK_COMPRESSION {
+            return Err(invalid_data(&format!("OpenVDB file version {} is too old", version)));
+        }
+        read_u32(&mut self.reader)?; // Library major version
+        read_u32(&mut self.reader)?; // Library minor version
+        let has_grid_offsets = read_u8(&mut self.reader)? != 0;
+        if !has_grid_offsets {
+            return Err(invalid_data("OpenVDB streams without grid offsets are not supported"));
+        }
+        let mut uuid = [0u8; 36];
+        self.reader.read_exact(&mut uuid)?;
+        self.skip_metadata()?;
+
+        // Grid descriptors, every grid is followed by the next descriptor
+        let grid_count = read_i32(&mut self.reader)?;
+        for _ in 0..grid_count {
+            let unique_name = read_string(&mut self.reader)?;
+            let grid_type = read_string(&mut self.reader)?;
+            read_string(&mut self.reader)?; // Parent of an instanced grid
+            let grid_pos = read_i64(&mut self.reader)?;
+            read_i64(&mut self.reader)?; // Start of the buffers
+            let end_pos = read_i64(&mut self.reader)?;
+
+            // Duplicate names get a suffix after a record separator
+            let name = unique_name.split('\u{1e}').next().unwrap_or("");
+            let half_float = grid_type.ends_with("_HalfFloat");
+            let base_type = grid_type.trim_end_matches("_HalfFloat");
+
+            let wanted = match grid_name {
+                Some(wanted) => wanted == name,
+                None => true,
+            };
+            if wanted && base_type == "Tree_float_5_4_3" {
+                self.reader.seek(SeekFrom::Start(grid_pos as u64))?;
+                self.half_float = half_float;
+                return self.read_float_grid();
+            }
+
+            self.reader.seek(SeekFrom::Start(end_pos as u64))?;
+        }
+
+        Err(invalid_data("no matching float grid in the OpenVDB file"))
+    }
+
+    fn read_float_grid(&mut self) -> io::Result<DensityGrid> {
+        self.compression = read_u32(&mut self.reader)?;
+        self.skip_metadata()?;
+        let index_to_world = self.read_transform()?;
+
+        // Topology, the tree structure with the value masks and the tile values
+        let buffer_count = read_i32(&mut self.reader)?;
+        if buffer_count != 1 {
+            return Err(invalid_data("only trees with a single buffer are supported"));
+        }
+        self.background = read_f32(&mut self.reader)?;
+        let root_tiles = read_u32(&mut self.reader)?;
+        let root_children = read_u32(&mut self.reader)?;
+        for _ in 0..root_tiles {
+            read_vec3i(&mut self.reader)?;
+            read_f32(&mut self.reader)?;
+            read_u8(&mut self.reader)?;
+        }
+        for _ in 0..root_children {
+            let origin = read_vec3i(&mut self.reader)?;
+            self.read_internal_topology(origin, UPPER_LOG2, LOWER_LOG2 + LEAF_LOG2)?;
+        }
+
+        // Buffers, the voxel values of every leaf in the same order
+        for i in 0..self.leaves.len() {
+            let value_mask = read_mask(&mut self.reader, 1 << (3 * LEAF_LOG2))?;
+            let values = self.read_values(1 << (3 * LEAF_LOG2), &value_mask)?;
+            self.leaves[i].values = values;
+        }
+
+        self.to_dense(index_to_world)
+    }
+
+    // Internal node of width 2^log2 children, where a child is 2^child_log2 voxels wide
+    fn read_internal_topology(&mut self, origin: Vector3<i32>, log2: u32, child_log2: u32) -> io::Result<()> {
+        let size = 1usize << (3 * log2);
+        let child_mask = read_mask(&mut self.reader, size)?;
+        let value_mask = read_mask(&mut self.reader, size)?;
+        let values = self.read_values(size, &value_mask)?;
+
+        for n in 0..size {
+            let local = vec3((n >> (2 * log2)) as i32, ((n >> log2) & ((1 << log2) - 1)) as i32, (n & ((1 << log2) - 1)) as i32);
+            let child_origin = origin + local * (1 << child_log2);
+
+            if mask_is_on(&child_mask, n) {
+                if child_log2 == LEAF_LOG2 {
+                    // Leaf topology is only its value mask, the buffers repeat it
+                    read_mask(&mut self.reader, 1 << (3 * LEAF_LOG2))?;
+                    self.leaves.push(Leaf { origin: child_origin, values: Vec::new() });
+                } else {
+                    self.read_internal_topology(child_origin, LOWER_LOG2, LEAF_LOG2)?;
+                }
+            } else if mask_is_on(&value_mask, n) && values[n] != 0.0 {
+                self.tiles.push(Tile { origin: child_origin, size: 1 << child_log2, value: values[n] });
+            }
+        }
+        Ok(())
+    }
+
+    // Reads a block of node values, which may leave out inactive values that can be restored from the masks
+    fn read_values(&mut self, count: usize, value_mask: &[u64]) -> io::Result<Vec<f32>> {
+        let metadata = read_u8(&mut self.reader)?;
+
+        let mut inactive0 = if metadata == 0 { self.background } else { -self.background };
+        let mut inactive1 = self.background;
+        if metadata == 2 || metadata == 4 || metadata == 5 {
+            inactive0 = read_f32(&mut self.reader)?;
+            if metadata == 5 {
+                inactive1 = read_f32(&mut self.reader)?;
+            }
+        }
+
+        let selection_mask = if metadata == 3 || metadata == 4 || metadata == 5 {
+            read_mask(&mut self.reader, count)?
+        } else {
+            Vec::new()
+        };
+
+        let active_only = self.compression & VDB_COMPRESS_ACTIVE_MASK != 0 && metadata != 6;
+        let stored = if active_only { (0..count).filter(|&i| mask_is_on(value_mask, i)).count() } else { count };
+        let values = self.read_data(stored)?;
+
+        if stored == count {
+            return Ok(values);
+        }
+
+        let mut next = values.into_iter();
+        Ok((0..count).map(|i| {
+            if mask_is_on(value_mask, i) {
+                next.next().unwrap_or(0.0)
+            } else if !selection_mask.is_empty() && mask_is_on(&selection_mask, i) {
+                inactive1
+            } else {
+                inactive0
+            }
+        }).collect())
+    }
+
+    fn read_data(&mut self, count: usize) -> io::Result<Vec<f32>> {
+        let value_size = if self.half_float { 2 } else { 4 };
+        let byte_count = count * value_size;
+
+        let bytes = if self.compression & VDB_COMPRESS_BLOSC != 0 {
+            // Blosc blocks are only understood when they were stored uncompressed
+            let compressed_size = read_i64(&mut self.reader)?;
+            if compressed_size > 0 {
+                return Err(invalid_data("Blosc compressed OpenVDB grids are not supported, save them with zip compression"));
+            }
+            read_bytes(&mut self.reader, (-compressed_size) as usize)?
+        } else if self.compression & VDB_COMPRESS_ZIP != 0 {
+            let zipped_size = read_i64(&mut self.reader)?;
+            if zipped_size <= 0 {
+                read_bytes(&mut self.reader, (-zipped_size) as usize)?
+            } else {
+                let zipped = read_bytes(&mut self.reader, zipped_size as usize)?;
+                let mut bytes = Vec::with_capacity(byte_count);
+                ZlibDecoder::new(&zipped[..]).read_to_end(&mut bytes)?;
+                bytes
+            }
+        } else {
+            read_bytes(&mut self.reader, byte_count)?
+        };
+
+        if bytes.len() != byte_count {
+            return Err(invalid_data("OpenVDB value block has the wrong size"));
+        }
+
+        Ok(if self.half_float {
+            bytes.chunks_exact(2).map(|b| half_to_f32(u16::from_le_bytes([b[0], b[1]]))).collect()
+        } else {
+            bytes.chunks_exact(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect()
+        })
+    }
+
+    // Index to world transform of the grid, voxel (i, j, k) has its center at index space position (i, j, k)
+    fn read_transform(&mut self) -> io::Result<Matrix4<f32>> {
+        let map_type = read_string(&mut self.reader)?;
+        match map_type.as_str() {
+            "UniformScaleMap" | "ScaleMap" => {
+                let scale = read_vec3d(&mut self.reader)?;
+                for _ in 0..4 { read_vec3d(&mut self.reader)?; }
+                Ok(Matrix4::from_nonuniform_scale(scale.x, scale.y, scale.z))
+            }
+            "UniformScaleTranslateMap" | "ScaleTranslateMap" => {
+                let translation = read_vec3d(&mut self.reader)?;
+                let scale = read_vec3d(&mut self.reader)?;
+                for _ in 0..4 { read_vec3d(&mut self.reader)?; }
+                Ok(Matrix4::from_translation(translation) * Matrix4::from_nonuniform_scale(scale.x, scale.y, scale.z))
+            }
+            "TranslationMap" => {
+                let translation = read_vec3d(&mut self.reader)?;
+                Ok(Matrix4::from_translation(translation))
+            }
+            "AffineMap" | "UnitaryMap" => {
+                // Stored for row vectors, which makes the rows of the file the columns of the matrix here
+                let mut m = [0.0f32; 16];
+                for v in m.iter_mut() {
+                    *v = read_f64(&mut self.reader)? as f32;
+                }
+                Ok(Matrix4::new(m[0], m[1], m[2], m[3], m[4], m[5], m[6], m[7], m[8], m[9], m[10], m[11], m[12], m[13], m[14], m[15]))
+            }
+            _ => Err(invalid_data(&format!("OpenVDB transform {} is not supported", map_type)))
+        }
+    }
+
+    // Metadata entries are prefixed with their size, so they can be skipped without knowing their type
+    fn skip_metadata(&mut self) -> io::Result<()> {
+        let count = read_u32(&mut self.reader)?;
+        for _ in 0..count {
+            read_string(&mut self.reader)?;
+            read_string(&mut self.reader)?;
+            let size = read_u32(&mut self.reader)?;
+            self.reader.seek(SeekFrom::Current(size as i64))?;
+        }
+        Ok(())
+    }
+
+    // Copies the leaves and tiles into a dense grid over their bounding box
+    fn to_dense(&self, index_to_world: Matrix4<f32>) -> io::Result<DensityGrid> {
+        let mut min = vec3(i32::MAX, i32::MAX, i32::MAX);
+        let mut max = vec3(i32::MIN, i32::MIN, i32::MIN);
+        let mut extend = |origin: Vector3<i32>, size: i32| {
+            min = vec3(min.x.min(origin.x), min.y.min(origin.y), min.z.min(origin.z));
+            max = vec3(max.x.max(origin.x + size), max.y.max(origin.y + size), max.z.max(origin.z + size));
+        };
+        for leaf in &self.leaves {
+            extend(leaf.origin, 1 << LEAF_LOG2);
+        }
+        for tile in &self.tiles {
+            extend(tile.origin, tile.size);
+        }
+        if min.x > max.x {
+            return Err(invalid_data("the OpenVDB grid is empty"));
+        }
+
+        let size = max - min;
+        let (nx, ny, nz) = (size.x as usize, size.y as usize, size.z as usize);
+        let mut data = vec![0.0f32; nx * ny * nz];
+        let index = |p: Vector3<i32>| -> usize {
+            let l = p - min;
+            l.x as usize + nx * (l.y as usize + ny * l.z as usize)
+        };
+
+        for tile in &self.tiles {
+            for z in 0..tile.size {
+                for y in 0..tile.size {
+                    for x in 0..tile.size {
+                        data[index(tile.origin + vec3(x, y, z))] = tile.value;
+                    }
+                }
+            }
+        }
+
+        let leaf_size = 1 << LEAF_LOG2;
+        for leaf in &self.leaves {
+            for (n, value) in leaf.values.iter().enumerate() {
+                let local = vec3((n >> (2 * LEAF_LOG2)) as i32, ((n >> LEAF_LOG2) & (leaf_size - 1)) as i32, (n & (leaf_size - 1)) as i32);
+                data[index(leaf.origin + local)] = f32::max(*value, 0.0);
+            }
+        }
+
+        // The unit cube of the dense grid spans from the lower corner of the first voxel to the upper corner of the last one
+        let corner = vec3(min.x as f32 - 0.5, min.y as f32 - 0.5, min.z as f32 - 0.5);
+        let local_to_index = Matrix4::from_translation(corner) * Matrix4::from_nonuniform_scale(nx as f32, ny as f32, nz as f32);
+
+        let mut grid = DensityGrid::new(nx, ny, nz, data, Vector3::zero(), vec3(1.0, 1.0, 1.0));
+        grid.set_transform(index_to_world * local_to_index);
+        Ok(grid)
+    }
+}
+
+fn mask_is_on(mask: &[u64], i: usize) -> bool {
+    mask[i >> 6] & (1u64 << (i & 63)) != 0
+}
+
+fn read_mask(reader: &mut impl Read, bits: usize) -> io::Result<Vec<u64>> {
+    (0..bits.div_ceil(64)).map(|_| read_u64(reader)).collect()
+}
+
+fn half_to_f32(h: u16) -> f32 {
+    let sign = if h & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exponent = ((h >> 10) & 0x1f) as i32;
+    let mantissa = (h & 0x3ff) as f32;
+
+    match exponent {
+        0 => sign * mantissa * f32::powi(2.0, -24),
+        31 => if mantissa == 0.0 { sign * f32::INFINITY } else { f32::NAN },
+        _ => sign * (1.0 + mantissa / 1024.0) * f32::powi(2.0, exponent - 15),
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn read_bytes(reader: &mut impl Read, count: usize) -> io::Result<Vec<u8>> {
+    let mut bytes = vec![0u8; count];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn read_u8(reader: &mut impl Read) -> io::Result<u8> {
+    let mut b = [0u8; 1];
+    reader.read_exact(&mut b)?;
+    Ok(b[0])
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut b = [0u8; 4];
+    reader.read_exact(&mut b)?;
+    Ok(u32::from_le_bytes(b))
+}
+
+fn read_i32(reader: &mut impl Read) -> io::Result<i32> {
+    Ok(read_u32(reader)? as i32)
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut b = [0u8; 8];
+    reader.read_exact(&mut b)?;
+    Ok(u64::from_le_bytes(b))
+}
+
+fn read_i64(reader: &mut impl Read) -> io::Result<i64> {
+    Ok(read_u64(reader)? as i64)
+}
+
+fn read_f32(reader: &mut impl Read) -> io::Result<f32> {
+    Ok(f32::from_bits(read_u32(reader)?))
+}
+
+fn read_f64(reader: &mut impl Read) -> io::Result<f64> {
+    Ok(f64::from_bits(read_u64(reader)?))
+}
+
+fn read_vec3i(reader: &mut impl Read) -> io::Result<Vector3<i32>> {
+    Ok(vec3(read_i32(reader)?, read_i32(reader)?, read_i32(reader)?))
+}
+
+fn read_vec3d(reader: &mut impl Read) -> io::Result<Vector3<f32>> {
+    Ok(vec3(read_f64(reader)? as f32, read_f64(reader)? as f32, read_f64(reader)? as f32))
+}
+
+fn read_string(reader: &mut impl Read) -> io::Result<String> {
+    let length = read_u32(reader)? as usize;
+    let bytes = read_bytes(reader, length)?;
+    String::from_utf8(bytes).map_err(|_| invalid_data("invalid string in OpenVDB file"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Write};
+    use flate2::{write::ZlibEncoder, Compression};
+
+    fn write_string(out: &mut Vec<u8>, text: &str) {
+        out.extend((text.len() as u32).to_le_bytes());
+        out.extend(text.as_bytes());
+    }
+
+    fn write_mask(out: &mut Vec<u8>, bits: usize, on: &[usize]) {
+        let mut words = vec![0u64; bits / 64];
+        for &i in on {
+            words[i >> 6] |= 1 << (i & 63);
+        }
+        for word in words {
+            out.extend(word.to_le_bytes());
+        }
+    }
+
+    fn write_zipped(out: &mut Vec<u8>, values: &[f32]) {
+        let raw: Vec<u8> = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&raw).unwrap();
+        let zipped = encoder.finish().unwrap();
+        out.extend((zipped.len() as i64).to_le_bytes());
+        out.extend(zipped);
+    }
+
+    // A file with one zip compressed density grid holding two neighbouring leaves, every third voxel is active
+    // with the value leaf * 512 + offset. Index space is scaled by 0.5 and moved to (1, 2, 3)
+    fn vdb_file() -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend(VDB_MAGIC.to_le_bytes());
+        out.extend(224u32.to_le_bytes());
+        out.extend(10u32.to_le_bytes());
+        out.extend(0u32.to_le_bytes());
+        out.push(1);
+        out.extend([b'0'; 36]);
+        out.extend(1u32.to_le_bytes());
+        write_string(&mut out, "creator");
+        write_string(&mut out, "string");
+        write_string(&mut out, "test");
+        out.extend(1i32.to_le_bytes());
+
+        write_string(&mut out, "density");
+        write_string(&mut out, "Tree_float_5_4_3");
+        write_string(&mut out, "");
+        let offsets = out.len();
+        out.extend([0u8; 24]);
+        let grid_start = out.len();
+        out.extend((VDB_COMPRESS_ZIP | VDB_COMPRESS_ACTIVE_MASK).to_le_bytes());
+        out.extend(0u32.to_le_bytes());
+        write_string(&mut out, "ScaleTranslateMap");
+        for v in [1.0f64, 2.0, 3.0, 0.5, 0.5, 0.5] {
+            out.extend(v.to_le_bytes());
+        }
+        for _ in 0..12 {
+            out.extend(0.0f64.to_le_bytes());
+        }
+
+        // One buffer, background 0, no tiles and one upper node at the origin
+        out.extend(1i32.to_le_bytes());
+        out.extend(0.0f32.to_le_bytes());
+        out.extend(0u32.to_le_bytes());
+        out.extend(1u32.to_le_bytes());
+        for v in [0i32, 0, 0] {
+            out.extend(v.to_le_bytes());
+        }
+        write_mask(&mut out, 32768, &[0]);
+        write_mask(&mut out, 32768, &[]);
+        out.push(3);
+        write_mask(&mut out, 32768, &[]);
+        write_zipped(&mut out, &[]);
+        // Lower node with leaves at (0, 0, 0) and (8, 0, 0)
+        write_mask(&mut out, 4096, &[0, 256]);
+        write_mask(&mut out, 4096, &[]);
+        out.push(0);
+        write_zipped(&mut out, &[]);
+        write_mask(&mut out, 512, &[]);
+        write_mask(&mut out, 512, &[]);
+
+        for leaf in 0..2 {
+            let on: Vec<usize> = (0..512).filter(|i| i % 3 == 0).collect();
+            write_mask(&mut out, 512, &on);
+            out.push(0);
+            let values: Vec<f32> = on.iter().map(|&i| (leaf * 512 + i) as f32).collect();
+            write_zipped(&mut out, &values);
+        }
+
+        let end = out.len();
+        out[offsets..offsets + 8].copy_from_slice(&(grid_start as i64).to_le_bytes());
+        out[offsets + 16..offsets + 24].copy_from_slice(&(end as i64).to_le_bytes());
+        out
+    }
+
+    fn vol_file(magic: &[u8; 4]) -> Vec<u8> {
+        let mut out = magic.to_vec();
+        for v in [1i32, 2, 2, 2, 1] {
+            out.extend(v.to_le_bytes());
+        }
+        for b in [0.0f32, 0.0, 0.0, 2.0, 2.0, 2.0] {
+            out.extend(b.to_le_bytes());
+        }
+        for i in 0..8 {
+            out.extend((i as f32).to_le_bytes());
+        }
+        out
+    }
+
+    fn temp_file(name: &str, bytes: &[u8]) -> String {
+        let path = std::env::temp_dir().join(format!("{}-{}", std::process::id(), name));
+        std::fs::write(&path, bytes).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn loads_vol_densities_at_voxel_centers() {
+        let path = temp_file("grid.vol", &vol_file(b"VOL\x03"));
+        let grid = GridLoader::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(grid.resolution(), (2, 2, 2));
+        assert_eq!(grid.max_density(), 7.0);
+        assert_eq!(grid.density(vec3(0.5, 0.5, 0.5)), 0.0);
+        assert_eq!(grid.density(vec3(1.5, 1.5, 1.5)), 7.0);
+        assert!((grid.density(vec3(1.0, 1.0, 1.0)) - 3.5).abs() < 1e-5);
+    }
+
+    #[test]
+    fn rejects_vol_files_with_a_wrong_header() {
+        let path = temp_file("bad.vol", &vol_file(b"VOL\x02"));
+        let result = GridLoader::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(error) if error.kind() == io::ErrorKind::InvalidData));
+    }
+
+    #[test]
+    fn loads_zipped_vdb_leaves_with_their_transform() {
+        let grid = VdbReader::new(Cursor::new(vdb_file())).read_grid(None).unwrap();
+        assert_eq!(grid.resolution(), (16, 8, 8));
+        assert_eq!(grid.max_density(), 1022.0);
+
+        let world = |x: f32, y: f32, z: f32| vec3(x, y, z) * 0.5 + vec3(1.0, 2.0, 3.0);
+        // Voxel offsets are x * 64 + y * 8 + z inside a leaf, inactive voxels take the background
+        assert_eq!(grid.density(world(0.0, 0.0, 3.0)), 3.0);
+        assert_eq!(grid.density(world(1.0, 0.0, 0.0)), 0.0);
+        assert_eq!(grid.density(world(8.0, 0.0, 0.0)), 512.0);
+        assert_eq!(grid.density(world(9.0, 0.0, 0.0)), 0.0);
+    }
+
+    #[test]
+    fn rejects_missing_vdb_grids() {
+        let result = VdbReader::new(Cursor::new(vdb_file())).read_grid(Some("temperature"));
+        assert!(result.is_err());
+
+        let mut file = vdb_file();
+        file[0] = 0;
+        assert!(VdbReader::new(Cursor::new(file)).read_grid(None).is_err());
+    }
+}
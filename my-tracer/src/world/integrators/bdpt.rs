@@ -0,0 +1,408 @@
+use core::f32;
+use cgmath::*;
+
+use super::Integrator;
+use crate::world::{material::Material, math::Math, ray::Ray, sampler::Sampler, scene::{Scene, EPSILON}};
+
+#[derive(Copy, Clone, PartialEq)]
+enum VertexKind {
+    Camera,
+    Light,
+    Surface,
+}
+
+#[derive(Copy, Clone)]
+struct Vertex {
+    kind: VertexKind,
+    position: Vector3<f32>,
+    // Faces the side the path arrived from, or the emitting side for light vertices
+    normal: Vector3<f32>,
+    albedo: Vector3<f32>,
+    material: Material,
+    obj_idx: i32,
+    beta: Vector3<f32>,
+    // Mirror or glass vertex, which can't be connected to
+    delta: bool,
+    // Area pdfs of this vertex being generated by the camera (fwd) and light (rev) subpath or the other way around
+    pdf_fwd: f32,
+    pdf_rev: f32,
+}
+
+impl Vertex {
+    fn camera(scene: &Scene) -> Vertex {
+        Vertex {
+            kind: VertexKind::Camera,
+            position: scene.camera().position,
+            normal: scene.camera().ahead(),
+            albedo: Vector3::zero(),
+            material: Material::Diffuse,
+            obj_idx: -1,
+            beta: vec3(1.0, 1.0, 1.0),
+            delta: false,
+            pdf_fwd: 1.0,
+            pdf_rev: 0.0,
+        }
+    }
+
+    fn is_on_light(&self, scene: &Scene) -> bool {
+        self.kind == VertexKind::Surface && scene.primitive(self.obj_idx).is_light()
+    }
+
+    // Value of the BSDF (or emission profile for lights) scattering from prev towards next
+    fn f(&self, prev: Vector3<f32>, next: Vector3<f32>) -> Vector3<f32> {
+        let wi = (next - self.position).normalize();
+        match self.kind {
+            VertexKind::Surface => {
+                let wo = (prev - self.position).normalize();
+                self.material.eval(self.albedo, self.normal, wo, wi)
+            }
+            VertexKind::Light => if self.normal.dot(wi) > 0.0 { vec3(1.0, 1.0, 1.0) } else { Vector3::zero() },
+            VertexKind::Camera => Vector3::zero(),
+        }
+    }
+
+    // Area pdf of this vertex sampling next, having been reached from prev
+    fn pdf(&self, scene: &Scene, prev: Vector3<f32>, next: &Vertex) -> f32 {
+        let wi = (next.position - self.position).normalize();
+        let pdf_dir = match self.kind {
+            VertexKind::Surface => {
+                if self.is_on_light(scene) {
+                    return self.light_emit_pdf(next);
+                }
+                let wo = (prev - self.position).normalize();
+                self.material.pdf(self.normal, wo, wi)
+            }
+            VertexKind::Light => return self.light_emit_pdf(next),
+            VertexKind::Camera => scene.camera().direction_pdf(wi),
+        };
+        Vertex::convert_density(pdf_dir, self, next)
+    }
+
+    // Area pdf of an emitter at this vertex sending its light subpath towards next
+    fn light_emit_pdf(&self, next: &Vertex) -> f32 {
+        let wi = (next.position - self.position).normalize();
+        let pdf_dir = f32::max(0.0, self.normal.dot(wi)) * f32::consts::FRAC_1_PI;
+        Vertex::convert_density(pdf_dir, self, next)
+    }
+
+    // Area pdf of a light subpath starting at this vertex
+    fn light_origin_pdf(&self, scene: &Scene) -> f32 {
+        scene.primitive(self.obj_idx).area_pdf() / scene.lights().len() as f32
+    }
+
+    fn convert_density(pdf_dir: f32, from: &Vertex, to: &Vertex) -> f32 {
+        let d = to.position - from.position;
+        let dist2 = d.magnitude2();
+        if dist2 == 0.0 { return 0.0; }
+
+        let mut pdf = pdf_dir / dist2;
+        if to.kind != VertexKind::Camera {
+            pdf *= f32::abs(to.normal.dot(d / dist2.sqrt()));
+        }
+        pdf
+    }
+}
+
+// Bidirectional path tracer, connects every vertex of a camera subpath with every vertex of a light subpath
+pub struct BdptIntegrator {
+    max_depth: u32,
+}
+
+impl BdptIntegrator {
+    pub fn new(max_depth: u32) -> BdptIntegrator {
+        BdptIntegrator { max_depth: max_depth }
+    }
+
+    // Extends path by sampling BSDFs, the skybox contributions of camera subpaths are added to env
+    fn random_walk(&self, scene: &Scene, mut ray: Ray, mut beta: Vector3<f32>, mut pdf_dir: f32, path: &mut Vec<Vertex>, max_vertices: usize, is_camera_path: bool, env: &mut Vector3<f32>, sampler: &mut dyn Sampler) {
+        let mut specular_bounce = false;
+
+        loop {
+            // Light subpaths take the dimensions after the longest camera subpath
+            sampler.start_bounce(if is_camera_path { path.len() as u32 - 1 } else { self.max_depth + 2 + path.len() as u32 });
+            scene.intersect_ray(&mut ray, sampler);
+
+            if ray.obj_idx < 0 {
+                if is_camera_path {
+                    let weight = if path.len() == 1 || specular_bounce { 1.0 } else { Math::power_heuristic(pdf_dir, scene.skybox().pdf(ray.dir)) };
+                    *env += beta.mul_element_wise(scene.skybox().color(ray.dir)) * weight;
+                }
+                break;
+            }
+
+            let primitive = scene.primitive(ray.obj_idx);
+            let I = ray.origin + ray.dir * ray.dist;
+            let mut normal = primitive.get_normal(I).normalize();
+            let front_face = normal.dot(ray.dir) <= 0.0;
+            if !front_face { normal = -normal; }
+            normal = primitive.get_shading_normal(I, normal, -ray.dir);
+
+            let prev = path.len() - 1;
+            let material = primitive.material_at(I).seen_from(front_face);
+            let mut vertex = Vertex {
+                kind: VertexKind::Surface,
+                position: I,
+                normal: normal,
+                albedo: primitive.get_albedo(I),
+                material: material,
+                obj_idx: ray.obj_idx,
+                beta: beta,
+                delta: material.is_specular(),
+                pdf_fwd: 0.0,
+                pdf_rev: 0.0,
+            };
+            vertex.pdf_fwd = Vertex::convert_density(pdf_dir, &path[prev], &vertex);
+
+            // Emitters don't scatter, camera subpaths keep them for the s = 0 strategy
+            if primitive.is_light() {
+                if is_camera_path { path.push(vertex); }
+                break;
+            }
+            path.push(vertex);
+            if path.len() >= max_vertices { break; }
+
+            if is_camera_path && !vertex.delta {
+                *env += beta.mul_element_wise(self.sample_skybox(scene, &vertex, path[prev].position, sampler));
+            }
+
+            let wo = -ray.dir;
+            let bsdf_sample = match vertex.material.sample(vertex.albedo, normal, wo, front_face, sampler) {
+                Some(s) => s,
+                None => break
+            };
+            let R = bsdf_sample.wi;
+
+            beta = beta.mul_element_wise(bsdf_sample.f * (f32::abs(normal.dot(R)) / bsdf_sample.pdf));
+
+            // Delta lobes get a pdf of zero, mis_weight skips the strategies that would need to connect to them
+            specular_bounce = bsdf_sample.is_specular;
+            pdf_dir = if specular_bounce { 0.0 } else { bsdf_sample.pdf };
+            let pdf_rev_dir = if specular_bounce { 0.0 } else { vertex.material.pdf(normal, R, wo) };
+            path[prev].pdf_rev = Vertex::convert_density(pdf_rev_dir, &vertex, &path[prev]);
+
+            ray = Ray::new(I + R * EPSILON, R, f32::MAX);
+        }
+    }
+
+    // The skybox isn't part of the light subpaths, so it is only weighted between light and BSDF sampling
+    fn sample_skybox(&self, scene: &Scene, vertex: &Vertex, prev: Vector3<f32>, sampler: &mut dyn Sampler) -> Vector3<f32> {
+        let (L, Le, pdf) = scene.skybox().sample(sampler);
+        let cos_i = if vertex.material.is_transmissive() { f32::abs(vertex.normal.dot(L)) } else { vertex.normal.dot(L) };
+        if pdf <= 0.0 || cos_i <= 0.0 { return Vector3::zero(); }
+
+        let mut shadow_ray = Ray::new(vertex.position + L * EPSILON, L, f32::MAX);
+        scene.intersect_ray(&mut shadow_ray, sampler);
+        if shadow_ray.obj_idx != -1 { return Vector3::zero(); }
+
+        let wo = (prev - vertex.position).normalize();
+        let BRDF = vertex.material.eval(vertex.albedo, vertex.normal, wo, L);
+        let weight = Math::power_heuristic(pdf, vertex.material.pdf(vertex.normal, wo, L));
+
+        Le.mul_element_wise(BRDF) * (cos_i * weight / pdf)
+    }
+
+    fn generate_light_subpath(&self, scene: &Scene, path: &mut Vec<Vertex>, sampler: &mut dyn Sampler) {
+        let lights = scene.lights();
+        if lights.is_empty() { return; }
+
+        sampler.start_bounce(self.max_depth + 2);
+        let light_idx = ((sampler.next_f32() * lights.len() as f32) as usize).min(lights.len() - 1);
+        let light = scene.primitive(lights[light_idx]);
+        let (position, normal) = match light.sample_area(sampler) {
+            Some(s) => s,
+            None => return
+        };
+
+        let pdf_pos = light.area_pdf() / lights.len() as f32;
+        let Le = light.get_albedo(position);
+
+        path.push(Vertex {
+            kind: VertexKind::Light,
+            position: position,
+            normal: normal,
+            albedo: Le,
+            material: Material::Diffuse,
+            obj_idx: lights[light_idx],
+            beta: Le / pdf_pos,
+            delta: false,
+            pdf_fwd: pdf_pos,
+            pdf_rev: 0.0,
+        });
+
+        // Cosine weighted emission
+        let dir = Math::random_cosine_hemisphere_vectorf32(sampler, normal);
+        let pdf_dir = normal.dot(dir) * f32::consts::FRAC_1_PI;
+        if pdf_dir <= 0.0 { return; }
+
+        let beta = path[0].beta * (normal.dot(dir) / pdf_dir);
+        let ray = Ray::new(position + dir * EPSILON, dir, f32::MAX);
+        let mut unused = Vector3::zero();
+        self.random_walk(scene, ray, beta, pdf_dir, path, self.max_depth as usize + 1, false, &mut unused, sampler);
+    }
+
+    // Contribution of the path made of the first s light and first t camera vertices, t = 1 results are splatted
+    fn connect(&self, scene: &Scene, light_path: &[Vertex], camera_path: &[Vertex], s: usize, t: usize, sampler: &mut dyn Sampler) -> Vector3<f32> {
+        let pt = &camera_path[t - 1];
+        if t > 1 && s != 0 && pt.is_on_light(scene) { return Vector3::zero(); }
+
+        if s == 0 {
+            if !pt.is_on_light(scene) { return Vector3::zero(); }
+            let L = pt.beta.mul_element_wise(pt.albedo);
+            return L * self.mis_weight(scene, light_path, camera_path, s, t);
+        }
+
+        let qs = &light_path[s - 1];
+        let qs_prev = if s > 1 { light_path[s - 2].position } else { qs.position };
+
+        if t == 1 {
+            let camera = scene.camera();
+            let (x, y) = match camera.project(qs.position) {
+                Some(p) => p,
+                None => return Vector3::zero()
+            };
+
+            let d = camera.position - qs.position;
+            let dist2 = d.magnitude2();
+            let dir = d / dist2.sqrt();
+            let cos_camera = -dir.dot(camera.ahead());
+            let cos_q = f32::abs(qs.normal.dot(dir));
+
+            let f = qs.f(qs_prev, camera.position);
+            if f == Vector3::zero() || !self.visible(scene, qs.position, camera.position, sampler) { return Vector3::zero(); }
+
+            // Pinhole importance times the geometry term
+            let importance = cos_q / (dist2 * camera.image_plane_area() * cos_camera * cos_camera * cos_camera);
+            let L = qs.beta.mul_element_wise(f) * importance;
+
+            // Light subpaths only start at the traced pixels, but land anywhere
+            scene.splat(x, y, L * (self.mis_weight(scene, light_path, camera_path, s, t) * scene.splat_scale()));
+            return Vector3::zero();
+        }
+
+        let pt_prev = camera_path[t - 2].position;
+        let f = qs.f(qs_prev, pt.position).mul_element_wise(pt.f(pt_prev, qs.position));
+        if f == Vector3::zero() { return Vector3::zero(); }
+
+        let d = pt.position - qs.position;
+        let dist2 = d.magnitude2();
+        let dir = d / dist2.sqrt();
+        let G = f32::abs(qs.normal.dot(dir)) * f32::abs(pt.normal.dot(dir)) / dist2;
+
+        if !self.visible(scene, qs.position, pt.position, sampler) { return Vector3::zero(); }
+
+        let L = qs.beta.mul_element_wise(f).mul_element_wise(pt.beta) * G;
+        L * self.mis_weight(scene, light_path, camera_path, s, t)
+    }
+
+    fn visible(&self, scene: &Scene, a: Vector3<f32>, b: Vector3<f32>, sampler: &mut dyn Sampler) -> bool {
+        let d = b - a;
+        let dist = d.magnitude();
+        let dir = d / dist;
+        let mut shadow_ray = Ray::new(a + dir * EPSILON, dir, dist - EPSILON * 2.0);
+        scene.intersect_ray(&mut shadow_ray, sampler);
+        shadow_ray.obj_idx == -1
+    }
+
+    // Power heuristic over every (s, t) strategy that could have created the same path
+    fn mis_weight(&self, scene: &Scene, light_path: &[Vertex], camera_path: &[Vertex], s: usize, t: usize) -> f32 {
+        if s + t == 2 { return 1.0; }
+
+        let camera = Vertex::camera(scene);
+        let pt = &camera_path[t - 1];
+        let qs = if s > 0 { Some(&light_path[s - 1]) } else { None };
+
+        // Emitters that can't be sampled (like planes) are only ever found by the camera subpath
+        if s == 0 && pt.light_origin_pdf(scene) == 0.0 { return 1.0; }
+
+        // Reverse pdfs of the vertices around the connection, as if the path had been sampled from the other side
+        let pt_rev = match qs {
+            None => pt.light_origin_pdf(scene),
+            Some(qs) => {
+                let qs_prev = if s > 1 { light_path[s - 2].position } else { qs.position };
+                qs.pdf(scene, qs_prev, pt)
+            }
+        };
+
+        let pt_prev_rev = if t > 1 {
+            match qs {
+                None => pt.light_emit_pdf(&camera_path[t - 2]),
+                Some(qs) => pt.pdf(scene, qs.position, &camera_path[t - 2]),
+            }
+        } else { 0.0 };
+
+        let qs_rev = match qs {
+            None => 0.0,
+            Some(qs) => if t == 1 { camera.pdf(scene, camera.position, qs) } else { pt.pdf(scene, camera_path[t - 2].position, qs) },
+        };
+
+        let qs_prev_rev = match qs {
+            Some(qs) if s > 1 => {
+                let connected = if t == 1 { &camera } else { pt };
+                qs.pdf(scene, connected.position, &light_path[s - 2])
+            }
+            _ => 0.0,
+        };
+
+        let remap = |pdf: f32| if pdf != 0.0 { pdf } else { 1.0 };
+
+        let mut sum = 0.0;
+
+        let mut ri = 1.0;
+        for i in (1..t).rev() {
+            let pdf_rev = if i == t - 1 { pt_rev } else if i == t - 2 { pt_prev_rev } else { camera_path[i].pdf_rev };
+            ri *= remap(pdf_rev) / remap(camera_path[i].pdf_fwd);
+            if !camera_path[i].delta && !camera_path[i - 1].delta {
+                sum += ri * ri;
+            }
+        }
+
+        let mut ri = 1.0;
+        for i in (0..s).rev() {
+            let pdf_rev = if i == s - 1 { qs_rev } else if i == s - 2 { qs_prev_rev } else { light_path[i].pdf_rev };
+            ri *= remap(pdf_rev) / remap(light_path[i].pdf_fwd);
+            if !light_path[i].delta && !(i > 0 && light_path[i - 1].delta) {
+                sum += ri * ri;
+            }
+        }
+
+        1.0 / (1.0 + sum)
+    }
+}
+
+impl Integrator for BdptIntegrator {
+    fn name(&self) -> &'static str {
+        "Bidirectional path tracer"
+    }
+
+    fn supports_adaptive_sampling(&self) -> bool {
+        false
+    }
+
+    fn radiance(&self, scene: &Scene, ray: &mut Ray, sampler: &mut dyn Sampler) -> Vector3<f32> {
+        let max_depth = self.max_depth as usize;
+        let mut E = Vector3::zero();
+
+        let mut camera_path = Vec::with_capacity(max_depth + 2);
+        camera_path.push(Vertex::camera(scene));
+        let pdf_dir = scene.camera().direction_pdf(ray.dir);
+        self.random_walk(scene, *ray, vec3(1.0, 1.0, 1.0), pdf_dir, &mut camera_path, max_depth + 2, true, &mut E, sampler);
+
+        let mut light_path = Vec::with_capacity(max_depth + 1);
+        self.generate_light_subpath(scene, &mut light_path, sampler);
+
+        for t in 1..camera_path.len() + 1 {
+            for s in 0..light_path.len() + 1 {
+                let depth = s as i32 + t as i32 - 2;
+                if (s == 1 && t == 1) || depth < 0 || depth > max_depth as i32 { continue; }
+
+                E += self.connect(scene, &light_path, &camera_path, s, t, sampler);
+            }
+        }
+
+        E
+    }
+
+    fn ui(&mut self, ui: &mut egui::Ui) -> bool {
+        ui.add(egui::Slider::new(&mut self.max_depth, 1..=32).text("Max depth")).changed()
+    }
+}
@@ -0,0 +1,122 @@
+use cgmath::*;
+
+use super::Integrator;
+use crate::world::{math::Math, ray::Ray, sampler::Sampler, scene::{Scene, EPSILON}};
+
+// Geometric normal mapped from [-1, 1] to [0, 1]
+pub struct NormalIntegrator;
+
+impl Integrator for NormalIntegrator {
+    fn name(&self) -> &'static str {
+        "Normals"
+    }
+
+    fn radiance(&self, scene: &Scene, ray: &mut Ray, sampler: &mut dyn Sampler) -> Vector3<f32> {
+        scene.intersect_ray(ray, sampler);
+        if ray.obj_idx < 0 { return Vector3::zero(); }
+
+        let I = ray.origin + ray.dir * ray.dist;
+        let primitive = scene.primitive(ray.obj_idx);
+        let normal = primitive.get_shading_normal(I, primitive.get_normal(I).normalize(), -ray.dir);
+        normal * 0.5 + vec3(0.5, 0.5, 0.5)
+    }
+}
+
+// Surface color without any lighting, the skybox is shown as is
+pub struct AlbedoIntegrator;
+
+impl Integrator for AlbedoIntegrator {
+    fn name(&self) -> &'static str {
+        "Albedo"
+    }
+
+    fn radiance(&self, scene: &Scene, ray: &mut Ray, sampler: &mut dyn Sampler) -> Vector3<f32> {
+        scene.intersect_ray(ray, sampler);
+        if ray.obj_idx < 0 { return scene.skybox().color(ray.dir); }
+
+        let I = ray.origin + ray.dir * ray.dist;
+        scene.primitive(ray.obj_idx).get_albedo(I)
+    }
+}
+
+// Hit distance as grayscale, white up close and black at max_distance or further
+pub struct DepthIntegrator {
+    max_distance: f32,
+}
+
+impl DepthIntegrator {
+    pub fn new(max_distance: f32) -> DepthIntegrator {
+        DepthIntegrator { max_distance: max_distance }
+    }
+}
+
+impl Integrator for DepthIntegrator {
+    fn name(&self) -> &'static str {
+        "Depth"
+    }
+
+    fn radiance(&self, scene: &Scene, ray: &mut Ray, sampler: &mut dyn Sampler) -> Vector3<f32> {
+        scene.intersect_ray(ray, sampler);
+        if ray.obj_idx < 0 { return Vector3::zero(); }
+
+        let d = 1.0 - f32::min(ray.dist / self.max_distance, 1.0);
+        vec3(d, d, d)
+    }
+
+    fn ui(&mut self, ui: &mut egui::Ui) -> bool {
+        ui.add(egui::Slider::new(&mut self.max_distance, 0.1..=100.0).text("Max distance")).changed()
+    }
+}
+
+// Gives every primitive a random but stable color
+pub struct PrimitiveIndexIntegrator;
+
+impl Integrator for PrimitiveIndexIntegrator {
+    fn name(&self) -> &'static str {
+        "Primitive index"
+    }
+
+    fn radiance(&self, scene: &Scene, ray: &mut Ray, sampler: &mut dyn Sampler) -> Vector3<f32> {
+        scene.intersect_ray(ray, sampler);
+        if ray.obj_idx < 0 { return Vector3::zero(); }
+
+        let hash = Math::wang_hash(ray.obj_idx as u32 + 1);
+        Math::rgb8_to_rgbf32(hash)
+    }
+}
+
+// Fraction of the cosine weighted hemisphere that is unoccluded within radius
+pub struct AmbientOcclusionIntegrator {
+    radius: f32,
+}
+
+impl AmbientOcclusionIntegrator {
+    pub fn new(radius: f32) -> AmbientOcclusionIntegrator {
+        AmbientOcclusionIntegrator { radius: radius }
+    }
+}
+
+impl Integrator for AmbientOcclusionIntegrator {
+    fn name(&self) -> &'static str {
+        "Ambient occlusion"
+    }
+
+    fn radiance(&self, scene: &Scene, ray: &mut Ray, sampler: &mut dyn Sampler) -> Vector3<f32> {
+        scene.intersect_ray(ray, sampler);
+        if ray.obj_idx < 0 { return vec3(1.0, 1.0, 1.0); }
+
+        let I = ray.origin + ray.dir * ray.dist;
+        let mut normal = scene.primitive(ray.obj_idx).get_normal(I).normalize();
+        if normal.dot(ray.dir) > 0.0 { normal = -normal; }
+
+        let R = Math::random_cosine_hemisphere_vectorf32(sampler, normal);
+        let mut occlusion_ray = Ray::new(I + R * EPSILON, R, self.radius);
+        scene.intersect_ray(&mut occlusion_ray, sampler);
+
+        if occlusion_ray.obj_idx < 0 { vec3(1.0, 1.0, 1.0) } else { Vector3::zero() }
+    }
+
+    fn ui(&mut self, ui: &mut egui::Ui) -> bool {
+        ui.add(egui::Slider::new(&mut self.radius, 0.01..=20.0).text("Radius")).changed()
+    }
+}
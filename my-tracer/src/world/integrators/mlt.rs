@@ -0,0 +1,270 @@
+use core::f32;
+use cgmath::*;
+use rayon::prelude::*;
+
+use super::{path::PathIntegrator, Integrator};
+use crate::world::{math::Math, ray::Ray, sampler::Sampler, sampling::Distribution1D, scene::Scene};
+
+#[derive(Copy, Clone)]
+struct PrimarySample {
+    value: f32,
+    last_modification: u64,
+    value_backup: f32,
+    modification_backup: u64,
+}
+
+// Sample stream in primary sample space that mutates the numbers handed out during the previous iteration.
+// Numbers are only mutated once they are asked for, so paths of different lengths can share one chain.
+struct MltSampler {
+    rng: u32,
+    sigma: f32,
+    large_step_probability: f32,
+    samples: Vec<PrimarySample>,
+    current_iteration: u64,
+    large_step: bool,
+    last_large_step_iteration: u64,
+    sample_index: usize,
+}
+
+impl MltSampler {
+    fn new(seed: u32, sigma: f32, large_step_probability: f32) -> MltSampler {
+        MltSampler {
+            rng: Math::wang_hash(seed.wrapping_mul(17).wrapping_add(1)) | 1,
+            sigma: sigma,
+            large_step_probability: large_step_probability,
+            samples: Vec::new(),
+            current_iteration: 0,
+            large_step: true,
+            last_large_step_iteration: 0,
+            sample_index: 0,
+        }
+    }
+
+    fn start_iteration(&mut self) {
+        self.current_iteration += 1;
+        self.large_step = self.rng.next_f32() < self.large_step_probability;
+        self.sample_index = 0;
+    }
+
+    fn accept(&mut self) {
+        if self.large_step {
+            self.last_large_step_iteration = self.current_iteration;
+        }
+    }
+
+    fn reject(&mut self) {
+        for sample in self.samples.iter_mut() {
+            if sample.last_modification == self.current_iteration {
+                sample.value = sample.value_backup;
+                sample.last_modification = sample.modification_backup;
+            }
+        }
+        self.current_iteration -= 1;
+    }
+
+    // Brings sample i up to date with every mutation it missed while it wasn't used
+    fn ensure_ready(&mut self, i: usize) {
+        if i >= self.samples.len() {
+            self.samples.resize(i + 1, PrimarySample { value: 0.0, last_modification: 0, value_backup: 0.0, modification_backup: 0 });
+        }
+
+        let mut sample = self.samples[i];
+
+        if sample.last_modification < self.last_large_step_iteration {
+            sample.value = self.rng.next_f32();
+            sample.last_modification = self.last_large_step_iteration;
+        }
+
+        sample.value_backup = sample.value;
+        sample.modification_backup = sample.last_modification;
+
+        if self.large_step {
+            sample.value = self.rng.next_f32();
+        } else {
+            // The small steps that were skipped add up to a single normal step with a wider sigma
+            let small_steps = (self.current_iteration - sample.last_modification) as f32;
+            let sigma = self.sigma * small_steps.sqrt();
+
+            let u1 = f32::max(self.rng.next_f32(), 1e-7);
+            let u2 = self.rng.next_f32();
+            let normal = f32::sqrt(-2.0 * u1.ln()) * f32::cos(2.0 * f32::consts::PI * u2);
+
+            sample.value += normal * sigma;
+            sample.value -= sample.value.floor();
+            sample.value = sample.value.min(1.0 - f32::EPSILON);
+        }
+        sample.last_modification = self.current_iteration;
+
+        self.samples[i] = sample;
+    }
+}
+
+impl Sampler for MltSampler {
+    fn next_f32(&mut self) -> f32 {
+        let i = self.sample_index;
+        self.sample_index += 1;
+        self.ensure_ready(i);
+        self.samples[i].value
+    }
+}
+
+struct Chain {
+    sampler: MltSampler,
+    rng: u32,
+    x: f32,
+    y: f32,
+    L: Vector3<f32>,
+}
+
+// Primary sample space Metropolis light transport (Kelemen et al.) around the path tracer.
+// The chains keep running across frames, every frame adds their splats as one more sample per pixel.
+pub struct MltIntegrator {
+    path: PathIntegrator,
+    // Paths traced by the bootstrap for every pixel of the image, it runs again whenever the accumulation restarts
+    bootstrap_samples_per_pixel: f32,
+    chain_count: u32,
+    mutations_per_pixel: u32,
+    sigma: f32,
+    large_step_probability: f32,
+    chains: Vec<Chain>,
+    // Average path contribution over primary sample space, estimated by the bootstrap
+    b: f32,
+}
+
+impl MltIntegrator {
+    pub fn new(bootstrap_samples_per_pixel: f32, chain_count: u32, mutations_per_pixel: u32, sigma: f32, large_step_probability: f32) -> MltIntegrator {
+        MltIntegrator {
+            path: PathIntegrator,
+            bootstrap_samples_per_pixel: bootstrap_samples_per_pixel,
+            chain_count: chain_count,
+            mutations_per_pixel: mutations_per_pixel,
+            sigma: sigma,
+            large_step_probability: large_step_probability,
+            chains: Vec::new(),
+            b: 0.0,
+        }
+    }
+
+    // Traces one path, the first two numbers of the stream pick the position on the screen
+    fn evaluate(&self, scene: &Scene, sampler: &mut MltSampler) -> (f32, f32, Vector3<f32>) {
+        let x = sampler.next_f32();
+        let y = sampler.next_f32();
+        let mut ray = scene.camera().calculate_primary_ray(x, y);
+        let L = self.path.radiance(scene, &mut ray, sampler);
+        (x, y, L)
+    }
+
+    fn contribution(L: Vector3<f32>) -> f32 {
+        let c = Math::luminance(L);
+        if c.is_finite() { f32::max(c, 0.0) } else { 0.0 }
+    }
+
+    // Estimates b and starts the chains in states picked proportional to their contribution
+    fn bootstrap(&mut self, scene: &Scene, seed: u32) {
+        let (width, height) = scene.resolution();
+        let samples = ((width * height) as f32 * self.bootstrap_samples_per_pixel) as u32;
+        let weights: Vec<f32> = (0..samples.max(self.chain_count)).into_par_iter().map(|i| {
+            let mut sampler = MltSampler::new(seed.wrapping_add(i), self.sigma, self.large_step_probability);
+            let (_, _, L) = self.evaluate(scene, &mut sampler);
+            MltIntegrator::contribution(L)
+        }).collect();
+
+        let distribution = Distribution1D::new(weights);
+        self.b = distribution.integral();
+
+        let mut rng = Math::wang_hash(seed) | 1;
+        let starts: Vec<(u32, u32)> = (0..self.chain_count).map(|c| {
+            let (_, _, idx) = distribution.sample_continuous(&mut rng);
+            (idx as u32, c)
+        }).collect();
+
+        self.chains = starts.into_par_iter().map(|(idx, c)| {
+            let mut sampler = MltSampler::new(seed.wrapping_add(idx), self.sigma, self.large_step_probability);
+            let (x, y, L) = self.evaluate(scene, &mut sampler);
+            Chain {
+                sampler: sampler,
+                rng: Math::wang_hash(seed ^ c.wrapping_mul(0x9e3779b9)) | 1,
+                x: x,
+                y: y,
+                L: L,
+            }
+        }).collect();
+    }
+}
+
+impl Integrator for MltIntegrator {
+    fn name(&self) -> &'static str {
+        "Metropolis light transport"
+    }
+
+    fn supports_adaptive_sampling(&self) -> bool {
+        false
+    }
+
+    // The chains keep paths found for the previous camera, they are bootstrapped again when it moves
+    fn supports_reprojection(&self) -> bool {
+        false
+    }
+
+    fn traces_pixels(&self) -> bool {
+        false
+    }
+
+    fn begin_frame(&mut self, scene: &Scene, frame: u32, seed: u32) {
+        if frame == 0 || self.chains.is_empty() {
+            self.bootstrap(scene, seed);
+        }
+        if self.b <= 0.0 { return; }
+
+        let (width, height) = scene.resolution();
+        let total_mutations = (width * height) as u64 * self.mutations_per_pixel as u64;
+        let mutations_per_chain = (total_mutations / self.chains.len() as u64).max(1);
+
+        // Every splat is a fraction of a sample, so that a full frame adds up to one sample per pixel
+        let scale = self.b * (width * height) as f32 / (mutations_per_chain * self.chains.len() as u64) as f32;
+
+        let mut chains = std::mem::take(&mut self.chains);
+        chains.par_iter_mut().for_each(|chain| {
+            for _ in 0..mutations_per_chain {
+                chain.sampler.start_iteration();
+                let (x, y, L) = self.evaluate(scene, &mut chain.sampler);
+
+                let current = MltIntegrator::contribution(chain.L);
+                let proposed = MltIntegrator::contribution(L);
+                let accept = if current > 0.0 { f32::min(1.0, proposed / current) } else { 1.0 };
+
+                // Both states are splatted, weighted by how likely the chain is to move (expected values)
+                if proposed > 0.0 {
+                    scene.splat(x, y, L * (accept * scale / proposed));
+                }
+                if current > 0.0 {
+                    scene.splat(chain.x, chain.y, chain.L * ((1.0 - accept) * scale / current));
+                }
+
+                if chain.rng.next_f32() < accept {
+                    chain.x = x;
+                    chain.y = y;
+                    chain.L = L;
+                    chain.sampler.accept();
+                } else {
+                    chain.sampler.reject();
+                }
+            }
+        });
+        self.chains = chains;
+    }
+
+    // Everything reaches the image through splats
+    fn radiance(&self, _scene: &Scene, _ray: &mut Ray, _sampler: &mut dyn Sampler) -> Vector3<f32> {
+        Vector3::zero()
+    }
+
+    fn ui(&mut self, ui: &mut egui::Ui) -> bool {
+        let mut changed = false;
+        changed |= ui.add(egui::Slider::new(&mut self.mutations_per_pixel, 1..=16).text("Mutations per pixel")).changed();
+        changed |= ui.add(egui::Slider::new(&mut self.chain_count, 16..=10000).text("Chains")).changed();
+        changed |= ui.add(egui::Slider::new(&mut self.sigma, 0.001..=0.1).logarithmic(true).text("Small step sigma")).changed();
+        changed |= ui.add(egui::Slider::new(&mut self.large_step_probability, 0.0..=1.0).text("Large step probability")).changed();
+        changed
+    }
+}
@@ -0,0 +1,78 @@
+use cgmath::*;
+
+use super::{aov::AovSample, lpe::LightPaths, ray::Ray, sampler::Sampler, scene::Scene};
+
+pub mod path;
+pub mod bdpt;
+pub mod photon;
+pub mod mlt;
+pub mod restir;
+pub mod spectral;
+pub mod debug;
+
+// Computes the color seen along a primary ray, Scene::update calls it once per pixel every frame
+pub trait Integrator: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    // Called before any pixel of a frame is traced, frame counts up from 0 since the last reset of the accumulation
+    fn begin_frame(&mut self, _scene: &Scene, _frame: u32, _seed: u32) {}
+
+    fn radiance(&self, scene: &Scene, ray: &mut Ray, sampler: &mut dyn Sampler) -> Vector3<f32>;
+
+    // Color of pixel idx for this frame, integrators that work on the whole image at once can look it up here
+    fn pixel(&self, scene: &Scene, _idx: usize, ray: &mut Ray, sampler: &mut dyn Sampler) -> Vector3<f32> {
+        self.radiance(scene, ray, sampler)
+    }
+
+    // Same as pixel, also fills the AOVs of the first hit and splits the color into the lighting AOVs and the light passes,
+    // the path to follow was started by the caller. Integrators that don't follow how the light reached the camera leave
+    // the lighting black, by default the first hit is found again after the pixel is traced.
+    fn pixel_with_aovs(&self, scene: &Scene, idx: usize, ray: &mut Ray, sampler: &mut dyn Sampler, aov: &mut AovSample, _paths: &mut LightPaths) -> Vector3<f32> {
+        let primary_ray = *ray;
+        let color = self.pixel(scene, idx, ray, sampler);
+        *aov = scene.primary_aovs(&primary_ray, sampler);
+        color
+    }
+
+    // True for integrators that split their color into the lighting AOVs and the light passes in pixel_with_aovs, the others leave them black
+    fn splits_lighting(&self) -> bool {
+        false
+    }
+
+    // False for integrators that need every pixel traced once per frame, like those that splat or reuse samples across pixels
+    fn supports_adaptive_sampling(&self) -> bool {
+        true
+    }
+
+    // False for integrators that only add to the image through splats, the pixels are then only traced for their AOVs
+    fn traces_pixels(&self) -> bool {
+        true
+    }
+
+    // False for integrators whose frames depend on how many came before, so their history can't be carried to a moved camera
+    fn supports_reprojection(&self) -> bool {
+        true
+    }
+
+    // Draws the integrator settings, returns true if they changed and the accumulated image is outdated
+    fn ui(&mut self, _ui: &mut egui::Ui) -> bool {
+        false
+    }
+}
+
+// Every integrator that can be picked from the UI, the first one is used by default
+pub fn all() -> Vec<Box<dyn Integrator>> {
+    vec![
+        Box::new(path::PathIntegrator),
+        Box::new(spectral::SpectralPathIntegrator),
+        Box::new(bdpt::BdptIntegrator::new(8)),
+        Box::new(photon::PhotonMappingIntegrator::new(200000, 0.1, 0.7)),
+        Box::new(mlt::MltIntegrator::new(0.25, 1000, 1, 0.01, 0.3)),
+        Box::new(restir::RestirIntegrator::new(32, 5, 30.0)),
+        Box::new(debug::NormalIntegrator),
+        Box::new(debug::AlbedoIntegrator),
+        Box::new(debug::DepthIntegrator::new(20.0)),
+        Box::new(debug::PrimitiveIndexIntegrator),
+        Box::new(debug::AmbientOcclusionIntegrator::new(1.0)),
+    ]
+}
@@ -0,0 +1,227 @@
+use core::f32;
+use cgmath::*;
+
+use super::Integrator;
+use crate::world::{aov::{AovSample, LightingAovs}, lpe::{Event, LightPaths, Scattering}, material::Material, math::Math, ray::Ray, sampler::Sampler, scene::{Scene, EPSILON}, subsurface::Subsurface};
+
+// Unidirectional path tracer with next event estimation and MIS
+pub struct PathIntegrator;
+
+impl Integrator for PathIntegrator {
+    fn name(&self) -> &'static str {
+        "Path tracer"
+    }
+
+    fn radiance(&self, scene: &Scene, ray: &mut Ray, sampler: &mut dyn Sampler) -> Vector3<f32> {
+        PathIntegrator::trace(scene, ray, sampler, None, &mut LightingAovs::zero(), &mut LightPaths::new(&[]))
+    }
+
+    fn pixel_with_aovs(&self, scene: &Scene, _idx: usize, ray: &mut Ray, sampler: &mut dyn Sampler, aov: &mut AovSample, paths: &mut LightPaths) -> Vector3<f32> {
+        let mut lighting = LightingAovs::zero();
+        let color = PathIntegrator::trace(scene, ray, sampler, Some(&mut *aov), &mut lighting, paths);
+        aov.lighting = lighting;
+        color
+    }
+
+    fn splits_lighting(&self) -> bool {
+        true
+    }
+}
+
+impl PathIntegrator {
+    // Traces a path, and adds every contribution to the lighting AOVs and the light passes too. first_hit gets the AOVs of the first surface.
+    fn trace(scene: &Scene, ray: &mut Ray, sampler: &mut dyn Sampler, mut first_hit: Option<&mut AovSample>, lighting: &mut LightingAovs, paths: &mut LightPaths) -> Vector3<f32> {
+        let mut depth = 0;
+
+        let mut T = vec3(1.0,1.0,1.0);
+        let mut E = vec3(0.0, 0.0, 0.0);
+
+        // Data of the previous bounce, used to weigh emitters hit by BSDF sampled rays
+        let mut prev_I = ray.origin;
+        let mut prev_bsdf_pdf = 0.0;
+        let mut specular_bounce = false;
+        // Part of the throughput the first surface scattered diffusely, volumes and subsurface scattering count as diffuse
+        let mut diffuse_share = vec3(1.0, 1.0, 1.0);
+        let ones = vec3(1.0, 1.0, 1.0);
+
+        loop {
+            sampler.start_bounce(depth);
+            scene.intersect_ray(ray, sampler);
+            if let Some(aov) = first_hit.take() {
+                *aov = scene.hit_aovs(ray);
+            }
+
+            // Scattering inside a volume before the ray reaches the surface
+            if let Some(interaction) = scene.sample_medium(ray, sampler) {
+                let wo = -ray.dir;
+                T = T.mul_element_wise(interaction.weight);
+                if let Some((direct, light)) = scene.sample_direct_light_medium(interaction.position, wo, interaction.phase, sampler) {
+                    let direct = T.mul_element_wise(direct);
+                    lighting.add_scattered(direct, diffuse_share, depth == 0);
+                    paths.add_direct(&[(Event::Volume, direct)], light);
+                    E += direct;
+                }
+
+                // The phase function is sampled exactly, so the throughput stays the same
+                let (R, phase_pdf) = interaction.phase.sample(wo, sampler);
+
+                let p = Scene::ray_survival_probability(T);
+                if p < sampler.next_f32() { break; }
+                T /= p;
+
+                paths.scatter(&[(Event::Volume, ones)]);
+                *ray = Ray::new(interaction.position, R, f32::MAX);
+                prev_I = interaction.position;
+                prev_bsdf_pdf = phase_pdf;
+                specular_bounce = false;
+
+                depth += 1;
+                continue;
+            }
+
+            if ray.obj_idx < 0 {
+                let sky = scene.skybox().color(ray.dir);
+                let contribution = if depth == 0 || specular_bounce {
+                    T.mul_element_wise(sky)
+                } else {
+                    let light_pdf = scene.light_select_pdf() * scene.skybox().pdf(ray.dir);
+                    T.mul_element_wise(sky) * Math::power_heuristic(prev_bsdf_pdf, light_pdf)
+                };
+                PathIntegrator::add_emitted(lighting, contribution, depth, diffuse_share);
+                paths.add_emitted(contribution, Event::Background);
+                E += contribution;
+                break;
+            }
+
+            // Intersection data
+            let primitive = scene.primitive(ray.obj_idx);
+            let I = ray.origin + ray.dir * ray.dist;
+            // Primary hits filter textures over the area covered by the pixel
+            let footprint = if depth == 0 { ray.dist * scene.camera().pixel_spread_angle(scene.resolution().1) } else { 0.0 };
+            let albedo = primitive.get_albedo_filtered(I, footprint);
+
+            // Emitters found by primary rays or through mirrors and glass are added directly, otherwise weighted against light sampling
+            if primitive.is_light() {
+                let contribution = if depth == 0 || specular_bounce {
+                    T.mul_element_wise(albedo)
+                } else {
+                    let light_pdf = scene.light_select_pdf() * primitive.light_pdf(prev_I);
+                    T.mul_element_wise(albedo) * Math::power_heuristic(prev_bsdf_pdf, light_pdf)
+                };
+                PathIntegrator::add_emitted(lighting, contribution, depth, diffuse_share);
+                paths.add_emitted(contribution, scene.emitter_event(ray.obj_idx));
+                E += contribution;
+                break;
+            }
+
+            let material = primitive.material_at(I);
+            let wo = -ray.dir;
+            let mut normal = primitive.get_normal(I);
+            let front_face = normal.dot(wo) >= 0.0;
+            if !front_face { normal = -normal; }
+            let material = material.seen_from(front_face);
+            normal = primitive.get_shading_normal(I, normal, wo);
+
+            // Subsurface scattering, the path continues from where the random walk leaves the object
+            if let Material::Subsurface { mean_free_path } = material {
+                if front_face {
+                    let exit = match Subsurface::random_walk(&primitive, I, normal, albedo, mean_free_path, sampler) {
+                        Some(e) => e,
+                        None => break
+                    };
+                    T = T.mul_element_wise(exit.weight);
+
+                    // The color comes from the walk, the exit is a white diffuse interface. Light paths see the walk as a single diffuse transmission.
+                    let walk = Event::Transmission(Scattering::Diffuse);
+                    if let Some(direct) = scene.sample_direct_light_lobes(exit.position, exit.normal, exit.normal, ones, Material::Diffuse, sampler) {
+                        let direct_light = T.mul_element_wise(direct.diffuse);
+                        lighting.add_scattered(direct_light, diffuse_share, depth == 0);
+                        paths.add_direct(&[(walk, direct_light)], direct.light);
+                        E += direct_light;
+                    }
+                    let R = Math::random_cosine_hemisphere_vectorf32(sampler, exit.normal);
+                    paths.scatter(&[(walk, ones)]);
+
+                    let p = Scene::ray_survival_probability(T);
+                    if p < sampler.next_f32() { break; }
+                    T /= p;
+
+                    *ray = Ray::new(exit.position + R * EPSILON, R, f32::MAX);
+                    prev_I = exit.position;
+                    prev_bsdf_pdf = exit.normal.dot(R) * f32::consts::FRAC_1_PI;
+                    specular_bounce = false;
+
+                    depth += 1;
+                    continue;
+                }
+            }
+
+            // NEE, the first surface splits it into the diffuse and specular passes
+            if !material.is_specular() {
+                if let Some(direct) = scene.sample_direct_light_lobes(I, normal, wo, albedo, material, sampler) {
+                    let (diffuse, specular) = (T.mul_element_wise(direct.diffuse), T.mul_element_wise(direct.specular));
+                    if depth == 0 {
+                        lighting.direct_diffuse += diffuse;
+                        lighting.direct_specular += specular;
+                    } else {
+                        lighting.add_scattered(diffuse + specular, diffuse_share, false);
+                    }
+                    let vertex = PathIntegrator::vertex(direct.transmitted);
+                    paths.add_direct(&[(vertex(Scattering::Diffuse), diffuse), (vertex(Scattering::Glossy), specular)], direct.light);
+                    E += diffuse + specular;
+                }
+            }
+
+            // Indirect bounces
+            let bsdf_sample = match material.sample(albedo, normal, wo, front_face, sampler) {
+                Some(s) => s,
+                None => break
+            };
+            let R = bsdf_sample.wi;
+
+            // Part of the sample the diffuse lobe scattered, per channel
+            let vertex = PathIntegrator::vertex(normal.dot(R) < 0.0);
+            let share = if bsdf_sample.is_specular { Vector3::zero() } else {
+                let (diffuse, f) = (material.eval_diffuse(albedo, normal, wo, R), bsdf_sample.f);
+                let share = |d: f32, f: f32| if f > 0.0 { (d / f).min(1.0) } else { 0.0 };
+                vec3(share(diffuse.x, f.x), share(diffuse.y, f.y), share(diffuse.z, f.z))
+            };
+            if depth == 0 { diffuse_share = share; }
+            if bsdf_sample.is_specular {
+                paths.scatter(&[(vertex(Scattering::Specular), ones)]);
+            } else {
+                paths.scatter(&[(vertex(Scattering::Diffuse), share), (vertex(Scattering::Glossy), ones - share)]);
+            }
+
+            T = T.mul_element_wise(bsdf_sample.f * (f32::abs(normal.dot(R)) / bsdf_sample.pdf));
+
+            // Russian Roulette
+            let p = Scene::ray_survival_probability(T);
+            if p < sampler.next_f32() { break; }
+            T /= p;
+
+            *ray = Ray::new(I + R * EPSILON, R, f32::MAX);
+            prev_I = I;
+            prev_bsdf_pdf = bsdf_sample.pdf;
+            specular_bounce = bsdf_sample.is_specular;
+
+            depth += 1;
+        }
+
+        return E;
+    }
+
+    // Light path event of a surface scattering, towards the side the normal faces or through the surface
+    pub(crate) fn vertex(transmitted: bool) -> impl Fn(Scattering) -> Event {
+        move |s| if transmitted { Event::Transmission(s) } else { Event::Reflection(s) }
+    }
+
+    // Emitters and the sky seen by the camera are emission, after one bounce they are direct light
+    pub(crate) fn add_emitted(lighting: &mut LightingAovs, contribution: Vector3<f32>, depth: u32, diffuse_share: Vector3<f32>) {
+        if depth == 0 {
+            lighting.emission += contribution;
+        } else {
+            lighting.add_scattered(contribution, diffuse_share, depth == 1);
+        }
+    }
+}
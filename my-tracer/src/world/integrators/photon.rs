@@ -0,0 +1,303 @@
+use core::f32;
+use cgmath::*;
+use rayon::prelude::*;
+
+use super::Integrator;
+use crate::world::{material::Material, math::Math, ray::Ray, sampler::Sampler, scene::{Scene, EPSILON}};
+
+const MAX_PHOTON_BOUNCES: u32 = 16;
+
+#[derive(Copy, Clone)]
+struct Photon {
+    position: Vector3<f32>,
+    // Direction the photon arrived from
+    wi: Vector3<f32>,
+    power: Vector3<f32>,
+}
+
+// Uniform grid hashed into a fixed number of buckets, the photons are sorted so that every bucket is a contiguous range
+struct PhotonGrid {
+    cell_size: f32,
+    bucket_start: Vec<u32>,
+    photons: Vec<Photon>,
+}
+
+impl PhotonGrid {
+    fn new() -> PhotonGrid {
+        PhotonGrid {
+            cell_size: 1.0,
+            bucket_start: vec![0, 0],
+            photons: Vec::new(),
+        }
+    }
+
+    fn build(&mut self, photons: Vec<Photon>, cell_size: f32) {
+        self.cell_size = cell_size;
+        let bucket_count = usize::max(photons.len() * 2, 1);
+
+        let buckets: Vec<usize> = photons.iter().map(|p| self.bucket(self.cell(p.position), bucket_count)).collect();
+
+        // Counting sort on the bucket index
+        let mut bucket_start = vec![0u32; bucket_count + 1];
+        for &b in &buckets {
+            bucket_start[b + 1] += 1;
+        }
+        for i in 1..bucket_count + 1 {
+            bucket_start[i] += bucket_start[i - 1];
+        }
+
+        let mut next = bucket_start.clone();
+        let mut sorted = photons.clone();
+        for (photon, &b) in photons.iter().zip(buckets.iter()) {
+            sorted[next[b] as usize] = *photon;
+            next[b] += 1;
+        }
+
+        self.bucket_start = bucket_start;
+        self.photons = sorted;
+    }
+
+    fn cell(&self, p: Vector3<f32>) -> Vector3<i32> {
+        vec3(
+            (p.x / self.cell_size).floor() as i32,
+            (p.y / self.cell_size).floor() as i32,
+            (p.z / self.cell_size).floor() as i32,
+        )
+    }
+
+    fn bucket(&self, cell: Vector3<i32>, bucket_count: usize) -> usize {
+        let h = (cell.x.wrapping_mul(73856093)) ^ (cell.y.wrapping_mul(19349663)) ^ (cell.z.wrapping_mul(83492791));
+        h as u32 as usize % bucket_count
+    }
+
+    fn for_each_in_radius(&self, p: Vector3<f32>, radius: f32, mut f: impl FnMut(&Photon)) {
+        let bucket_count = self.bucket_start.len() - 1;
+        let min = self.cell(p - vec3(radius, radius, radius));
+        let max = self.cell(p + vec3(radius, radius, radius));
+
+        // Neighbouring cells can share a bucket, which must only be visited once
+        let mut visited: Vec<usize> = Vec::with_capacity(8);
+        for x in min.x..=max.x {
+            for y in min.y..=max.y {
+                for z in min.z..=max.z {
+                    let b = self.bucket(vec3(x, y, z), bucket_count);
+                    if visited.contains(&b) { continue; }
+                    visited.push(b);
+
+                    for photon in &self.photons[self.bucket_start[b] as usize..self.bucket_start[b + 1] as usize] {
+                        if (photon.position - p).magnitude2() <= radius * radius {
+                            f(photon);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Path tracer that takes caustics (light reaching a diffuse surface through mirrors or glass) from a photon map.
+// A new photon map is traced every frame with a shrinking radius, so the accumulated image converges (progressive photon mapping).
+pub struct PhotonMappingIntegrator {
+    photons_per_frame: u32,
+    initial_radius: f32,
+    alpha: f32,
+    radius: f32,
+    grid: PhotonGrid,
+}
+
+impl PhotonMappingIntegrator {
+    pub fn new(photons_per_frame: u32, initial_radius: f32, alpha: f32) -> PhotonMappingIntegrator {
+        PhotonMappingIntegrator {
+            photons_per_frame: photons_per_frame,
+            initial_radius: initial_radius,
+            alpha: alpha,
+            radius: initial_radius,
+            grid: PhotonGrid::new(),
+        }
+    }
+
+    // Follows a photon from a light through specular bounces, it is only stored if it then lands on a diffuse surface
+    fn trace_photon(scene: &Scene, sampler: &mut dyn Sampler) -> Option<Photon> {
+        let lights = scene.lights();
+        if lights.is_empty() { return None; }
+
+        let light_idx = ((sampler.next_f32() * lights.len() as f32) as usize).min(lights.len() - 1);
+        let light = scene.primitive(lights[light_idx]);
+        let (position, normal) = light.sample_area(sampler)?;
+
+        let pdf_pos = light.area_pdf() / lights.len() as f32;
+        let dir = Math::random_cosine_hemisphere_vectorf32(sampler, normal);
+
+        // Le * cos / (pdf_pos * cos / PI)
+        let mut power = light.get_albedo(position) * (f32::consts::PI / pdf_pos);
+        let mut ray = Ray::new(position + dir * EPSILON, dir, f32::MAX);
+        let mut specular_chain = false;
+
+        for _ in 0..MAX_PHOTON_BOUNCES {
+            scene.intersect_ray(&mut ray, sampler);
+            if ray.obj_idx < 0 { return None; }
+
+            let primitive = scene.primitive(ray.obj_idx);
+            if primitive.is_light() { return None; }
+
+            let I = ray.origin + ray.dir * ray.dist;
+            let material = primitive.material_at(I);
+
+            if !material.is_specular() {
+                if !specular_chain { return None; }
+                return Some(Photon {
+                    position: I,
+                    wi: -ray.dir,
+                    power: power,
+                });
+            }
+
+            let wo = -ray.dir;
+            let mut normal = primitive.get_normal(I).normalize();
+            let front_face = normal.dot(wo) >= 0.0;
+            if !front_face { normal = -normal; }
+            let material = material.seen_from(front_face);
+            normal = primitive.get_shading_normal(I, normal, wo);
+
+            let bsdf_sample = material.sample(primitive.get_albedo(I), normal, wo, front_face, sampler)?;
+            let R = bsdf_sample.wi;
+            power = power.mul_element_wise(bsdf_sample.f * (f32::abs(normal.dot(R)) / bsdf_sample.pdf));
+            specular_chain = true;
+
+            ray = Ray::new(I + R * EPSILON, R, f32::MAX);
+        }
+
+        None
+    }
+
+    // Density estimate of the caustic photons around I
+    fn estimate_caustics(&self, I: Vector3<f32>, normal: Vector3<f32>, wo: Vector3<f32>, albedo: Vector3<f32>, material: Material) -> Vector3<f32> {
+        let mut sum = Vector3::zero();
+        self.grid.for_each_in_radius(I, self.radius, |photon| {
+            if photon.wi.dot(normal) <= 0.0 { return; }
+            sum += material.eval(albedo, normal, wo, photon.wi).mul_element_wise(photon.power);
+        });
+
+        sum / (f32::consts::PI * self.radius * self.radius * self.photons_per_frame as f32)
+    }
+}
+
+impl Integrator for PhotonMappingIntegrator {
+    fn name(&self) -> &'static str {
+        "Photon mapping"
+    }
+
+    fn supports_adaptive_sampling(&self) -> bool {
+        false
+    }
+
+    // The radius shrinks with the frames accumulated so far
+    fn supports_reprojection(&self) -> bool {
+        false
+    }
+
+    fn begin_frame(&mut self, scene: &Scene, frame: u32, seed: u32) {
+        // Radius reduction of progressive photon mapping, every frame keeps a fraction alpha of the new photons
+        if frame == 0 {
+            self.radius = self.initial_radius;
+        } else {
+            let n = frame as f32;
+            self.radius *= f32::sqrt((n + self.alpha) / (n + 1.0));
+        }
+
+        let photons: Vec<Photon> = (0..self.photons_per_frame).into_par_iter().filter_map(|i| {
+            let mut photon_seed = Math::wang_hash(i.wrapping_add(seed).wrapping_mul(31).wrapping_add(7));
+            PhotonMappingIntegrator::trace_photon(scene, &mut photon_seed)
+        }).collect();
+
+        self.grid.build(photons, 2.0 * self.radius);
+    }
+
+    fn radiance(&self, scene: &Scene, ray: &mut Ray, sampler: &mut dyn Sampler) -> Vector3<f32> {
+        let mut depth = 0;
+
+        let mut T = vec3(1.0,1.0,1.0);
+        let mut E = vec3(0.0, 0.0, 0.0);
+
+        let mut prev_I = ray.origin;
+        let mut prev_bsdf_pdf = 0.0;
+        let mut specular_bounce = false;
+        // Set once the path has left a diffuse surface
+        let mut caustic_chain = false;
+
+        loop {
+            sampler.start_bounce(depth);
+            scene.intersect_ray(ray, sampler);
+
+            if ray.obj_idx < 0 {
+                let sky = scene.skybox().color(ray.dir);
+                if depth == 0 || specular_bounce {
+                    E += T.mul_element_wise(sky);
+                } else {
+                    let light_pdf = scene.light_select_pdf() * scene.skybox().pdf(ray.dir);
+                    E += T.mul_element_wise(sky) * Math::power_heuristic(prev_bsdf_pdf, light_pdf);
+                }
+                break;
+            }
+
+            let primitive = scene.primitive(ray.obj_idx);
+            let I = ray.origin + ray.dir * ray.dist;
+            let albedo = primitive.get_albedo(I);
+
+            // Light reaching a diffuse surface through a specular chain is already in the photon map
+            if primitive.is_light() {
+                if depth == 0 || (specular_bounce && !caustic_chain) {
+                    E += T.mul_element_wise(albedo);
+                } else if !specular_bounce {
+                    let light_pdf = scene.light_select_pdf() * primitive.light_pdf(prev_I);
+                    E += T.mul_element_wise(albedo) * Math::power_heuristic(prev_bsdf_pdf, light_pdf);
+                }
+                break;
+            }
+
+            let material = primitive.material_at(I);
+            let wo = -ray.dir;
+            let mut normal = primitive.get_normal(I).normalize();
+            let front_face = normal.dot(wo) >= 0.0;
+            if !front_face { normal = -normal; }
+            let material = material.seen_from(front_face);
+            normal = primitive.get_shading_normal(I, normal, wo);
+
+            if !material.is_specular() {
+                E += T.mul_element_wise(scene.sample_direct_light(I, normal, wo, albedo, material, sampler));
+                E += T.mul_element_wise(self.estimate_caustics(I, normal, wo, albedo, material));
+                caustic_chain = true;
+            }
+
+            let bsdf_sample = match material.sample(albedo, normal, wo, front_face, sampler) {
+                Some(s) => s,
+                None => break
+            };
+            let R = bsdf_sample.wi;
+
+            T = T.mul_element_wise(bsdf_sample.f * (f32::abs(normal.dot(R)) / bsdf_sample.pdf));
+
+            // Russian Roulette
+            let p = Scene::ray_survival_probability(T);
+            if p < sampler.next_f32() { break; }
+            T /= p;
+
+            *ray = Ray::new(I + R * EPSILON, R, f32::MAX);
+            prev_I = I;
+            prev_bsdf_pdf = bsdf_sample.pdf;
+            specular_bounce = bsdf_sample.is_specular;
+
+            depth += 1;
+        }
+
+        E
+    }
+
+    fn ui(&mut self, ui: &mut egui::Ui) -> bool {
+        let mut changed = false;
+        changed |= ui.add(egui::Slider::new(&mut self.photons_per_frame, 10000..=2000000).text("Photons per frame")).changed();
+        changed |= ui.add(egui::Slider::new(&mut self.initial_radius, 0.001..=1.0).logarithmic(true).text("Initial radius")).changed();
+        changed |= ui.add(egui::Slider::new(&mut self.alpha, 0.1..=1.0).text("Alpha")).changed();
+        changed
+    }
+}
@@ -0,0 +1,439 @@
+use core::f32;
+use cgmath::*;
+use rayon::prelude::*;
+
+use super::Integrator;
+use crate::world::{camera::Camera, material::Material, math::Math, ray::Ray, sampler::Sampler, scene::{Scene, EPSILON}};
+
+const MAX_SPECULAR_BOUNCES: u32 = 8;
+
+#[derive(Copy, Clone)]
+struct LightCandidate {
+    // Index of the light primitive, -1 for a direction towards the skybox
+    light: i32,
+    // Point on the light, or the direction for the skybox
+    position: Vector3<f32>,
+    normal: Vector3<f32>,
+}
+
+// Weighted reservoir holding one light candidate, W is its unbiased contribution weight
+#[derive(Copy, Clone)]
+struct Reservoir {
+    sample: LightCandidate,
+    weight_sum: f32,
+    M: f32,
+    W: f32,
+}
+
+impl Reservoir {
+    fn empty() -> Reservoir {
+        Reservoir {
+            sample: LightCandidate { light: -1, position: Vector3::zero(), normal: Vector3::zero() },
+            weight_sum: 0.0,
+            M: 0.0,
+            W: 0.0,
+        }
+    }
+
+    // Streams in one candidate, returns true if it replaced the current sample
+    fn update(&mut self, candidate: LightCandidate, weight: f32, sampler: &mut dyn Sampler) -> bool {
+        if !(weight > 0.0) || !weight.is_finite() { return false; }
+
+        self.weight_sum += weight;
+        if sampler.next_f32() * self.weight_sum < weight {
+            self.sample = candidate;
+            return true;
+        }
+        false
+    }
+}
+
+// First diffuse or glossy surface seen through a pixel, after following mirrors and glass
+#[derive(Copy, Clone)]
+struct Surface {
+    valid: bool,
+    position: Vector3<f32>,
+    normal: Vector3<f32>,
+    wo: Vector3<f32>,
+    albedo: Vector3<f32>,
+    material: Material,
+    // Distance from the camera to the first hit, used to reject reuse across depth discontinuities
+    depth: f32,
+    throughput: Vector3<f32>,
+    // Emitters and skybox seen on the way to the surface
+    emitted: Vector3<f32>,
+}
+
+impl Surface {
+    fn miss(emitted: Vector3<f32>) -> Surface {
+        Surface {
+            valid: false,
+            position: Vector3::zero(),
+            normal: Vector3::zero(),
+            wo: Vector3::zero(),
+            albedo: Vector3::zero(),
+            material: Material::Diffuse,
+            depth: f32::MAX,
+            throughput: Vector3::zero(),
+            emitted: emitted,
+        }
+    }
+
+    // Surfaces that are close in depth and orientation can share their light samples
+    fn similar(&self, other: &Surface, depth: f32) -> bool {
+        other.valid
+            && self.normal.dot(other.normal) > 0.9
+            && f32::abs(depth - other.depth) < 0.1 * depth
+    }
+}
+
+// Direct lighting with spatiotemporal reservoir resampling (ReSTIR, Bitterli et al.).
+// Every pixel resamples a handful of light candidates, then reuses the reservoir of the reprojected pixel of the previous frame and of a few neighbours.
+// Reservoirs persist across frames, also when the camera moves, so even a single frame is low in noise.
+pub struct RestirIntegrator {
+    candidates: u32,
+    spatial_neighbours: u32,
+    spatial_radius: f32,
+    // Caps the history of the temporal reservoir relative to the current one
+    max_history: f32,
+    temporal_reuse: bool,
+    // Uses shadow rays when normalizing reused samples, without them shadow edges come out slightly too dark
+    unbiased: bool,
+    width: u32,
+    height: u32,
+    surfaces: Vec<Surface>,
+    reservoirs: Vec<Reservoir>,
+    colors: Vec<Vector3<f32>>,
+    prev_camera: Option<Camera>,
+}
+
+impl RestirIntegrator {
+    pub fn new(candidates: u32, spatial_neighbours: u32, spatial_radius: f32) -> RestirIntegrator {
+        RestirIntegrator {
+            candidates: candidates,
+            spatial_neighbours: spatial_neighbours,
+            spatial_radius: spatial_radius,
+            max_history: 20.0,
+            temporal_reuse: true,
+            unbiased: true,
+            width: 0,
+            height: 0,
+            surfaces: Vec::new(),
+            reservoirs: Vec::new(),
+            colors: Vec::new(),
+            prev_camera: None,
+        }
+    }
+
+    // Follows the ray through specular bounces until it reaches a surface that can be lit
+    fn find_surface(scene: &Scene, ray: &mut Ray, sampler: &mut dyn Sampler) -> Surface {
+        let mut T = vec3(1.0, 1.0, 1.0);
+        let mut depth = 0.0;
+
+        for bounce in 0..MAX_SPECULAR_BOUNCES {
+            sampler.start_bounce(bounce);
+            scene.intersect_ray(ray, sampler);
+
+            if ray.obj_idx < 0 {
+                return Surface::miss(T.mul_element_wise(scene.skybox().color(ray.dir)));
+            }
+
+            let primitive = scene.primitive(ray.obj_idx);
+            let I = ray.origin + ray.dir * ray.dist;
+            let albedo = primitive.get_albedo(I);
+            if bounce == 0 { depth = ray.dist; }
+
+            if primitive.is_light() {
+                return Surface::miss(T.mul_element_wise(albedo));
+            }
+
+            let material = primitive.material_at(I);
+            let wo = -ray.dir;
+            let mut normal = primitive.get_normal(I).normalize();
+            let front_face = normal.dot(wo) >= 0.0;
+            if !front_face { normal = -normal; }
+            let material = material.seen_from(front_face);
+            normal = primitive.get_shading_normal(I, normal, wo);
+
+            if !material.is_specular() {
+                return Surface {
+                    valid: true,
+                    position: I,
+                    normal: normal,
+                    wo: wo,
+                    albedo: albedo,
+                    material: material,
+                    depth: depth,
+                    throughput: T,
+                    emitted: Vector3::zero(),
+                };
+            }
+
+            let bsdf_sample = match material.sample(albedo, normal, wo, front_face, sampler) {
+                Some(s) => s,
+                None => break
+            };
+            let R = bsdf_sample.wi;
+            T = T.mul_element_wise(bsdf_sample.f * (f32::abs(normal.dot(R)) / bsdf_sample.pdf));
+            *ray = Ray::new(I + R * EPSILON, R, f32::MAX);
+        }
+
+        Surface::miss(Vector3::zero())
+    }
+
+    // Picks a light (or the skybox) uniformly and a point on it, the pdf is per unit area, or per solid angle for the skybox
+    fn sample_candidate(scene: &Scene, sampler: &mut dyn Sampler) -> Option<(LightCandidate, f32)> {
+        let select_pdf = scene.light_select_pdf();
+        let lights = scene.lights();
+        let light_count = lights.len() + 1;
+        let light_idx = ((sampler.next_f32() * light_count as f32) as usize).min(light_count - 1);
+
+        if light_idx == lights.len() {
+            let (dir, _, pdf) = scene.skybox().sample(sampler);
+            if pdf <= 0.0 { return None; }
+            return Some((LightCandidate { light: -1, position: dir, normal: -dir }, select_pdf * pdf));
+        }
+
+        let light = scene.primitive(lights[light_idx]);
+        let (position, normal) = light.sample_area(sampler)?;
+        Some((LightCandidate { light: lights[light_idx], position: position, normal: normal }, select_pdf * light.area_pdf()))
+    }
+
+    // Light reflected towards the camera by the candidate, ignoring shadows
+    fn unshadowed(scene: &Scene, surface: &Surface, candidate: &LightCandidate) -> Vector3<f32> {
+        let (wi, Le, G) = if candidate.light < 0 {
+            (candidate.position, scene.skybox().color(candidate.position), 1.0)
+        } else {
+            let d = candidate.position - surface.position;
+            let dist2 = d.magnitude2();
+            let wi = d / dist2.sqrt();
+            let cos_l = candidate.normal.dot(-wi);
+            if cos_l <= 0.0 { return Vector3::zero(); }
+            (wi, scene.primitive(candidate.light).get_albedo(candidate.position), cos_l / dist2)
+        };
+
+        let cos_i = if surface.material.is_transmissive() { f32::abs(surface.normal.dot(wi)) } else { surface.normal.dot(wi) };
+        if cos_i <= 0.0 { return Vector3::zero(); }
+
+        surface.material.eval(surface.albedo, surface.normal, surface.wo, wi).mul_element_wise(Le) * (cos_i * G)
+    }
+
+    // Target function the candidates are resampled towards
+    fn target(scene: &Scene, surface: &Surface, candidate: &LightCandidate) -> f32 {
+        let p = Math::luminance(RestirIntegrator::unshadowed(scene, surface, candidate));
+        if p.is_finite() { f32::max(p, 0.0) } else { 0.0 }
+    }
+
+    fn visible(scene: &Scene, surface: &Surface, candidate: &LightCandidate, sampler: &mut dyn Sampler) -> bool {
+        let (dir, dist) = if candidate.light < 0 {
+            (candidate.position, f32::MAX)
+        } else {
+            let d = candidate.position - surface.position;
+            let dist = d.magnitude();
+            (d / dist, dist - EPSILON * 2.0)
+        };
+
+        let mut shadow_ray = Ray::new(surface.position + dir * EPSILON, dir, dist);
+        scene.intersect_ray(&mut shadow_ray, sampler);
+        shadow_ray.obj_idx == -1
+    }
+
+    // Resampled importance sampling over fresh light candidates
+    fn initial_reservoir(&self, scene: &Scene, surface: &Surface, sampler: &mut dyn Sampler) -> Reservoir {
+        let mut reservoir = Reservoir::empty();
+
+        for _ in 0..self.candidates {
+            if let Some((candidate, pdf)) = RestirIntegrator::sample_candidate(scene, sampler) {
+                let weight = RestirIntegrator::target(scene, surface, &candidate) / pdf;
+                reservoir.update(candidate, weight, sampler);
+            }
+            reservoir.M += 1.0;
+        }
+
+        let p = RestirIntegrator::target(scene, surface, &reservoir.sample);
+        if p > 0.0 {
+            reservoir.W = reservoir.weight_sum / (reservoir.M * p);
+        }
+
+        // Occluded samples are dropped right away, so that they are not passed on to other pixels
+        if reservoir.W > 0.0 && !RestirIntegrator::visible(scene, surface, &reservoir.sample, sampler) {
+            reservoir.W = 0.0;
+        }
+        reservoir
+    }
+
+    // Merges reservoirs that were built for other surfaces, the first one must belong to the current surface.
+    // Normalizing by the history of the surfaces that could have produced the chosen sample keeps the result unbiased.
+    fn combine(&self, scene: &Scene, surface: &Surface, inputs: &[(Reservoir, Surface)], sampler: &mut dyn Sampler) -> Reservoir {
+        let mut reservoir = Reservoir::empty();
+
+        for (input, _) in inputs {
+            let weight = RestirIntegrator::target(scene, surface, &input.sample) * input.W * input.M;
+            reservoir.update(input.sample, weight, sampler);
+            reservoir.M += input.M;
+        }
+
+        let p = RestirIntegrator::target(scene, surface, &reservoir.sample);
+        if p <= 0.0 { return reservoir; }
+
+        let mut Z = 0.0;
+        for (i, (input, input_surface)) in inputs.iter().enumerate() {
+            if i == 0 {
+                Z += input.M;
+                continue;
+            }
+            if RestirIntegrator::target(scene, input_surface, &reservoir.sample) <= 0.0 { continue; }
+            if self.unbiased && !RestirIntegrator::visible(scene, input_surface, &reservoir.sample, sampler) { continue; }
+            Z += input.M;
+        }
+
+        if Z > 0.0 {
+            reservoir.W = reservoir.weight_sum / (Z * p);
+        }
+        if reservoir.W > 0.0 && !RestirIntegrator::visible(scene, surface, &reservoir.sample, sampler) {
+            reservoir.W = 0.0;
+        }
+        reservoir
+    }
+
+    fn shade(scene: &Scene, surface: &Surface, reservoir: &Reservoir) -> Vector3<f32> {
+        if !surface.valid || reservoir.W <= 0.0 {
+            return surface.emitted;
+        }
+        let direct = RestirIntegrator::unshadowed(scene, surface, &reservoir.sample) * reservoir.W;
+        surface.emitted + surface.throughput.mul_element_wise(direct)
+    }
+
+    fn pixel_seed(i: usize, seed: u32, pass: u32) -> u32 {
+        Math::wang_hash((i as u32).wrapping_add(seed).wrapping_mul(31).wrapping_add(pass * 0x9e3779b9)) | 1
+    }
+
+    // Pixel of the previous frame that saw the point p
+    fn reproject(&self, camera: &Camera, p: Vector3<f32>) -> Option<usize> {
+        let (x, y) = camera.project(p)?;
+        let px = ((x * self.width as f32 + 0.5) as u32).min(self.width - 1);
+        let py = ((y * self.height as f32 + 0.5) as u32).min(self.height - 1);
+        Some((px + py * self.width) as usize)
+    }
+}
+
+impl Integrator for RestirIntegrator {
+    fn name(&self) -> &'static str {
+        "ReSTIR direct lighting"
+    }
+
+    fn supports_adaptive_sampling(&self) -> bool {
+        false
+    }
+
+    fn begin_frame(&mut self, scene: &Scene, _frame: u32, seed: u32) {
+        let (width, height) = scene.resolution();
+        if width != self.width || height != self.height {
+            self.width = width;
+            self.height = height;
+            self.surfaces.clear();
+            self.reservoirs.clear();
+            self.prev_camera = None;
+        }
+
+        let f_width = width as f32;
+        let f_height = height as f32;
+        let camera = scene.camera();
+
+        let surfaces: Vec<Surface> = (0..(width * height) as usize).into_par_iter().map(|i| {
+            let mut sampler = RestirIntegrator::pixel_seed(i, seed, 0);
+            let x = (i as f32 % f_width) + sampler.next_f32() - 0.5;
+            let y = (i / width as usize) as f32 + sampler.next_f32() - 0.5;
+            let mut ray = camera.calculate_primary_ray(x / f_width, y / f_height);
+            RestirIntegrator::find_surface(scene, &mut ray, &mut sampler)
+        }).collect();
+
+        let initial: Vec<Reservoir> = surfaces.par_iter().enumerate().map(|(i, surface)| {
+            if !surface.valid { return Reservoir::empty(); }
+            let mut sampler = RestirIntegrator::pixel_seed(i, seed, 1);
+            self.initial_reservoir(scene, surface, &mut sampler)
+        }).collect();
+
+        // Temporal reuse, the previous frame is looked up where the surface was seen by the previous camera
+        let temporal: Vec<Reservoir> = match (&self.prev_camera, self.temporal_reuse && !self.reservoirs.is_empty()) {
+            (Some(prev_camera), true) => surfaces.par_iter().enumerate().map(|(i, surface)| {
+                if !surface.valid { return initial[i]; }
+
+                let prev_idx = match self.reproject(prev_camera, surface.position) {
+                    Some(idx) => idx,
+                    None => return initial[i]
+                };
+                let prev_surface = &self.surfaces[prev_idx];
+                let prev_depth = (surface.position - prev_camera.position).magnitude();
+                if !surface.similar(prev_surface, prev_depth) { return initial[i]; }
+
+                let mut prev = self.reservoirs[prev_idx];
+                prev.M = f32::min(prev.M, self.max_history * initial[i].M);
+
+                let mut sampler = RestirIntegrator::pixel_seed(i, seed, 2);
+                self.combine(scene, surface, &[(initial[i], *surface), (prev, *prev_surface)], &mut sampler)
+            }).collect(),
+            _ => initial
+        };
+
+        // Spatial reuse from random pixels nearby
+        let spatial: Vec<Reservoir> = surfaces.par_iter().enumerate().map(|(i, surface)| {
+            if !surface.valid || self.spatial_neighbours == 0 { return temporal[i]; }
+
+            let mut sampler = RestirIntegrator::pixel_seed(i, seed, 3);
+            let x = (i % width as usize) as f32;
+            let y = (i / width as usize) as f32;
+
+            let mut inputs = vec![(temporal[i], *surface)];
+            for _ in 0..self.spatial_neighbours {
+                let r = self.spatial_radius * sampler.next_f32().sqrt();
+                let phi = 2.0 * f32::consts::PI * sampler.next_f32();
+                let nx = x + r * phi.cos();
+                let ny = y + r * phi.sin();
+                if nx < 0.0 || ny < 0.0 || nx >= f_width || ny >= f_height { continue; }
+
+                let n = nx as usize + ny as usize * width as usize;
+                if n == i || !surface.similar(&surfaces[n], surface.depth) { continue; }
+                inputs.push((temporal[n], surfaces[n]));
+            }
+
+            if inputs.len() == 1 { return temporal[i]; }
+            self.combine(scene, surface, &inputs, &mut sampler)
+        }).collect();
+
+        self.colors = surfaces.par_iter().zip(spatial.par_iter())
+            .map(|(surface, reservoir)| RestirIntegrator::shade(scene, surface, reservoir))
+            .collect();
+
+        self.surfaces = surfaces;
+        self.reservoirs = spatial;
+        self.prev_camera = Some(*camera);
+    }
+
+    // Everything is computed for the whole image in begin_frame
+    fn pixel(&self, scene: &Scene, idx: usize, ray: &mut Ray, sampler: &mut dyn Sampler) -> Vector3<f32> {
+        match self.colors.get(idx) {
+            Some(color) => *color,
+            None => self.radiance(scene, ray, sampler)
+        }
+    }
+
+    // Without neighbours to reuse from, a single ray only gets the initial resampling
+    fn radiance(&self, scene: &Scene, ray: &mut Ray, sampler: &mut dyn Sampler) -> Vector3<f32> {
+        let surface = RestirIntegrator::find_surface(scene, ray, sampler);
+        if !surface.valid { return surface.emitted; }
+        let reservoir = self.initial_reservoir(scene, &surface, sampler);
+        RestirIntegrator::shade(scene, &surface, &reservoir)
+    }
+
+    fn ui(&mut self, ui: &mut egui::Ui) -> bool {
+        let mut changed = false;
+        changed |= ui.add(egui::Slider::new(&mut self.candidates, 1..=64).text("Light candidates")).changed();
+        changed |= ui.add(egui::Slider::new(&mut self.spatial_neighbours, 0..=16).text("Spatial neighbours")).changed();
+        changed |= ui.add(egui::Slider::new(&mut self.spatial_radius, 1.0..=64.0).text("Spatial radius")).changed();
+        changed |= ui.add(egui::Slider::new(&mut self.max_history, 1.0..=100.0).text("Max history")).changed();
+        changed |= ui.checkbox(&mut self.temporal_reuse, "Temporal reuse").changed();
+        changed |= ui.checkbox(&mut self.unbiased, "Unbiased").changed();
+        changed
+    }
+}
@@ -0,0 +1,262 @@
+use core::f32;
+use cgmath::*;
+
+use super::{path::PathIntegrator, Integrator};
+use crate::world::{aov::{AovSample, LightingAovs}, lpe::{Event, LightPaths, Scattering}, material::Material, math::Math, ray::Ray, sampler::Sampler, scene::{Scene, EPSILON}, spectrum::SampledWavelengths};
+
+// Path tracer that carries four wavelengths instead of RGB (hero wavelength sampling).
+// Colors are turned into spectra where they are used, and dielectrics with dispersion split white light into its colors.
+pub struct SpectralPathIntegrator;
+
+impl SpectralPathIntegrator {
+    // Materials work in RGB, their tints, sheen, thin films and metal Fresnel mix the channels, so they are evaluated with
+    // the RGB albedo and the resulting reflectance is turned into a spectrum at the sampled wavelengths
+    fn eval(material: Material, albedo: Vector3<f32>, lambda: &SampledWavelengths, normal: Vector3<f32>, wo: Vector3<f32>, wi: Vector3<f32>) -> Vector4<f32> {
+        lambda.from_rgb(material.eval(albedo, normal, wo, wi))
+    }
+
+    // Spectral version of Scene::sample_direct_light_lobes, scatter returns the BSDF (or phase function) times cosine, the part of it
+    // scattered by diffuse lobes and its pdf. Returns the light reaching I and its diffuse part, the direction towards the light and its event.
+    fn sample_direct_light(scene: &Scene, I: Vector3<f32>, lambda: &SampledWavelengths, sampler: &mut dyn Sampler, scatter: impl Fn(Vector3<f32>) -> Option<(Vector4<f32>, Vector4<f32>, f32)>) -> Option<(Vector4<f32>, Vector4<f32>, Vector3<f32>, Event)> {
+        let (L, dist_to_light, Le, light_pdf, light) = scene.sample_light_direction(I, sampler)?;
+        let (f, f_diffuse, scatter_pdf) = scatter(L)?;
+
+        let visibility = scene.visibility(I, L, dist_to_light, sampler);
+        if visibility <= 0.0 { return None; }
+
+        let weight = Math::power_heuristic(light_pdf, scatter_pdf);
+        let incoming = lambda.from_rgb(Le) * (visibility * weight / light_pdf);
+        Some((incoming.mul_element_wise(f), incoming.mul_element_wise(f_diffuse), L, light))
+    }
+
+    fn survival_probability(T: Vector4<f32>) -> f32 {
+        T.x.max(T.y).max(T.z).max(T.w).clamp(0.0, 1.0)
+    }
+
+    // Traces a path, and adds every contribution to the lighting AOVs and the light passes too. first_hit gets the AOVs of the first surface.
+    // Contributions are turned into RGB as they are found, with the wavelengths the path still follows at that point.
+    fn trace(scene: &Scene, ray: &mut Ray, sampler: &mut dyn Sampler, mut first_hit: Option<&mut AovSample>, lighting: &mut LightingAovs, paths: &mut LightPaths) -> Vector3<f32> {
+        let mut lambda = SampledWavelengths::sample(sampler.next_f32());
+        let mut depth = 0;
+
+        let mut T = vec4(1.0, 1.0, 1.0, 1.0);
+        let mut E = Vector3::zero();
+
+        let mut prev_I = ray.origin;
+        let mut prev_bsdf_pdf = 0.0;
+        let mut specular_bounce = false;
+        // Part of the throughput the first surface scattered diffusely, volumes count as diffuse
+        let mut diffuse_share = vec3(1.0, 1.0, 1.0);
+        let ones = vec3(1.0, 1.0, 1.0);
+
+        loop {
+            sampler.start_bounce(depth);
+            scene.intersect_ray(ray, sampler);
+            if let Some(aov) = first_hit.take() {
+                *aov = scene.hit_aovs(ray);
+            }
+
+            if let Some(interaction) = scene.sample_medium(ray, sampler) {
+                let wo = -ray.dir;
+                let phase = interaction.phase;
+                T = T.mul_element_wise(lambda.from_rgb(interaction.weight));
+                if let Some((direct, _, _, light)) = SpectralPathIntegrator::sample_direct_light(scene, interaction.position, &lambda, sampler, |L| {
+                    let p = phase.eval(wo, L);
+                    Some((vec4(p, p, p, p), Vector4::zero(), p))
+                }) {
+                    let direct = lambda.to_rgb(T.mul_element_wise(direct));
+                    lighting.add_scattered(direct, diffuse_share, depth == 0);
+                    paths.add_direct(&[(Event::Volume, direct)], light);
+                    E += direct;
+                }
+
+                let (R, phase_pdf) = phase.sample(wo, sampler);
+
+                let p = SpectralPathIntegrator::survival_probability(T);
+                if p < sampler.next_f32() { break; }
+                T /= p;
+
+                paths.scatter(&[(Event::Volume, ones)]);
+                *ray = Ray::new(interaction.position, R, f32::MAX);
+                prev_I = interaction.position;
+                prev_bsdf_pdf = phase_pdf;
+                specular_bounce = false;
+
+                depth += 1;
+                continue;
+            }
+
+            if ray.obj_idx < 0 {
+                let sky = lambda.from_rgb(scene.skybox().color(ray.dir));
+                let weight = if depth == 0 || specular_bounce { 1.0 } else {
+                    let light_pdf = scene.light_select_pdf() * scene.skybox().pdf(ray.dir);
+                    Math::power_heuristic(prev_bsdf_pdf, light_pdf)
+                };
+                let contribution = lambda.to_rgb(T.mul_element_wise(sky) * weight);
+                PathIntegrator::add_emitted(lighting, contribution, depth, diffuse_share);
+                paths.add_emitted(contribution, Event::Background);
+                E += contribution;
+                break;
+            }
+
+            let primitive = scene.primitive(ray.obj_idx);
+            let I = ray.origin + ray.dir * ray.dist;
+            let footprint = if depth == 0 { ray.dist * scene.camera().pixel_spread_angle(scene.resolution().1) } else { 0.0 };
+            let albedo = primitive.get_albedo_filtered(I, footprint);
+
+            if primitive.is_light() {
+                let emission = lambda.from_rgb(albedo);
+                let weight = if depth == 0 || specular_bounce { 1.0 } else {
+                    let light_pdf = scene.light_select_pdf() * primitive.light_pdf(prev_I);
+                    Math::power_heuristic(prev_bsdf_pdf, light_pdf)
+                };
+                let contribution = lambda.to_rgb(T.mul_element_wise(emission) * weight);
+                PathIntegrator::add_emitted(lighting, contribution, depth, diffuse_share);
+                paths.add_emitted(contribution, scene.emitter_event(ray.obj_idx));
+                E += contribution;
+                break;
+            }
+
+            let mut material = primitive.material_at(I);
+            let wo = -ray.dir;
+            let mut normal = primitive.get_normal(I);
+            let front_face = normal.dot(wo) >= 0.0;
+            if !front_face { normal = -normal; }
+            material = material.seen_from(front_face);
+            normal = primitive.get_shading_normal(I, normal, wo);
+
+            // Dispersion sends every wavelength in its own direction, only the hero wavelength is followed from here
+            if material.is_dispersive() {
+                material = material.at_wavelength(lambda.hero());
+                lambda.terminate_secondary();
+            }
+
+            // NEE, the first surface splits it into the diffuse and specular passes
+            if !material.is_specular() {
+                if let Some((direct, diffuse, L, light)) = SpectralPathIntegrator::sample_direct_light(scene, I, &lambda, sampler, |L| {
+                    let cos_i = if material.is_transmissive() { f32::abs(normal.dot(L)) } else { normal.dot(L) };
+                    if cos_i <= 0.0 { return None; }
+                    let f = SpectralPathIntegrator::eval(material, albedo, &lambda, normal, wo, L) * cos_i;
+                    let f_diffuse = lambda.from_rgb(material.eval_diffuse(albedo, normal, wo, L)) * cos_i;
+                    Some((f, f_diffuse, material.pdf(normal, wo, L)))
+                }) {
+                    let diffuse = lambda.to_rgb(T.mul_element_wise(diffuse));
+                    let specular = lambda.to_rgb(T.mul_element_wise(direct)) - diffuse;
+                    if depth == 0 {
+                        lighting.direct_diffuse += diffuse;
+                        lighting.direct_specular += specular;
+                    } else {
+                        lighting.add_scattered(diffuse + specular, diffuse_share, false);
+                    }
+                    let vertex = PathIntegrator::vertex(normal.dot(L) < 0.0);
+                    paths.add_direct(&[(vertex(Scattering::Diffuse), diffuse), (vertex(Scattering::Glossy), specular)], light);
+                    E += diffuse + specular;
+                }
+            }
+
+            let bsdf_sample = match material.sample(albedo, normal, wo, front_face, sampler) {
+                Some(s) => s,
+                None => break
+            };
+            let R = bsdf_sample.wi;
+            let cos_r = f32::abs(normal.dot(R));
+
+            // Part of the sample the diffuse lobe scattered, per channel
+            let vertex = PathIntegrator::vertex(normal.dot(R) < 0.0);
+            let share = if bsdf_sample.is_specular { Vector3::zero() } else {
+                let (diffuse, f) = (material.eval_diffuse(albedo, normal, wo, R), bsdf_sample.f);
+                let share = |d: f32, f: f32| if f > 0.0 { (d / f).min(1.0) } else { 0.0 };
+                vec3(share(diffuse.x, f.x), share(diffuse.y, f.y), share(diffuse.z, f.z))
+            };
+            if depth == 0 { diffuse_share = share; }
+            if bsdf_sample.is_specular {
+                paths.scatter(&[(vertex(Scattering::Specular), ones)]);
+            } else {
+                paths.scatter(&[(vertex(Scattering::Diffuse), share), (vertex(Scattering::Glossy), ones - share)]);
+            }
+
+            // The sampled RGB value is turned into a spectrum like in eval, the upsampling is linear so this includes specular lobes
+            T = T.mul_element_wise(lambda.from_rgb(bsdf_sample.f) * (cos_r / bsdf_sample.pdf));
+
+            let p = SpectralPathIntegrator::survival_probability(T);
+            if p < sampler.next_f32() { break; }
+            T /= p;
+
+            *ray = Ray::new(I + R * EPSILON, R, f32::MAX);
+            prev_I = I;
+            prev_bsdf_pdf = bsdf_sample.pdf;
+            specular_bounce = bsdf_sample.is_specular;
+
+            depth += 1;
+        }
+
+        E
+    }
+}
+
+impl Integrator for SpectralPathIntegrator {
+    fn name(&self) -> &'static str {
+        "Spectral path tracer"
+    }
+
+    fn radiance(&self, scene: &Scene, ray: &mut Ray, sampler: &mut dyn Sampler) -> Vector3<f32> {
+        SpectralPathIntegrator::trace(scene, ray, sampler, None, &mut LightingAovs::zero(), &mut LightPaths::new(&[]))
+    }
+
+    fn pixel_with_aovs(&self, scene: &Scene, _idx: usize, ray: &mut Ray, sampler: &mut dyn Sampler, aov: &mut AovSample, paths: &mut LightPaths) -> Vector3<f32> {
+        let mut lighting = LightingAovs::zero();
+        let color = SpectralPathIntegrator::trace(scene, ray, sampler, Some(&mut *aov), &mut lighting, paths);
+        aov.lighting = lighting;
+        color
+    }
+
+    fn splits_lighting(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::sampler::{PixelSampler, SamplerKind};
+
+    // Remembers the bounce every number was drawn in, None before the first one
+    struct Recorder {
+        sampler: PixelSampler,
+        bounce: Option<u32>,
+        draws: Vec<(Option<u32>, f32)>,
+    }
+
+    impl Sampler for Recorder {
+        fn next_f32(&mut self) -> f32 {
+            let value = self.sampler.next_f32();
+            self.draws.push((self.bounce, value));
+            value
+        }
+
+        fn start_bounce(&mut self, depth: u32) {
+            self.bounce = Some(depth);
+            self.sampler.start_bounce(depth);
+        }
+    }
+
+    #[test]
+    fn hero_wavelength_and_first_bounce_draw_from_different_dimensions() {
+        let mut scene = Scene::new(32, 24, "src/textures/grem.jpg");
+        scene.build();
+
+        // Sobol gives the same number for the same dimension of a sample, so a shared dimension shows up as equal draws
+        for index in 0..16 {
+            let mut sampler = PixelSampler::new(SamplerKind::Sobol, 7, 400, 32, index);
+            let (x, y) = (sampler.next_f32(), sampler.next_f32());
+            let mut recorder = Recorder { sampler: sampler, bounce: None, draws: Vec::new() };
+            let mut ray = scene.camera().calculate_primary_ray((16.0 + x) / 32.0, (12.0 + y) / 24.0);
+            SpectralPathIntegrator.radiance(&scene, &mut ray, &mut recorder);
+
+            let wavelength = recorder.draws[0];
+            let first_bounce = recorder.draws.iter().find(|(bounce, _)| *bounce == Some(0)).expect("the first bounce draws nothing");
+            assert_eq!(wavelength.0, None);
+            assert_ne!(wavelength.1, first_bounce.1, "sample {}", index);
+        }
+    }
+}
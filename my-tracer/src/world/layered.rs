@@ -0,0 +1,198 @@
+use core::f32;
+use cgmath::*;
+
+use super::{material::{BsdfSample, Material}, math::Math, sampler::Sampler};
+
+// Wavelengths (in nm) averaged for the red, green and blue reflectance of a thin film
+const FILM_WAVELENGTHS: [[f32; 3]; 3] = [[580.0, 610.0, 640.0], [520.0, 550.0, 580.0], [440.0, 465.0, 490.0]];
+
+// Dielectric layer on top of another material, like the lacquer of car paint.
+// Light reflects off the rough coat or enters it, gets absorbed on the way to the base and back, and only the part
+// the coat lets through in both directions reaches the viewer. The base is evaluated without bending the directions,
+// and light reflected back down inside the coat is lost, so a coat never adds energy to its base.
+// An optional thin film on top of the coat adds interference colors, like soap bubbles, oil slicks or anodized metal.
+#[derive(Copy, Clone, PartialEq)]
+pub struct Coat {
+    pub ior: f32,
+    pub roughness: f32,
+    // Absorption coefficient times the thickness of the coat, per channel. Crossing it at normal incidence keeps exp(-absorption)
+    pub absorption: Vector3<f32>,
+    // Thickness of the film in nm, zero leaves it out
+    pub film_thickness: f32,
+    pub film_ior: f32,
+}
+
+impl Coat {
+    pub fn new(ior: f32, roughness: f32) -> Coat {
+        Coat {
+            ior: ior,
+            roughness: roughness,
+            absorption: Vector3::zero(),
+            film_thickness: 0.0,
+            film_ior: 1.33,
+        }
+    }
+
+    // Thin film of the given thickness (nm) and index of refraction on top of the coat
+    pub fn with_film(mut self, thickness: f32, ior: f32) -> Coat {
+        self.film_thickness = thickness;
+        self.film_ior = ior;
+        self
+    }
+
+    pub fn eval(&self, base: &Material, albedo: Vector3<f32>, normal: Vector3<f32>, wo: Vector3<f32>, wi: Vector3<f32>) -> Vector3<f32> {
+        let cos_o = normal.dot(wo);
+        let cos_i = normal.dot(wi);
+        if cos_o <= 0.0 || cos_i == 0.0 { return Vector3::zero(); }
+
+        let mut f = Vector3::zero();
+        if cos_i > 0.0 {
+            let alpha = Material::alpha(self.roughness);
+            let h = (wo + wi).normalize();
+            let d = Material::ggx_d(normal.dot(h), alpha);
+            let g = Material::smith_g1(cos_o, alpha) * Material::smith_g1(cos_i, alpha);
+            f += self.reflectance(wo.dot(h)) * (d * g / (4.0 * cos_o * cos_i));
+        }
+
+        // A delta base can only be reached by sampling
+        if base.is_specular() { return f; }
+
+        f + base.eval(albedo, normal, wo, wi).mul_element_wise(self.transmittance(normal, wo, wi))
+    }
+
+    // The diffuse part of the base, seen through the coat
+    pub fn eval_diffuse(&self, base: &Material, albedo: Vector3<f32>, normal: Vector3<f32>, wo: Vector3<f32>, wi: Vector3<f32>) -> Vector3<f32> {
+        if normal.dot(wo) <= 0.0 || normal.dot(wi) == 0.0 || base.is_specular() { return Vector3::zero(); }
+        base.eval_diffuse(albedo, normal, wo, wi).mul_element_wise(self.transmittance(normal, wo, wi))
+    }
+
+    pub fn pdf(&self, base: &Material, normal: Vector3<f32>, wo: Vector3<f32>, wi: Vector3<f32>) -> f32 {
+        let cos_o = normal.dot(wo);
+        let cos_i = normal.dot(wi);
+        if cos_o <= 0.0 || cos_i == 0.0 { return 0.0; }
+
+        let p_coat = self.coat_probability(cos_o);
+        let mut pdf = 0.0;
+        if cos_i > 0.0 {
+            let alpha = Material::alpha(self.roughness);
+            let h = (wo + wi).normalize();
+            let wo_dot_h = wo.dot(h);
+            if wo_dot_h > 0.0 {
+                let cos_h = normal.dot(h);
+                pdf += p_coat * Material::ggx_d(cos_h, alpha) * cos_h / (4.0 * wo_dot_h);
+            }
+        }
+
+        if base.is_specular() { return pdf; }
+
+        pdf + (1.0 - p_coat) * base.pdf(normal, wo, wi)
+    }
+
+    pub fn sample(&self, base: &Material, albedo: Vector3<f32>, normal: Vector3<f32>, wo: Vector3<f32>, front_face: bool, sampler: &mut dyn Sampler) -> Option<BsdfSample> {
+        let cos_o = normal.dot(wo);
+        if cos_o <= 0.0 { return None; }
+
+        let p_coat = self.coat_probability(cos_o);
+        let wi = if sampler.next_f32() < p_coat {
+            let alpha = Material::alpha(self.roughness);
+            let r1 = sampler.next_f32();
+            let r2 = sampler.next_f32();
+
+            let cos_theta = f32::sqrt((1.0 - r1) / (1.0 + (alpha * alpha - 1.0) * r1));
+            let sin_theta = f32::sqrt(f32::max(0.0, 1.0 - cos_theta * cos_theta));
+            let phi = 2.0 * f32::consts::PI * r2;
+
+            let (tangent, bitangent) = Math::orthonormal_basis(normal);
+            let h = tangent * (sin_theta * phi.cos()) + bitangent * (sin_theta * phi.sin()) + normal * cos_theta;
+
+            let wi = Math::reflect(-wo, h);
+            if normal.dot(wi) <= 0.0 { return None; }
+            wi
+        } else {
+            let mut base_sample = base.sample(albedo, normal, wo, front_face, sampler)?;
+            if base_sample.is_specular {
+                // The delta lobe keeps its pdf of one, so the chance of picking the base goes into f
+                let transmittance = self.transmittance(normal, wo, base_sample.wi);
+                base_sample.f = base_sample.f.mul_element_wise(transmittance) / (1.0 - p_coat);
+                return Some(base_sample);
+            }
+            base_sample.wi
+        };
+
+        let pdf = self.pdf(base, normal, wo, wi);
+        if pdf <= 0.0 { return None; }
+
+        Some(BsdfSample {
+            wi: wi,
+            f: self.eval(base, albedo, normal, wo, wi),
+            pdf: pdf,
+            is_specular: false,
+        })
+    }
+
+    // Chance of sampling the coat instead of the base, follows the reflectance at wo
+    fn coat_probability(&self, cos_o: f32) -> f32 {
+        Math::luminance(self.reflectance(cos_o)).clamp(0.1, 0.9)
+    }
+
+    // Fraction of light reflected by the top of the coat
+    fn reflectance(&self, cos: f32) -> Vector3<f32> {
+        if self.film_thickness > 0.0 {
+            return self.film_reflectance(cos);
+        }
+        let f = Material::dielectric_fresnel(cos.clamp(0.0, 1.0), 1.0 / self.ior);
+        vec3(f, f, f)
+    }
+
+    // Airy reflectance of a film between air and the coat, averaged over both polarizations and a few wavelengths per channel
+    fn film_reflectance(&self, cos: f32) -> Vector3<f32> {
+        let cos_1 = cos.clamp(0.0, 1.0);
+        let sin2_1 = 1.0 - cos_1 * cos_1;
+        let (n_1, n_2, n_3) = (1.0, self.film_ior, self.ior);
+        let cos_2 = f32::sqrt(f32::max(0.0, 1.0 - sin2_1 / (n_2 * n_2)));
+        let cos_3 = f32::sqrt(f32::max(0.0, 1.0 - sin2_1 / (n_3 * n_3)));
+
+        let r12_s = (n_1 * cos_1 - n_2 * cos_2) / (n_1 * cos_1 + n_2 * cos_2);
+        let r12_p = (n_2 * cos_1 - n_1 * cos_2) / (n_2 * cos_1 + n_1 * cos_2);
+        let r23_s = (n_2 * cos_2 - n_3 * cos_3) / (n_2 * cos_2 + n_3 * cos_3);
+        let r23_p = (n_3 * cos_2 - n_2 * cos_3) / (n_3 * cos_2 + n_2 * cos_3);
+
+        let airy = |r12: f32, r23: f32, cos_phase: f32| {
+            let interference = 2.0 * r12 * r23 * cos_phase;
+            (r12 * r12 + r23 * r23 + interference) / (1.0 + r12 * r12 * r23 * r23 + interference)
+        };
+
+        let mut reflectance = [0.0; 3];
+        for (channel, wavelengths) in FILM_WAVELENGTHS.iter().enumerate() {
+            for lambda in wavelengths {
+                // Phase difference between the reflections off the top and the bottom of the film
+                let phase = 4.0 * f32::consts::PI * n_2 * self.film_thickness * cos_2 / lambda;
+                let cos_phase = phase.cos();
+                reflectance[channel] += 0.5 * (airy(r12_s, r23_s, cos_phase) + airy(r12_p, r23_p, cos_phase)) / wavelengths.len() as f32;
+            }
+        }
+        Vector3::from(reflectance)
+    }
+
+    // Fraction of the light that passes the coat to the base along wo and leaves along wi,
+    // transmission of the base through the bottom only crosses it once
+    fn transmittance(&self, normal: Vector3<f32>, wo: Vector3<f32>, wi: Vector3<f32>) -> Vector3<f32> {
+        let cos_o = normal.dot(wo);
+        let cos_i = normal.dot(wi);
+        let mut transmittance = vec3(1.0, 1.0, 1.0) - self.reflectance(cos_o);
+        let mut length = 1.0 / self.refracted_cos(cos_o);
+        if cos_i > 0.0 {
+            transmittance = transmittance.mul_element_wise(vec3(1.0, 1.0, 1.0) - self.reflectance(cos_i));
+            length += 1.0 / self.refracted_cos(cos_i);
+        }
+
+        let absorbed = self.absorption * -length;
+        transmittance.mul_element_wise(vec3(absorbed.x.exp(), absorbed.y.exp(), absorbed.z.exp()))
+    }
+
+    // Cosine of a direction inside the coat, for light entering it at cos
+    fn refracted_cos(&self, cos: f32) -> f32 {
+        let sin2 = (1.0 - cos * cos) / (self.ior * self.ior);
+        f32::max(f32::sqrt(f32::max(0.0, 1.0 - sin2)), 1e-4)
+    }
+}
@@ -0,0 +1,513 @@
+use std::collections::HashMap;
+use cgmath::*;
+
+// Most DFA states an expression may need, so a pathological one can't stall the render
+const MAX_STATES: usize = 1024;
+
+// How a path scattered at one of its vertices
+#[derive(Copy, Clone, PartialEq)]
+pub enum Scattering {
+    Diffuse,
+    Glossy,
+    Specular,
+}
+
+// One vertex of a path as light path expressions see it. A path starts at the camera, scatters at surfaces
+// and in volumes, and ends on a light (given by its light group) or the background.
+#[derive(Copy, Clone, PartialEq)]
+pub enum Event {
+    Camera,
+    Reflection(Scattering),
+    Transmission(Scattering),
+    Volume,
+    Light(usize),
+    Background,
+}
+
+impl Event {
+    // Index of the event in the alphabet the expressions are compiled to, lights come last, one symbol per group
+    fn symbol(&self) -> usize {
+        let scattering = |s: &Scattering| match s {
+            Scattering::Diffuse => 0,
+            Scattering::Glossy => 1,
+            Scattering::Specular => 2,
+        };
+        match self {
+            Event::Camera => 0,
+            Event::Reflection(s) => 1 + scattering(s),
+            Event::Transmission(s) => 4 + scattering(s),
+            Event::Volume => 7,
+            Event::Background => 8,
+            Event::Light(group) => 9 + group,
+        }
+    }
+
+    // Inverse of symbol, as the type and scattering letters of the expressions
+    fn letters(symbol: usize) -> (char, Option<char>, Option<usize>) {
+        let scattering = ['D', 'G', 'S'];
+        match symbol {
+            0 => ('C', None, None),
+            1..=3 => ('R', Some(scattering[symbol - 1]), None),
+            4..=6 => ('T', Some(scattering[symbol - 4]), None),
+            7 => ('V', None, None),
+            8 => ('B', None, None),
+            _ => ('L', None, Some(symbol - 9)),
+        }
+    }
+}
+
+// Parsed expression, every leaf is the set of symbols it matches
+enum Node {
+    Symbols(Vec<bool>),
+    Concat(Vec<Node>),
+    Alternation(Vec<Node>),
+    Star(Box<Node>),
+    Plus(Box<Node>),
+    Optional(Box<Node>),
+}
+
+// Recursive descent parser of the expression syntax
+struct Parser<'a> {
+    chars: Vec<char>,
+    pos: usize,
+    groups: &'a [String],
+}
+
+impl<'a> Parser<'a> {
+    fn alphabet(&self) -> usize {
+        9 + self.groups.len()
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        while self.pos < self.chars.len() && self.chars[self.pos].is_whitespace() {
+            self.pos += 1;
+        }
+        self.chars.get(self.pos).copied()
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        match self.peek() {
+            Some(p) if p == c => { self.pos += 1; Ok(()) }
+            Some(p) => Err(format!("expected '{}' at {}, found '{}'", c, self.pos, p)),
+            None => Err(format!("expected '{}' at the end", c)),
+        }
+    }
+
+    // alternation := concat ('|' concat)*
+    fn alternation(&mut self) -> Result<Node, String> {
+        let mut options = vec![self.concat()?];
+        while self.peek() == Some('|') {
+            self.pos += 1;
+            options.push(self.concat()?);
+        }
+        Ok(if options.len() == 1 { options.pop().unwrap() } else { Node::Alternation(options) })
+    }
+
+    // concat := repeat*
+    fn concat(&mut self) -> Result<Node, String> {
+        let mut items = Vec::new();
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' { break; }
+            items.push(self.repeat()?);
+        }
+        Ok(Node::Concat(items))
+    }
+
+    // repeat := atom ('*' | '+' | '?')*
+    fn repeat(&mut self) -> Result<Node, String> {
+        let mut node = self.atom()?;
+        loop {
+            node = match self.peek() {
+                Some('*') => Node::Star(Box::new(node)),
+                Some('+') => Node::Plus(Box::new(node)),
+                Some('?') => Node::Optional(Box::new(node)),
+                _ => return Ok(node),
+            };
+            self.pos += 1;
+        }
+    }
+
+    // atom := '(' alternation ')' | '[' '^'? symbols+ ']' | symbols
+    fn atom(&mut self) -> Result<Node, String> {
+        match self.peek() {
+            Some('(') => {
+                self.pos += 1;
+                let node = self.alternation()?;
+                self.expect(')')?;
+                Ok(node)
+            }
+            Some('[') => {
+                self.pos += 1;
+                let negated = self.peek() == Some('^');
+                if negated { self.pos += 1; }
+
+                let mut set = vec![false; self.alphabet()];
+                while self.peek() != Some(']') {
+                    for (s, matched) in self.symbols()?.into_iter().enumerate() {
+                        set[s] |= matched;
+                    }
+                }
+                self.pos += 1;
+                if negated {
+                    set.iter_mut().for_each(|s| *s = !*s);
+                }
+                Ok(Node::Symbols(set))
+            }
+            _ => Ok(Node::Symbols(self.symbols()?)),
+        }
+    }
+
+    // symbols := '.' | type letter | scattering letter | '<' type scattering? label? '>', where '.' stands for any type or scattering
+    fn symbols(&mut self) -> Result<Vec<bool>, String> {
+        let c = self.peek().ok_or("unexpected end of the expression")?;
+        self.pos += 1;
+        let (kind, scattering, label) = match c {
+            '.' => ('.', '.', None),
+            'C' | 'R' | 'T' | 'V' | 'L' | 'B' => (c, '.', None),
+            'D' | 'G' | 'S' => ('.', c, None),
+            '<' => {
+                let kind = self.peek().ok_or("unexpected end of the expression")?;
+                self.pos += 1;
+                // The scattering can be left out before a label
+                let scattering = match self.peek().ok_or("unexpected end of the expression")? {
+                    '\'' => '.',
+                    c => { self.pos += 1; c }
+                };
+                let label = if self.peek() == Some('\'') { Some(self.label()?) } else { None };
+                self.expect('>')?;
+                if !"CRTVLB.".contains(kind) { return Err(format!("unknown event type '{}'", kind)); }
+                if !"DGS.".contains(scattering) { return Err(format!("unknown scattering type '{}'", scattering)); }
+                (kind, scattering, label)
+            }
+            _ => return Err(format!("unexpected '{}' at {}", c, self.pos - 1)),
+        };
+
+        Ok((0..self.alphabet()).map(|symbol| {
+            let (k, s, group) = Event::letters(symbol);
+            (kind == '.' || kind == k)
+                && (scattering == '.' || s == Some(scattering))
+                && label.map_or(true, |l| group == Some(l))
+        }).collect())
+    }
+
+    // label := "'" name "'", the name of a light group
+    fn label(&mut self) -> Result<usize, String> {
+        self.expect('\'')?;
+        let start = self.pos;
+        while self.pos < self.chars.len() && self.chars[self.pos] != '\'' {
+            self.pos += 1;
+        }
+        let name: String = self.chars[start..self.pos].iter().collect();
+        self.expect('\'')?;
+        self.groups.iter().position(|g| *g == name).ok_or(format!("unknown light group '{}'", name))
+    }
+}
+
+// Thompson construction, every state has edges on a set of symbols (the index of a leaf) or on nothing
+struct Nfa {
+    edges: Vec<Vec<(Option<usize>, usize)>>,
+    leaves: Vec<Vec<bool>>,
+}
+
+impl Nfa {
+    fn state(&mut self) -> usize {
+        self.edges.push(Vec::new());
+        self.edges.len() - 1
+    }
+
+    // Adds the states of node, returns its start and end
+    fn build(&mut self, node: &Node) -> (usize, usize) {
+        let (start, end) = (self.state(), self.state());
+        match node {
+            Node::Symbols(set) => {
+                self.leaves.push(set.clone());
+                self.edges[start].push((Some(self.leaves.len() - 1), end));
+            }
+            Node::Concat(items) => {
+                let mut last = start;
+                for item in items {
+                    let (s, e) = self.build(item);
+                    self.edges[last].push((None, s));
+                    last = e;
+                }
+                self.edges[last].push((None, end));
+            }
+            Node::Alternation(options) => {
+                for option in options {
+                    let (s, e) = self.build(option);
+                    self.edges[start].push((None, s));
+                    self.edges[e].push((None, end));
+                }
+            }
+            Node::Star(inner) | Node::Plus(inner) | Node::Optional(inner) => {
+                let (s, e) = self.build(inner);
+                self.edges[start].push((None, s));
+                self.edges[e].push((None, end));
+                if !matches!(node, Node::Plus(_)) { self.edges[start].push((None, end)); }
+                if !matches!(node, Node::Optional(_)) { self.edges[e].push((None, s)); }
+            }
+        }
+        (start, end)
+    }
+
+    // The states plus every state reachable from them without a symbol, sorted
+    fn closure(&self, states: Vec<usize>) -> Vec<usize> {
+        let mut reached = vec![false; self.edges.len()];
+        let mut stack = states;
+        while let Some(s) = stack.pop() {
+            if reached[s] { continue; }
+            reached[s] = true;
+            stack.extend(self.edges[s].iter().filter(|(leaf, _)| leaf.is_none()).map(|(_, t)| *t));
+        }
+        (0..self.edges.len()).filter(|&s| reached[s]).collect()
+    }
+}
+
+// Light path expression compiled to a DFA over the events of a path. The syntax follows OSL: C camera, R reflection,
+// T transmission, V volume, L light and B background, D diffuse, G glossy and S specular scattering, <TS> for a type
+// and scattering together, <L'name'> for the lights of a group, '.' for anything, [...] and [^...] for (negated) sets,
+// and |, *, +, ? and parentheses as in regular expressions. For example C<RD>L is direct diffuse light and C<RD>.*B
+// all light from the background that first scattered diffusely.
+pub struct LightPathExpression {
+    // Next state of every state for every symbol, None if the path can't match anymore
+    transitions: Vec<Vec<Option<usize>>>,
+    accepting: Vec<bool>,
+}
+
+impl LightPathExpression {
+    // Compiles the expression for a scene with the given light groups
+    pub fn parse(source: &str, groups: &[String]) -> Result<LightPathExpression, String> {
+        let mut parser = Parser { chars: source.chars().collect(), pos: 0, groups: groups };
+        let node = parser.alternation()?;
+        if parser.peek().is_some() {
+            return Err(format!("unexpected '{}' at {}", parser.chars[parser.pos], parser.pos));
+        }
+
+        let mut nfa = Nfa { edges: Vec::new(), leaves: Vec::new() };
+        let (start, end) = nfa.build(&node);
+
+        // Subset construction, state 0 is the start
+        let alphabet = parser.alphabet();
+        let mut sets = vec![nfa.closure(vec![start])];
+        let mut ids: HashMap<Vec<usize>, usize> = HashMap::new();
+        ids.insert(sets[0].clone(), 0);
+        let mut transitions = Vec::new();
+        let mut current = 0;
+        while current < sets.len() {
+            let mut row = vec![None; alphabet];
+            for (symbol, next) in row.iter_mut().enumerate() {
+                let targets: Vec<usize> = sets[current].iter()
+                    .flat_map(|&s| nfa.edges[s].iter())
+                    .filter(|(leaf, _)| leaf.map_or(false, |l| nfa.leaves[l][symbol]))
+                    .map(|(_, t)| *t)
+                    .collect();
+                if targets.is_empty() { continue; }
+
+                let set = nfa.closure(targets);
+                *next = Some(match ids.get(&set) {
+                    Some(&id) => id,
+                    None => {
+                        if sets.len() == MAX_STATES { return Err("the expression is too complex".to_string()); }
+                        ids.insert(set.clone(), sets.len());
+                        sets.push(set);
+                        sets.len() - 1
+                    }
+                });
+            }
+            transitions.push(row);
+            current += 1;
+        }
+
+        Ok(LightPathExpression {
+            accepting: sets.iter().map(|set| set.contains(&end)).collect(),
+            transitions: transitions,
+        })
+    }
+
+    fn next(&self, state: usize, event: Event) -> Option<usize> {
+        self.transitions[state][event.symbol()]
+    }
+}
+
+// A buffer of the light whose paths match an expression
+pub struct LightPass {
+    pub name: String,
+    pub source: String,
+    pub expression: LightPathExpression,
+}
+
+impl LightPass {
+    pub fn new(name: &str, source: &str, groups: &[String]) -> Result<LightPass, String> {
+        Ok(LightPass {
+            name: name.to_string(),
+            source: source.to_string(),
+            expression: LightPathExpression::parse(source, groups)?,
+        })
+    }
+
+    // Name of the layer in exported EXR files, prefixed so it can't clash with the other AOVs
+    pub fn layer(&self) -> String {
+        format!("lpe_{}", self.name.replace(char::is_whitespace, "_"))
+    }
+}
+
+// Follows the paths of one pixel through the expressions of the light passes and sums the light of every pass.
+// A vertex can scatter with several lobes at once, so instead of a single state every expression keeps the part of
+// the throughput (per channel) that is in each of its states. The parts come from different event strings, which end
+// in different states of a DFA, so adding them up never counts light twice.
+pub struct LightPaths<'a> {
+    passes: &'a [LightPass],
+    states: Vec<Vec<(usize, Vector3<f32>)>>,
+    // Light of every pass summed over the samples of the pixel
+    pub values: Vec<Vector3<f32>>,
+}
+
+impl<'a> LightPaths<'a> {
+    pub fn new(passes: &'a [LightPass]) -> LightPaths<'a> {
+        LightPaths {
+            passes: passes,
+            states: vec![Vec::new(); passes.len()],
+            values: vec![Vector3::zero(); passes.len()],
+        }
+    }
+
+    // Starts the next path at the camera
+    pub fn start(&mut self) {
+        for (pass, states) in self.passes.iter().zip(self.states.iter_mut()) {
+            states.clear();
+            if let Some(s) = pass.expression.next(0, Event::Camera) {
+                states.push((s, vec3(1.0, 1.0, 1.0)));
+            }
+        }
+    }
+
+    // The path continues from a vertex that scattered with the given events, shares are the parts of the
+    // throughput (per channel) each of them scattered and add up to one
+    pub fn scatter(&mut self, shares: &[(Event, Vector3<f32>)]) {
+        for (pass, states) in self.passes.iter().zip(self.states.iter_mut()) {
+            let mut next: Vec<(usize, Vector3<f32>)> = Vec::with_capacity(states.len());
+            for &(state, weight) in states.iter() {
+                for &(event, share) in shares {
+                    let s = match pass.expression.next(state, event) {
+                        Some(s) => s,
+                        None => continue
+                    };
+                    let weight = weight.mul_element_wise(share);
+                    match next.iter_mut().find(|(t, _)| *t == s) {
+                        Some((_, w)) => *w += weight,
+                        None => next.push((s, weight)),
+                    }
+                }
+            }
+            *states = next;
+        }
+    }
+
+    // Light reaching the camera from a light (or the background) hit by the path
+    pub fn add_emitted(&mut self, contribution: Vector3<f32>, light: Event) {
+        for ((pass, states), value) in self.passes.iter().zip(self.states.iter()).zip(self.values.iter_mut()) {
+            for &(state, weight) in states {
+                if pass.expression.next(state, light).map_or(false, |s| pass.expression.accepting[s]) {
+                    *value += contribution.mul_element_wise(weight);
+                }
+            }
+        }
+    }
+
+    // Light sampled at the current vertex, split into the light each of the given events scattered towards the camera
+    pub fn add_direct(&mut self, lobes: &[(Event, Vector3<f32>)], light: Event) {
+        for ((pass, states), value) in self.passes.iter().zip(self.states.iter()).zip(self.values.iter_mut()) {
+            for &(state, weight) in states {
+                for &(event, contribution) in lobes {
+                    let s = pass.expression.next(state, event).and_then(|s| pass.expression.next(s, light));
+                    if s.map_or(false, |s| pass.expression.accepting[s]) {
+                        *value += contribution.mul_element_wise(weight);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn groups() -> Vec<String> {
+        vec!["key".to_string(), "fill".to_string()]
+    }
+
+    fn matches(source: &str, events: &[Event]) -> bool {
+        let expression = LightPathExpression::parse(source, &groups()).unwrap();
+        let mut state = Some(0);
+        for &event in events {
+            state = state.and_then(|s| expression.next(s, event));
+        }
+        state.map_or(false, |s| expression.accepting[s])
+    }
+
+    const DIFFUSE: Event = Event::Reflection(Scattering::Diffuse);
+    const GLOSSY: Event = Event::Reflection(Scattering::Glossy);
+    const GLASS: Event = Event::Transmission(Scattering::Specular);
+
+    #[test]
+    fn matches_direct_and_indirect_diffuse() {
+        assert!(matches("C<RD>L", &[Event::Camera, DIFFUSE, Event::Light(0)]));
+        assert!(!matches("C<RD>L", &[Event::Camera, GLOSSY, Event::Light(0)]));
+        assert!(!matches("C<RD>L", &[Event::Camera, DIFFUSE, DIFFUSE, Event::Light(0)]));
+        assert!(!matches("C<RD>L", &[Event::Camera, DIFFUSE, Event::Background]));
+
+        assert!(matches("C<RD>.+L", &[Event::Camera, DIFFUSE, GLASS, Event::Volume, Event::Light(1)]));
+        assert!(!matches("C<RD>.+L", &[Event::Camera, DIFFUSE, Event::Light(1)]));
+    }
+
+    #[test]
+    fn matches_light_groups_and_the_background() {
+        assert!(matches("C.*<L'key'>", &[Event::Camera, GLOSSY, DIFFUSE, Event::Light(0)]));
+        assert!(!matches("C.*<L'key'>", &[Event::Camera, GLOSSY, DIFFUSE, Event::Light(1)]));
+        assert!(matches("C.*B", &[Event::Camera, Event::Background]));
+        assert!(matches("C.*B", &[Event::Camera, GLASS, GLASS, Event::Background]));
+        assert!(!matches("C.*B", &[Event::Camera, GLASS, Event::Light(0)]));
+    }
+
+    #[test]
+    fn matches_sets_alternations_and_scattering_letters() {
+        assert!(matches("C[^D]*L", &[Event::Camera, GLOSSY, GLASS, Event::Light(0)]));
+        assert!(!matches("C[^D]*L", &[Event::Camera, GLOSSY, DIFFUSE, Event::Light(0)]));
+        assert!(matches("C(S|G)?L", &[Event::Camera, Event::Light(1)]));
+        assert!(matches("C(S|G)?L", &[Event::Camera, GLASS, Event::Light(1)]));
+        assert!(!matches("C(S|G)?L", &[Event::Camera, Event::Volume, Event::Light(1)]));
+        assert!(matches("C <T S> V L", &[Event::Camera, GLASS, Event::Volume, Event::Light(0)]));
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        for source in ["C<RD", "C(R", "C)L", "CXL", "C<QD>L", "C<RX>L", "C[RD", "C<L'rim'>"] {
+            assert!(LightPathExpression::parse(source, &groups()).is_err(), "{} was accepted", source);
+        }
+    }
+
+    #[test]
+    fn rejects_expressions_with_too_many_states() {
+        // Remembering which of the last n events were reflections takes 2^n states
+        let source = |n: usize| format!("C.*R{}L", ".".repeat(n));
+        assert!(LightPathExpression::parse(&source(6), &groups()).is_ok());
+        assert!(LightPathExpression::parse(&source(10), &groups()).is_err());
+    }
+
+    #[test]
+    fn splits_the_throughput_of_several_lobes() {
+        let passes = vec![
+            LightPass::new("diffuse", "C<RD>L", &groups()).unwrap(),
+            LightPass::new("glossy", "C<RG>L", &groups()).unwrap(),
+        ];
+        let mut paths = LightPaths::new(&passes);
+        paths.start();
+        paths.scatter(&[(DIFFUSE, vec3(0.25, 0.25, 0.25)), (GLOSSY, vec3(0.75, 0.75, 0.75))]);
+        paths.add_emitted(vec3(1.0, 2.0, 4.0), Event::Light(0));
+
+        assert_eq!(paths.values[0], vec3(0.25, 0.5, 1.0));
+        assert_eq!(paths.values[1], vec3(0.75, 1.5, 3.0));
+        assert_eq!(passes[0].layer(), "lpe_diffuse");
+    }
+}
@@ -0,0 +1,323 @@
+use core::f32;
+use std::sync::RwLock;
+use cgmath::*;
+use super::{energy::EnergyCompensation, layered::Coat, math::Math, principled::Principled, sampler::Sampler, texture::TexturedParameters};
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Material {
+    Diffuse,
+    Glossy { roughness: f32 },
+    Mirror,
+    // ior is the index of refraction at 587.6 nm, dispersion the B coefficient (in um^2) of Cauchy's equation
+    Dielectric { ior: f32, dispersion: f32 },
+    // Light travels through the inside of the object, mean_free_path is the average distance between scattering events per channel.
+    // The path tracer walks through the object, other integrators shade it as a diffuse surface.
+    Subsurface { mean_free_path: Vector3<f32> },
+    // Layered Disney style BSDF, the albedo is its base color
+    Principled(Principled),
+    // A dielectric coat over another material, see Material::coated
+    Coated { coat: Coat, base: BaseMaterialId },
+}
+
+// Index of the base of a coated material in the base material table
+#[derive(Copy, Clone, PartialEq)]
+pub struct BaseMaterialId(usize);
+
+lazy_static::lazy_static! {
+    // Bases of the coated materials, equal bases share an entry so equal coated materials compare equal
+    static ref BASE_MATERIALS: RwLock<Vec<Material>> = RwLock::new(Vec::new());
+}
+
+impl BaseMaterialId {
+    fn insert(material: Material) -> BaseMaterialId {
+        let mut materials = BASE_MATERIALS.write().unwrap();
+        if let Some(idx) = materials.iter().position(|m| *m == material) {
+            return BaseMaterialId(idx);
+        }
+        materials.push(material);
+        BaseMaterialId(materials.len() - 1)
+    }
+
+    pub fn get(&self) -> Material {
+        BASE_MATERIALS.read().unwrap()[self.0]
+    }
+}
+
+pub struct BsdfSample {
+    pub wi: Vector3<f32>,
+    pub f: Vector3<f32>,
+    pub pdf: f32,
+    // Sampled from a delta distribution, eval and pdf can't reproduce it
+    pub is_specular: bool,
+}
+
+// All directions point away from the surface, wo towards the viewer and wi towards the light.
+// The normal faces wo, front_face tells whether that is the outside of the primitive.
+impl Material {
+    // Mirrors and dielectrics scatter in a single direction, so light sampling can't hit them
+    pub fn is_specular(&self) -> bool {
+        match self {
+            Material::Mirror | Material::Dielectric { .. } => true,
+            _ => false,
+        }
+    }
+
+    // Dielectrics whose index of refraction depends on the wavelength
+    pub fn is_dispersive(&self) -> bool {
+        match self {
+            Material::Dielectric { dispersion, .. } => *dispersion != 0.0,
+            _ => false,
+        }
+    }
+
+    // Puts a coat on top of base. Materials are small copies that are passed around everywhere,
+    // so they only keep the index of their base in the base material table.
+    pub fn coated(base: Material, coat: Coat) -> Material {
+        Material::Coated { coat: coat, base: BaseMaterialId::insert(base) }
+    }
+
+    // Materials that scatter light through the surface, so light sampling has to consider the lower hemisphere too
+    pub fn is_transmissive(&self) -> bool {
+        match self {
+            Material::Principled(p) => p.is_transmissive(),
+            Material::Coated { base, .. } => base.get().is_transmissive(),
+            _ => false,
+        }
+    }
+
+    // The material as seen from the side of the normal, the principled BSDF stores its index of refraction relative to wo
+    pub fn seen_from(&self, front_face: bool) -> Material {
+        match self {
+            Material::Principled(p) if !front_face => Material::Principled(Principled { ior: 1.0 / p.ior, ..*p }),
+            // The coat only covers the outside
+            Material::Coated { base, .. } if !front_face => base.get().seen_from(front_face),
+            _ => *self,
+        }
+    }
+
+    // The material as seen by light of a single wavelength (in nm)
+    pub fn at_wavelength(&self, lambda: f32) -> Material {
+        match self {
+            Material::Dielectric { ior, dispersion } => {
+                let um = lambda * 0.001;
+                let d_line = 0.5876;
+                Material::Dielectric {
+                    ior: ior + dispersion * (1.0 / (um * um) - 1.0 / (d_line * d_line)),
+                    dispersion: 0.0,
+                }
+            }
+            _ => *self,
+        }
+    }
+
+    // The material with values read from textures. Roughness only changes glossy and principled materials. With a metalness
+    // map diffuse and glossy materials follow the metalness workflow of the principled BSDF, which blends its dielectric and
+    // metal lobes by metalness. Mirrors, dielectrics, subsurface and coated materials ignore metalness.
+    // The other principled parameters only change principled materials, the index of refraction also dielectrics and
+    // the mean free path subsurface materials.
+    // Coated materials keep their base, since that would have to be stored again for every hit.
+    pub fn textured(&self, textures: &TexturedParameters) -> Material {
+        let (roughness, metalness) = (textures.roughness, textures.metalness);
+        let material = match (self, roughness) {
+            (Material::Glossy { .. }, Some(r)) => Material::Glossy { roughness: r },
+            (Material::Principled(p), Some(r)) => Material::Principled(Principled { roughness: r, ..*p }),
+            _ => *self,
+        };
+
+        let material = match (material, metalness) {
+            (Material::Principled(p), Some(m)) => Material::Principled(Principled { metallic: m, ..p }),
+            (Material::Diffuse, Some(m)) => Material::Principled(Principled { metallic: m, roughness: roughness.unwrap_or(1.0), ..Principled::new() }),
+            (Material::Glossy { roughness: r }, Some(m)) => Material::Principled(Principled { metallic: m, roughness: r, ..Principled::new() }),
+            _ => material,
+        };
+
+        match material {
+            Material::Principled(p) => Material::Principled(Principled {
+                specular: textures.specular.unwrap_or(p.specular),
+                specular_tint: textures.specular_tint.unwrap_or(p.specular_tint),
+                anisotropic: textures.anisotropic.unwrap_or(p.anisotropic),
+                sheen: textures.sheen.unwrap_or(p.sheen),
+                sheen_tint: textures.sheen_tint.unwrap_or(p.sheen_tint),
+                clearcoat: textures.clearcoat.unwrap_or(p.clearcoat),
+                clearcoat_gloss: textures.clearcoat_gloss.unwrap_or(p.clearcoat_gloss),
+                transmission: textures.transmission.unwrap_or(p.transmission),
+                ior: textures.ior.unwrap_or(p.ior),
+                ..p
+            }),
+            Material::Dielectric { ior, dispersion } => Material::Dielectric { ior: textures.ior.unwrap_or(ior), dispersion: dispersion },
+            Material::Subsurface { mean_free_path } => Material::Subsurface {
+                mean_free_path: textures.mean_free_path.map_or(mean_free_path, |scale| mean_free_path.mul_element_wise(scale)),
+            },
+            _ => material,
+        }
+    }
+
+    pub fn eval(&self, albedo: Vector3<f32>, normal: Vector3<f32>, wo: Vector3<f32>, wi: Vector3<f32>) -> Vector3<f32> {
+        match self {
+            Material::Principled(p) => return p.eval(albedo, normal, wo, wi),
+            Material::Coated { coat, base } => return coat.eval(&base.get(), albedo, normal, wo, wi),
+            _ => {}
+        }
+
+        let cos_i = normal.dot(wi);
+        let cos_o = normal.dot(wo);
+        if cos_i <= 0.0 || cos_o <= 0.0 { return Vector3::zero(); }
+
+        match self {
+            Material::Diffuse | Material::Subsurface { .. } => albedo * f32::consts::FRAC_1_PI,
+            Material::Glossy { roughness } => {
+                let alpha = Material::alpha(*roughness);
+                let h = (wo + wi).normalize();
+
+                let d = Material::ggx_d(normal.dot(h), alpha);
+                let g = Material::smith_g1(cos_o, alpha) * Material::smith_g1(cos_i, alpha);
+                let f = Material::schlick_fresnel(albedo, wo.dot(h));
+
+                f * (d * g / (4.0 * cos_o * cos_i)) + EnergyCompensation::separable(EnergyCompensation::schlick_average(albedo), *roughness, cos_o, cos_i)
+            }
+            Material::Mirror | Material::Dielectric { .. } | Material::Principled(_) | Material::Coated { .. } => Vector3::zero(),
+        }
+    }
+
+    // Part of eval scattered by diffuse lobes, the rest is specular. Used to split the lighting passes.
+    pub fn eval_diffuse(&self, albedo: Vector3<f32>, normal: Vector3<f32>, wo: Vector3<f32>, wi: Vector3<f32>) -> Vector3<f32> {
+        match self {
+            Material::Diffuse | Material::Subsurface { .. } => self.eval(albedo, normal, wo, wi),
+            Material::Principled(p) => p.eval_diffuse(albedo, normal, wo, wi),
+            Material::Coated { coat, base } => coat.eval_diffuse(&base.get(), albedo, normal, wo, wi),
+            Material::Glossy { .. } | Material::Mirror | Material::Dielectric { .. } => Vector3::zero(),
+        }
+    }
+
+    pub fn pdf(&self, normal: Vector3<f32>, wo: Vector3<f32>, wi: Vector3<f32>) -> f32 {
+        match self {
+            Material::Principled(p) => return p.pdf(normal, wo, wi),
+            Material::Coated { coat, base } => return coat.pdf(&base.get(), normal, wo, wi),
+            _ => {}
+        }
+
+        let cos_i = normal.dot(wi);
+        if cos_i <= 0.0 || normal.dot(wo) <= 0.0 { return 0.0; }
+
+        match self {
+            Material::Diffuse | Material::Subsurface { .. } => cos_i * f32::consts::FRAC_1_PI,
+            Material::Glossy { roughness } => {
+                let alpha = Material::alpha(*roughness);
+                let h = (wo + wi).normalize();
+                let cos_h = normal.dot(h);
+                let wo_dot_h = wo.dot(h);
+                let p_cosine = Material::compensation_probability(*roughness, normal.dot(wo));
+                let p_cosine_wi = p_cosine * cos_i * f32::consts::FRAC_1_PI;
+                if wo_dot_h <= 0.0 { return p_cosine_wi; }
+                (1.0 - p_cosine) * Material::ggx_d(cos_h, alpha) * cos_h / (4.0 * wo_dot_h) + p_cosine_wi
+            }
+            Material::Mirror | Material::Dielectric { .. } | Material::Principled(_) | Material::Coated { .. } => 0.0,
+        }
+    }
+
+    pub fn sample(&self, albedo: Vector3<f32>, normal: Vector3<f32>, wo: Vector3<f32>, front_face: bool, sampler: &mut dyn Sampler) -> Option<BsdfSample> {
+        let wi = match self {
+            Material::Mirror => {
+                let wi = Math::reflect(-wo, normal);
+                return Some(Material::specular_sample(wi, albedo, normal));
+            }
+            Material::Dielectric { ior, .. } => {
+                let eta = if front_face { 1.0 / ior } else { *ior };
+                let cos_o = normal.dot(wo).min(1.0);
+                let fresnel = Material::dielectric_fresnel(cos_o, eta);
+
+                // Pick reflection or refraction proportional to the fresnel term
+                let sin2_t = eta * eta * (1.0 - cos_o * cos_o);
+                let total_internal_reflection = sin2_t >= 1.0;
+                let wi = if total_internal_reflection || sampler.next_f32() < fresnel {
+                    Math::reflect(-wo, normal)
+                } else {
+                    let cos_t = f32::sqrt(1.0 - sin2_t);
+                    (-wo * eta + normal * (eta * cos_o - cos_t)).normalize()
+                };
+                return Some(Material::specular_sample(wi, albedo, normal));
+            }
+            Material::Diffuse | Material::Subsurface { .. } => Math::random_cosine_hemisphere_vectorf32(sampler, normal),
+            Material::Glossy { roughness } if sampler.next_f32() < Material::compensation_probability(*roughness, normal.dot(wo)) => {
+                Math::random_cosine_hemisphere_vectorf32(sampler, normal)
+            }
+            Material::Glossy { roughness } => {
+                let alpha = Material::alpha(*roughness);
+                let r1 = sampler.next_f32();
+                let r2 = sampler.next_f32();
+
+                // Sample a microfacet normal proportional to D(h) * cos(theta_h)
+                let cos_theta = f32::sqrt((1.0 - r1) / (1.0 + (alpha * alpha - 1.0) * r1));
+                let sin_theta = f32::sqrt(f32::max(0.0, 1.0 - cos_theta * cos_theta));
+                let phi = 2.0 * f32::consts::PI * r2;
+
+                let (tangent, bitangent) = Math::orthonormal_basis(normal);
+                let h = tangent * (sin_theta * phi.cos()) + bitangent * (sin_theta * phi.sin()) + normal * cos_theta;
+
+                Math::reflect(-wo, h)
+            }
+            Material::Principled(p) => p.sample(normal, wo, sampler)?,
+            Material::Coated { coat, base } => return coat.sample(&base.get(), albedo, normal, wo, front_face, sampler),
+        };
+
+        let pdf = self.pdf(normal, wo, wi);
+        if pdf <= 0.0 { return None; }
+
+        Some(BsdfSample {
+            wi: wi,
+            f: self.eval(albedo, normal, wo, wi),
+            pdf: pdf,
+            is_specular: false,
+        })
+    }
+
+    // Chance that a glossy material samples a cosine distributed direction, which follows the energy compensation lobe better than GGX
+    fn compensation_probability(roughness: f32, cos_o: f32) -> f32 {
+        f32::min(EnergyCompensation::separable_loss(roughness, cos_o), 0.5)
+    }
+
+    // Delta lobe with pdf 1, f is chosen so that f * |cos| / pdf equals the tint
+    fn specular_sample(wi: Vector3<f32>, tint: Vector3<f32>, normal: Vector3<f32>) -> BsdfSample {
+        let cos_i = f32::max(f32::abs(normal.dot(wi)), 1e-6);
+        BsdfSample {
+            wi: wi,
+            f: tint / cos_i,
+            pdf: 1.0,
+            is_specular: true,
+        }
+    }
+
+    // Unpolarized fresnel reflectance, eta is the ratio of the incident over the transmitted index of refraction
+    pub(crate) fn dielectric_fresnel(cos_i: f32, eta: f32) -> f32 {
+        let sin2_t = eta * eta * (1.0 - cos_i * cos_i);
+        if sin2_t >= 1.0 { return 1.0; }
+
+        let cos_t = f32::sqrt(1.0 - sin2_t);
+        let r_parallel = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+        let r_perpendicular = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+        0.5 * (r_parallel * r_parallel + r_perpendicular * r_perpendicular)
+    }
+
+    pub(crate) fn alpha(roughness: f32) -> f32 {
+        f32::max(roughness * roughness, 0.002)
+    }
+
+    pub(crate) fn ggx_d(cos_h: f32, alpha: f32) -> f32 {
+        if cos_h <= 0.0 { return 0.0; }
+        let a2 = alpha * alpha;
+        // Written with sin^2 so the peak of very smooth surfaces doesn't cancel out to zero
+        let cos2 = f32::min(cos_h * cos_h, 1.0);
+        let d = (1.0 - cos2) + cos2 * a2;
+        a2 / (f32::consts::PI * d * d)
+    }
+
+    pub(crate) fn smith_g1(cos_v: f32, alpha: f32) -> f32 {
+        let a2 = alpha * alpha;
+        2.0 * cos_v / (cos_v + f32::sqrt(a2 + (1.0 - a2) * cos_v * cos_v))
+    }
+
+    fn schlick_fresnel(f0: Vector3<f32>, cos: f32) -> Vector3<f32> {
+        let m = f32::powi(1.0 - cos.clamp(0.0, 1.0), 5);
+        f0 + (vec3(1.0, 1.0, 1.0) - f0) * m
+    }
+}
@@ -1,22 +1,13 @@
 use cgmath::*;
+use super::sampler::Sampler;
 
 pub struct Math;
 
-static mut SEED : u32 = 0x11112222;
-
 impl Math{
     pub fn reflect(incident: Vector3<f32>, normal: Vector3<f32>) -> Vector3<f32> {
         incident - 2.0 * incident.dot(normal) * normal
     }
 
-    pub fn random_seed_uint() -> u32{
-        unsafe {
-            SEED ^= SEED << 13;
-            SEED ^= SEED >> 17;
-            SEED ^= SEED << 5;
-            return SEED;    
-        }
-    }
 
     pub fn random_uint(seed : &mut u32) -> u32 {
         *seed ^= *seed << 13;
@@ -39,20 +30,65 @@ impl Math{
         Math::random_uint(seed) as f32 * 2.3283064365387e-10
     }
 
-    pub fn random_uniform_vectorf32(seed : &mut u32) -> Vector3<f32>{
-        let x = Math::random_f32(seed) * 2.0 - 1.0;
-        let y = Math::random_f32(seed) * 2.0 - 1.0;
-        let z = Math::random_f32(seed) * 2.0 - 1.0;
+    pub fn random_uniform_vectorf32(sampler: &mut dyn Sampler) -> Vector3<f32>{
+        let x = sampler.next_f32() * 2.0 - 1.0;
+        let y = sampler.next_f32() * 2.0 - 1.0;
+        let z = sampler.next_f32() * 2.0 - 1.0;
 
         vec3(x,y,z).normalize()
     }
 
-    pub fn random_uniform_hemisphere_vectorf32(seed : &mut u32, normal: Vector3<f32>) -> Vector3<f32>{
-        let v = Math::random_uniform_vectorf32(seed);
+    // Uniformly distributed direction on the unit sphere, pdf is 1 / (4 PI)
+    pub fn random_uniform_sphere_vectorf32(sampler: &mut dyn Sampler) -> Vector3<f32>{
+        let z = 1.0 - 2.0 * sampler.next_f32();
+        let phi = 2.0 * std::f32::consts::PI * sampler.next_f32();
+        let r = f32::sqrt(f32::max(0.0, 1.0 - z * z));
+        vec3(r * phi.cos(), r * phi.sin(), z)
+    }
+
+    pub fn random_uniform_hemisphere_vectorf32(sampler: &mut dyn Sampler, normal: Vector3<f32>) -> Vector3<f32>{
+        let v = Math::random_uniform_vectorf32(sampler);
         if normal.dot(v) < 0.0 { return -v }
         return v;
     }
 
+    // Cosine weighted direction around the normal, pdf is cos(theta) / PI
+    pub fn random_cosine_hemisphere_vectorf32(sampler: &mut dyn Sampler, normal: Vector3<f32>) -> Vector3<f32>{
+        let r1 = sampler.next_f32();
+        let r2 = sampler.next_f32();
+
+        let r = f32::sqrt(r1);
+        let phi = 2.0 * std::f32::consts::PI * r2;
+        let x = r * phi.cos();
+        let y = r * phi.sin();
+        let z = f32::sqrt(f32::max(0.0, 1.0 - r1));
+
+        let (tangent, bitangent) = Math::orthonormal_basis(normal);
+        (tangent * x + bitangent * y + normal * z).normalize()
+    }
+
+    // Builds a tangent and bitangent perpendicular to the (normalized) input vector
+    pub fn orthonormal_basis(n: Vector3<f32>) -> (Vector3<f32>, Vector3<f32>){
+        let sign = 1.0f32.copysign(n.z);
+        let a = -1.0 / (sign + n.z);
+        let b = n.x * n.y * a;
+        let tangent = vec3(1.0 + sign * n.x * n.x * a, sign * b, -sign * n.x);
+        let bitangent = vec3(b, sign + n.y * n.y * a, -n.y);
+        (tangent, bitangent)
+    }
+
+    // Power heuristic (beta = 2) for multiple importance sampling
+    pub fn power_heuristic(pdf_a: f32, pdf_b: f32) -> f32{
+        let a2 = pdf_a * pdf_a;
+        let b2 = pdf_b * pdf_b;
+        if a2 + b2 == 0.0 { return 0.0; }
+        a2 / (a2 + b2)
+    }
+
+    pub fn luminance(color: Vector3<f32>) -> f32{
+        0.2126 * color.x + 0.7152 * color.y + 0.0722 * color.z
+    }
+
     pub fn random_range_f32(seed : &mut u32, min:f32, max:f32) -> f32 {
         Math::random_f32(seed) * (max - min) 
     }
@@ -0,0 +1,371 @@
+use core::f32;
+use cgmath::*;
+
+use super::{math::Math, primitives::Object, sampler::Sampler};
+
+// Henyey-Greenstein phase function, g > 0 scatters forwards and g < 0 backwards.
+// Like the BSDFs, wo points towards the viewer and wi towards the light.
+#[derive(Copy, Clone)]
+pub struct HenyeyGreenstein {
+    pub g: f32,
+}
+
+impl HenyeyGreenstein {
+    pub fn new(g: f32) -> HenyeyGreenstein {
+        HenyeyGreenstein { g: g.clamp(-0.99, 0.99) }
+    }
+
+    pub fn eval(&self, wo: Vector3<f32>, wi: Vector3<f32>) -> f32 {
+        HenyeyGreenstein::phase(wo.dot(wi), self.g)
+    }
+
+    // Samples wi exactly proportional to the phase function, so eval / pdf is always 1
+    pub fn sample(&self, wo: Vector3<f32>, sampler: &mut dyn Sampler) -> (Vector3<f32>, f32) {
+        let u1 = sampler.next_f32();
+        let u2 = sampler.next_f32();
+        let g = self.g;
+
+        let cos_theta = if g.abs() < 1e-3 {
+            1.0 - 2.0 * u1
+        } else {
+            let sqr = (1.0 - g * g) / (1.0 + g - 2.0 * g * u1);
+            -(1.0 + g * g - sqr * sqr) / (2.0 * g)
+        };
+        let sin_theta = f32::sqrt(f32::max(0.0, 1.0 - cos_theta * cos_theta));
+        let phi = 2.0 * f32::consts::PI * u2;
+
+        let (tangent, bitangent) = Math::orthonormal_basis(wo);
+        let wi = (tangent * (sin_theta * phi.cos()) + bitangent * (sin_theta * phi.sin()) + wo * cos_theta).normalize();
+
+        (wi, HenyeyGreenstein::phase(cos_theta, g))
+    }
+
+    fn phase(cos_theta: f32, g: f32) -> f32 {
+        let denom = 1.0 + g * g + 2.0 * g * cos_theta;
+        (1.0 - g * g) / (4.0 * f32::consts::PI * denom * f32::sqrt(f32::max(denom, 1e-7)))
+    }
+}
+
+// Dense grid of density values filling the unit cube [0, 1]^3 of its local space, values are stored at the cell centers.
+// The transform places that cube in the world, so grids can be moved, scaled and rotated.
+pub struct DensityGrid {
+    nx: usize,
+    ny: usize,
+    nz: usize,
+    data: Vec<f32>,
+    max_density: f32,
+    local_to_world: Matrix4<f32>,
+    world_to_local: Matrix4<f32>,
+}
+
+impl DensityGrid {
+    // Grid stretched over the axis aligned box from min to max, with x running fastest in data
+    pub fn new(nx: usize, ny: usize, nz: usize, data: Vec<f32>, min: Vector3<f32>, max: Vector3<f32>) -> DensityGrid {
+        assert_eq!(data.len(), nx * ny * nz, "density grid has the wrong number of values");
+        let max_density = data.iter().fold(0.0, |m: f32, &d| m.max(d));
+
+        let mut grid = DensityGrid {
+            nx: nx,
+            ny: ny,
+            nz: nz,
+            data: data,
+            max_density: max_density,
+            local_to_world: Matrix4::identity(),
+            world_to_local: Matrix4::identity(),
+        };
+        grid.set_transform(Matrix4::from_translation(min) * Matrix4::from_nonuniform_scale(max.x - min.x, max.y - min.y, max.z - min.z));
+        grid
+    }
+
+    // Puff of smoke made from a few octaves of value noise, fading out towards the edges of the box
+    pub fn procedural_smoke(resolution: usize, seed: u32, min: Vector3<f32>, max: Vector3<f32>) -> DensityGrid {
+        let n = resolution.max(2);
+        let mut data = vec![0.0; n * n * n];
+
+        for z in 0..n {
+            for y in 0..n {
+                for x in 0..n {
+                    let p = vec3(x as f32 + 0.5, y as f32 + 0.5, z as f32 + 0.5) / n as f32;
+                    let falloff = f32::max(0.0, 1.0 - (p - vec3(0.5, 0.5, 0.5)).magnitude() * 2.0);
+
+                    let mut noise = 0.0;
+                    let mut amplitude = 0.5;
+                    let mut frequency = 4.0;
+                    for octave in 0..4 {
+                        noise += amplitude * DensityGrid::value_noise(p * frequency, seed.wrapping_add(octave));
+                        amplitude *= 0.5;
+                        frequency *= 2.0;
+                    }
+
+                    data[x + n * (y + n * z)] = f32::max(0.0, noise * 2.0 * falloff - 0.2);
+                }
+            }
+        }
+
+        DensityGrid::new(n, n, n, data, min, max)
+    }
+
+    pub fn resolution(&self) -> (usize, usize, usize) {
+        (self.nx, self.ny, self.nz)
+    }
+
+    pub fn transform(&self) -> Matrix4<f32> {
+        self.local_to_world
+    }
+
+    // Places the unit cube of the grid in the world, the matrix has to be invertible
+    pub fn set_transform(&mut self, local_to_world: Matrix4<f32>) {
+        self.local_to_world = local_to_world;
+        self.world_to_local = local_to_world.invert().expect("density grid transform can't be inverted");
+    }
+
+    // Range of distances along the ray inside the placed grid, found in local space where the grid is a unit cube
+    pub fn interval(&self, origin: Vector3<f32>, dir: Vector3<f32>) -> (f32, f32) {
+        let local_origin = self.world_to_local.transform_point(Point3::from_vec(origin)).to_vec();
+        let local_dir = self.world_to_local.transform_vector(dir);
+        slab_interval(local_origin, local_dir, Vector3::zero(), vec3(1.0, 1.0, 1.0))
+    }
+
+    pub fn max_density(&self) -> f32 {
+        self.max_density
+    }
+
+    // Trilinearly interpolated density at the world position p, zero outside of the grid
+    pub fn density(&self, p: Vector3<f32>) -> f32 {
+        let local = self.world_to_local.transform_point(Point3::from_vec(p));
+        let x = local.x * self.nx as f32 - 0.5;
+        let y = local.y * self.ny as f32 - 0.5;
+        let z = local.z * self.nz as f32 - 0.5;
+
+        let (x0, y0, z0) = (x.floor(), y.floor(), z.floor());
+        let (fx, fy, fz) = (x - x0, y - y0, z - z0);
+        let (x0, y0, z0) = (x0 as i64, y0 as i64, z0 as i64);
+
+        let d00 = self.lookup(x0, y0, z0) * (1.0 - fx) + self.lookup(x0 + 1, y0, z0) * fx;
+        let d10 = self.lookup(x0, y0 + 1, z0) * (1.0 - fx) + self.lookup(x0 + 1, y0 + 1, z0) * fx;
+        let d01 = self.lookup(x0, y0, z0 + 1) * (1.0 - fx) + self.lookup(x0 + 1, y0, z0 + 1) * fx;
+        let d11 = self.lookup(x0, y0 + 1, z0 + 1) * (1.0 - fx) + self.lookup(x0 + 1, y0 + 1, z0 + 1) * fx;
+
+        let d0 = d00 * (1.0 - fy) + d10 * fy;
+        let d1 = d01 * (1.0 - fy) + d11 * fy;
+        d0 * (1.0 - fz) + d1 * fz
+    }
+
+    fn lookup(&self, x: i64, y: i64, z: i64) -> f32 {
+        if x < 0 || y < 0 || z < 0 || x >= self.nx as i64 || y >= self.ny as i64 || z >= self.nz as i64 {
+            return 0.0;
+        }
+        self.data[x as usize + self.nx * (y as usize + self.ny * z as usize)]
+    }
+
+    fn value_noise(p: Vector3<f32>, seed: u32) -> f32 {
+        let lattice = |x: i32, y: i32, z: i32| -> f32 {
+            let h = Math::wang_hash(seed ^ Math::wang_hash(x as u32 ^ Math::wang_hash(y as u32 ^ Math::wang_hash(z as u32))));
+            h as f32 / u32::MAX as f32
+        };
+        let smooth = |t: f32| t * t * (3.0 - 2.0 * t);
+
+        let (x0, y0, z0) = (p.x.floor(), p.y.floor(), p.z.floor());
+        let (fx, fy, fz) = (smooth(p.x - x0), smooth(p.y - y0), smooth(p.z - z0));
+        let (x0, y0, z0) = (x0 as i32, y0 as i32, z0 as i32);
+
+        let mut value = 0.0;
+        for (dz, wz) in [(0, 1.0 - fz), (1, fz)] {
+            for (dy, wy) in [(0, 1.0 - fy), (1, fy)] {
+                for (dx, wx) in [(0, 1.0 - fx), (1, fx)] {
+                    value += lattice(x0 + dx, y0 + dy, z0 + dz) * wx * wy * wz;
+                }
+            }
+        }
+        value
+    }
+}
+
+pub enum Density {
+    Constant,
+    Grid(DensityGrid),
+}
+
+// Region of space a volume fills
+pub enum VolumeBounds {
+    Everywhere,
+    // Inside of a sphere, or behind a plane
+    Shape(Object),
+    Box { min: Vector3<f32>, max: Vector3<f32> },
+}
+
+impl VolumeBounds {
+    // Range of distances along the ray that lie inside the bounds
+    fn interval(&self, origin: Vector3<f32>, dir: Vector3<f32>, t_max: f32) -> Option<(f32, f32)> {
+        let (t0, t1) = match self {
+            VolumeBounds::Everywhere => (0.0, t_max),
+            VolumeBounds::Shape(object) => object.interval(origin, dir)?,
+            VolumeBounds::Box { min, max } => slab_interval(origin, dir, *min, *max),
+        };
+
+        let t0 = t0.max(0.0);
+        let t1 = t1.min(t_max);
+        if t0 < t1 { Some((t0, t1)) } else { None }
+    }
+}
+
+// Distances along the ray between which it is inside the axis aligned box
+fn slab_interval(origin: Vector3<f32>, dir: Vector3<f32>, min: Vector3<f32>, max: Vector3<f32>) -> (f32, f32) {
+    let mut t0 = f32::MIN;
+    let mut t1 = f32::MAX;
+    for axis in 0..3 {
+        let inv = 1.0 / dir[axis];
+        let mut near = (min[axis] - origin[axis]) * inv;
+        let mut far = (max[axis] - origin[axis]) * inv;
+        if near > far { std::mem::swap(&mut near, &mut far); }
+        if near.is_nan() || far.is_nan() { continue; }
+        t0 = t0.max(near);
+        t1 = t1.min(far);
+    }
+    (t0, t1)
+}
+
+// A scattering event inside a volume
+#[derive(Copy, Clone)]
+pub struct MediumInteraction {
+    pub position: Vector3<f32>,
+    pub dist: f32,
+    // Single scattering albedo, the factor the path throughput picks up at this event
+    pub weight: Vector3<f32>,
+    pub phase: HenyeyGreenstein,
+}
+
+// Participating medium filling some region of the scene.
+// The extinction is the same for every color channel, the color tints the light that is scattered instead of absorbed.
+pub struct Volume {
+    sigma_a: f32,
+    sigma_s: f32,
+    color: Vector3<f32>,
+    phase: HenyeyGreenstein,
+    density: Density,
+    bounds: VolumeBounds,
+}
+
+impl Volume {
+    pub fn homogeneous(sigma_a: f32, sigma_s: f32, g: f32, bounds: VolumeBounds) -> Volume {
+        Volume {
+            sigma_a: sigma_a,
+            sigma_s: sigma_s,
+            color: vec3(1.0, 1.0, 1.0),
+            phase: HenyeyGreenstein::new(g),
+            density: Density::Constant,
+            bounds: bounds,
+        }
+    }
+
+    // The coefficients are scaled by the density of the grid, which also bounds the volume
+    pub fn heterogeneous(grid: DensityGrid, sigma_a: f32, sigma_s: f32, g: f32) -> Volume {
+        Volume {
+            sigma_a: sigma_a,
+            sigma_s: sigma_s,
+            color: vec3(1.0, 1.0, 1.0),
+            phase: HenyeyGreenstein::new(g),
+            density: Density::Grid(grid),
+            bounds: VolumeBounds::Everywhere,
+        }
+    }
+
+    pub fn set_color(&mut self, color: Vector3<f32>) {
+        self.color = color;
+    }
+
+    // Limits the volume to the given region, grids stay limited to their own box as well
+    pub fn set_bounds(&mut self, bounds: VolumeBounds) {
+        self.bounds = bounds;
+    }
+
+    fn interval(&self, origin: Vector3<f32>, dir: Vector3<f32>, t_max: f32) -> Option<(f32, f32)> {
+        let (t0, t1) = self.bounds.interval(origin, dir, t_max)?;
+        match &self.density {
+            Density::Constant => Some((t0, t1)),
+            Density::Grid(grid) => {
+                let (g0, g1) = grid.interval(origin, dir);
+                let (t0, t1) = (t0.max(g0), t1.min(g1));
+                if t0 < t1 { Some((t0, t1)) } else { None }
+            }
+        }
+    }
+
+    fn sigma_t(&self) -> f32 {
+        self.sigma_a + self.sigma_s
+    }
+
+    fn interaction(&self, position: Vector3<f32>, dist: f32) -> MediumInteraction {
+        MediumInteraction {
+            position: position,
+            dist: dist,
+            weight: self.color * (self.sigma_s / self.sigma_t()),
+            phase: self.phase,
+        }
+    }
+
+    // Distance to the first real collision along the ray before t_max, found by delta tracking
+    pub fn sample_interaction(&self, origin: Vector3<f32>, dir: Vector3<f32>, t_max: f32, sampler: &mut dyn Sampler) -> Option<MediumInteraction> {
+        let sigma_t = self.sigma_t();
+        if sigma_t <= 0.0 { return None; }
+        let (t0, t1) = self.interval(origin, dir, t_max)?;
+
+        match &self.density {
+            Density::Constant => {
+                let t = t0 - f32::ln(1.0 - sampler.next_f32()) / sigma_t;
+                if t >= t1 { return None; }
+                Some(self.interaction(origin + dir * t, t))
+            }
+            Density::Grid(grid) => {
+                // Majorant of the extinction, the gap to the real extinction is filled with null collisions
+                let majorant = sigma_t * grid.max_density();
+                if majorant <= 0.0 { return None; }
+
+                let mut t = t0;
+                loop {
+                    t -= f32::ln(1.0 - sampler.next_f32()) / majorant;
+                    if t >= t1 { return None; }
+
+                    let p = origin + dir * t;
+                    if sampler.next_f32() * majorant < sigma_t * grid.density(p) {
+                        return Some(self.interaction(p, t));
+                    }
+                }
+            }
+        }
+    }
+
+    // Fraction of light that makes it through the volume between the origin and t_max, using ratio tracking for grids
+    pub fn transmittance(&self, origin: Vector3<f32>, dir: Vector3<f32>, t_max: f32, sampler: &mut dyn Sampler) -> f32 {
+        let sigma_t = self.sigma_t();
+        if sigma_t <= 0.0 { return 1.0; }
+        let (t0, t1) = match self.interval(origin, dir, t_max) {
+            Some(interval) => interval,
+            None => return 1.0
+        };
+
+        match &self.density {
+            Density::Constant => f32::exp(-sigma_t * (t1 - t0)),
+            Density::Grid(grid) => {
+                let majorant = sigma_t * grid.max_density();
+                if majorant <= 0.0 { return 1.0; }
+
+                let mut transmittance = 1.0;
+                let mut t = t0;
+                loop {
+                    t -= f32::ln(1.0 - sampler.next_f32()) / majorant;
+                    if t >= t1 { break; }
+
+                    transmittance *= 1.0 - sigma_t * grid.density(origin + dir * t) / majorant;
+
+                    // Russian roulette once hardly any light gets through, survivors are divided by their own transmittance
+                    if transmittance < 0.1 {
+                        if sampler.next_f32() >= transmittance { return 0.0; }
+                        transmittance = 1.0;
+                    }
+                }
+                transmittance
+            }
+        }
+    }
+}
@@ -2,4 +2,26 @@ pub mod camera;
 pub mod ray;
 pub mod scene;
 pub mod primitives;
-pub mod math;
\ No newline at end of file
+pub mod math;
+pub mod material;
+pub mod sampling;
+pub mod skybox;
+pub mod integrators;
+pub mod film;
+pub mod sampler;
+pub mod medium;
+pub mod grid_loader;
+pub mod spectrum;
+pub mod subsurface;
+pub mod texture;
+pub mod procedural;
+pub mod principled;
+pub mod layered;
+pub mod energy;
+pub mod furnace;
+pub mod adaptive;
+pub mod denoiser;
+pub mod reprojection;
+pub mod aov;
+pub mod lpe;
+pub mod tiles;
@@ -1,6 +1,12 @@
 use core::f32;
 use cgmath::*;
-use super::{math::Math, ray::Ray};
+use super::{material::Material, math::Math, ray::Ray, sampler::Sampler, texture::{MaterialTextures, TexCoord}};
+
+pub struct LightSample {
+    pub dir: Vector3<f32>,
+    pub dist: f32,
+    pub pdf: f32,
+}
 
 #[derive(Copy, Clone)]
 pub enum Object {
@@ -26,6 +32,57 @@ impl Object{
         }
     }
 
+    pub fn set_material(&mut self, material: Material){
+        match self {
+            Object::Cube(ref mut c) => c.material = material,
+            Object::Sphere(ref mut s) => s.material = material,
+            Object::Plane(ref mut p) => p.material = material,
+        }
+    }
+
+    pub fn material(&self) -> Material {
+        match self {
+            Object::Cube(c) => c.material,
+            Object::Sphere(s) => s.material,
+            Object::Plane(p) => p.material,
+        }
+    }
+
+    // The material at pos, with the textures of its parameters applied
+    pub fn material_at(&self, pos: Vector3<f32>) -> Material {
+        let textures = self.textures();
+        if !textures.has_parameters() {
+            return self.material();
+        }
+
+        self.material().textured(&textures.parameters(&self.get_tex_coord(pos)))
+    }
+
+    // Chance that a ray hitting pos stops there, see MaterialTextures::opacity
+    pub fn opacity(&self, pos: Vector3<f32>) -> f32 {
+        let textures = self.textures();
+        if !textures.has_opacity() {
+            return 1.0;
+        }
+        textures.opacity(&self.get_tex_coord(pos))
+    }
+
+    pub fn set_textures(&mut self, textures: MaterialTextures){
+        match self {
+            Object::Cube(ref mut c) => c.textures = textures,
+            Object::Sphere(ref mut s) => s.textures = textures,
+            Object::Plane(ref mut p) => p.textures = textures,
+        }
+    }
+
+    pub fn textures(&self) -> MaterialTextures {
+        match self {
+            Object::Cube(c) => c.textures,
+            Object::Sphere(s) => s.textures,
+            Object::Plane(p) => p.textures,
+        }
+    }
+
     pub fn set_idx(&mut self, idx: i32){
         match self {
             Object::Cube(ref mut c) => c.idx = idx,
@@ -52,10 +109,74 @@ impl Object{
     }
     
     pub fn get_albedo(&self, pos:Vector3<f32>) -> Vector3<f32> {
+        self.get_albedo_filtered(pos, 0.0)
+    }
+
+    // Albedo (or emission for lights) averaged over a footprint, the width in world units of the area seen by the ray
+    pub fn get_albedo_filtered(&self, pos:Vector3<f32>, footprint: f32) -> Vector3<f32> {
         match self {
             Object::Cube(c) => vec3(1.0, 1.0, 0.0),
-            Object::Sphere(s) => s.get_albedo(pos),
-            Object::Plane(p) => p.get_albedo(pos),
+            Object::Sphere(s) => s.get_albedo(pos, footprint),
+            Object::Plane(p) => p.get_albedo(pos, footprint),
+        }
+    }
+
+    // Normal used for shading, normal is the geometric normal at pos facing wo
+    pub fn get_shading_normal(&self, pos:Vector3<f32>, normal: Vector3<f32>, wo: Vector3<f32>) -> Vector3<f32> {
+        let textures = self.textures();
+        if textures.normal.is_none() && textures.bump.is_none() {
+            return normal;
+        }
+
+        let (dpdu, dpdv) = self.get_tangents(pos);
+        let shading = textures.perturb_normal(normal, dpdu, dpdv, &self.get_tex_coord(pos), |offset| self.get_tex_coord(pos + offset));
+        Object::bend_normal(shading, normal, wo)
+    }
+
+    // Shading normals can reflect wo below the geometric surface, where light would leak through it.
+    // Those are bent back towards the geometric normal until the reflection stays above the surface.
+    fn bend_normal(shading: Vector3<f32>, geometric: Vector3<f32>, wo: Vector3<f32>) -> Vector3<f32> {
+        let threshold = f32::min(0.9 * geometric.dot(wo), 0.01);
+        let valid = |n: Vector3<f32>| n.dot(wo) > 0.0 && Math::reflect(-wo, n).dot(geometric) >= threshold;
+        if valid(shading) { return shading; }
+
+        let mut low = 0.0;
+        let mut high = 1.0;
+        for _ in 0..8 {
+            let mid = 0.5 * (low + high);
+            if valid(shading.lerp(geometric, mid).normalize()) { high = mid; } else { low = mid; }
+        }
+        shading.lerp(geometric, high).normalize()
+    }
+
+    // Derivatives of the position along the texture coordinates
+    pub fn get_tangents(&self, pos:Vector3<f32>) -> (Vector3<f32>, Vector3<f32>) {
+        match self {
+            Object::Cube(c) => c.get_tangents(pos),
+            Object::Sphere(s) => s.get_tangents(pos),
+            Object::Plane(p) => p.get_tangents(pos),
+        }
+    }
+
+    pub fn get_tex_coord(&self, pos:Vector3<f32>) -> TexCoord {
+        let local = match self {
+            Object::Cube(c) => c.to_local(pos),
+            Object::Sphere(s) => s.to_local(pos),
+            Object::Plane(p) => pos,
+        };
+        TexCoord {
+            uv: self.get_uv(pos),
+            position: pos,
+            local: local,
+        }
+    }
+
+    // Texture coordinates of a point on the surface
+    pub fn get_uv(&self, pos:Vector3<f32>) -> Vector2<f32> {
+        match self {
+            Object::Cube(c) => c.get_uv(pos),
+            Object::Sphere(s) => s.get_uv(pos),
+            Object::Plane(p) => p.get_uv(pos),
         }
     }
 
@@ -67,11 +188,39 @@ impl Object{
         }
     }
 
-    pub fn get_random_position(&self, normal: Vector3<f32>, seed: &mut u32) -> Vector3<f32>{
+    // Samples a direction from p towards the light, pdf is with respect to solid angle
+    pub fn sample_light(&self, p: Vector3<f32>, sampler: &mut dyn Sampler) -> Option<LightSample>{
+        match self {
+            Object::Cube(c) => None,
+            Object::Sphere(s) => s.sample_solid_angle(p, sampler),
+            Object::Plane(p) => None, // Infinite planes can't be sampled, they are only found by bounce rays
+        }
+    }
+
+    // Solid angle pdf of Object::sample_light choosing a direction from p that hits this light
+    pub fn light_pdf(&self, p: Vector3<f32>) -> f32{
+        match self {
+            Object::Cube(c) => 0.0,
+            Object::Sphere(s) => s.solid_angle_pdf(p),
+            Object::Plane(p) => 0.0,
+        }
+    }
+
+    // Uniformly samples a point on the surface, returns the position and its normal
+    pub fn sample_area(&self, sampler: &mut dyn Sampler) -> Option<(Vector3<f32>, Vector3<f32>)>{
+        match self {
+            Object::Cube(c) => None,
+            Object::Sphere(s) => Some(s.sample_area(sampler)),
+            Object::Plane(p) => None,
+        }
+    }
+
+    // Area pdf of Object::sample_area, zero for surfaces that can't be sampled
+    pub fn area_pdf(&self) -> f32{
         match self {
-            Object::Cube(c) => Vector3::zero(),
-            Object::Sphere(s) => s.get_random_position(normal, seed),
-            Object::Plane(p) => Vector3::zero(),
+            Object::Cube(c) => 0.0,
+            Object::Sphere(s) => 1.0 / s.get_area(),
+            Object::Plane(p) => 0.0,
         }
     }
 
@@ -83,11 +232,12 @@ impl Object{
         }
     }
 
-    pub fn get_light_pdf(&self) -> f32{
+    // Range of distances along a ray that lie inside the primitive, planes enclose the half space behind them
+    pub fn interval(&self, origin: Vector3<f32>, dir: Vector3<f32>) -> Option<(f32, f32)>{
         match self {
-            Object::Cube(c) => 1.0,
-            Object::Sphere(s) => 0.5, // We only sample hemisphere facing the surface
-            Object::Plane(p) =>  1.0,
+            Object::Cube(c) => None,
+            Object::Sphere(s) => s.interval(origin, dir),
+            Object::Plane(p) => p.interval(origin, dir),
         }
     }
 }
@@ -99,6 +249,8 @@ pub struct Sphere{
     r: f32,
     r2: f32,
     color: Vector3<f32>,
+    material: Material,
+    textures: MaterialTextures,
     pub is_light: bool,
 }
 
@@ -110,6 +262,8 @@ impl Sphere {
             r : size,
             r2 : size * size,
             color: color,
+            material: Material::Diffuse,
+            textures: MaterialTextures::new(),
             is_light: false,
         }
     }
@@ -123,7 +277,9 @@ impl Sphere {
         let b = dot(O, D);
         let c = dot(O, O) - self.r2;
 
-        let mut d = b * b - c;
+        // Same as b * b - c, without the cancellation that put hits of distant rays below the surface
+        let f = O - D * b;
+        let mut d = self.r2 - dot(f, f);
         if d <= 0.0 { return; }
 
         d = f32::sqrt(d);
@@ -146,15 +302,101 @@ impl Sphere {
     }
 
     pub fn get_normal(&self, p:Vector3<f32>) -> Vector3<f32> {
-        (p - self.position) * self.r2
+        (p - self.position) / self.r
+    }
+
+    pub fn interval(&self, origin: Vector3<f32>, dir: Vector3<f32>) -> Option<(f32, f32)> {
+        let O = origin - self.position;
+        let b = dot(O, dir);
+
+        let f = O - dir * b;
+        let d = self.r2 - dot(f, f);
+        if d <= 0.0 { return None; }
+
+        let d = f32::sqrt(d);
+        Some((-b - d, -b + d))
+    }
+
+    pub fn get_albedo(&self, p:Vector3<f32>, footprint: f32) -> Vector3<f32> {
+        // One unit of v spans half the circumference
+        let coord = TexCoord { uv: self.get_uv(p), position: p, local: self.to_local(p) };
+        self.textures.color(self.color, self.is_light, &coord, footprint / (f32::consts::PI * self.r))
+    }
+
+    pub fn to_local(&self, p:Vector3<f32>) -> Vector3<f32> {
+        p - self.position
+    }
+
+    // Latitude-longitude mapping, the same one the skybox uses
+    pub fn get_uv(&self, p:Vector3<f32>) -> Vector2<f32> {
+        let d = (p - self.position) / self.r;
+        let phi = f32::atan2(d.z, d.x);
+        let u = (if phi > 0.0 { phi } else { phi + 2.0 * f32::consts::PI }) * (f32::consts::FRAC_1_PI / 2.0);
+        let v = f32::acos(d.y.clamp(-1.0, 1.0)) * f32::consts::FRAC_1_PI;
+        vec2(u, v)
+    }
+
+    pub fn get_tangents(&self, p:Vector3<f32>) -> (Vector3<f32>, Vector3<f32>) {
+        let d = (p - self.position) / self.r;
+        let sin_theta = f32::sqrt(d.x * d.x + d.z * d.z);
+
+        // u is undefined at the poles
+        if sin_theta < 1e-6 {
+            return Math::orthonormal_basis(d);
+        }
+
+        let cos_phi = d.x / sin_theta;
+        let sin_phi = d.z / sin_theta;
+        let dpdu = vec3(-d.z, 0.0, d.x) * (2.0 * f32::consts::PI * self.r);
+        let dpdv = vec3(d.y * cos_phi, -sin_theta, d.y * sin_phi) * (f32::consts::PI * self.r);
+        (dpdu, dpdv)
+    }
+
+    // Uniformly samples the cone of directions from p that hit the sphere
+    pub fn sample_solid_angle(&self, p: Vector3<f32>, sampler: &mut dyn Sampler) -> Option<LightSample>{
+        let w = self.position - p;
+        let d2 = w.magnitude2();
+        if d2 <= self.r2 { return None; }
+
+        let d = d2.sqrt();
+        let one_minus_cos_max = Sphere::one_minus_cos_max(self.r2 / d2);
+
+        let r1 = sampler.next_f32();
+        let r2 = sampler.next_f32();
+        let cos_theta = 1.0 - r1 * one_minus_cos_max;
+        let sin_theta = f32::sqrt(f32::max(0.0, 1.0 - cos_theta * cos_theta));
+        let phi = 2.0 * f32::consts::PI * r2;
+
+        let axis = w / d;
+        let (tangent, bitangent) = Math::orthonormal_basis(axis);
+        let dir = (tangent * (sin_theta * phi.cos()) + bitangent * (sin_theta * phi.sin()) + axis * cos_theta).normalize();
+
+        // Distance to the near side of the sphere along the sampled direction
+        let dist = d * cos_theta - f32::sqrt(f32::max(0.0, self.r2 - d2 * sin_theta * sin_theta));
+
+        Some(LightSample {
+            dir: dir,
+            dist: dist,
+            pdf: 1.0 / (2.0 * f32::consts::PI * one_minus_cos_max),
+        })
     }
 
-    pub fn get_albedo(&self, p:Vector3<f32>) -> Vector3<f32> {
-        self.color
+    // Uniform point on the surface and its normal, the pdf per unit area is 1 / area
+    pub fn sample_area(&self, sampler: &mut dyn Sampler) -> (Vector3<f32>, Vector3<f32>){
+        let normal = Math::random_uniform_sphere_vectorf32(sampler);
+        (self.position + normal * self.r, normal)
     }
 
-    pub fn get_random_position(&self, normal: Vector3<f32>, seed: &mut u32) -> Vector3<f32>{
-        Math::random_uniform_hemisphere_vectorf32(seed, -normal) * self.r + self.position
+    pub fn solid_angle_pdf(&self, p: Vector3<f32>) -> f32{
+        let d2 = (self.position - p).magnitude2();
+        if d2 <= self.r2 { return 0.0; }
+
+        1.0 / (2.0 * f32::consts::PI * Sphere::one_minus_cos_max(self.r2 / d2))
+    }
+
+    // 1 - sqrt(1 - sin2), written to stay accurate for distant spheres
+    fn one_minus_cos_max(sin2_max: f32) -> f32{
+        sin2_max / (1.0 + f32::sqrt(f32::max(0.0, 1.0 - sin2_max)))
     }
 
     pub fn get_area(&self) -> f32{
@@ -168,6 +410,8 @@ pub struct Cube{
     m: Matrix4<f32>,
     inv_m: Matrix4<f32>,
     size: f32,
+    material: Material,
+    textures: MaterialTextures,
 }
 
 impl Cube {
@@ -176,13 +420,48 @@ impl Cube {
             idx : 0,
             m : m,
             inv_m : m.invert().unwrap(),
-            size : size
+            size : size,
+            material: Material::Diffuse,
+            textures: MaterialTextures::new(),
         }
     }
 
     pub fn intersect(&self, ray: &mut Ray){
         // Intersect stuff
     }
+
+    pub fn to_local(&self, p:Vector3<f32>) -> Vector3<f32> {
+        Point3::to_vec(self.inv_m.transform_point(Point3::from_vec(p)))
+    }
+
+    // Every face is mapped to the full [0, 1] range, picked by the axis the local position is furthest along
+    pub fn get_uv(&self, p:Vector3<f32>) -> Vector2<f32> {
+        let local = self.to_local(p) / self.size;
+        let abs = local.map(f32::abs);
+
+        let uv = if abs.x >= abs.y && abs.x >= abs.z {
+            vec2(-local.z * local.x.signum(), -local.y)
+        } else if abs.y >= abs.z {
+            vec2(local.x, local.z * local.y.signum())
+        } else {
+            vec2(local.x * local.z.signum(), -local.y)
+        };
+        uv + vec2(0.5, 0.5)
+    }
+
+    pub fn get_tangents(&self, p:Vector3<f32>) -> (Vector3<f32>, Vector3<f32>) {
+        let local = self.to_local(p);
+        let abs = local.map(f32::abs);
+
+        let (dpdu, dpdv) = if abs.x >= abs.y && abs.x >= abs.z {
+            (vec3(0.0, 0.0, -local.x.signum()), vec3(0.0, -1.0, 0.0))
+        } else if abs.y >= abs.z {
+            (vec3(1.0, 0.0, 0.0), vec3(0.0, 0.0, local.y.signum()))
+        } else {
+            (vec3(local.z.signum(), 0.0, 0.0), vec3(0.0, -1.0, 0.0))
+        };
+        (self.m.transform_vector(dpdu * self.size), self.m.transform_vector(dpdv * self.size))
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -191,6 +470,8 @@ pub struct Plane{
     dist: f32,
     direction: Vector3<f32>,
     color: Vector3<f32>,
+    material: Material,
+    textures: MaterialTextures,
     pub is_light: bool,
 }
 
@@ -201,6 +482,8 @@ impl Plane{
             dist : dist,
             direction : direction,
             color: color,
+            material: Material::Diffuse,
+            textures: MaterialTextures::new(),
             is_light: false
         }
     }
@@ -217,8 +500,30 @@ impl Plane{
         self.direction
     }
 
-    pub fn get_albedo(&self, p:Vector3<f32>) -> Vector3<f32> {
-        self.color
+    pub fn interval(&self, origin: Vector3<f32>, dir: Vector3<f32>) -> Option<(f32, f32)> {
+        let height = Vector3::dot(origin, self.direction) + self.dist;
+        let speed = dot(dir, self.direction);
+        if speed == 0.0 {
+            return if height < 0.0 { Some((0.0, f32::MAX)) } else { None };
+        }
+
+        let t = -height / speed;
+        if speed > 0.0 { Some((f32::MIN, t)) } else { Some((t, f32::MAX)) }
+    }
+
+    pub fn get_albedo(&self, p:Vector3<f32>, footprint: f32) -> Vector3<f32> {
+        let coord = TexCoord { uv: self.get_uv(p), position: p, local: p };
+        self.textures.color(self.color, self.is_light, &coord, footprint)
+    }
+
+    // Planar mapping that tiles the texture every unit along two axes of the plane
+    pub fn get_uv(&self, p:Vector3<f32>) -> Vector2<f32> {
+        let (tangent, bitangent) = Math::orthonormal_basis(self.direction);
+        vec2(p.dot(tangent), p.dot(bitangent))
+    }
+
+    pub fn get_tangents(&self, p:Vector3<f32>) -> (Vector3<f32>, Vector3<f32>) {
+        Math::orthonormal_basis(self.direction)
     }
 
     pub fn get_area(&self) -> f32{
@@ -0,0 +1,358 @@
+use core::f32;
+use cgmath::*;
+
+use super::{energy::EnergyCompensation, material::Material, math::Math, sampler::Sampler};
+
+// Disney style principled BSDF. The base color is the albedo of the primitive, every other parameter lies between 0 and 1.
+// It combines a diffuse and sheen lobe, an anisotropic GGX reflection, a rough dielectric transmission and a clearcoat,
+// samples them proportional to a weight per lobe and evaluates all of them for every direction.
+#[derive(Copy, Clone, PartialEq)]
+pub struct Principled {
+    pub metallic: f32,
+    pub roughness: f32,
+    // Scales the dielectric reflection, 0.5 is what the index of refraction predicts
+    pub specular: f32,
+    // Tints the dielectric reflection towards the base color
+    pub specular_tint: f32,
+    // Stretches the highlight along the tangent
+    pub anisotropic: f32,
+    pub sheen: f32,
+    pub sheen_tint: f32,
+    pub clearcoat: f32,
+    pub clearcoat_gloss: f32,
+    pub transmission: f32,
+    // Index of refraction of the side opposite to wo, relative to the side of wo (see Material::seen_from)
+    pub ior: f32,
+}
+
+impl Principled {
+    pub fn new() -> Principled {
+        Principled {
+            metallic: 0.0,
+            roughness: 0.5,
+            specular: 0.5,
+            specular_tint: 0.0,
+            anisotropic: 0.0,
+            sheen: 0.0,
+            sheen_tint: 0.5,
+            clearcoat: 0.0,
+            clearcoat_gloss: 1.0,
+            transmission: 0.0,
+            ior: 1.5,
+        }
+    }
+
+    pub fn eval(&self, base: Vector3<f32>, normal: Vector3<f32>, wo: Vector3<f32>, wi: Vector3<f32>) -> Vector3<f32> {
+        let cos_o = normal.dot(wo);
+        let cos_i = normal.dot(wi);
+        if cos_o <= 0.0 || cos_i == 0.0 { return Vector3::zero(); }
+
+        let (tangent, bitangent) = Math::orthonormal_basis(normal);
+        let local = |v: Vector3<f32>| vec3(v.dot(tangent), v.dot(bitangent), v.dot(normal));
+        let (alpha_x, alpha_y) = self.alphas();
+
+        if cos_i < 0.0 {
+            let weight = (1.0 - self.metallic) * self.transmission;
+            if weight <= 0.0 { return Vector3::zero(); }
+
+            let h = match self.refraction_half_vector(normal, wo, wi) {
+                Some(h) => h,
+                None => return Vector3::zero()
+            };
+            let wo_h = wo.dot(h);
+            let wi_h = wi.dot(h);
+            let denom = wo_h + self.ior * wi_h;
+
+            let d = Principled::ggx_d(local(h), alpha_x, alpha_y);
+            let g = Principled::smith_g(local(wo), local(wi), alpha_x, alpha_y);
+            let f = Material::dielectric_fresnel(wo_h, 1.0 / self.ior);
+
+            // Scaled by ior^2 like the dielectric material, so the throughput doesn't change when light enters or leaves
+            let t = weight * (1.0 - f) * d * g * f32::abs(wi_h * wo_h) * self.ior * self.ior / (f32::abs(cos_i) * cos_o * denom * denom);
+            return base * (t * self.clearcoat_transmission(cos_o));
+        }
+
+        let h = (wo + wi).normalize();
+        let cos_d = wi.dot(h);
+        let mut f = self.diffuse(base, cos_o, cos_i, cos_d);
+
+        let d = Principled::ggx_d(local(h), alpha_x, alpha_y);
+        let g = Principled::smith_g(local(wo), local(wi), alpha_x, alpha_y);
+        f += self.fresnel(base, cos_d) * (d * g / (4.0 * cos_i * cos_o));
+        let (roughness_o, roughness_i) = (self.projected_roughness(local(wo)), self.projected_roughness(local(wi)));
+        f += EnergyCompensation::correlated(self.fresnel_average(base), roughness_o, roughness_i, cos_o, cos_i);
+
+        // Everything below the clearcoat only gets the light it lets through, on the way in and out
+        f *= self.clearcoat_transmission(cos_o) * self.clearcoat_transmission(cos_i);
+
+        if self.clearcoat > 0.0 {
+            let d = Principled::gtr1_d(normal.dot(h), self.clearcoat_alpha());
+            let g = Principled::smith_g1_isotropic(cos_o, 0.25) * Principled::smith_g1_isotropic(cos_i, 0.25);
+            let fr = 0.04 + 0.96 * Principled::schlick_weight(cos_d);
+            let c = 0.25 * self.clearcoat * d * g * fr / (4.0 * cos_i * cos_o);
+            f += vec3(c, c, c);
+        }
+
+        f
+    }
+
+    // The diffuse and sheen part of eval
+    pub fn eval_diffuse(&self, base: Vector3<f32>, normal: Vector3<f32>, wo: Vector3<f32>, wi: Vector3<f32>) -> Vector3<f32> {
+        let cos_o = normal.dot(wo);
+        let cos_i = normal.dot(wi);
+        if cos_o <= 0.0 || cos_i <= 0.0 { return Vector3::zero(); }
+
+        let cos_d = wi.dot((wo + wi).normalize());
+        self.diffuse(base, cos_o, cos_i, cos_d) * (self.clearcoat_transmission(cos_o) * self.clearcoat_transmission(cos_i))
+    }
+
+    pub fn pdf(&self, normal: Vector3<f32>, wo: Vector3<f32>, wi: Vector3<f32>) -> f32 {
+        let cos_o = normal.dot(wo);
+        let cos_i = normal.dot(wi);
+        if cos_o <= 0.0 || cos_i == 0.0 { return 0.0; }
+
+        let (tangent, bitangent) = Math::orthonormal_basis(normal);
+        let local = |v: Vector3<f32>| vec3(v.dot(tangent), v.dot(bitangent), v.dot(normal));
+        let (alpha_x, alpha_y) = self.alphas();
+        let [diffuse, specular, transmission, clearcoat] = self.lobe_probabilities(cos_o);
+
+        if cos_i < 0.0 {
+            if transmission <= 0.0 { return 0.0; }
+            let h = match self.refraction_half_vector(normal, wo, wi) {
+                Some(h) => h,
+                None => return 0.0
+            };
+            let wi_h = wi.dot(h);
+            let denom = wo.dot(h) + self.ior * wi_h;
+            let pdf_h = Principled::ggx_d(local(h), alpha_x, alpha_y) * normal.dot(h);
+            return transmission * pdf_h * f32::abs(self.ior * self.ior * wi_h) / (denom * denom);
+        }
+
+        let h = (wo + wi).normalize();
+        let wo_h = wo.dot(h);
+        if wo_h <= 0.0 { return diffuse * cos_i * f32::consts::FRAC_1_PI; }
+        let cos_h = normal.dot(h);
+
+        let mut pdf = diffuse * cos_i * f32::consts::FRAC_1_PI;
+        pdf += specular * Principled::ggx_d(local(h), alpha_x, alpha_y) * cos_h / (4.0 * wo_h);
+        if clearcoat > 0.0 {
+            pdf += clearcoat * Principled::gtr1_d(cos_h, self.clearcoat_alpha()) * cos_h / (4.0 * wo_h);
+        }
+        pdf
+    }
+
+    // Picks a lobe and samples a direction from it, Material::sample evaluates the whole BSDF for it
+    pub fn sample(&self, normal: Vector3<f32>, wo: Vector3<f32>, sampler: &mut dyn Sampler) -> Option<Vector3<f32>> {
+        let cos_o = normal.dot(wo);
+        if cos_o <= 0.0 { return None; }
+
+        let (tangent, bitangent) = Math::orthonormal_basis(normal);
+        let world = |v: Vector3<f32>| tangent * v.x + bitangent * v.y + normal * v.z;
+        let (alpha_x, alpha_y) = self.alphas();
+        let [diffuse, specular, transmission, _] = self.lobe_probabilities(cos_o);
+
+        let u = sampler.next_f32();
+        let (r1, r2) = (sampler.next_f32(), sampler.next_f32());
+
+        if u < diffuse {
+            return Some(Math::random_cosine_hemisphere_vectorf32(sampler, normal));
+        }
+
+        if u < diffuse + specular {
+            let h = world(Principled::sample_ggx(r1, r2, alpha_x, alpha_y));
+            return Principled::above(normal, Math::reflect(-wo, h));
+        }
+
+        if u < diffuse + specular + transmission {
+            let h = world(Principled::sample_ggx(r1, r2, alpha_x, alpha_y));
+            let cos_h = wo.dot(h);
+            if cos_h <= 0.0 { return None; }
+
+            // Refraction from the side of wo into the other side
+            let eta = 1.0 / self.ior;
+            let sin2_t = eta * eta * (1.0 - cos_h * cos_h);
+            if sin2_t >= 1.0 { return None; }
+            let cos_t = f32::sqrt(1.0 - sin2_t);
+            let wi = (-wo * eta + h * (eta * cos_h - cos_t)).normalize();
+            return if normal.dot(wi) < 0.0 { Some(wi) } else { None };
+        }
+
+        // Clearcoat
+        let alpha = self.clearcoat_alpha();
+        let a2 = alpha * alpha;
+        let cos_theta = f32::sqrt(f32::max(0.0, (1.0 - f32::powf(a2, 1.0 - r1)) / (1.0 - a2)));
+        let sin_theta = f32::sqrt(f32::max(0.0, 1.0 - cos_theta * cos_theta));
+        let phi = 2.0 * f32::consts::PI * r2;
+        let h = world(vec3(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta));
+        Principled::above(normal, Math::reflect(-wo, h))
+    }
+
+    // Burley diffuse with retro-reflection at grazing angles, plus sheen. It only gets the light the specular reflection lets through,
+    // divided by the average of that so a white surface reflects everything after the light bounced between both layers.
+    fn diffuse(&self, base: Vector3<f32>, cos_o: f32, cos_i: f32, cos_d: f32) -> Vector3<f32> {
+        let diffuse_weight = (1.0 - self.metallic) * (1.0 - self.transmission);
+        if diffuse_weight <= 0.0 { return Vector3::zero(); }
+
+        let fd90 = 0.5 + 2.0 * self.roughness * cos_d * cos_d;
+        let fl = Principled::schlick_weight(cos_i);
+        let fv = Principled::schlick_weight(cos_o);
+        let fd = (1.0 + (fd90 - 1.0) * fl) * (1.0 + (fd90 - 1.0) * fv);
+        let specular_transmission = (1.0 - self.dielectric_reflectance(cos_o)) * (1.0 - self.dielectric_reflectance(cos_i)) / (1.0 - self.dielectric_average());
+        let mut f = base * (diffuse_weight * fd * specular_transmission * f32::consts::FRAC_1_PI);
+
+        let sheen_color = vec3(1.0, 1.0, 1.0).lerp(Principled::tint(base), self.sheen_tint);
+        f += sheen_color * (diffuse_weight * self.sheen * Principled::schlick_weight(cos_d));
+        f
+    }
+
+    // Reflections that end up below the surface are lost, pdf only accounts for the transmission lobe there
+    fn above(normal: Vector3<f32>, wi: Vector3<f32>) -> Option<Vector3<f32>> {
+        if normal.dot(wi) > 0.0 { Some(wi) } else { None }
+    }
+
+    pub fn is_transmissive(&self) -> bool {
+        self.metallic < 1.0 && self.transmission > 0.0
+    }
+
+    // Probabilities of sampling the diffuse, specular, transmission and clearcoat lobe, roughly proportional to the energy they reflect
+    fn lobe_probabilities(&self, cos_o: f32) -> [f32; 4] {
+        let fresnel = Material::dielectric_fresnel(cos_o, 1.0 / self.ior);
+        let dielectric = 1.0 - self.metallic;
+
+        let specular = f32::max(self.metallic + dielectric * fresnel * self.specular * 2.0, 0.05);
+        // The cosine lobe also covers the energy compensation of the specular lobe
+        let diffuse = dielectric * (1.0 - self.transmission) + specular * EnergyCompensation::correlated_loss(self.roughness, cos_o).min(0.5);
+        let transmission = dielectric * self.transmission * (1.0 - fresnel);
+        let clearcoat = 0.25 * self.clearcoat;
+
+        let total = diffuse + specular + transmission + clearcoat;
+        [diffuse / total, specular / total, transmission / total, clearcoat / total]
+    }
+
+    // Reflectance of the specular lobe, blends the dielectric fresnel with a metal tinted by the base color
+    fn fresnel(&self, base: Vector3<f32>, cos_d: f32) -> Vector3<f32> {
+        let tint = vec3(1.0, 1.0, 1.0).lerp(Principled::tint(base), self.specular_tint);
+        let dielectric = tint * (Material::dielectric_fresnel(cos_d, 1.0 / self.ior) * self.specular * 2.0);
+        let metal = base + (vec3(1.0, 1.0, 1.0) - base) * Principled::schlick_weight(cos_d);
+        dielectric.lerp(metal, self.metallic)
+    }
+
+    // Fresnel reflectance of the dielectric specular lobe towards the macro normal
+    fn dielectric_reflectance(&self, cos: f32) -> f32 {
+        f32::min(Material::dielectric_fresnel(cos, 1.0 / self.ior) * self.specular * 2.0, 1.0)
+    }
+
+    // Cosine weighted average of dielectric_reflectance, using d'Eon's fit of the fresnel average
+    fn dielectric_average(&self) -> f32 {
+        let eta = f32::max(self.ior, 1.0 / self.ior);
+        f32::min((eta - 1.0) / (4.08567 + 1.00071 * eta) * self.specular * 2.0, 0.99)
+    }
+
+    // Part of the light that passes the clearcoat
+    fn clearcoat_transmission(&self, cos: f32) -> f32 {
+        1.0 - 0.25 * self.clearcoat * (0.04 + 0.96 * Principled::schlick_weight(cos))
+    }
+
+    // Cosine weighted average of fresnel over the hemisphere
+    fn fresnel_average(&self, base: Vector3<f32>) -> Vector3<f32> {
+        let tint = vec3(1.0, 1.0, 1.0).lerp(Principled::tint(base), self.specular_tint);
+        let f0 = f32::powi((self.ior - 1.0) / (self.ior + 1.0), 2) * self.specular * 2.0;
+        let dielectric = EnergyCompensation::schlick_average(tint * f0);
+        dielectric.lerp(EnergyCompensation::schlick_average(base), self.metallic)
+    }
+
+    // Microfacet normal of a refraction from wo to wi, on the side of the normal
+    fn refraction_half_vector(&self, normal: Vector3<f32>, wo: Vector3<f32>, wi: Vector3<f32>) -> Option<Vector3<f32>> {
+        let h = wo + wi * self.ior;
+        if h.magnitude2() <= 0.0 { return None; }
+        let h = h.normalize();
+        let h = if h.dot(normal) < 0.0 { -h } else { h };
+
+        // Both directions have to lie on opposite sides of the microfacet
+        if wo.dot(h) <= 0.0 || wi.dot(h) >= 0.0 { return None; }
+        Some(h)
+    }
+
+    fn alphas(&self) -> (f32, f32) {
+        let aspect = f32::sqrt(1.0 - 0.9 * self.anisotropic);
+        let alpha = self.roughness * self.roughness;
+        (f32::max(alpha / aspect, 0.002), f32::max(alpha * aspect, 0.002))
+    }
+
+    // Roughness of an anisotropic lobe as seen from direction w (in the local frame) for the energy compensation.
+    // The albedo tables only know isotropic lobes, blending with the mean roughness keeps the approximation from adding energy.
+    fn projected_roughness(&self, w: Vector3<f32>) -> f32 {
+        let (alpha_x, alpha_y) = self.alphas();
+        let sin2 = w.x * w.x + w.y * w.y;
+        if sin2 <= 0.0 { return self.roughness; }
+        let alpha2 = (w.x * w.x * alpha_x * alpha_x + w.y * w.y * alpha_y * alpha_y) / sin2;
+        // Alpha is the square of the roughness
+        let roughness = f32::sqrt(f32::sqrt(alpha2));
+        f32::sqrt(roughness * self.roughness)
+    }
+
+    fn clearcoat_alpha(&self) -> f32 {
+        0.1 + (0.001 - 0.1) * self.clearcoat_gloss
+    }
+
+    // Hue and saturation of the base color without its luminance
+    fn tint(base: Vector3<f32>) -> Vector3<f32> {
+        let luminance = Math::luminance(base);
+        if luminance > 0.0 { base / luminance } else { vec3(1.0, 1.0, 1.0) }
+    }
+
+    fn schlick_weight(cos: f32) -> f32 {
+        f32::powi(1.0 - cos.clamp(0.0, 1.0), 5)
+    }
+
+    // Anisotropic GGX distribution, h in the local frame of the normal
+    fn ggx_d(h: Vector3<f32>, alpha_x: f32, alpha_y: f32) -> f32 {
+        if h.z <= 0.0 { return 0.0; }
+        let e = (h.x * h.x) / (alpha_x * alpha_x) + (h.y * h.y) / (alpha_y * alpha_y) + h.z * h.z;
+        1.0 / (f32::consts::PI * alpha_x * alpha_y * e * e)
+    }
+
+    // Height correlated Smith masking and shadowing
+    pub(crate) fn smith_g(wo: Vector3<f32>, wi: Vector3<f32>, alpha_x: f32, alpha_y: f32) -> f32 {
+        1.0 / (1.0 + Principled::smith_lambda(wo, alpha_x, alpha_y) + Principled::smith_lambda(wi, alpha_x, alpha_y))
+    }
+
+    fn smith_lambda(w: Vector3<f32>, alpha_x: f32, alpha_y: f32) -> f32 {
+        let cos2 = w.z * w.z;
+        if cos2 <= 0.0 { return f32::MAX; }
+        let tan2_alpha2 = (w.x * w.x * alpha_x * alpha_x + w.y * w.y * alpha_y * alpha_y) / cos2;
+        (-1.0 + f32::sqrt(1.0 + tan2_alpha2)) * 0.5
+    }
+
+    fn smith_g1_isotropic(cos_v: f32, alpha: f32) -> f32 {
+        let a2 = alpha * alpha;
+        2.0 * cos_v / (cos_v + f32::sqrt(a2 + (1.0 - a2) * cos_v * cos_v))
+    }
+
+    // Generalized Trowbridge-Reitz with gamma 1, the long tailed distribution of the clearcoat
+    fn gtr1_d(cos_h: f32, alpha: f32) -> f32 {
+        if cos_h <= 0.0 { return 0.0; }
+        let a2 = alpha * alpha;
+        (a2 - 1.0) / (f32::consts::PI * f32::ln(a2) * (1.0 + (a2 - 1.0) * cos_h * cos_h))
+    }
+
+    // Microfacet normal distributed proportional to D(h) * cos(theta_h), in the local frame
+    fn sample_ggx(r1: f32, r2: f32, alpha_x: f32, alpha_y: f32) -> Vector3<f32> {
+        let mut phi = f32::atan(alpha_y / alpha_x * f32::tan(2.0 * f32::consts::PI * r2 + 0.5 * f32::consts::PI));
+        if r2 > 0.5 { phi += f32::consts::PI; }
+        let (sin_phi, cos_phi) = phi.sin_cos();
+
+        let alpha2 = 1.0 / (cos_phi * cos_phi / (alpha_x * alpha_x) + sin_phi * sin_phi / (alpha_y * alpha_y));
+        let tan2_theta = alpha2 * r1 / f32::max(1.0 - r1, 1e-7);
+        let cos_theta = 1.0 / f32::sqrt(1.0 + tan2_theta);
+        let sin_theta = f32::sqrt(f32::max(0.0, 1.0 - cos_theta * cos_theta));
+
+        vec3(sin_theta * cos_phi, sin_theta * sin_phi, cos_theta)
+    }
+}
+
+impl Default for Principled {
+    fn default() -> Self {
+        Principled::new()
+    }
+}
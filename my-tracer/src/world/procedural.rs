@@ -0,0 +1,302 @@
+use core::f32;
+use cgmath::*;
+
+use super::{math::Math, texture::{TexCoord, TextureMap}};
+
+// Position a procedural node is evaluated at, uv coordinates are used as (u, v, 0)
+#[derive(Copy, Clone)]
+pub enum Coordinates {
+    UV,
+    World,
+    // Relative to the primitive, so the pattern moves with it
+    Object,
+}
+
+// Handle to a node in a TextureGraph
+#[derive(Copy, Clone)]
+pub struct NodeId(usize);
+
+// Every node outputs a color, patterns and noise output grey values between 0 and 1.
+// scale is the frequency of a pattern, the number of repetitions per unit of its coordinates.
+#[derive(Copy, Clone)]
+pub enum Node {
+    Constant(Vector3<f32>),
+    Image(TextureMap),
+    Checker { coords: Coordinates, scale: f32, a: NodeId, b: NodeId },
+    // Lines of width line_width (relative to a cell) between the cells
+    Grid { coords: Coordinates, scale: f32, line_width: f32, line: NodeId, fill: NodeId },
+    Perlin { coords: Coordinates, scale: f32 },
+    Simplex { coords: Coordinates, scale: f32 },
+    // Sum of octaves of Perlin noise, each one lacunarity times the frequency and gain times the amplitude of the previous
+    Fbm { coords: Coordinates, scale: f32, octaves: u32, lacunarity: f32, gain: f32 },
+    // Distance to the closest feature point, feature points are spread one per cell
+    Worley { coords: Coordinates, scale: f32 },
+    // Veins along the x axis, distorted by turbulence
+    Marble { coords: Coordinates, scale: f32, turbulence: f32, a: NodeId, b: NodeId },
+    // Rings around the y axis
+    Wood { coords: Coordinates, scale: f32, turbulence: f32, a: NodeId, b: NodeId },
+    // Linear gradient from a at start to b at end
+    Gradient { coords: Coordinates, start: Vector3<f32>, end: Vector3<f32>, a: NodeId, b: NodeId },
+    Mix { a: NodeId, b: NodeId, factor: NodeId },
+    Multiply { a: NodeId, b: NodeId },
+    // Maps the range [from_min, from_max] of the input to [to_min, to_max], values outside of it are clamped
+    Remap { input: NodeId, from_min: f32, from_max: f32, to_min: f32, to_max: f32 },
+}
+
+// A small expression graph of texture nodes. Inputs have to be added before the nodes using them,
+// the last node added is the output of the graph.
+pub struct TextureGraph {
+    nodes: Vec<Node>,
+}
+
+impl TextureGraph {
+    pub fn new() -> TextureGraph {
+        TextureGraph { nodes: Vec::new() }
+    }
+
+    pub fn add(&mut self, node: Node) -> NodeId {
+        self.nodes.push(node);
+        NodeId(self.nodes.len() - 1)
+    }
+
+    pub fn constant(&mut self, value: Vector3<f32>) -> NodeId {
+        self.add(Node::Constant(value))
+    }
+
+    // image samples the image textures used by the graph
+    pub fn eval(&self, coord: &TexCoord, image: &dyn Fn(TextureMap, &TexCoord) -> Vector3<f32>) -> Vector3<f32> {
+        if self.nodes.is_empty() { return Vector3::zero(); }
+        self.eval_node(NodeId(self.nodes.len() - 1), coord, image)
+    }
+
+    fn eval_node(&self, id: NodeId, coord: &TexCoord, image: &dyn Fn(TextureMap, &TexCoord) -> Vector3<f32>) -> Vector3<f32> {
+        let input = |id: NodeId| self.eval_node(id, coord, image);
+        let grey = |v: f32| vec3(v, v, v);
+
+        match self.nodes[id.0] {
+            Node::Constant(value) => value,
+            Node::Image(map) => image(map, coord),
+            Node::Checker { coords, scale, a, b } => {
+                let p = TextureGraph::position(coords, coord) * scale;
+                let parity = (p.x.floor() + p.y.floor() + p.z.floor()) as i64;
+                if parity.rem_euclid(2) == 0 { input(a) } else { input(b) }
+            }
+            Node::Grid { coords, scale, line_width, line, fill } => {
+                let p = TextureGraph::position(coords, coord) * scale;
+                let dims = if let Coordinates::UV = coords { 2 } else { 3 };
+                let half = line_width * 0.5;
+                let on_line = (0..dims).any(|i| {
+                    let f = p[i] - p[i].floor();
+                    f < half || f > 1.0 - half
+                });
+                if on_line { input(line) } else { input(fill) }
+            }
+            Node::Perlin { coords, scale } => grey(0.5 + 0.5 * Noise::perlin(TextureGraph::position(coords, coord) * scale)),
+            Node::Simplex { coords, scale } => grey(0.5 + 0.5 * Noise::simplex(TextureGraph::position(coords, coord) * scale)),
+            Node::Fbm { coords, scale, octaves, lacunarity, gain } => {
+                let p = TextureGraph::position(coords, coord) * scale;
+                grey(0.5 + 0.5 * Noise::fbm(p, octaves, lacunarity, gain))
+            }
+            Node::Worley { coords, scale } => grey(Noise::worley(TextureGraph::position(coords, coord) * scale).min(1.0)),
+            Node::Marble { coords, scale, turbulence, a, b } => {
+                let p = TextureGraph::position(coords, coord) * scale;
+                let t = 0.5 + 0.5 * f32::sin((p.x + turbulence * Noise::turbulence(p, 6)) * f32::consts::PI);
+                input(a).lerp(input(b), t)
+            }
+            Node::Wood { coords, scale, turbulence, a, b } => {
+                let p = TextureGraph::position(coords, coord) * scale;
+                let rings = f32::sqrt(p.x * p.x + p.z * p.z) + turbulence * Noise::perlin(p);
+                let t = f32::powi(0.5 + 0.5 * f32::sin(rings * 2.0 * f32::consts::PI), 3);
+                input(a).lerp(input(b), t)
+            }
+            Node::Gradient { coords, start, end, a, b } => {
+                let p = TextureGraph::position(coords, coord);
+                let dir = end - start;
+                let length2 = dir.magnitude2();
+                let t = if length2 > 0.0 { ((p - start).dot(dir) / length2).clamp(0.0, 1.0) } else { 0.0 };
+                input(a).lerp(input(b), t)
+            }
+            Node::Mix { a, b, factor } => {
+                let t = input(factor);
+                input(a).mul_element_wise(vec3(1.0, 1.0, 1.0) - t) + input(b).mul_element_wise(t)
+            }
+            Node::Multiply { a, b } => input(a).mul_element_wise(input(b)),
+            Node::Remap { input: i, from_min, from_max, to_min, to_max } => {
+                let range = from_max - from_min;
+                input(i).map(|v| {
+                    let t = if range != 0.0 { ((v - from_min) / range).clamp(0.0, 1.0) } else { 0.0 };
+                    to_min + t * (to_max - to_min)
+                })
+            }
+        }
+    }
+
+    fn position(coords: Coordinates, coord: &TexCoord) -> Vector3<f32> {
+        match coords {
+            Coordinates::UV => coord.uv.extend(0.0),
+            Coordinates::World => coord.position,
+            Coordinates::Object => coord.local,
+        }
+    }
+}
+
+impl Default for TextureGraph {
+    fn default() -> Self {
+        TextureGraph::new()
+    }
+}
+
+// Ken Perlin's reference permutation
+const PERMUTATION: [u8; 256] = [
+    151, 160, 137, 91, 90, 15, 131, 13, 201, 95, 96, 53, 194, 233, 7, 225, 140, 36, 103, 30, 69, 142, 8, 99, 37, 240, 21, 10, 23,
+    190, 6, 148, 247, 120, 234, 75, 0, 26, 197, 62, 94, 252, 219, 203, 117, 35, 11, 32, 57, 177, 33, 88, 237, 149, 56, 87, 174,
+    20, 125, 136, 171, 168, 68, 175, 74, 165, 71, 134, 139, 48, 27, 166, 77, 146, 158, 231, 83, 111, 229, 122, 60, 211, 133,
+    230, 220, 105, 92, 41, 55, 46, 245, 40, 244, 102, 143, 54, 65, 25, 63, 161, 1, 216, 80, 73, 209, 76, 132, 187, 208, 89,
+    18, 169, 200, 196, 135, 130, 116, 188, 159, 86, 164, 100, 109, 198, 173, 186, 3, 64, 52, 217, 226, 250, 124, 123, 5, 202,
+    38, 147, 118, 126, 255, 82, 85, 212, 207, 206, 59, 227, 47, 16, 58, 17, 182, 189, 28, 42, 223, 183, 170, 213, 119, 248,
+    152, 2, 44, 154, 163, 70, 221, 153, 101, 155, 167, 43, 172, 9, 129, 22, 39, 253, 19, 98, 108, 110, 79, 113, 224, 232, 178,
+    185, 112, 104, 218, 246, 97, 228, 251, 34, 242, 193, 238, 210, 144, 12, 191, 179, 162, 241, 81, 51, 145, 235, 249, 14,
+    239, 107, 49, 192, 214, 31, 181, 199, 106, 157, 184, 84, 204, 176, 115, 121, 50, 45, 127, 4, 150, 254, 138, 236, 205, 93,
+    222, 114, 67, 29, 24, 72, 243, 141, 128, 195, 78, 66, 215, 61, 156, 180,
+];
+
+// Edge midpoints of a cube, the gradients of simplex noise
+const GRAD3: [[f32; 3]; 12] = [
+    [1.0, 1.0, 0.0], [-1.0, 1.0, 0.0], [1.0, -1.0, 0.0], [-1.0, -1.0, 0.0],
+    [1.0, 0.0, 1.0], [-1.0, 0.0, 1.0], [1.0, 0.0, -1.0], [-1.0, 0.0, -1.0],
+    [0.0, 1.0, 1.0], [0.0, -1.0, 1.0], [0.0, 1.0, -1.0], [0.0, -1.0, -1.0],
+];
+
+pub struct Noise;
+
+impl Noise {
+    // Improved Perlin noise, roughly between -1 and 1
+    pub fn perlin(p: Vector3<f32>) -> f32 {
+        let (xi, yi, zi) = (p.x.floor() as i32, p.y.floor() as i32, p.z.floor() as i32);
+        let (x, y, z) = (p.x - p.x.floor(), p.y - p.y.floor(), p.z - p.z.floor());
+        let (u, v, w) = (Noise::fade(x), Noise::fade(y), Noise::fade(z));
+
+        let a = Noise::perm(xi) + yi;
+        let aa = Noise::perm(a) + zi;
+        let ab = Noise::perm(a + 1) + zi;
+        let b = Noise::perm(xi + 1) + yi;
+        let ba = Noise::perm(b) + zi;
+        let bb = Noise::perm(b + 1) + zi;
+
+        let lerp = |t: f32, a: f32, b: f32| a + t * (b - a);
+        lerp(w,
+            lerp(v,
+                lerp(u, Noise::grad(Noise::perm(aa), x, y, z), Noise::grad(Noise::perm(ba), x - 1.0, y, z)),
+                lerp(u, Noise::grad(Noise::perm(ab), x, y - 1.0, z), Noise::grad(Noise::perm(bb), x - 1.0, y - 1.0, z))),
+            lerp(v,
+                lerp(u, Noise::grad(Noise::perm(aa + 1), x, y, z - 1.0), Noise::grad(Noise::perm(ba + 1), x - 1.0, y, z - 1.0)),
+                lerp(u, Noise::grad(Noise::perm(ab + 1), x, y - 1.0, z - 1.0), Noise::grad(Noise::perm(bb + 1), x - 1.0, y - 1.0, z - 1.0))))
+    }
+
+    // 3D simplex noise (Gustavson), roughly between -1 and 1
+    pub fn simplex(p: Vector3<f32>) -> f32 {
+        const F3: f32 = 1.0 / 3.0;
+        const G3: f32 = 1.0 / 6.0;
+
+        // Skew to find the simplex cell
+        let s = (p.x + p.y + p.z) * F3;
+        let (i, j, k) = ((p.x + s).floor(), (p.y + s).floor(), (p.z + s).floor());
+        let t = (i + j + k) * G3;
+        let x0 = vec3(p.x - (i - t), p.y - (j - t), p.z - (k - t));
+
+        // Offsets of the second and third corner, depending on which of the six simplices we're in
+        let (o1, o2) = if x0.x >= x0.y {
+            if x0.y >= x0.z { ([1, 0, 0], [1, 1, 0]) }
+            else if x0.x >= x0.z { ([1, 0, 0], [1, 0, 1]) }
+            else { ([0, 0, 1], [1, 0, 1]) }
+        } else {
+            if x0.y < x0.z { ([0, 0, 1], [0, 1, 1]) }
+            else if x0.x < x0.z { ([0, 1, 0], [0, 1, 1]) }
+            else { ([0, 1, 0], [1, 1, 0]) }
+        };
+
+        let corners = [[0, 0, 0], o1, o2, [1, 1, 1]];
+        let (i, j, k) = (i as i32, j as i32, k as i32);
+
+        let mut sum = 0.0;
+        for (c, o) in corners.iter().enumerate() {
+            let offset = vec3(o[0] as f32, o[1] as f32, o[2] as f32) - vec3(G3, G3, G3) * c as f32;
+            let d = x0 - offset;
+            let falloff = 0.6 - d.magnitude2();
+            if falloff <= 0.0 { continue; }
+
+            let g = GRAD3[(Noise::perm(i + o[0] + Noise::perm(j + o[1] + Noise::perm(k + o[2]))) % 12) as usize];
+            sum += falloff.powi(4) * (g[0] * d.x + g[1] * d.y + g[2] * d.z);
+        }
+        32.0 * sum
+    }
+
+    // Fractional Brownian motion over Perlin noise, normalized to roughly -1 to 1
+    pub fn fbm(p: Vector3<f32>, octaves: u32, lacunarity: f32, gain: f32) -> f32 {
+        let mut sum = 0.0;
+        let mut amplitude = 1.0;
+        let mut total = 0.0;
+        let mut frequency = 1.0;
+        for _ in 0..octaves {
+            sum += amplitude * Noise::perlin(p * frequency);
+            total += amplitude;
+            amplitude *= gain;
+            frequency *= lacunarity;
+        }
+        if total > 0.0 { sum / total } else { 0.0 }
+    }
+
+    // Sum of the absolute value of octaves of Perlin noise
+    pub fn turbulence(p: Vector3<f32>, octaves: u32) -> f32 {
+        let mut sum = 0.0;
+        let mut amplitude = 1.0;
+        let mut frequency = 1.0;
+        for _ in 0..octaves {
+            sum += amplitude * f32::abs(Noise::perlin(p * frequency));
+            amplitude *= 0.5;
+            frequency *= 2.0;
+        }
+        sum
+    }
+
+    // Distance to the closest feature point (F1 of Worley noise)
+    pub fn worley(p: Vector3<f32>) -> f32 {
+        let cell = vec3(p.x.floor(), p.y.floor(), p.z.floor());
+        let mut closest = f32::MAX;
+
+        for dz in -1..=1 {
+            for dy in -1..=1 {
+                for dx in -1..=1 {
+                    let neighbour = cell + vec3(dx as f32, dy as f32, dz as f32);
+                    let feature = neighbour + Noise::cell_point(neighbour);
+                    closest = closest.min((feature - p).magnitude2());
+                }
+            }
+        }
+        closest.sqrt()
+    }
+
+    // Random point inside the unit cell
+    fn cell_point(cell: Vector3<f32>) -> Vector3<f32> {
+        let seed = (cell.x as i32 as u32).wrapping_mul(73856093) ^ (cell.y as i32 as u32).wrapping_mul(19349663) ^ (cell.z as i32 as u32).wrapping_mul(83492791);
+        let h1 = Math::wang_hash(seed);
+        let h2 = Math::wang_hash(h1);
+        let h3 = Math::wang_hash(h2);
+        vec3(h1 as f32, h2 as f32, h3 as f32) * 2.3283064365387e-10
+    }
+
+    fn perm(i: i32) -> i32 {
+        PERMUTATION[(i & 255) as usize] as i32
+    }
+
+    fn fade(t: f32) -> f32 {
+        t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+    }
+
+    fn grad(hash: i32, x: f32, y: f32, z: f32) -> f32 {
+        let h = hash & 15;
+        let u = if h < 8 { x } else { y };
+        let v = if h < 4 { y } else if h == 12 || h == 14 { x } else { z };
+        (if h & 1 == 0 { u } else { -u }) + (if h & 2 == 0 { v } else { -v })
+    }
+}
@@ -0,0 +1,93 @@
+use cgmath::*;
+use rayon::prelude::*;
+
+use super::{aov::{AovBuffers, AovSample}, camera::Camera};
+
+// Pixels of the previous frame a pixel takes its history from, with their bilinear weights. Rejected taps weigh zero.
+pub type HistoryTaps = [(usize, f32); 4];
+
+// Temporal reprojection. When the camera moves, every pixel looks up the surface it now sees in the previous frame and keeps
+// the accumulated color found there, unless the depth or normal stored there tells it was another surface (a disocclusion).
+// The history counts for at most max_history samples, so while the camera keeps moving new samples are blended in as an
+// exponential moving average, and once it stops the pixels converge as usual.
+pub struct Reprojection {
+    pub enabled: bool,
+    // Most samples the history of a pixel counts for, every new sample gets at least a 1 / (max_history + 1) share
+    pub max_history: u32,
+    // Allowed difference between the stored depth and the distance of the surface to the previous camera, relative to that distance
+    pub depth_tolerance: f32,
+    // Smallest cosine between the stored normal and the normal of the surface
+    pub normal_tolerance: f32,
+}
+
+impl Reprojection {
+    pub fn new() -> Reprojection {
+        Reprojection {
+            enabled: true,
+            max_history: 16,
+            depth_tolerance: 0.1,
+            normal_tolerance: 0.9,
+        }
+    }
+
+    // Finds the history of every pixel. surfaces are seen through the pixel centers of the current camera,
+    // aovs hold the averaged depths and normals every pixel of the previous frame saw.
+    pub fn find_history(&self, previous: &Camera, current: &Camera, surfaces: &[AovSample], aovs: &AovBuffers, width: usize, height: usize) -> Vec<HistoryTaps> {
+        (0..width * height).into_par_iter().map(|i| {
+            let mut taps = [(0, 0.0); 4];
+            let surface = &surfaces[i];
+            let dir = current.calculate_primary_ray((i % width) as f32 / width as f32, (i / width) as f32 / height as f32).dir;
+
+            // The sky is at infinity, it is found in the same direction from the previous camera
+            let sky = surface.normal == Vector3::zero();
+            let position = current.position + dir * surface.depth;
+            let (x, y) = match previous.project(if sky { previous.position + dir } else { position }) {
+                Some(p) => p,
+                None => return taps
+            };
+            let prev_depth = (position - previous.position).magnitude();
+
+            // Pixel centers are at whole coordinates
+            let (fx, fy) = (x * width as f32, y * height as f32);
+            let (x0, y0) = (fx.floor(), fy.floor());
+            let (tx, ty) = (fx - x0, fy - y0);
+            let corners = [(0, 0, (1.0 - tx) * (1.0 - ty)), (1, 0, tx * (1.0 - ty)), (0, 1, (1.0 - tx) * ty), (1, 1, tx * ty)];
+
+            for (tap, (dx, dy, weight)) in taps.iter_mut().zip(corners) {
+                let (qx, qy) = (x0 as i32 + dx, y0 as i32 + dy);
+                if qx < 0 || qy < 0 || qx >= width as i32 || qy >= height as i32 { continue; }
+                let q = qy as usize * width + qx as usize;
+
+                let accepted = if sky {
+                    aovs.depth[q] == 0.0
+                } else {
+                    // Stored normals are averages, so the cosine is taken relative to their length
+                    let normal = aovs.normal[q];
+                    f32::abs(aovs.depth[q] - prev_depth) < self.depth_tolerance * prev_depth
+                        && normal.dot(surface.normal) > self.normal_tolerance * normal.magnitude()
+                };
+                if accepted { *tap = (q, weight); }
+            }
+            taps
+        }).collect()
+    }
+
+    // Blends the values of the taps, None if all of them were rejected
+    pub fn resample<T>(taps: &HistoryTaps, values: &[T]) -> Option<T>
+        where T: Copy + std::ops::Mul<f32, Output = T> + std::ops::Add<Output = T> + std::ops::Div<f32, Output = T> {
+        let weight_sum: f32 = taps.iter().map(|t| t.1).sum();
+        if weight_sum <= 0.0 { return None; }
+
+        let mut sum = values[taps[0].0] * taps[0].1;
+        for (q, weight) in &taps[1..] {
+            sum = sum + values[*q] * *weight;
+        }
+        Some(sum / weight_sum)
+    }
+}
+
+impl Default for Reprojection {
+    fn default() -> Self {
+        Reprojection::new()
+    }
+}
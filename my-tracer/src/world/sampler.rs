@@ -0,0 +1,394 @@
+use super::math::Math;
+
+// Dimensions drawn before the first bounce, the position within the pixel and the hero wavelength of the spectral path tracer
+const CAMERA_DIMENSIONS: u32 = 3;
+// Dimensions reserved for every bounce, draws past them come from the fallback random stream
+const BOUNCE_DIMENSIONS: u32 = 12;
+// Dimensions of the Halton sequence, one prime base each
+const HALTON_DIMENSIONS: usize = 128;
+// Side of the tileable blue noise mask
+const BLUE_NOISE_SIZE: usize = 64;
+
+lazy_static::lazy_static! {
+    static ref HALTON: Halton = Halton::generate(HALTON_DIMENSIONS, 0x5EED1234);
+    static ref BLUE_NOISE: Vec<f32> = Qmc::blue_noise(BLUE_NOISE_SIZE, 0xB10E5EED);
+}
+
+// Stream of uniform random numbers in [0, 1) that integrators draw from, so the numbers can come from somewhere else than xorshift
+pub trait Sampler {
+    fn next_f32(&mut self) -> f32;
+
+    // Called at the start of every bounce, so low discrepancy samplers give the same dimensions to the same bounce in every sample
+    fn start_bounce(&mut self, _depth: u32) {}
+}
+
+// A plain xorshift state is the default stream
+impl Sampler for u32 {
+    fn next_f32(&mut self) -> f32 {
+        Math::random_f32(self).min(1.0 - f32::EPSILON)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum SamplerKind {
+    Random,
+    // Sobol (0,2) sequence with Owen scrambling per pixel, padded to more dimensions by shuffling the index per pair of dimensions
+    Sobol,
+    // Halton with random permutations of the digits, and a different start in the sequence per pixel
+    Halton,
+    // One scrambled Sobol sequence for the whole image, offset per pixel by a blue noise mask so the error is spread as blue noise
+    BlueNoise,
+}
+
+impl SamplerKind {
+    pub const ALL: [SamplerKind; 4] = [SamplerKind::Random, SamplerKind::Sobol, SamplerKind::Halton, SamplerKind::BlueNoise];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SamplerKind::Random => "Random",
+            SamplerKind::Sobol => "Sobol (Owen scrambled)",
+            SamplerKind::Halton => "Halton (permuted digits)",
+            SamplerKind::BlueNoise => "Blue noise",
+        }
+    }
+}
+
+// Sampler of one sample of one pixel. Dimensions 0 and 1 place the sample within the pixel, after that every bounce gets its own block.
+pub struct PixelSampler {
+    kind: SamplerKind,
+    seed: u32,
+    pixel_seed: u32,
+    x: u32,
+    y: u32,
+    index: u32,
+    dimension: u32,
+    // Until the first bounce starts dimensions are handed out in order without limit
+    bounce_end: u32,
+    rng: u32,
+}
+
+impl PixelSampler {
+    pub fn new(kind: SamplerKind, seed: u32, pixel: u32, width: u32, index: u32) -> PixelSampler {
+        let pixel_seed = Qmc::hash(seed, pixel);
+        PixelSampler {
+            kind: kind,
+            seed: seed,
+            pixel_seed: pixel_seed,
+            x: pixel % width,
+            y: pixel / width,
+            index: index,
+            dimension: 0,
+            bounce_end: u32::MAX,
+            rng: Qmc::hash(pixel_seed, index).max(1),
+        }
+    }
+
+    // Seed of a whole frame, for the integrators that do work before the pixels are traced
+    pub fn frame_seed(seed: u32, index: u32) -> u32 {
+        Qmc::hash(seed ^ 0xF4A3E, index)
+    }
+}
+
+impl Sampler for PixelSampler {
+    fn next_f32(&mut self) -> f32 {
+        let dimension = self.dimension;
+        self.dimension += 1;
+        if dimension >= self.bounce_end { return self.rng.next_f32(); }
+
+        match self.kind {
+            SamplerKind::Random => self.rng.next_f32(),
+            SamplerKind::Sobol => Qmc::sobol(self.index, dimension, self.pixel_seed),
+            SamplerKind::Halton => {
+                if dimension as usize >= HALTON_DIMENSIONS { return self.rng.next_f32(); }
+                HALTON.sample(self.index.wrapping_add(self.pixel_seed >> 12), dimension as usize)
+            }
+            SamplerKind::BlueNoise => {
+                let offset = Qmc::hash(self.seed ^ 0xB10E, dimension);
+                let x = self.x.wrapping_add(offset) as usize % BLUE_NOISE_SIZE;
+                let y = self.y.wrapping_add(offset >> 16) as usize % BLUE_NOISE_SIZE;
+                let value = Qmc::sobol(self.index, dimension, self.seed) + BLUE_NOISE[y * BLUE_NOISE_SIZE + x];
+                (value - value.floor()).min(1.0 - f32::EPSILON)
+            }
+        }
+    }
+
+    fn start_bounce(&mut self, depth: u32) {
+        self.dimension = CAMERA_DIMENSIONS + depth * BOUNCE_DIMENSIONS;
+        self.bounce_end = self.dimension + BOUNCE_DIMENSIONS;
+    }
+}
+
+// Digit permutations of the Halton sequence, one per digit of every prime base
+struct Halton {
+    bases: Vec<u32>,
+    digits: Vec<usize>,
+    permutations: Vec<Vec<u16>>,
+}
+
+impl Halton {
+    fn generate(dimensions: usize, seed: u32) -> Halton {
+        let mut bases = Vec::with_capacity(dimensions);
+        let mut candidate = 2;
+        while bases.len() < dimensions {
+            if bases.iter().all(|p| candidate % p != 0) { bases.push(candidate); }
+            candidate += 1;
+        }
+
+        // Enough digits to tell every u32 index apart
+        let digits: Vec<usize> = bases.iter().map(|&b| f32::ceil(32.0 / f32::log2(b as f32)) as usize).collect();
+
+        let mut rng = seed;
+        let permutations = bases.iter().zip(&digits).map(|(&b, &n)| {
+            let mut permutation = Vec::with_capacity(b as usize * n);
+            for _ in 0..n {
+                let mut digit: Vec<u16> = (0..b as u16).collect();
+                for i in (1..digit.len()).rev() {
+                    digit.swap(i, Math::random_uint(&mut rng) as usize % (i + 1));
+                }
+                permutation.extend(digit);
+            }
+            permutation
+        }).collect();
+
+        Halton {
+            bases: bases,
+            digits: digits,
+            permutations: permutations,
+        }
+    }
+
+    // Radical inverse with every digit permuted, the leading zeros included so the points stay stratified
+    fn sample(&self, index: u32, dimension: usize) -> f32 {
+        let base = self.bases[dimension];
+        let permutation = &self.permutations[dimension];
+        let inv_base = 1.0 / base as f64;
+
+        let mut a = index;
+        let mut scale = 1.0;
+        let mut value = 0.0;
+        for digit in 0..self.digits[dimension] {
+            scale *= inv_base;
+            value += permutation[digit * base as usize + (a % base) as usize] as f64 * scale;
+            a /= base;
+        }
+        (value as f32).min(1.0 - f32::EPSILON)
+    }
+}
+
+// Building blocks of the low discrepancy samplers
+struct Qmc;
+
+impl Qmc {
+    fn hash(a: u32, b: u32) -> u32 {
+        Math::wang_hash(a ^ Math::wang_hash(b).wrapping_add(0x9E3779B9))
+    }
+
+    // Owen scrambled Sobol (Burley 2020), dimensions are padded from 2D Sobol with an index shuffled per pair of dimensions
+    fn sobol(index: u32, dimension: u32, seed: u32) -> f32 {
+        let pair_seed = Qmc::hash(seed, dimension / 2);
+        let shuffled = Qmc::nested_uniform_scramble(index, pair_seed);
+
+        let value = if dimension % 2 == 0 {
+            shuffled.reverse_bits()
+        } else {
+            // Second dimension of Sobol, its direction numbers follow from v ^= v >> 1
+            let mut v = 1u32 << 31;
+            let mut value = 0;
+            let mut i = shuffled;
+            while i != 0 {
+                if i & 1 != 0 { value ^= v; }
+                i >>= 1;
+                v ^= v >> 1;
+            }
+            value
+        };
+
+        let scrambled = Qmc::nested_uniform_scramble(value, Qmc::hash(pair_seed, dimension));
+        (scrambled >> 8) as f32 / (1u32 << 24) as f32
+    }
+
+    // Owen scrambling of the bits of x, each bit is flipped depending on the bits above it
+    fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+        let mut x = x.reverse_bits();
+        x = x.wrapping_add(seed);
+        x ^= x.wrapping_mul(0x6C50B47C);
+        x ^= x.wrapping_mul(0xB82F1E52);
+        x ^= x.wrapping_mul(0xC7AFE638);
+        x ^= x.wrapping_mul(0x8D22F6E6);
+        x.reverse_bits()
+    }
+
+    // Tileable blue noise mask made with void and cluster (Ulichney), values are ranks spread over [0, 1)
+    fn blue_noise(size: usize, seed: u32) -> Vec<f32> {
+        let n = size * size;
+        let sigma = 1.5;
+
+        // Gaussian energy every point spreads over the torus
+        let mut kernel = vec![0.0; n];
+        for y in 0..size {
+            for x in 0..size {
+                let dx = usize::min(x, size - x) as f32;
+                let dy = usize::min(y, size - y) as f32;
+                kernel[y * size + x] = f32::exp(-(dx * dx + dy * dy) / (2.0 * sigma * sigma));
+            }
+        }
+        let splat = |energy: &mut Vec<f32>, p: usize, sign: f32| {
+            let (px, py) = (p % size, p / size);
+            for y in 0..size {
+                for x in 0..size {
+                    energy[y * size + x] += sign * kernel[((y + size - py) % size) * size + (x + size - px) % size];
+                }
+            }
+        };
+        // Tightest cluster among the set points, or largest void among the empty ones
+        let extreme = |energy: &Vec<f32>, set: &Vec<bool>, state: bool, largest: bool| {
+            let mut best = 0;
+            let mut best_energy = if largest { f32::MIN } else { f32::MAX };
+            for p in 0..n {
+                if set[p] != state { continue; }
+                if (largest && energy[p] > best_energy) || (!largest && energy[p] < best_energy) {
+                    best = p;
+                    best_energy = energy[p];
+                }
+            }
+            best
+        };
+
+        // Initial pattern of random points, relaxed by moving the tightest cluster into the largest void until that changes nothing
+        let initial = n / 10;
+        let mut set = vec![false; n];
+        let mut energy = vec![0.0; n];
+        let mut rng = seed;
+        let mut count = 0;
+        while count < initial {
+            let p = Math::random_uint(&mut rng) as usize % n;
+            if set[p] { continue; }
+            set[p] = true;
+            splat(&mut energy, p, 1.0);
+            count += 1;
+        }
+        for _ in 0..n {
+            let cluster = extreme(&energy, &set, true, true);
+            set[cluster] = false;
+            splat(&mut energy, cluster, -1.0);
+            let void = extreme(&energy, &set, false, false);
+            set[void] = true;
+            splat(&mut energy, void, 1.0);
+            if void == cluster { break; }
+        }
+
+        let mut rank = vec![0; n];
+
+        // Points of the initial pattern are ranked by removing the tightest cluster
+        let (mut removed, mut removed_energy) = (set.clone(), energy.clone());
+        for r in (0..initial).rev() {
+            let cluster = extreme(&removed_energy, &removed, true, true);
+            removed[cluster] = false;
+            splat(&mut removed_energy, cluster, -1.0);
+            rank[cluster] = r;
+        }
+
+        // The rest fill the largest void. Past half the void of the set points is also the tightest cluster of the empty ones.
+        for r in initial..n {
+            let void = extreme(&energy, &set, false, false);
+            set[void] = true;
+            splat(&mut energy, void, 1.0);
+            rank[void] = r;
+        }
+
+        rank.iter().map(|&r| (r as f32 + 0.5) / n as f32).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn draw(kind: SamplerKind, pixel: u32, index: u32) -> Vec<f32> {
+        let mut sampler = PixelSampler::new(kind, 7, pixel, 16, index);
+        let mut values = vec![sampler.next_f32(), sampler.next_f32()];
+        for depth in 0..3 {
+            sampler.start_bounce(depth);
+            // One more draw than a bounce holds, so the fallback stream is used too
+            for _ in 0..=BOUNCE_DIMENSIONS {
+                values.push(sampler.next_f32());
+            }
+        }
+        values
+    }
+
+    #[test]
+    fn every_kind_is_deterministic_and_in_range() {
+        for kind in SamplerKind::ALL {
+            for pixel in [0, 5, 37] {
+                for index in [0, 1, 100] {
+                    let values = draw(kind, pixel, index);
+                    assert!(values.iter().all(|&v| (0.0..1.0).contains(&v)), "{} out of range", kind.name());
+                    assert_eq!(values, draw(kind, pixel, index), "{} is not deterministic", kind.name());
+                }
+            }
+            assert_ne!(draw(kind, 0, 0), draw(kind, 1, 0), "{} repeats between pixels", kind.name());
+            assert_ne!(draw(kind, 0, 0), draw(kind, 0, 1), "{} repeats between samples", kind.name());
+        }
+    }
+
+    #[test]
+    fn bounces_get_the_same_dimensions_however_many_were_drawn_before() {
+        // Random is one plain stream, it has no dimensions to line up
+        for kind in [SamplerKind::Sobol, SamplerKind::Halton, SamplerKind::BlueNoise] {
+            let mut a = PixelSampler::new(kind, 3, 9, 16, 4);
+            let mut b = PixelSampler::new(kind, 3, 9, 16, 4);
+            a.start_bounce(0);
+            b.start_bounce(0);
+            a.next_f32();
+            for _ in 0..5 {
+                b.next_f32();
+            }
+            a.start_bounce(1);
+            b.start_bounce(1);
+            assert_eq!(a.next_f32(), b.next_f32(), "{}", kind.name());
+        }
+    }
+
+    // Index of the stratum every value falls in, each must be hit once
+    fn assert_stratified(strata: impl Iterator<Item = usize>, count: usize) {
+        let mut hits = vec![0; count];
+        for s in strata {
+            hits[s] += 1;
+        }
+        assert!(hits.iter().all(|&h| h == 1), "{:?}", hits);
+    }
+
+    #[test]
+    fn sobol_pixels_are_stratified_in_two_dimensions() {
+        for pixel in [0, 11] {
+            let points: Vec<(f32, f32)> = (0..16).map(|index| {
+                let mut sampler = PixelSampler::new(SamplerKind::Sobol, 1, pixel, 16, index);
+                (sampler.next_f32(), sampler.next_f32())
+            }).collect();
+            assert_stratified(points.iter().map(|p| (p.0 * 16.0) as usize), 16);
+            assert_stratified(points.iter().map(|p| (p.1 * 16.0) as usize), 16);
+            assert_stratified(points.iter().map(|p| (p.1 * 4.0) as usize * 4 + (p.0 * 4.0) as usize), 16);
+        }
+    }
+
+    #[test]
+    fn halton_pixels_are_stratified_in_their_bases() {
+        for pixel in [0, 11] {
+            let points: Vec<(f32, f32)> = (0..18).map(|index| {
+                let mut sampler = PixelSampler::new(SamplerKind::Halton, 1, pixel, 16, index);
+                (sampler.next_f32(), sampler.next_f32())
+            }).collect();
+            assert_stratified(points[0..16].iter().map(|p| (p.0 * 16.0) as usize), 16);
+            assert_stratified(points[0..9].iter().map(|p| (p.1 * 9.0) as usize), 9);
+            assert_stratified(points[0..6].iter().map(|p| (p.1 * 3.0) as usize * 2 + (p.0 * 2.0) as usize), 6);
+        }
+    }
+
+    #[test]
+    fn blue_noise_mask_holds_every_rank_once() {
+        let n = BLUE_NOISE_SIZE * BLUE_NOISE_SIZE;
+        assert_eq!(BLUE_NOISE.len(), n);
+        assert_stratified(BLUE_NOISE.iter().map(|&v| (v * n as f32) as usize), n);
+    }
+}
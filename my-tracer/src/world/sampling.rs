@@ -0,0 +1,173 @@
+use super::sampler::Sampler;
+
+// Piecewise constant distribution over [0, 1), built from a list of (non-negative) weights
+pub struct Distribution1D {
+    func: Vec<f32>,
+    cdf: Vec<f32>,
+    integral: f32,
+}
+
+impl Distribution1D {
+    pub fn new(func: Vec<f32>) -> Distribution1D {
+        let n = func.len();
+        let mut cdf = vec![0.0; n + 1];
+
+        for i in 1..n + 1 {
+            cdf[i] = cdf[i - 1] + func[i - 1] / n as f32;
+        }
+
+        let integral = cdf[n];
+
+        // Fall back to uniform sampling if every weight is zero
+        if integral == 0.0 {
+            for i in 1..n + 1 {
+                cdf[i] = i as f32 / n as f32;
+            }
+        } else {
+            for i in 1..n + 1 {
+                cdf[i] /= integral;
+            }
+        }
+
+        Distribution1D {
+            func: func,
+            cdf: cdf,
+            integral: integral,
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.func.len()
+    }
+
+    pub fn integral(&self) -> f32 {
+        self.integral
+    }
+
+    // Returns the sampled offset in [0, 1), its pdf and the index of the segment it falls in
+    pub fn sample_continuous(&self, sampler: &mut dyn Sampler) -> (f32, f32, usize) {
+        let u = sampler.next_f32();
+        self.sample_continuous_with(u)
+    }
+
+    pub fn sample_continuous_with(&self, u: f32) -> (f32, f32, usize) {
+        let offset = self.find_segment(u);
+
+        let mut du = u - self.cdf[offset];
+        let width = self.cdf[offset + 1] - self.cdf[offset];
+        if width > 0.0 {
+            du /= width;
+        }
+
+        let pdf = self.pdf(offset);
+        let x = (offset as f32 + du) / self.count() as f32;
+        (x.min(1.0 - f32::EPSILON), pdf, offset)
+    }
+
+    pub fn pdf(&self, offset: usize) -> f32 {
+        if self.integral > 0.0 { self.func[offset] / self.integral } else { 1.0 }
+    }
+
+    fn find_segment(&self, u: f32) -> usize {
+        // Last cdf entry that is <= u
+        let idx = self.cdf.partition_point(|&c| c <= u);
+        idx.saturating_sub(1).min(self.count() - 1)
+    }
+}
+
+// Distribution over [0, 1)^2, sampling a row by its marginal first and the column conditionally after
+pub struct Distribution2D {
+    conditional: Vec<Distribution1D>,
+    marginal: Distribution1D,
+}
+
+impl Distribution2D {
+    pub fn new(func: &[f32], width: usize, height: usize) -> Distribution2D {
+        let conditional: Vec<Distribution1D> = (0..height)
+            .map(|v| Distribution1D::new(func[v * width..(v + 1) * width].to_vec()))
+            .collect();
+
+        let marginal = Distribution1D::new(conditional.iter().map(|d| d.integral()).collect());
+
+        Distribution2D {
+            conditional: conditional,
+            marginal: marginal,
+        }
+    }
+
+    // Returns the sampled (u, v) and its pdf with respect to area in [0, 1)^2
+    pub fn sample_continuous(&self, sampler: &mut dyn Sampler) -> ((f32, f32), f32) {
+        let (v, pdf_v, row) = self.marginal.sample_continuous(sampler);
+        let (u, pdf_u, _) = self.conditional[row].sample_continuous(sampler);
+        ((u, v), pdf_u * pdf_v)
+    }
+
+    pub fn pdf(&self, u: f32, v: f32) -> f32 {
+        let width = self.conditional[0].count();
+        let height = self.marginal.count();
+
+        let iu = ((u * width as f32) as usize).min(width - 1);
+        let iv = ((v * height as f32) as usize).min(height - 1);
+
+        if self.marginal.integral() == 0.0 {
+            return 1.0;
+        }
+        self.conditional[iv].func[iu] / self.marginal.integral()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_segments_proportional_to_their_weight() {
+        let distribution = Distribution1D::new(vec![1.0, 0.0, 3.0]);
+        assert_eq!(distribution.integral(), 4.0 / 3.0);
+        assert_eq!(distribution.pdf(0), 0.75);
+        assert_eq!(distribution.pdf(2), 2.25);
+
+        let mut counts = [0; 3];
+        let n = 10000;
+        for i in 0..n {
+            let u = (i as f32 + 0.5) / n as f32;
+            let (x, pdf, offset) = distribution.sample_continuous_with(u);
+            assert!((0.0..1.0).contains(&x));
+            assert_eq!(offset, (x * 3.0) as usize);
+            assert_eq!(pdf, distribution.pdf(offset));
+            counts[offset] += 1;
+        }
+        assert_eq!(counts, [2500, 0, 7500]);
+    }
+
+    #[test]
+    fn falls_back_to_uniform_without_weights() {
+        let distribution = Distribution1D::new(vec![0.0; 4]);
+        assert_eq!(distribution.pdf(1), 1.0);
+        let (x, _, offset) = distribution.sample_continuous_with(0.6);
+        assert!((x - 0.6).abs() < 1e-6);
+        assert_eq!(offset, 2);
+    }
+
+    #[test]
+    fn pdf_2d_matches_the_samples_and_integrates_to_one() {
+        let (width, height) = (4, 3);
+        let func: Vec<f32> = (0..width * height).map(|i| (i % 5) as f32).collect();
+        let distribution = Distribution2D::new(&func, width, height);
+
+        let mut seed: u32 = 12345;
+        for _ in 0..1000 {
+            let ((u, v), pdf) = distribution.sample_continuous(&mut seed);
+            assert!(pdf > 0.0);
+            assert!((pdf - distribution.pdf(u, v)).abs() < 1e-4 * pdf);
+        }
+
+        let mut integral = 0.0;
+        for y in 0..height {
+            for x in 0..width {
+                integral += distribution.pdf((x as f32 + 0.5) / width as f32, (y as f32 + 0.5) / height as f32) / (width * height) as f32;
+            }
+        }
+        assert!((integral - 1.0).abs() < 1e-5);
+    }
+}
@@ -1,71 +1,285 @@
 use core::f32;
 use cgmath::*;
-use image::{ImageBuffer, Rgb};
 use num_traits::clamp;
 use rayon::prelude::*;
 
-use super::{camera::Camera, math::Math, primitives::{Object, Plane, Sphere}, ray::Ray};
+use super::{adaptive::AdaptiveSampling, aov::{Aov, AovBuffers, AovSample, LightingAovs}, camera::Camera, denoiser::Denoiser, film::SplatBuffer, furnace::{Furnace, FurnaceResult, FURNACE_COSINES}, grid_loader::GridLoader, integrators::{self, Integrator}, layered::Coat, lpe::{Event, LightPass, LightPaths}, material::Material, math::Math, medium::{DensityGrid, HenyeyGreenstein, MediumInteraction, Volume, VolumeBounds}, primitives::{Object, Plane, Sphere}, principled::Principled, procedural::{Coordinates, Node, TextureGraph}, ray::Ray, reprojection::Reprojection, sampler::{PixelSampler, Sampler, SamplerKind}, skybox::Skybox, texture::{MaterialTextures, TextureCache, TextureMap}, tiles::{RenderMode, Tile, TileOrder, TileScheduler}};
 
-const EPSILON : f32 = 0.0001;
+pub(crate) const EPSILON : f32 = 0.0001;
+
+// Light sampled at a surface, already multiplied with the lobes that scatter it and divided by the pdfs
+pub(crate) struct DirectLight {
+    pub diffuse: Vector3<f32>,
+    pub specular: Vector3<f32>,
+    // True if the light arrives from behind the surface
+    pub transmitted: bool,
+    pub light: Event,
+}
+
+// Scenes that show off the features, all built around the same three spheres
+#[derive(Copy, Clone, PartialEq)]
+pub enum ExampleScene {
+    Spheres,
+    // Ground fog and a smoke grid
+    Volumes,
+    // Smoke loaded from an OpenVDB file
+    SmokeCache,
+    // Wax with random walk subsurface scattering
+    Subsurface,
+    // Image textures
+    Textures,
+    // Marble from a procedural texture graph
+    Procedural,
+    // Brushed metal, car paint and frosted glass made with the principled BSDF
+    Principled,
+    // Clear coats and thin films over other materials
+    Coated,
+}
+
+impl ExampleScene {
+    pub const ALL: [ExampleScene; 8] = [ExampleScene::Spheres, ExampleScene::Volumes, ExampleScene::SmokeCache, ExampleScene::Subsurface, ExampleScene::Textures, ExampleScene::Procedural, ExampleScene::Principled, ExampleScene::Coated];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ExampleScene::Spheres => "Spheres",
+            ExampleScene::Volumes => "Volumes",
+            ExampleScene::SmokeCache => "Smoke cache",
+            ExampleScene::Subsurface => "Subsurface",
+            ExampleScene::Textures => "Textures",
+            ExampleScene::Procedural => "Procedural textures",
+            ExampleScene::Principled => "Principled",
+            ExampleScene::Coated => "Coated",
+        }
+    }
+}
 
 pub struct Scene {
     camera : Camera,
     primitives : Vec<Object>,
+    // Material id of every primitive, primitives with equal materials share the index of the first of them
+    material_ids: Vec<i32>,
     lights: Vec<i32>,
+    // Light group of every entry in lights, an index into light_groups
+    light_group_of: Vec<usize>,
+    light_groups: Vec<String>,
+    // Name and expression of the light passes added by the user
+    custom_passes: Vec<(String, String)>,
+    // Why each custom pass was left out of light_passes, empty if it wasn't
+    custom_pass_errors: Vec<String>,
+    // One pass per light group, the environment and the custom passes, compiled for the current light groups
+    light_passes: Vec<LightPass>,
+    // Light pass being typed in the UI, and why the last one couldn't be added
+    new_pass: (String, String),
+    pass_error: String,
+    volumes: Vec<Volume>,
     accumulated: f32,
     width: u32,
     height: u32,
-    skybox: ImageBuffer<Rgb<f32>, Vec<f32>>,
-    sky_width: f32,
-    sky_height: f32,
-    aspect: f32
+    skybox: Skybox,
+    // Environment of the scene while the furnace shows its uniform white sky instead
+    environment: Option<Skybox>,
+    aspect: f32,
+    integrators: Vec<Box<dyn Integrator>>,
+    active_integrator: usize,
+    sampler: SamplerKind,
+    // Every random number of a render derives from this seed, the pixel and the sample index,
+    // so the same seed and sample count give the same image regardless of the number of threads
+    seed: u32,
+    // Seed of the pixel samplers, changed whenever the history is reprojected so the samples that follow don't repeat it
+    sample_seed: u32,
+    splats: SplatBuffer,
+    // Pixels traced this frame, less than all of them in bucket mode or with a crop
+    traced_pixels: usize,
+    adaptive: AdaptiveSampling,
+    denoiser: Denoiser,
+    aovs: AovBuffers,
+    // Buffer shown in the window, and the one pixels_rgb8 last showed
+    view: Aov,
+    shown: Aov,
+    // Set by the UI, the AOVs are saved with the next frame
+    save_aovs_requested: bool,
+    // Whether the accumulated frames filled the AOVs, and where the last save went or why it failed
+    aovs_recorded: bool,
+    aov_status: String,
+    reprojection: Reprojection,
+    tiles: TileScheduler,
+    furnace_results: Vec<FurnaceResult>
 }
 
 impl Scene{
     pub fn new(width: u32, height:u32, skybox_path : &str) -> Scene{
-        let mut texture = image::open(&skybox_path).unwrap().into_rgb32f();
-        
-        let sky_width = texture.width() as f32;
-        let sky_height = texture.height() as f32;
-
-        // Adjusts HDR values
-        for x in 0..texture.width(){
-            for y in 0..texture.height(){
-                for i in 0..3  {
-                    texture[(x,y)][i] = f32::sqrt(texture[(x,y)][i]);
-                }
-            }
-        }
-        
-        Scene{
+        let mut scene = Scene{
             camera: Camera::new((width as f32) / (height as f32)),
             primitives: Vec::new(),
+            material_ids: Vec::new(),
             lights: Vec::new(),
+            light_group_of: Vec::new(),
+            light_groups: Vec::new(),
+            custom_passes: Vec::new(),
+            custom_pass_errors: Vec::new(),
+            light_passes: Vec::new(),
+            new_pass: (String::new(), String::new()),
+            pass_error: String::new(),
+            volumes: Vec::new(),
             accumulated: 0.0,
             width: width,
             height: height,
-            skybox: texture,
-            sky_width: sky_width,
-            sky_height: sky_height,
-            aspect: (width as f32) / (height as f32)
-        }
+            skybox: Skybox::new(skybox_path),
+            environment: None,
+            aspect: (width as f32) / (height as f32),
+            integrators: integrators::all(),
+            active_integrator: 0,
+            sampler: SamplerKind::Random,
+            seed: 0,
+            sample_seed: 0,
+            splats: SplatBuffer::new((width * height) as usize),
+            traced_pixels: 0,
+            adaptive: AdaptiveSampling::new(width, height),
+            denoiser: Denoiser::new(),
+            aovs: AovBuffers::new((width * height) as usize),
+            view: Aov::Beauty,
+            shown: Aov::Beauty,
+            save_aovs_requested: false,
+            aovs_recorded: false,
+            aov_status: String::new(),
+            reprojection: Reprojection::new(),
+            tiles: TileScheduler::new(),
+            furnace_results: Vec::new()
+        };
+        scene.update_light_passes();
+        scene
     }
 
     pub fn add_object(&mut self, mut obj: Object){
         obj.set_idx(self.primitives.len() as i32);
         obj.set_light(false);
-        self.primitives.push(obj);
+        self.push_primitive(obj);
+    }
+
+    // Adds a light in a light group of its own
+    pub fn add_light(&mut self, obj: Object){
+        let group = format!("light_{}", self.lights.len());
+        self.add_light_to_group(obj, &group);
     }
 
-    pub fn add_light(&mut self, mut obj: Object){
+    // Adds a light to the named light group, lights of a group share a light pass
+    pub fn add_light_to_group(&mut self, mut obj: Object, group: &str){
+        assert!(!group.contains('\''), "light group names can't contain quotes");
         obj.set_idx(self.primitives.len() as i32);
         obj.set_light(true);
-        self.primitives.push(obj);
+        self.push_primitive(obj);
         self.lights.push(obj.idx());
+
+        let group_idx = match self.light_groups.iter().position(|g| g == group) {
+            Some(idx) => idx,
+            None => {
+                self.light_groups.push(group.to_string());
+                self.light_groups.len() - 1
+            }
+        };
+        self.light_group_of.push(group_idx);
+        self.update_light_passes();
+    }
+
+    // Adds a light pass with the light of the paths matching a light path expression, like C<RD>L for direct diffuse light
+    pub fn add_light_pass(&mut self, name: &str, expression: &str) -> Result<(), String>{
+        if self.light_passes.iter().any(|p| p.name == name) { return Err(format!("there already is a pass named {}", name)); }
+        LightPass::new(name, expression, &self.light_groups)?;
+        self.custom_passes.push((name.to_string(), expression.to_string()));
+        self.update_light_passes();
+        Ok(())
+    }
+
+    pub fn remove_light_pass(&mut self, idx: usize){
+        self.custom_passes.remove(idx);
+        self.update_light_passes();
+    }
+
+    // Compiles the light passes for the current light groups and restarts the render with a buffer for each of them.
+    // Custom passes naming a group that no longer exists are left out.
+    fn update_light_passes(&mut self){
+        let mut passes: Vec<LightPass> = self.light_groups.iter()
+            .map(|group| LightPass::new(group, &format!("C.*<L'{}'>", group), &self.light_groups).unwrap())
+            .collect();
+        passes.push(LightPass::new("environment", "C.*B", &self.light_groups).unwrap());
+        self.custom_pass_errors.clear();
+        for (name, expression) in &self.custom_passes {
+            match LightPass::new(name, expression, &self.light_groups) {
+                Ok(pass) => {
+                    passes.push(pass);
+                    self.custom_pass_errors.push(String::new());
+                }
+                Err(e) => self.custom_pass_errors.push(e),
+            }
+        }
+
+        self.aovs.set_light_pass_count(passes.len());
+        self.light_passes = passes;
+        if let Aov::LightPass(_) = self.view { self.view = Aov::Beauty; }
+        self.accumulated = 0.0;
+    }
+
+    fn push_primitive(&mut self, obj: Object){
+        let material = obj.material();
+        let material_id = self.primitives.iter().position(|p| p.material() == material).unwrap_or(self.primitives.len());
+        self.material_ids.push(material_id as i32);
+        self.primitives.push(obj);
+    }
+
+    pub fn add_volume(&mut self, volume: Volume){
+        self.volumes.push(volume);
+    }
+
+    // Removes every primitive, light and volume, and brings back the environment if the furnace replaced it
+    fn clear(&mut self){
+        self.primitives.clear();
+        self.material_ids.clear();
+        self.lights.clear();
+        self.light_group_of.clear();
+        self.light_groups.clear();
+        self.volumes.clear();
+        if let Some(environment) = self.environment.take() {
+            self.skybox = environment;
+        }
+    }
+
+    // White furnace: one white sphere per BSDF under a uniform white sky, spheres that conserve energy can't be told apart from it
+    pub fn build_furnace(&mut self){
+        self.clear();
+        self.environment = Some(std::mem::replace(&mut self.skybox, Skybox::uniform(vec3(1.0, 1.0, 1.0))));
+
+        let cases = Furnace::cases();
+        let columns = 6;
+        let rows = cases.len().div_ceil(columns);
+        for (i, case) in cases.iter().enumerate() {
+            let (column, row) = ((i % columns) as f32, (i / columns) as f32);
+            let x = (column - (columns - 1) as f32 * 0.5) * 1.3;
+            let y = ((rows - 1) as f32 * 0.5 - row) * 1.3;
+            let mut sphere = Object::Sphere(Sphere::new(vec3(x, y, 4.0), 0.55, vec3(1.0, 1.0, 1.0)));
+            sphere.set_material(case.material);
+            self.add_object(sphere);
+        }
+        self.update_light_passes();
     }
 
     pub fn build(&mut self){
-        self.add_object(Object::Plane(Plane::new(1.0, vec3(0.0, 1.0, 0.0), vec3(0.8, 0.8, 0.8)))); // Ground
+        self.build_example(ExampleScene::Spheres);
+    }
+
+    // Three spheres on a checkerboard between two colored lights, the example adds its own objects in front of them
+    pub fn build_example(&mut self, example: ExampleScene){
+        self.clear();
+
+        // Checkerboard ground, one unit per square
+        let mut checker = TextureGraph::new();
+        let light_squares = checker.constant(vec3(1.0, 1.0, 1.0));
+        let dark_squares = checker.constant(vec3(0.4, 0.4, 0.4));
+        checker.add(Node::Checker { coords: Coordinates::UV, scale: 1.0, a: light_squares, b: dark_squares });
+        let mut ground_textures = MaterialTextures::new();
+        ground_textures.base_color = Some(TextureMap::new(TextureCache::add_procedural(checker)));
+        let mut ground = Object::Plane(Plane::new(1.0, vec3(0.0, 1.0, 0.0), vec3(0.8, 0.8, 0.8)));
+        ground.set_textures(ground_textures);
+        self.add_object(ground);
         // self.add_object(Object::Plane(Plane::new(5.0, vec3(0.0, -1.0, 0.0), vec3(0.8, 0.8, 0.8))));
         // self.add_object(Object::Plane(Plane::new(5.0, vec3(-1.0, 0.0, 0.0), vec3(0.8, 0.8, 0.8)))); 
         // self.add_object(Object::Plane(Plane::new(5.0, vec3(1.0, 0.0, 0.0), vec3(0.8, 0.8, 0.8)))); 
@@ -73,141 +287,783 @@ impl Scene{
         // self.add_object(Object::Plane(Plane::new(10.0, vec3(0.0, 0.0, 1.0), vec3(0.8, 0.8, 0.8)))); 
 
         self.add_object(Object::Sphere(Sphere::new(vec3(-2.5, 0.0, 8.0), 1.0, vec3(0.1, 0.75, 0.75))));
-        self.add_object(Object::Sphere(Sphere::new(vec3(0.0, 0.0, 8.0), 1.0, vec3(0.75, 0.1, 0.75))));
+        let mut glossy_sphere = Object::Sphere(Sphere::new(vec3(0.0, 0.0, 8.0), 1.0, vec3(0.75, 0.1, 0.75)));
+        glossy_sphere.set_material(Material::Glossy { roughness: 0.3 });
+        self.add_object(glossy_sphere);
         self.add_object(Object::Sphere(Sphere::new(vec3(2.5, 0.0, 8.0), 1.0, vec3(0.75, 0.75, 0.1))));
 
+        match example {
+            ExampleScene::Spheres => {
+                let mut glass_sphere = Object::Sphere(Sphere::new(vec3(1.2, -0.4, 6.0), 0.6, vec3(1.0, 1.0, 1.0)));
+                glass_sphere.set_material(Material::Dielectric { ior: 1.5, dispersion: 0.0042 });
+                self.add_object(glass_sphere);
+            }
+            ExampleScene::Volumes => {
+                // Only the path tracer (and MLT on top of it) renders participating media
+                self.add_volume(Volume::homogeneous(0.0, 0.3, 0.0, VolumeBounds::Shape(Object::Plane(Plane::new(0.5, vec3(0.0, 1.0, 0.0), vec3(1.0, 1.0, 1.0)))))); // Ground fog
+                self.add_volume(Volume::heterogeneous(DensityGrid::procedural_smoke(64, 7, vec3(-1.5, -1.0, 9.5), vec3(1.5, 2.0, 12.5)), 0.5, 4.0, 0.3)); // Smoke
+            }
+            ExampleScene::SmokeCache => {
+                // Simulation cache, placed by the transform in the file
+                self.add_volume(Volume::heterogeneous(GridLoader::load("src/volumes/smoke.vdb").unwrap(), 0.5, 4.0, 0.3));
+            }
+            ExampleScene::Subsurface => {
+                let mut wax_sphere = Object::Sphere(Sphere::new(vec3(-1.2, -0.4, 6.0), 0.6, vec3(0.9, 0.75, 0.5)));
+                wax_sphere.set_material(Material::Subsurface { mean_free_path: vec3(0.3, 0.15, 0.08) });
+                self.add_object(wax_sphere);
+            }
+            ExampleScene::Textures => {
+                let mut textured_sphere = Object::Sphere(Sphere::new(vec3(0.0, 1.6, 8.0), 0.6, vec3(1.0, 1.0, 1.0)));
+                let mut textures = MaterialTextures::new();
+                textures.base_color = Some(TextureMap::new(TextureCache::load("src/textures/test.png", true).unwrap()));
+                textured_sphere.set_textures(textures);
+                self.add_object(textured_sphere);
+            }
+            ExampleScene::Procedural => {
+                let mut marble = TextureGraph::new();
+                let white = marble.constant(vec3(0.9, 0.9, 0.85));
+                let vein = marble.constant(vec3(0.2, 0.2, 0.25));
+                marble.add(Node::Marble { coords: Coordinates::Object, scale: 2.0, turbulence: 4.0, a: white, b: vein });
+                let mut marble_textures = MaterialTextures::new();
+                marble_textures.base_color = Some(TextureMap::new(TextureCache::add_procedural(marble)));
+                let mut marble_sphere = Object::Sphere(Sphere::new(vec3(0.0, -0.4, 5.5), 0.6, vec3(1.0, 1.0, 1.0)));
+                marble_sphere.set_textures(marble_textures);
+                self.add_object(marble_sphere);
+            }
+            ExampleScene::Principled => {
+                let mut brushed_metal = Object::Sphere(Sphere::new(vec3(1.2, -0.4, 6.0), 0.6, vec3(0.95, 0.65, 0.35)));
+                brushed_metal.set_material(Material::Principled(Principled { metallic: 1.0, roughness: 0.35, anisotropic: 0.8, ..Principled::new() }));
+                self.add_object(brushed_metal);
+                let mut car_paint = Object::Sphere(Sphere::new(vec3(-1.2, 1.0, 7.0), 0.6, vec3(0.6, 0.05, 0.05)));
+                car_paint.set_material(Material::Principled(Principled { roughness: 0.4, clearcoat: 1.0, ..Principled::new() }));
+                self.add_object(car_paint);
+                let mut frosted_glass = Object::Sphere(Sphere::new(vec3(1.2, 1.0, 7.0), 0.6, vec3(1.0, 1.0, 1.0)));
+                frosted_glass.set_material(Material::Principled(Principled { roughness: 0.2, transmission: 1.0, ..Principled::new() }));
+                self.add_object(frosted_glass);
+            }
+            ExampleScene::Coated => {
+                // Metallic car paint under a tinted lacquer, anodized metal and a soap bubble
+                let mut lacquer = Coat::new(1.5, 0.03);
+                lacquer.absorption = vec3(0.0, 0.3, 0.6);
+                let mut metallic_paint = Object::Sphere(Sphere::new(vec3(-1.2, 1.0, 7.0), 0.6, vec3(0.6, 0.05, 0.05)));
+                metallic_paint.set_material(Material::coated(Material::Principled(Principled { metallic: 0.6, roughness: 0.45, ..Principled::new() }), lacquer));
+                self.add_object(metallic_paint);
+                let mut anodized = Object::Sphere(Sphere::new(vec3(0.0, 1.6, 8.0), 0.6, vec3(0.7, 0.7, 0.75)));
+                anodized.set_material(Material::coated(Material::Glossy { roughness: 0.3 }, Coat::new(1.5, 0.3).with_film(450.0, 1.8)));
+                self.add_object(anodized);
+                let mut soap_bubble = Object::Sphere(Sphere::new(vec3(-1.2, -0.4, 6.0), 0.6, vec3(1.0, 1.0, 1.0)));
+                soap_bubble.set_material(Material::coated(Material::Dielectric { ior: 1.0, dispersion: 0.0 }, Coat::new(1.0, 0.0).with_film(500.0, 1.33)));
+                self.add_object(soap_bubble);
+            }
+        }
 
         self.add_light(Object::Sphere(Sphere::new(vec3(-3.8, 2.0, 8.0), 0.5, vec3(15.0, 3.0, 2.0))));
         self.add_light(Object::Sphere(Sphere::new(vec3(3.8, 2.0, 8.0), 0.5, vec3(2.0, 3.0, 15.0))));
-
+        self.update_light_passes();
     }
 
     pub fn update(&mut self, delta_time: f32, pixels: &mut Vec<Vector3<f32>>, pixels_rgb8: &mut Vec<u32>){
-        if self.camera.update(delta_time, self.aspect) {self.accumulated = 0.0;}
+        // With the denoiser on the view only changes when it runs, other buffers than beauty are shown every frame
+        let beauty = self.view == Aov::Beauty;
+        let denoise = beauty && self.denoiser.enabled;
+        let moved = self.trace_frame(delta_time, pixels, pixels_rgb8, beauty && !denoise);
 
+        if !beauty {
+            let colors = self.aovs.display(self.view, pixels, self.adaptive.sample_counts());
+            pixels_rgb8.par_iter_mut().zip(colors.par_iter()).for_each(|(pixel_rgb8, color)| {
+                *pixel_rgb8 = Math::rgbf32_to_rgb8(*color);
+            });
+        } else if denoise && (moved || self.shown != Aov::Beauty || (self.accumulated as u32 - 1) % self.denoiser.interval.max(1) == 0) {
+            self.denoise(pixels, pixels_rgb8);
+        }
+        self.shown = self.view;
 
-        let base_seed = Math::random_seed_uint();
+        if self.save_aovs_requested {
+            self.save_aovs_requested = false;
+            self.aov_status = match self.save_aovs("aovs.exr", pixels) {
+                Ok(()) => "Saved to aovs.exr".to_string(),
+                Err(e) => format!("Failed to save: {}", e),
+            };
+        }
+    }
+
+    // Writes the accumulated beauty image and all AOVs as layers of an EXR file
+    pub fn save_aovs(&self, path: &str, pixels: &Vec<Vector3<f32>>) -> exr::error::Result<()>{
+        let lighting = self.integrators[self.active_integrator].splits_lighting();
+        self.aovs.save_exr(path, self.width as usize, self.height as usize, pixels, self.adaptive.sample_counts(), &self.light_passes, lighting)
+    }
+
+    // Adds one frame of samples to the accumulated pixels, and shows them in pixels_rgb8 if display is set.
+    // Returns true if the camera moved.
+    fn trace_frame(&mut self, delta_time: f32, pixels: &mut Vec<Vector3<f32>>, pixels_rgb8: &mut Vec<u32>, display: bool) -> bool{
+        // The AOVs are only recorded while something looks at them, the denoiser and reprojection are guided by them.
+        // Once they are needed again the render starts over, so they aren't averaged with frames that left them out.
+        let reproject = self.reprojection.enabled && self.integrators[self.active_integrator].supports_reprojection();
+        let record = self.aovs.enabled || self.view != Aov::Beauty || self.denoiser.enabled || reproject;
+        if record && !self.aovs_recorded { self.accumulated = 0.0; }
+        self.aovs_recorded = record;
+
+        let previous_camera = self.camera;
+        let moved = self.camera.update(delta_time, self.aspect);
+        if moved {
+            // Finished buckets can't be carried over, the render starts over with the first tile
+            if self.reprojection.enabled && self.accumulated > 0.0 && self.tiles.mode == RenderMode::Progressive && self.integrators[self.active_integrator].supports_reprojection() {
+                self.reproject_history(&previous_camera, pixels);
+            } else {
+                self.accumulated = 0.0;
+            }
+        }
+
+        let frame_seed = PixelSampler::frame_seed(self.seed, self.accumulated as u32);
 
         let accum = self.accumulated;
+        if accum == 0.0 {
+            self.adaptive.reset();
+            self.tiles.reset();
+            self.sample_seed = self.seed;
+        }
+
+        // Integrators that work on the whole image take one sample of every pixel each frame
+        let adaptive = self.integrators[self.active_integrator].supports_adaptive_sampling();
+        let bucket = adaptive && self.tiles.mode == RenderMode::Bucket;
+        let tiles = self.tiles.next_tiles(self.width, self.height, bucket);
+        self.traced_pixels = tiles.iter().map(|tile| tile.pixel_count()).sum();
 
         let f_width = self.width as f32;
         let f_height = self.height as f32;
 
-        pixels.par_iter_mut().zip(pixels_rgb8.par_iter_mut()).enumerate().for_each(|(i, (pixel, pixel_rgb8))| {
-            let mut seed =  (i as u32).wrapping_add(base_seed).wrapping_mul(17).wrapping_add(1);
-            seed = Math::wang_hash(seed);
+        self.splats.clear();
+
+        // The integrator is taken out for a moment, since it needs to look at the scene it is part of
+        let mut integrators = std::mem::take(&mut self.integrators);
+        integrators[self.active_integrator].begin_frame(self, accum as u32, frame_seed);
+        self.integrators = integrators;
+
+        let integrator = &self.integrators[self.active_integrator];
+
+        // Even and odd samples are kept apart for the error estimate of adaptive sampling
+        let trace_pixel = |i: usize| {
+            let count = if bucket { self.tiles.bucket_spp } else if adaptive { self.adaptive.samples_this_frame(i) } else { 1 };
+            let first = self.adaptive.sample_count(i);
+
+            let mut halves = [Vector3::zero(); 2];
+            let mut aov = AovSample::zero();
+            let mut paths = LightPaths::new(&self.light_passes);
+            for index in first..first + count {
+                let mut sampler = PixelSampler::new(self.sampler, self.sample_seed, i as u32, self.width, index);
+
+                let x = (i as f32 % f_width) + sampler.next_f32() - 0.5;
+                let y = (i as f32 / f_width) + sampler.next_f32() - 0.5;
 
-            let x = (i as f32 % f_width) + Math::random_f32(&mut seed) - 0.5;
-            let y = (i as f32 / f_width) + Math::random_f32(&mut seed) - 0.5;
+                let mut primary_ray = self.camera.calculate_primary_ray(x / f_width, y / f_height);
 
-            let mut primary_ray = self.camera.calculate_primary_ray(x / f_width, y / f_height);
+                if record {
+                    let mut sample = AovSample::zero();
+                    paths.start();
+                    halves[index as usize % 2] += integrator.pixel_with_aovs(self, i, &mut primary_ray, &mut sampler, &mut sample, &mut paths);
+                    aov.add(sample);
+                } else {
+                    halves[index as usize % 2] += integrator.pixel(self, i, &mut primary_ray, &mut sampler);
+                }
+            }
+            (halves, aov, paths.values, count)
+        };
+
+        // Every thread traces whole tiles, pixels outside of them take no samples this frame.
+        // Integrators that only splat count one sample per pixel without tracing it, unless the AOVs are needed.
+        let traced: Vec<Vec<(usize, ([Vector3<f32>; 2], AovSample, Vec<Vector3<f32>>, u32))>> = if integrator.traces_pixels() || record {
+            tiles.par_iter().map(|tile| tile.pixels(self.width).map(|i| (i, trace_pixel(i))).collect()).collect()
+        } else {
+            tiles.iter().map(|tile| tile.pixels(self.width).map(|i| (i, ([Vector3::zero(); 2], AovSample::zero(), Vec::new(), 1))).collect()).collect()
+        };
+        let mut samples = vec![([Vector3::zero(); 2], AovSample::zero(), Vec::new(), 0); pixels.len()];
+        for (i, sample) in traced.into_iter().flatten() {
+            samples[i] = sample;
+        }
+
+        // Splats are only complete once every pixel has been traced, they belong to the first sample of the frame
+        pixels.par_iter_mut().zip(pixels_rgb8.par_iter_mut()).enumerate().for_each(|(i, (pixel, pixel_rgb8))| {
+            let (halves, _, _, count) = samples[i];
+            if count == 0 { return; }
 
-            let color = self.ray_color(&mut primary_ray, &mut seed);
-            *pixel = (vec3(color.z, color.y, color.x) + *pixel * accum) / (accum + 1.0);
-            *pixel_rgb8 = Math::rgbf32_to_rgb8(*pixel);
+            let previous = self.adaptive.sample_count(i) as f32;
+            let color = halves[0] + halves[1] + self.splats.get(i);
+            *pixel = (vec3(color.z, color.y, color.x) + *pixel * previous) / (previous + count as f32);
+            if display { *pixel_rgb8 = Math::rgbf32_to_rgb8(*pixel); }
         });
+
+        for (i, (mut halves, aov, passes, count)) in samples.into_iter().enumerate() {
+            if count == 0 { continue; }
+            let previous = self.adaptive.sample_count(i);
+            if record { self.aovs.add(i, aov, &passes, count, previous); }
+            halves[previous as usize % 2] += self.splats.get(i);
+            self.adaptive.add(i, halves, count);
+        }
+        self.adaptive.update_errors();
+
         self.accumulated += 1.0;
+        moved
+    }
+
+    // Carries the accumulated pixels over to the moved camera, pixels that now see a surface the previous camera didn't start over
+    fn reproject_history(&mut self, previous_camera: &Camera, pixels: &mut Vec<Vector3<f32>>){
+        let (width, height) = (self.width as usize, self.height as usize);
+        let surfaces: Vec<AovSample> = (0..pixels.len()).into_par_iter().map(|i| {
+            let ray = self.camera.calculate_primary_ray((i % width) as f32 / width as f32, (i / width) as f32 / height as f32);
+            let mut sampler = PixelSampler::new(self.sampler, self.sample_seed, i as u32, self.width, 0);
+            self.primary_aovs(&ray, &mut sampler)
+        }).collect();
+
+        let taps = self.reprojection.find_history(previous_camera, &self.camera, &surfaces, &self.aovs, width, height);
+        let history: Vec<Vector3<f32>> = taps.par_iter().map(|t| Reprojection::resample(t, pixels).unwrap_or(Vector3::zero())).collect();
+
+        *pixels = history;
+        self.adaptive.reproject(&taps, self.reprojection.max_history);
+        self.aovs.reproject(&taps, &surfaces);
+        self.sample_seed = PixelSampler::frame_seed(self.sample_seed, self.accumulated as u32);
     }
 
-    fn intersect_ray(&self, ray: &mut Ray) {
-        for prim in &self.primitives{
-            prim.intersect(ray);
+    // Renders without a window until every pixel is below the adaptive sampling threshold, every bucket is done,
+    // or for at most max_frames frames. Returns the number of frames rendered.
+    pub fn render(&mut self, pixels: &mut Vec<Vector3<f32>>, pixels_rgb8: &mut Vec<u32>, max_frames: u32) -> u32{
+        self.accumulated = 0.0;
+        let region = self.tiles.region(self.width, self.height);
+        while (self.accumulated as u32) < max_frames {
+            self.trace_frame(0.0, pixels, pixels_rgb8, true);
+            if self.adaptive.converged(&region) || self.tiles.finished(self.width, self.height) { break; }
         }
+        if self.denoiser.enabled {
+            self.denoise(pixels, pixels_rgb8);
+        }
+        self.accumulated as u32
+    }
+
+    // Shows the denoised pixels in pixels_rgb8, the accumulated pixels stay as they are
+    fn denoise(&self, pixels: &Vec<Vector3<f32>>, pixels_rgb8: &mut Vec<u32>){
+        let denoised = self.denoiser.denoise(pixels, &self.aovs, self.width as usize, self.height as usize);
+        pixels_rgb8.par_iter_mut().zip(denoised.par_iter()).for_each(|(pixel_rgb8, color)| {
+            *pixel_rgb8 = Math::rgbf32_to_rgb8(*color);
+        });
     }
 
-    fn ray_color(&self, ray: &mut Ray, seed: &mut u32) -> Vector3<f32>{
-        let mut depth = 0;
+    // AOVs of the first surface along a primary ray, for integrators that don't report their first hit
+    pub(crate) fn primary_aovs(&self, ray: &Ray, sampler: &mut dyn Sampler) -> AovSample {
+        let mut ray = *ray;
+        self.intersect_ray(&mut ray, sampler);
+        self.hit_aovs(&ray)
+    }
 
-        let mut T = vec3(1.0,1.0,1.0);
-        let mut E = vec3(0.0, 0.0, 0.0);
+    // AOVs of the surface a primary ray hit, the integrator adds the lighting
+    pub(crate) fn hit_aovs(&self, ray: &Ray) -> AovSample {
+        if ray.obj_idx < 0 {
+            return AovSample { albedo: vec3(1.0, 1.0, 1.0), ids: Some((-1, -1)), ..AovSample::zero() };
+        }
 
-        while true {
-            self.intersect_ray(ray);
+        let primitive = self.primitive(ray.obj_idx);
+        let I = ray.origin + ray.dir * ray.dist;
+        let wo = -ray.dir;
+        let mut normal = primitive.get_normal(I).normalize();
+        if normal.dot(wo) < 0.0 { normal = -normal; }
+
+        AovSample {
+            // Emitters keep their color in the lighting
+            albedo: if primitive.is_light() { vec3(1.0, 1.0, 1.0) } else { primitive.get_albedo(I) },
+            normal: primitive.get_shading_normal(I, normal, wo),
+            depth: ray.dist,
+            position: I,
+            ids: Some((ray.obj_idx, self.material_ids[ray.obj_idx as usize])),
+            lighting: LightingAovs::zero(),
+        }
+    }
+
+    // Records the AOVs even when nothing else needs them, so they can be saved
+    pub fn set_record_aovs(&mut self, record: bool){
+        self.aovs.enabled = record;
+    }
+
+    pub fn set_denoiser(&mut self, enabled: bool){
+        self.denoiser.enabled = enabled;
+    }
+
+    // Turns on adaptive sampling, pixels stop once their relative error is below the threshold
+    pub fn set_adaptive_threshold(&mut self, threshold: f32){
+        self.adaptive.enabled = true;
+        self.adaptive.threshold = threshold;
+    }
+
+    // Selects the integrator with the given name, returns false if there is none
+    pub fn set_integrator(&mut self, name: &str) -> bool{
+        match self.integrators.iter().position(|i| i.name() == name) {
+            Some(idx) => {
+                self.active_integrator = idx;
+                self.accumulated = 0.0;
+                true
+            }
+            None => false
+        }
+    }
 
-            if ray.obj_idx < 0 { 
-                E += T.mul_element_wise(self.sample_skybox(ray.dir));
-                break;
+    // Selects the sampler with the given name, returns false if there is none
+    pub fn set_sampler(&mut self, name: &str) -> bool{
+        match SamplerKind::ALL.iter().find(|kind| kind.name() == name) {
+            Some(kind) => {
+                self.sampler = *kind;
+                self.accumulated = 0.0;
+                true
             }
+            None => false
+        }
+    }
+
+    // Renders in tiles of tile_size pixels handed out in the given order. In bucket mode every tile takes bucket_spp samples before the next one starts.
+    pub fn set_tiles(&mut self, mode: RenderMode, order: TileOrder, tile_size: u32, bucket_spp: u32){
+        self.tiles.mode = mode;
+        self.tiles.order = order;
+        self.tiles.tile_size = tile_size;
+        self.tiles.bucket_spp = bucket_spp;
+        self.accumulated = 0.0;
+    }
+
+    // Renders only the pixels from (x0, y0) up to but not including (x1, y1), or the whole image if None
+    pub fn set_crop(&mut self, crop: Option<Tile>){
+        self.tiles.crop = crop;
+        self.accumulated = 0.0;
+    }
+
+    // Restarts the render with another seed
+    pub fn set_seed(&mut self, seed: u32){
+        self.seed = seed;
+        self.accumulated = 0.0;
+    }
+
+    // Integrator and sampler selection and settings, changing any of them restarts accumulation
+    pub fn ui(&mut self, ui: &mut egui::Ui){
+        let mut active = self.active_integrator;
+        egui::ComboBox::from_label("Integrator")
+            .selected_text(self.integrators[active].name())
+            .show_ui(ui, |ui| {
+                for (i, integrator) in self.integrators.iter().enumerate() {
+                    ui.selectable_value(&mut active, i, integrator.name());
+                }
+            });
+
+        if active != self.active_integrator {
+            self.active_integrator = active;
+            self.accumulated = 0.0;
+        }
 
-            // Intersection data
-            let primitive = self.primitives[ray.obj_idx as usize];
-            let I = ray.origin + ray.dir * ray.dist;
-            let albedo = primitive.get_albedo(I);
+        let mut sampler = self.sampler;
+        egui::ComboBox::from_label("Sampler")
+            .selected_text(sampler.name())
+            .show_ui(ui, |ui| {
+                for kind in SamplerKind::ALL {
+                    ui.selectable_value(&mut sampler, kind, kind.name());
+                }
+            });
+
+        if sampler != self.sampler {
+            self.sampler = sampler;
+            self.accumulated = 0.0;
+        }
+
+        let mut seed = self.seed;
+        if ui.add(egui::DragValue::new(&mut seed).prefix("Seed ")).changed() {
+            self.set_seed(seed);
+        }
+
+        if self.integrators[self.active_integrator].ui(ui) {
+            self.accumulated = 0.0;
+        }
 
-            // If intersecting with light, simply return light color
-            if primitive.is_light() && depth == 0{
-                if depth == 0{
-                    E += albedo;
-                    break;
+        // Only integrators that split up the lighting fill its passes and the light passes, the others don't offer them
+        let lighting = self.integrators[self.active_integrator].splits_lighting();
+        let mut view = if self.view.is_lighting() && !lighting { Aov::Beauty } else { self.view };
+        let view_name = match view {
+            Aov::LightPass(idx) => self.light_passes[idx].name.as_str(),
+            _ => view.name()
+        };
+        egui::ComboBox::from_label("Buffer")
+            .selected_text(view_name)
+            .show_ui(ui, |ui| {
+                for aov in Aov::ALL.into_iter().filter(|aov| lighting || !aov.is_lighting()) {
+                    ui.selectable_value(&mut view, aov, aov.name());
                 }
-                else {
-                    break;
+                if lighting {
+                    for (i, pass) in self.light_passes.iter().enumerate() {
+                        ui.selectable_value(&mut view, Aov::LightPass(i), pass.name.as_str());
+                    }
                 }
+            });
+        self.view = view;
+        ui.checkbox(&mut self.aovs.enabled, "Record AOVs");
+        if ui.add_enabled(self.aovs_recorded, egui::Button::new("Save EXR layers")).clicked() {
+            self.save_aovs_requested = true;
+        }
+        if !self.aov_status.is_empty() {
+            ui.label(&self.aov_status);
+        }
+
+        egui::CollapsingHeader::new("Light passes").show(ui, |ui| {
+            if !lighting {
+                ui.label("The integrator doesn't split up the light, the passes need one of the path tracers");
+            }
+            ui.label(format!("Light groups: {}, and the environment", self.light_groups.join(", ")));
+            let mut removed = None;
+            for (i, ((name, expression), error)) in self.custom_passes.iter().zip(&self.custom_pass_errors).enumerate() {
+                ui.horizontal(|ui| {
+                    ui.label(format!("{}: {}", name, expression));
+                    if ui.button("Remove").clicked() { removed = Some(i); }
+                });
+                if !error.is_empty() {
+                    ui.label(format!("Left out: {}", error));
+                }
+            }
+            if let Some(i) = removed {
+                self.remove_light_pass(i);
             }
 
-            let normal = primitive.get_normal(I);
-            let R = Math::random_uniform_hemisphere_vectorf32(seed, normal);
+            ui.horizontal(|ui| {
+                ui.add(egui::TextEdit::singleline(&mut self.new_pass.0).hint_text("Name").desired_width(80.0));
+                ui.add(egui::TextEdit::singleline(&mut self.new_pass.1).hint_text("C<RD>L").desired_width(120.0));
+                if ui.button("Add").clicked() {
+                    let (name, expression) = self.new_pass.clone();
+                    self.pass_error = match self.add_light_pass(&name, &expression) {
+                        Ok(()) => String::new(),
+                        Err(e) => e,
+                    };
+                }
+            });
+            if !self.pass_error.is_empty() {
+                ui.label(format!("Invalid expression: {}", self.pass_error));
+            }
+        });
 
-            // Light data
-            let light = self.sample_random_light(seed);
-            let mut L = light.get_random_position(normal, seed) - I;
-            let light_normal = light.get_normal(I);
-            let light_pdf = light.get_light_pdf();
-            let dist_to_light = L.magnitude();
-            L = L.normalize();
+        egui::CollapsingHeader::new("Adaptive sampling").show(ui, |ui| {
+            let adaptive = &mut self.adaptive;
+            ui.checkbox(&mut adaptive.enabled, "Enabled");
+            ui.add(egui::Slider::new(&mut adaptive.threshold, 0.001..=0.2).logarithmic(true).text("Relative error"));
+            ui.add(egui::Slider::new(&mut adaptive.min_samples, 2..=256).text("Min samples"));
+            ui.add(egui::Slider::new(&mut adaptive.max_samples_per_frame, 1..=16).text("Max samples per frame"));
+            if !self.integrators[self.active_integrator].supports_adaptive_sampling() {
+                ui.label("The integrator samples every pixel each frame");
+            }
+            ui.label(format!("Converged: {:.1}%", adaptive.converged_fraction(&self.tiles.region(self.width, self.height)) * 100.0));
+        });
 
+        egui::CollapsingHeader::new("Tiles").show(ui, |ui| {
+            let (width, height) = (self.width, self.height);
+            let tiles = &mut self.tiles;
+            let (mode, order, tile_size, bucket_spp, crop) = (tiles.mode, tiles.order, tiles.tile_size, tiles.bucket_spp, tiles.crop);
+            egui::ComboBox::from_label("Mode")
+                .selected_text(tiles.mode.name())
+                .show_ui(ui, |ui| {
+                    for mode in RenderMode::ALL {
+                        ui.selectable_value(&mut tiles.mode, mode, mode.name());
+                    }
+                });
+            egui::ComboBox::from_label("Order")
+                .selected_text(tiles.order.name())
+                .show_ui(ui, |ui| {
+                    for order in TileOrder::ALL {
+                        ui.selectable_value(&mut tiles.order, order, order.name());
+                    }
+                });
+            ui.add(egui::Slider::new(&mut tiles.tile_size, 8..=256).logarithmic(true).text("Tile size"));
+            ui.add(egui::Slider::new(&mut tiles.bucket_spp, 1..=4096).logarithmic(true).text("Bucket samples"));
 
-            let BRDF = albedo * f32::consts::FRAC_1_PI;
+            let mut cropped = tiles.crop.is_some();
+            ui.checkbox(&mut cropped, "Crop");
+            if cropped {
+                let mut region = tiles.crop.unwrap_or(Tile::new(0, 0, width, height));
+                ui.horizontal(|ui| {
+                    ui.add(egui::DragValue::new(&mut region.x0).range(0..=width).prefix("x "));
+                    ui.add(egui::DragValue::new(&mut region.y0).range(0..=height).prefix("y "));
+                    ui.add(egui::DragValue::new(&mut region.x1).range(0..=width).prefix("to x "));
+                    ui.add(egui::DragValue::new(&mut region.y1).range(0..=height).prefix("to y "));
+                });
+                tiles.crop = Some(region);
+            } else {
+                tiles.crop = None;
+            }
 
-            // Check light direction
-            let cos_o = light_normal.dot(-L);
-            let cos_i = normal.dot(L);
+            if tiles.mode == RenderMode::Bucket {
+                let (done, count) = tiles.progress(width, height);
+                ui.label(format!("Tiles done: {} of {}", done, count));
+                if !self.integrators[self.active_integrator].supports_adaptive_sampling() {
+                    ui.label("The integrator renders progressively");
+                }
+            }
+            if (mode, order, tile_size, bucket_spp) != (tiles.mode, tiles.order, tiles.tile_size, tiles.bucket_spp) || crop != tiles.crop {
+                self.accumulated = 0.0;
+            }
+        });
 
-            if cos_o > 0.0 && cos_i > 0.0 {
-                // Shadows
-                let mut shadow_ray = Ray::new(I + L * EPSILON, L, dist_to_light - EPSILON * 2.0);
-                self.intersect_ray(&mut shadow_ray);
+        egui::CollapsingHeader::new("Denoiser").show(ui, |ui| {
+            let denoiser = &mut self.denoiser;
+            ui.checkbox(&mut denoiser.enabled, "Enabled");
+            ui.add(egui::Slider::new(&mut denoiser.interval, 1..=64).text("Every n frames"));
+            ui.add(egui::Slider::new(&mut denoiser.iterations, 1..=8).text("Iterations"));
+            ui.add(egui::Slider::new(&mut denoiser.sigma_color, 0.01..=4.0).logarithmic(true).text("Color"));
+            ui.add(egui::Slider::new(&mut denoiser.sigma_normal, 1.0..=256.0).logarithmic(true).text("Normal"));
+            ui.add(egui::Slider::new(&mut denoiser.sigma_depth, 0.001..=1.0).logarithmic(true).text("Depth"));
+        });
 
-                // NEE
-                if shadow_ray.obj_idx == -1 {   
-                    let light_color = light.get_albedo(I);
-                    let light_area = light.get_area();
-                    let solid_angle = (light_area * cos_o) / (dist_to_light * dist_to_light);
+        egui::CollapsingHeader::new("Temporal reprojection").show(ui, |ui| {
+            let reprojection = &mut self.reprojection;
+            ui.checkbox(&mut reprojection.enabled, "Enabled");
+            ui.add(egui::Slider::new(&mut reprojection.max_history, 1..=64).text("Max history"));
+            ui.add(egui::Slider::new(&mut reprojection.depth_tolerance, 0.01..=0.5).logarithmic(true).text("Depth tolerance"));
+            ui.add(egui::Slider::new(&mut reprojection.normal_tolerance, 0.0..=1.0).text("Normal tolerance"));
+            if !self.integrators[self.active_integrator].supports_reprojection() {
+                ui.label("The integrator restarts when the camera moves");
+            }
+        });
 
-                    E += T.mul_element_wise(light_color.mul_element_wise(BRDF * solid_angle * cos_i * self.lights.len() as f32 * light_pdf)) ;
+        egui::CollapsingHeader::new("Example scenes").show(ui, |ui| {
+            for example in ExampleScene::ALL {
+                if ui.button(example.name()).clicked() {
+                    self.build_example(example);
                 }
             }
-   
-            // Russian Roulette
-            let p = Scene::ray_survival_probability(T);
-            if p < Math::random_f32(seed) { break; }
+        });
 
-            // Indirect bounces
-            *ray = Ray::new(I + R * EPSILON, R, f32::MAX);
+        egui::CollapsingHeader::new("White furnace").show(ui, |ui| {
+            if ui.button("Load furnace scene").clicked() {
+                self.build_furnace();
+            }
+            if ui.button("Verify BSDFs").clicked() {
+                self.furnace_results = Furnace::verify(0.02);
+            }
+            for result in &self.furnace_results {
+                let albedo: Vec<String> = result.albedo.iter().zip(FURNACE_COSINES).map(|(a, cos)| format!("{:.3} at {:.0}°", a, cos.acos().to_degrees())).collect();
+                ui.label(format!("{} {}: {}", if result.passed { "ok" } else { "FAILED" }, result.name, albedo.join(", ")));
+            }
+        });
+    }
 
-            T = T.mul_element_wise(BRDF.mul_element_wise(normal.dot(R) * 2.0 * f32::consts::PI)) / p;
+    // Finds the closest hit, surfaces with an opacity below one are passed through stochastically with a number from the sampler
+    pub(crate) fn intersect_ray(&self, ray: &mut Ray, sampler: &mut dyn Sampler) {
+        let mut segment = *ray;
+        let mut offset = 0.0;
 
-            depth += 1;
+        loop {
+            for prim in &self.primitives{
+                prim.intersect(&mut segment);
+            }
+            if segment.obj_idx < 0 { return; }
+
+            let I = segment.origin + segment.dir * segment.dist;
+            let opacity = self.primitives[segment.obj_idx as usize].opacity(I);
+            if opacity >= 1.0 || (opacity > 0.0 && sampler.next_f32() < opacity) {
+                ray.dist = offset + segment.dist;
+                ray.obj_idx = segment.obj_idx;
+                return;
+            }
+
+            // Continue just behind the skipped surface
+            offset += segment.dist + EPSILON;
+            if offset >= ray.dist { return; }
+            segment = Ray::new(ray.origin + ray.dir * offset, ray.dir, ray.dist - offset);
         }
+    }
+
+    pub(crate) fn primitive(&self, idx: i32) -> Object {
+        self.primitives[idx as usize]
+    }
 
-        return E;
+    pub(crate) fn skybox(&self) -> &Skybox {
+        &self.skybox
     }
 
-    fn ray_survival_probability(color: Vector3<f32>) -> f32{
+    pub(crate) fn camera(&self) -> &Camera {
+        &self.camera
+    }
+
+    pub(crate) fn resolution(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    pub(crate) fn lights(&self) -> &[i32] {
+        &self.lights
+    }
+
+    // Number of pixels one path started at a traced pixel stands in for. Paths that splat onto any pixel, like the light
+    // subpaths of BDPT, are multiplied with it so the pixels get their full contribution when only some pixels are traced.
+    pub(crate) fn splat_scale(&self) -> f32 {
+        (self.width * self.height) as f32 / self.traced_pixels.max(1) as f32
+    }
+
+    // Adds color to the pixel at screen coordinates (x, y) in [0, 1) for the current frame
+    pub(crate) fn splat(&self, x: f32, y: f32, color: Vector3<f32>) {
+        let px = ((x * self.width as f32) as u32).min(self.width - 1);
+        let py = ((y * self.height as f32) as u32).min(self.height - 1);
+        self.splats.add((px + py * self.width) as usize, color);
+    }
+
+    // Picks one light (or the skybox) and returns its MIS weighted contribution at I
+    pub(crate) fn sample_direct_light(&self, I: Vector3<f32>, normal: Vector3<f32>, wo: Vector3<f32>, albedo: Vector3<f32>, material: Material, sampler: &mut dyn Sampler) -> Vector3<f32>{
+        self.sample_light_with(I, sampler, |L| {
+            // Transmissive materials also receive light from behind the surface
+            let cos_i = if material.is_transmissive() { f32::abs(normal.dot(L)) } else { normal.dot(L) };
+            if cos_i <= 0.0 { return None; }
+            Some((material.eval(albedo, normal, wo, L) * cos_i, material.pdf(normal, wo, L)))
+        })
+    }
+
+    // Same as sample_direct_light, split into the light scattered by the diffuse and by the specular lobes, None if no light arrives
+    pub(crate) fn sample_direct_light_lobes(&self, I: Vector3<f32>, normal: Vector3<f32>, wo: Vector3<f32>, albedo: Vector3<f32>, material: Material, sampler: &mut dyn Sampler) -> Option<DirectLight>{
+        let ((diffuse, specular, transmitted), light, light_idx) = self.sample_incoming_light(I, sampler, |L| {
+            let cos_i = if material.is_transmissive() { f32::abs(normal.dot(L)) } else { normal.dot(L) };
+            if cos_i <= 0.0 { return None; }
+            let f = material.eval(albedo, normal, wo, L) * cos_i;
+            let diffuse = material.eval_diffuse(albedo, normal, wo, L) * cos_i;
+            Some(((diffuse, f - diffuse, normal.dot(L) < 0.0), material.pdf(normal, wo, L)))
+        })?;
+        Some(DirectLight {
+            diffuse: light.mul_element_wise(diffuse),
+            specular: light.mul_element_wise(specular),
+            transmitted: transmitted,
+            light: self.light_event(light_idx),
+        })
+    }
+
+    // Same as sample_direct_light, for a scattering event inside a volume. Also returns the light it came from.
+    pub(crate) fn sample_direct_light_medium(&self, I: Vector3<f32>, wo: Vector3<f32>, phase: HenyeyGreenstein, sampler: &mut dyn Sampler) -> Option<(Vector3<f32>, Event)>{
+        let (f, light, light_idx) = self.sample_incoming_light(I, sampler, |L| {
+            let p = phase.eval(wo, L);
+            Some((vec3(p, p, p), p))
+        })?;
+        Some((light.mul_element_wise(f), self.light_event(light_idx)))
+    }
+
+    // Shared part of the direct light estimators, scatter returns the BSDF (or phase function) times cosine and its pdf for a direction
+    fn sample_light_with(&self, I: Vector3<f32>, sampler: &mut dyn Sampler, scatter: impl Fn(Vector3<f32>) -> Option<(Vector3<f32>, f32)>) -> Vector3<f32>{
+        match self.sample_incoming_light(I, sampler, scatter) {
+            Some((f, light, _)) => light.mul_element_wise(f),
+            None => Vector3::zero()
+        }
+    }
+
+    // Samples a light like sample_light_with, but leaves multiplying with what scatter returned to the caller.
+    // Returns that, the MIS weighted light arriving from the sampled direction divided by its pdf, and the index of the light.
+    fn sample_incoming_light<T>(&self, I: Vector3<f32>, sampler: &mut dyn Sampler, scatter: impl Fn(Vector3<f32>) -> Option<(T, f32)>) -> Option<(T, Vector3<f32>, usize)>{
+        let (light_idx, L, dist_to_light, Le, light_pdf) = self.sample_light(I, sampler)?;
+        let (f, scatter_pdf) = scatter(L)?;
+
+        let visibility = self.visibility(I, L, dist_to_light, sampler);
+        if visibility <= 0.0 { return None; }
+
+        let weight = Math::power_heuristic(light_pdf, scatter_pdf);
+        Some((f, Le * (visibility * weight / light_pdf), light_idx))
+    }
+
+    // Picks one light (or the skybox) and a direction from I towards it. Returns the direction, the distance to the light,
+    // its radiance, the solid angle pdf including the light selection and the event of a path ending on the light
+    pub(crate) fn sample_light_direction(&self, I: Vector3<f32>, sampler: &mut dyn Sampler) -> Option<(Vector3<f32>, f32, Vector3<f32>, f32, Event)>{
+        let (light_idx, L, dist_to_light, Le, pdf) = self.sample_light(I, sampler)?;
+        Some((L, dist_to_light, Le, pdf, self.light_event(light_idx)))
+    }
+
+    // Same as sample_light_direction, with the index of the light in lights instead of its event, the skybox is the last one
+    fn sample_light(&self, I: Vector3<f32>, sampler: &mut dyn Sampler) -> Option<(usize, Vector3<f32>, f32, Vector3<f32>, f32)>{
+        let light_count = self.lights.len() + 1;
+        let light_idx = ((sampler.next_f32() * light_count as f32) as usize).min(light_count - 1);
+
+        // The last index stands for the skybox
+        let (L, dist_to_light, Le, pdf) = if light_idx == self.lights.len() {
+            let (dir, color, pdf) = self.skybox.sample(sampler);
+            (dir, f32::MAX, color, pdf)
+        } else {
+            let light = self.primitives[self.lights[light_idx] as usize];
+            let sample = light.sample_light(I, sampler)?;
+            (sample.dir, sample.dist, light.get_albedo(I + sample.dir * sample.dist), sample.pdf)
+        };
+        if pdf <= 0.0 { return None; }
+
+        Some((light_idx, L, dist_to_light, Le, self.light_select_pdf() * pdf))
+    }
+
+    // Event ending a path on light light_idx of lights, or on the skybox
+    fn light_event(&self, light_idx: usize) -> Event{
+        match self.light_group_of.get(light_idx) {
+            Some(&group) => Event::Light(group),
+            None => Event::Background
+        }
+    }
+
+    // Event ending a path on the emitter with primitive index obj_idx
+    pub(crate) fn emitter_event(&self, obj_idx: i32) -> Event{
+        self.light_event(self.lights.iter().position(|&l| l == obj_idx).unwrap_or(self.lights.len()))
+    }
+
+    // Fraction of the light from dist away in direction L that reaches I, zero if something is in between
+    pub(crate) fn visibility(&self, I: Vector3<f32>, L: Vector3<f32>, dist: f32, sampler: &mut dyn Sampler) -> f32{
+        let mut shadow_ray = Ray::new(I + L * EPSILON, L, dist - EPSILON * 2.0);
+        self.intersect_ray(&mut shadow_ray, sampler);
+        if shadow_ray.obj_idx != -1 { return 0.0; }
+
+        self.transmittance(I, L, dist, sampler)
+    }
+
+    // Samples where the ray (already intersected with the scene) first scatters in a volume, None if it reaches ray.dist
+    pub(crate) fn sample_medium(&self, ray: &Ray, sampler: &mut dyn Sampler) -> Option<MediumInteraction>{
+        // Every volume tracks its own first collision, the closest one is a collision of all of them combined
+        let mut closest: Option<MediumInteraction> = None;
+        for volume in &self.volumes {
+            let t_max = closest.map_or(ray.dist, |c| c.dist);
+            if let Some(interaction) = volume.sample_interaction(ray.origin, ray.dir, t_max, sampler) {
+                closest = Some(interaction);
+            }
+        }
+        closest
+    }
+
+    // Fraction of light passing through the volumes between origin and origin + dir * dist
+    pub(crate) fn transmittance(&self, origin: Vector3<f32>, dir: Vector3<f32>, dist: f32, sampler: &mut dyn Sampler) -> f32{
+        let mut transmittance = 1.0;
+        for volume in &self.volumes {
+            transmittance *= volume.transmittance(origin, dir, dist, sampler);
+            if transmittance <= 0.0 { break; }
+        }
+        transmittance
+    }
+
+    // Probability of picking any single light, the skybox counts as one of them
+    pub(crate) fn light_select_pdf(&self) -> f32{
+        1.0 / (self.lights.len() + 1) as f32
+    }
+
+    pub(crate) fn ray_survival_probability(color: Vector3<f32>) -> f32{
         clamp(f32::max(color.x, f32::max(color.y, color.z)), 0.0, 1.0)
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    fn sample_random_light(&self, seed: &mut u32) -> Object {
-        let random_index = Math::random_range_u32(seed, 0,self.lights.len() as u32 - 1);
-        self.primitives[self.lights[random_index as usize] as usize]
+    // Renders a few frames on a pool with the given number of threads, the environment is any small image
+    fn render(integrator: &str, threads: usize) -> Vec<Vector3<f32>> {
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+        pool.install(|| {
+            let (width, height) = (32, 24);
+            let mut scene = Scene::new(width, height, "src/textures/grem.jpg");
+            scene.build();
+            scene.set_seed(7);
+            assert!(scene.set_integrator(integrator));
+            let mut pixels = vec![Vector3::zero(); (width * height) as usize];
+            let mut pixels_rgb8 = vec![0; (width * height) as usize];
+            for _ in 0..3 {
+                scene.update(0.0, &mut pixels, &mut pixels_rgb8);
+            }
+            pixels
+        })
     }
 
-    fn sample_skybox(&self, dir :Vector3<f32>) -> Vector3<f32>{
-        let phi = f32::atan2(dir.z, dir.x);
-        let u = (self.sky_width * (if phi > 0.0 { phi } else {(phi + 2.0 * f32::consts::PI)}) * (f32::consts::FRAC_1_PI / 2.0) - 0.5) as u32;
-        let v = (self.sky_height * f32::acos(dir.y) * f32::consts::FRAC_1_PI - 0.5) as u32;
-        //let skyIdx = (u + v * self.skybox.width()) % (self.skybox.width() * self.skybox.height());
-        let color = self.skybox[(u, v)];
-        vec3(color[0],color[1], color[2])
+    #[test]
+    fn same_seed_renders_the_same_image_on_any_number_of_threads() {
+        for integrator in ["Path tracer", "Bidirectional path tracer", "Metropolis light transport"] {
+            assert!(render(integrator, 1) == render(integrator, 3), "{} depends on the thread count", integrator);
+        }
     }
-}
\ No newline at end of file
+}
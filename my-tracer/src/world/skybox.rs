@@ -0,0 +1,117 @@
+use core::f32;
+use cgmath::*;
+use image::{ImageBuffer, Rgb};
+
+use super::{math::Math, sampler::Sampler, sampling::Distribution2D};
+
+pub struct Skybox {
+    texture: ImageBuffer<Rgb<f32>, Vec<f32>>,
+    width: f32,
+    height: f32,
+    distribution: Distribution2D,
+}
+
+impl Skybox {
+    pub fn new(path: &str) -> Skybox {
+        let mut texture = image::open(&path).unwrap().into_rgb32f();
+
+        let width = texture.width() as f32;
+        let height = texture.height() as f32;
+
+        // Adjusts HDR values
+        for x in 0..texture.width(){
+            for y in 0..texture.height(){
+                for i in 0..3  {
+                    texture[(x,y)][i] = f32::sqrt(texture[(x,y)][i]);
+                }
+            }
+        }
+
+        // Importance of each texel, weighted by sin(theta) to account for the stretching near the poles
+        let w = texture.width() as usize;
+        let h = texture.height() as usize;
+        let mut func = vec![0.0; w * h];
+        for y in 0..h {
+            let sin_theta = f32::sin(f32::consts::PI * (y as f32 + 0.5) / height);
+            for x in 0..w {
+                let c = texture[(x as u32, y as u32)];
+                func[y * w + x] = Math::luminance(vec3(c[0], c[1], c[2])) * sin_theta;
+            }
+        }
+
+        Skybox {
+            texture: texture,
+            width: width,
+            height: height,
+            distribution: Distribution2D::new(&func, w, h),
+        }
+    }
+
+    // Sky with the same color in every direction, like the white furnace
+    pub fn uniform(color: Vector3<f32>) -> Skybox {
+        // Enough rows for the importance sampling to follow sin(theta)
+        let (w, h) = (4, 64);
+        let texture = ImageBuffer::from_pixel(w, h, Rgb([color.x, color.y, color.z]));
+
+        let mut func = vec![0.0; (w * h) as usize];
+        for y in 0..h as usize {
+            let sin_theta = f32::sin(f32::consts::PI * (y as f32 + 0.5) / h as f32);
+            for x in 0..w as usize {
+                func[y * w as usize + x] = Math::luminance(color) * sin_theta;
+            }
+        }
+
+        Skybox {
+            texture: texture,
+            width: w as f32,
+            height: h as f32,
+            distribution: Distribution2D::new(&func, w as usize, h as usize),
+        }
+    }
+
+    pub fn color(&self, dir: Vector3<f32>) -> Vector3<f32> {
+        let (u, v) = Skybox::dir_to_uv(dir);
+        // Texel x covers u from x / width to (x + 1) / width, the same as in the importance sampling distribution
+        let x = ((self.width * u) as u32).min(self.width as u32 - 1);
+        let y = ((self.height * v) as u32).min(self.height as u32 - 1);
+        let color = self.texture[(x, y)];
+        vec3(color[0],color[1], color[2])
+    }
+
+    // Samples a direction proportional to the skybox luminance, returns the direction, its radiance and solid angle pdf
+    pub fn sample(&self, sampler: &mut dyn Sampler) -> (Vector3<f32>, Vector3<f32>, f32) {
+        let ((u, v), map_pdf) = self.distribution.sample_continuous(sampler);
+        let dir = Skybox::uv_to_dir(u, v);
+
+        let sin_theta = f32::sin(v * f32::consts::PI);
+        if sin_theta <= 0.0 || map_pdf <= 0.0 {
+            return (dir, Vector3::zero(), 0.0);
+        }
+
+        let pdf = map_pdf / (2.0 * f32::consts::PI * f32::consts::PI * sin_theta);
+        (dir, self.color(dir), pdf)
+    }
+
+    // Solid angle pdf of sampling the given direction with Skybox::sample
+    pub fn pdf(&self, dir: Vector3<f32>) -> f32 {
+        let (u, v) = Skybox::dir_to_uv(dir);
+
+        let sin_theta = f32::sin(v * f32::consts::PI);
+        if sin_theta <= 0.0 { return 0.0; }
+
+        self.distribution.pdf(u, v) / (2.0 * f32::consts::PI * f32::consts::PI * sin_theta)
+    }
+
+    fn dir_to_uv(dir: Vector3<f32>) -> (f32, f32) {
+        let phi = f32::atan2(dir.z, dir.x);
+        let u = (if phi > 0.0 { phi } else { phi + 2.0 * f32::consts::PI }) * (f32::consts::FRAC_1_PI / 2.0);
+        let v = f32::acos(dir.y.clamp(-1.0, 1.0)) * f32::consts::FRAC_1_PI;
+        (u, v)
+    }
+
+    fn uv_to_dir(u: f32, v: f32) -> Vector3<f32> {
+        let phi = u * 2.0 * f32::consts::PI;
+        let theta = v * f32::consts::PI;
+        vec3(theta.sin() * phi.cos(), theta.cos(), theta.sin() * phi.sin())
+    }
+}
@@ -0,0 +1,148 @@
+use cgmath::*;
+
+pub const LAMBDA_MIN: f32 = 360.0;
+pub const LAMBDA_MAX: f32 = 830.0;
+
+// Integral of the CIE Y matching function, normalizes a constant spectrum of 1 to a luminance of 1
+const CIE_Y_INTEGRAL: f32 = 106.856895;
+
+// Basis spectra of Smits' RGB to spectrum conversion, 10 bins evenly spread over 380 - 720 nm
+const SMITS_WHITE: [f32; 10] = [1.0000, 1.0000, 0.9999, 0.9993, 0.9992, 0.9998, 1.0000, 1.0000, 1.0000, 1.0000];
+const SMITS_CYAN: [f32; 10] = [0.9710, 0.9426, 1.0007, 1.0007, 1.0007, 1.0007, 0.1564, 0.0000, 0.0000, 0.0000];
+const SMITS_MAGENTA: [f32; 10] = [1.0000, 1.0000, 0.9685, 0.2229, 0.0000, 0.0458, 0.8369, 1.0000, 1.0000, 0.9959];
+const SMITS_YELLOW: [f32; 10] = [0.0001, 0.0000, 0.1088, 0.6651, 1.0000, 1.0000, 0.9996, 0.9586, 0.9685, 0.9840];
+const SMITS_RED: [f32; 10] = [0.1012, 0.0515, 0.0000, 0.0000, 0.0000, 0.0000, 0.8325, 1.0149, 1.0149, 1.0149];
+const SMITS_GREEN: [f32; 10] = [0.0000, 0.0000, 0.0273, 0.7937, 1.0000, 0.9418, 0.1719, 0.0000, 0.0000, 0.0025];
+const SMITS_BLUE: [f32; 10] = [1.0000, 1.0000, 0.8916, 0.3323, 0.0000, 0.0000, 0.0003, 0.0369, 0.0483, 0.0496];
+
+lazy_static::lazy_static! {
+    // Color a constant spectrum of 1 ends up as, dividing by it keeps white surfaces white
+    static ref WHITE_RGB: Vector3<f32> = {
+        let mut xyz = Vector3::zero();
+        let steps = (LAMBDA_MAX - LAMBDA_MIN) as usize;
+        for i in 0..steps {
+            xyz += Spectrum::cie_xyz(LAMBDA_MIN + i as f32 + 0.5);
+        }
+        Spectrum::xyz_to_rgb(xyz / CIE_Y_INTEGRAL)
+    };
+}
+
+// The wavelengths (in nm) carried by a path. The first one is the hero wavelength, the others are spread evenly over the visible range from it.
+#[derive(Copy, Clone)]
+pub struct SampledWavelengths {
+    lambda: [f32; 4],
+    pdf: [f32; 4],
+}
+
+impl SampledWavelengths {
+    pub fn sample(u: f32) -> SampledWavelengths {
+        let mut lambda = [0.0; 4];
+        for i in 0..4 {
+            let offset = (u + i as f32 / 4.0).fract();
+            lambda[i] = LAMBDA_MIN + offset * (LAMBDA_MAX - LAMBDA_MIN);
+        }
+
+        SampledWavelengths {
+            lambda: lambda,
+            pdf: [1.0 / (LAMBDA_MAX - LAMBDA_MIN); 4],
+        }
+    }
+
+    pub fn hero(&self) -> f32 {
+        self.lambda[0]
+    }
+
+    // After a wavelength dependent event (like refraction with dispersion) only the hero wavelength can follow the path
+    pub fn terminate_secondary(&mut self) {
+        if self.pdf[1] == 0.0 { return; }
+        self.pdf[0] /= 4.0;
+        for i in 1..4 {
+            self.pdf[i] = 0.0;
+        }
+    }
+
+    // Spectral values of an RGB color at the sampled wavelengths
+    pub fn from_rgb(&self, rgb: Vector3<f32>) -> Vector4<f32> {
+        vec4(
+            Spectrum::rgb_to_spectrum(rgb, self.lambda[0]),
+            Spectrum::rgb_to_spectrum(rgb, self.lambda[1]),
+            Spectrum::rgb_to_spectrum(rgb, self.lambda[2]),
+            Spectrum::rgb_to_spectrum(rgb, self.lambda[3]),
+        )
+    }
+
+    // Monte Carlo estimate of the color of the spectral radiance L, through CIE XYZ
+    pub fn to_rgb(&self, L: Vector4<f32>) -> Vector3<f32> {
+        let mut xyz = Vector3::zero();
+        for i in 0..4 {
+            if self.pdf[i] == 0.0 { continue; }
+            xyz += Spectrum::cie_xyz(self.lambda[i]) * (L[i] / self.pdf[i]);
+        }
+
+        let rgb = Spectrum::xyz_to_rgb(xyz / (4.0 * CIE_Y_INTEGRAL));
+        rgb.div_element_wise(*WHITE_RGB)
+    }
+}
+
+pub struct Spectrum;
+
+impl Spectrum {
+    // Smits' method, the color is built from white plus one secondary and one primary color basis spectrum
+    pub fn rgb_to_spectrum(rgb: Vector3<f32>, lambda: f32) -> f32 {
+        let (r, g, b) = (rgb.x, rgb.y, rgb.z);
+        let s = |basis: &[f32; 10]| Spectrum::smits_basis(basis, lambda);
+
+        let value = if r <= g && r <= b {
+            r * s(&SMITS_WHITE) + if g <= b {
+                (g - r) * s(&SMITS_CYAN) + (b - g) * s(&SMITS_BLUE)
+            } else {
+                (b - r) * s(&SMITS_CYAN) + (g - b) * s(&SMITS_GREEN)
+            }
+        } else if g <= r && g <= b {
+            g * s(&SMITS_WHITE) + if r <= b {
+                (r - g) * s(&SMITS_MAGENTA) + (b - r) * s(&SMITS_BLUE)
+            } else {
+                (b - g) * s(&SMITS_MAGENTA) + (r - b) * s(&SMITS_RED)
+            }
+        } else {
+            b * s(&SMITS_WHITE) + if r <= g {
+                (r - b) * s(&SMITS_YELLOW) + (g - r) * s(&SMITS_GREEN)
+            } else {
+                (g - b) * s(&SMITS_YELLOW) + (r - g) * s(&SMITS_RED)
+            }
+        };
+
+        f32::max(value, 0.0)
+    }
+
+    // Linear interpolation between the bin centers
+    fn smits_basis(basis: &[f32; 10], lambda: f32) -> f32 {
+        let x = ((lambda - 380.0) / 34.0 - 0.5).clamp(0.0, 9.0);
+        let i = (x as usize).min(8);
+        let t = x - i as f32;
+        basis[i] * (1.0 - t) + basis[i + 1] * t
+    }
+
+    // Analytic fit of the CIE 1931 matching functions by Wyman, Sloan and Shirley
+    pub fn cie_xyz(lambda: f32) -> Vector3<f32> {
+        let g = |mu: f32, sigma_low: f32, sigma_high: f32| {
+            let t = (lambda - mu) / if lambda < mu { sigma_low } else { sigma_high };
+            f32::exp(-0.5 * t * t)
+        };
+
+        vec3(
+            1.056 * g(599.8, 37.9, 31.0) + 0.362 * g(442.0, 16.0, 26.7) - 0.065 * g(501.1, 20.4, 26.2),
+            0.821 * g(568.8, 46.9, 40.5) + 0.286 * g(530.9, 16.3, 31.1),
+            1.217 * g(437.0, 11.8, 36.0) + 0.681 * g(459.0, 26.0, 13.8),
+        )
+    }
+
+    // Linear sRGB primaries with a D65 white point
+    pub fn xyz_to_rgb(xyz: Vector3<f32>) -> Vector3<f32> {
+        vec3(
+            3.2404542 * xyz.x - 1.5371385 * xyz.y - 0.4985314 * xyz.z,
+            -0.9692660 * xyz.x + 1.8760108 * xyz.y + 0.0415560 * xyz.z,
+            0.0556434 * xyz.x - 0.2040259 * xyz.y + 1.0572252 * xyz.z,
+        )
+    }
+}
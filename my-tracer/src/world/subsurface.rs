@@ -0,0 +1,88 @@
+use core::f32;
+use cgmath::*;
+
+use super::{math::Math, medium::HenyeyGreenstein, primitives::Object, sampler::Sampler, scene::{Scene, EPSILON}};
+
+// Upper limit on the scattering events of a single walk, very long walks carry almost no energy anyway
+const MAX_BOUNCES: usize = 256;
+
+pub struct SubsurfaceExit {
+    pub position: Vector3<f32>,
+    // Normal of the surface at the exit, pointing outwards
+    pub normal: Vector3<f32>,
+    pub weight: Vector3<f32>,
+}
+
+// Random walk subsurface scattering. Light enters the object, scatters isotropically through its inside
+// and leaves it somewhere else, the object is treated as a dense volume bounded by its surface.
+pub struct Subsurface;
+
+impl Subsurface {
+    // Walks from I (on the surface, normal pointing outwards) through the inside of the primitive until the path leaves it.
+    // albedo is the color the material should have after all the scattering, mean_free_path the average distance between events per channel.
+    pub fn random_walk(primitive: &Object, I: Vector3<f32>, normal: Vector3<f32>, albedo: Vector3<f32>, mean_free_path: Vector3<f32>, sampler: &mut dyn Sampler) -> Option<SubsurfaceExit> {
+        let sigma_t = vec3(
+            1.0 / f32::max(mean_free_path.x, 1e-4),
+            1.0 / f32::max(mean_free_path.y, 1e-4),
+            1.0 / f32::max(mean_free_path.z, 1e-4),
+        );
+        let sigma_s = sigma_t.mul_element_wise(Subsurface::single_scattering_albedo(albedo));
+        let phase = HenyeyGreenstein::new(0.0);
+
+        // The light enters through a diffuse interface
+        let mut position = I - normal * EPSILON;
+        let mut dir = Math::random_cosine_hemisphere_vectorf32(sampler, -normal);
+        let mut weight = vec3(1.0, 1.0, 1.0);
+
+        for _ in 0..MAX_BOUNCES {
+            let (_, t_exit) = primitive.interval(position, dir)?;
+            let t_exit = f32::max(t_exit, 0.0);
+
+            // The distance is sampled for one channel, the pdf is the average over all of them (one sample MIS)
+            let channel = ((sampler.next_f32() * 3.0) as usize).min(2);
+            let t = -f32::ln(1.0 - sampler.next_f32()) / sigma_t[channel];
+
+            if t >= t_exit {
+                let transmittance = Subsurface::transmittance(sigma_t, t_exit);
+                let pdf = (transmittance.x + transmittance.y + transmittance.z) / 3.0;
+                if pdf <= 0.0 { return None; }
+                weight = weight.mul_element_wise(transmittance) / pdf;
+
+                let exit = position + dir * t_exit;
+                return Some(SubsurfaceExit {
+                    position: exit,
+                    normal: primitive.get_normal(exit),
+                    weight: weight,
+                });
+            }
+
+            let transmittance = Subsurface::transmittance(sigma_t, t);
+            let density = sigma_t.mul_element_wise(transmittance);
+            let pdf = (density.x + density.y + density.z) / 3.0;
+            if pdf <= 0.0 { return None; }
+            weight = weight.mul_element_wise(sigma_s.mul_element_wise(transmittance)) / pdf;
+
+            position += dir * t;
+            dir = phase.sample(-dir, sampler).0;
+
+            let p = Scene::ray_survival_probability(weight);
+            if p < sampler.next_f32() { return None; }
+            weight /= p;
+        }
+
+        None
+    }
+
+    // Inverts the multiple scattering albedo of a semi-infinite slab to the albedo of a single event (Chiang et al. 2016)
+    pub fn single_scattering_albedo(albedo: Vector3<f32>) -> Vector3<f32> {
+        albedo.map(|a| {
+            let a = a.clamp(0.0, 0.999);
+            let s = 4.09712 + 4.20863 * a - f32::sqrt(9.59217 + 41.6808 * a + 17.7126 * a * a);
+            1.0 - s * s
+        })
+    }
+
+    fn transmittance(sigma_t: Vector3<f32>, dist: f32) -> Vector3<f32> {
+        sigma_t.map(|s| f32::exp(-s * dist))
+    }
+}
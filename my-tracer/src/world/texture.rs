@@ -0,0 +1,403 @@
+use core::f32;
+use std::{collections::HashMap, sync::{Mutex, RwLock}};
+use cgmath::*;
+use image::{DynamicImage, ImageReader, ImageResult};
+
+use super::procedural::TextureGraph;
+
+#[derive(Copy, Clone)]
+pub enum WrapMode {
+    Repeat,
+    MirroredRepeat,
+    Clamp,
+}
+
+#[derive(Copy, Clone)]
+pub enum Filter {
+    Nearest,
+    Bilinear,
+    // Blends the two mip levels closest to the footprint of the lookup
+    Trilinear,
+}
+
+// Handle to a texture in the TextureCache
+#[derive(Copy, Clone, PartialEq)]
+pub struct TextureId(usize);
+
+// Where a texture is looked up, procedural textures can use any of these
+#[derive(Copy, Clone)]
+pub struct TexCoord {
+    pub uv: Vector2<f32>,
+    pub position: Vector3<f32>,
+    // Position relative to the primitive
+    pub local: Vector3<f32>,
+}
+
+enum CachedTexture {
+    Image(Texture),
+    Procedural(TextureGraph),
+}
+
+lazy_static::lazy_static! {
+    static ref TEXTURES: RwLock<Vec<CachedTexture>> = RwLock::new(Vec::new());
+    static ref LOADED: Mutex<HashMap<(String, bool), TextureId>> = Mutex::new(HashMap::new());
+}
+
+struct MipLevel {
+    width: usize,
+    height: usize,
+    texels: Vec<Vector3<f32>>,
+}
+
+// An image with all its mip levels, level 0 is the full resolution
+pub struct Texture {
+    levels: Vec<MipLevel>,
+}
+
+// Textures are loaded once and shared by every primitive using them
+pub struct TextureCache;
+
+impl TextureCache {
+    // Colors stored as 8 bit sRGB are converted to linear values, data like roughness should be loaded with srgb false
+    pub fn load(path: &str, srgb: bool) -> ImageResult<TextureId> {
+        let mut loaded = LOADED.lock().unwrap();
+        if let Some(id) = loaded.get(&(path.to_string(), srgb)) {
+            return Ok(*id);
+        }
+
+        // The format is guessed from the contents, some files don't match their extension
+        let image = ImageReader::open(path)?.with_guessed_format()?.decode()?;
+        let id = TextureCache::add(Texture::from_image(image, srgb));
+        loaded.insert((path.to_string(), srgb), id);
+        Ok(id)
+    }
+
+    pub fn add(texture: Texture) -> TextureId {
+        TextureCache::insert(CachedTexture::Image(texture))
+    }
+
+    pub fn add_procedural(graph: TextureGraph) -> TextureId {
+        TextureCache::insert(CachedTexture::Procedural(graph))
+    }
+
+    fn insert(texture: CachedTexture) -> TextureId {
+        let mut textures = TEXTURES.write().unwrap();
+        textures.push(texture);
+        TextureId(textures.len() - 1)
+    }
+
+    // Wrapping and filtering only apply to images
+    pub fn sample(id: TextureId, coord: &TexCoord, footprint: f32, wrap: WrapMode, filter: Filter) -> Vector3<f32> {
+        TextureCache::sample_from(&TEXTURES.read().unwrap(), id, coord, footprint, wrap, filter)
+    }
+
+    // Procedural textures can contain images, those are sampled without locking the cache again
+    fn sample_from(textures: &[CachedTexture], id: TextureId, coord: &TexCoord, footprint: f32, wrap: WrapMode, filter: Filter) -> Vector3<f32> {
+        match &textures[id.0] {
+            CachedTexture::Image(texture) => texture.sample(coord.uv, footprint, wrap, filter),
+            CachedTexture::Procedural(graph) => graph.eval(coord, &|map, coord| {
+                TextureCache::sample_from(textures, map.texture, coord, footprint, map.wrap, map.filter)
+            }),
+        }
+    }
+}
+
+impl Texture {
+    pub fn from_image(image: DynamicImage, srgb: bool) -> Texture {
+        // Floating point images (HDR, EXR) are already linear
+        let decode = srgb && !matches!(image, DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_));
+        let image = image.into_rgb32f();
+
+        let texels = image.pixels().map(|p| {
+            let c = vec3(p[0], p[1], p[2]);
+            if decode { c.map(Texture::srgb_to_linear) } else { c }
+        }).collect();
+
+        Texture::new(image.width() as usize, image.height() as usize, texels)
+    }
+
+    pub fn new(width: usize, height: usize, texels: Vec<Vector3<f32>>) -> Texture {
+        let mut levels = vec![MipLevel { width: width, height: height, texels: texels }];
+
+        // Every level halves the resolution with a box filter, odd sizes repeat their last row or column
+        while levels.last().map_or(false, |l| l.width > 1 || l.height > 1) {
+            let prev = levels.last().unwrap();
+            let w = usize::max(prev.width / 2, 1);
+            let h = usize::max(prev.height / 2, 1);
+
+            let mut texels = Vec::with_capacity(w * h);
+            for y in 0..h {
+                for x in 0..w {
+                    let mut sum = Vector3::zero();
+                    for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                        let px = usize::min(x * 2 + dx, prev.width - 1);
+                        let py = usize::min(y * 2 + dy, prev.height - 1);
+                        sum += prev.texels[py * prev.width + px];
+                    }
+                    texels.push(sum * 0.25);
+                }
+            }
+            levels.push(MipLevel { width: w, height: h, texels: texels });
+        }
+
+        Texture { levels: levels }
+    }
+
+    // footprint is the width of the lookup in uv units, only used by trilinear filtering
+    pub fn sample(&self, uv: Vector2<f32>, footprint: f32, wrap: WrapMode, filter: Filter) -> Vector3<f32> {
+        match filter {
+            Filter::Nearest => {
+                let level = &self.levels[0];
+                let x = (uv.x * level.width as f32).floor() as i64;
+                let y = (uv.y * level.height as f32).floor() as i64;
+                level.texel(x, y, wrap)
+            }
+            Filter::Bilinear => self.levels[0].bilinear(uv, wrap),
+            Filter::Trilinear => {
+                let size = usize::max(self.levels[0].width, self.levels[0].height) as f32;
+                let lod = f32::log2(f32::max(footprint * size, 1e-8)).clamp(0.0, (self.levels.len() - 1) as f32);
+
+                let lower = lod.floor() as usize;
+                let upper = usize::min(lower + 1, self.levels.len() - 1);
+                let t = lod - lower as f32;
+
+                let a = self.levels[lower].bilinear(uv, wrap);
+                if t <= 0.0 { return a; }
+                a * (1.0 - t) + self.levels[upper].bilinear(uv, wrap) * t
+            }
+        }
+    }
+
+    fn srgb_to_linear(c: f32) -> f32 {
+        if c <= 0.04045 { c / 12.92 } else { f32::powf((c + 0.055) / 1.055, 2.4) }
+    }
+}
+
+impl MipLevel {
+    fn texel(&self, x: i64, y: i64, wrap: WrapMode) -> Vector3<f32> {
+        let x = MipLevel::wrap(x, self.width as i64, wrap);
+        let y = MipLevel::wrap(y, self.height as i64, wrap);
+        self.texels[y * self.width + x]
+    }
+
+    fn bilinear(&self, uv: Vector2<f32>, wrap: WrapMode) -> Vector3<f32> {
+        // Texel centers are at half integer coordinates
+        let x = uv.x * self.width as f32 - 0.5;
+        let y = uv.y * self.height as f32 - 0.5;
+        let x0 = x.floor();
+        let y0 = y.floor();
+        let fx = x - x0;
+        let fy = y - y0;
+        let (x0, y0) = (x0 as i64, y0 as i64);
+
+        self.texel(x0, y0, wrap) * ((1.0 - fx) * (1.0 - fy))
+            + self.texel(x0 + 1, y0, wrap) * (fx * (1.0 - fy))
+            + self.texel(x0, y0 + 1, wrap) * ((1.0 - fx) * fy)
+            + self.texel(x0 + 1, y0 + 1, wrap) * (fx * fy)
+    }
+
+    fn wrap(i: i64, size: i64, wrap: WrapMode) -> usize {
+        let i = match wrap {
+            WrapMode::Repeat => i.rem_euclid(size),
+            WrapMode::MirroredRepeat => {
+                let i = i.rem_euclid(size * 2);
+                if i < size { i } else { size * 2 - 1 - i }
+            }
+            WrapMode::Clamp => i.clamp(0, size - 1),
+        };
+        i as usize
+    }
+}
+
+// A texture as used by a material, with its own wrapping and filtering
+#[derive(Copy, Clone)]
+pub struct TextureMap {
+    pub texture: TextureId,
+    pub wrap: WrapMode,
+    pub filter: Filter,
+}
+
+impl TextureMap {
+    pub fn new(texture: TextureId) -> TextureMap {
+        TextureMap {
+            texture: texture,
+            wrap: WrapMode::Repeat,
+            filter: Filter::Trilinear,
+        }
+    }
+
+    pub fn sample(&self, coord: &TexCoord, footprint: f32) -> Vector3<f32> {
+        TextureCache::sample(self.texture, coord, footprint, self.wrap, self.filter)
+    }
+}
+
+// Textures of a primitive, each one is optional. Colors are multiplied with the color of the primitive,
+// the material parameters are read from the red channel and replace the values of the material (see Material::textured).
+#[derive(Copy, Clone)]
+pub struct MaterialTextures {
+    pub base_color: Option<TextureMap>,
+    pub roughness: Option<TextureMap>,
+    pub metalness: Option<TextureMap>,
+    // Parameters of the principled BSDF with the same names
+    pub specular: Option<TextureMap>,
+    pub specular_tint: Option<TextureMap>,
+    pub anisotropic: Option<TextureMap>,
+    pub sheen: Option<TextureMap>,
+    pub sheen_tint: Option<TextureMap>,
+    pub clearcoat: Option<TextureMap>,
+    pub clearcoat_gloss: Option<TextureMap>,
+    pub transmission: Option<TextureMap>,
+    // A value v gives an index of refraction of 1 + v, for principled and dielectric materials
+    pub ior: Option<TextureMap>,
+    // Multiplies the mean free path of subsurface materials per channel
+    pub mean_free_path: Option<TextureMap>,
+    // Only used when the primitive is a light, in place of the base color
+    pub emission: Option<TextureMap>,
+    // Tangent space normal map (OpenGL convention, green points towards the top of the image), loaded with srgb false
+    pub normal: Option<TextureMap>,
+    // Height map, bump_scale is the height in world units of a white texel
+    pub bump: Option<TextureMap>,
+    pub bump_scale: f32,
+    // Surfaces are skipped where the opacity is zero and hit with a probability equal to it in between.
+    // opacity_scale multiplies the opacity map, without a map it is the opacity of the whole surface.
+    pub opacity: Option<TextureMap>,
+    pub opacity_scale: f32,
+    // Number of times the texture repeats per uv unit
+    pub uv_scale: f32,
+}
+
+// Material parameters read from the textures at one point, None where the primitive has no texture for them
+#[derive(Copy, Clone)]
+pub struct TexturedParameters {
+    pub roughness: Option<f32>,
+    pub metalness: Option<f32>,
+    pub specular: Option<f32>,
+    pub specular_tint: Option<f32>,
+    pub anisotropic: Option<f32>,
+    pub sheen: Option<f32>,
+    pub sheen_tint: Option<f32>,
+    pub clearcoat: Option<f32>,
+    pub clearcoat_gloss: Option<f32>,
+    pub transmission: Option<f32>,
+    pub ior: Option<f32>,
+    pub mean_free_path: Option<Vector3<f32>>,
+}
+
+impl MaterialTextures {
+    pub fn new() -> MaterialTextures {
+        MaterialTextures {
+            base_color: None,
+            roughness: None,
+            metalness: None,
+            specular: None,
+            specular_tint: None,
+            anisotropic: None,
+            sheen: None,
+            sheen_tint: None,
+            clearcoat: None,
+            clearcoat_gloss: None,
+            transmission: None,
+            ior: None,
+            mean_free_path: None,
+            emission: None,
+            normal: None,
+            bump: None,
+            bump_scale: 0.01,
+            opacity: None,
+            opacity_scale: 1.0,
+            uv_scale: 1.0,
+        }
+    }
+
+    pub fn color(&self, color: Vector3<f32>, is_light: bool, coord: &TexCoord, footprint: f32) -> Vector3<f32> {
+        let map = if is_light { self.emission } else { self.base_color };
+        match map {
+            Some(map) => color.mul_element_wise(map.sample(&self.scaled(coord), footprint * self.uv_scale)),
+            None => color,
+        }
+    }
+
+    // True if any material parameter comes from a texture
+    pub fn has_parameters(&self) -> bool {
+        [self.roughness, self.metalness, self.specular, self.specular_tint, self.anisotropic, self.sheen, self.sheen_tint,
+            self.clearcoat, self.clearcoat_gloss, self.transmission, self.ior, self.mean_free_path].iter().any(|map| map.is_some())
+    }
+
+    pub fn parameters(&self, coord: &TexCoord) -> TexturedParameters {
+        let coord = self.scaled(coord);
+        let red = |map: Option<TextureMap>| map.map(|map| map.sample(&coord, 0.0).x);
+        TexturedParameters {
+            roughness: red(self.roughness),
+            metalness: red(self.metalness),
+            specular: red(self.specular),
+            specular_tint: red(self.specular_tint),
+            anisotropic: red(self.anisotropic),
+            sheen: red(self.sheen),
+            sheen_tint: red(self.sheen_tint),
+            clearcoat: red(self.clearcoat),
+            clearcoat_gloss: red(self.clearcoat_gloss),
+            transmission: red(self.transmission),
+            ior: red(self.ior).map(|v| 1.0 + v),
+            mean_free_path: self.mean_free_path.map(|map| map.sample(&coord, 0.0)),
+        }
+    }
+
+    pub fn has_opacity(&self) -> bool {
+        self.opacity.is_some() || self.opacity_scale < 1.0
+    }
+
+    pub fn opacity(&self, coord: &TexCoord) -> f32 {
+        let opacity = match self.opacity {
+            Some(map) => map.sample(&self.scaled(coord), 0.0).x,
+            None => 1.0,
+        };
+        (opacity * self.opacity_scale).clamp(0.0, 1.0)
+    }
+
+    fn scaled(&self, coord: &TexCoord) -> TexCoord {
+        TexCoord { uv: coord.uv * self.uv_scale, ..*coord }
+    }
+
+    // Applies the bump and normal maps to the normal n, dpdu and dpdv are the derivatives of the position along the uv coordinates.
+    // coord_at gives the texture coordinates of the surface moved by an offset, for the derivatives of the height.
+    pub fn perturb_normal(&self, n: Vector3<f32>, dpdu: Vector3<f32>, dpdv: Vector3<f32>, coord: &TexCoord, coord_at: impl Fn(Vector3<f32>) -> TexCoord) -> Vector3<f32> {
+        let mut n = n;
+
+        // The surface is displaced along the normal, its derivatives are estimated with forward differences
+        if let Some(map) = self.bump {
+            let delta = 0.0005;
+            let height = |coord: &TexCoord| map.sample(&self.scaled(coord), 0.0).x * self.bump_scale;
+            let h = height(coord);
+            let dhdu = (height(&coord_at(dpdu * delta)) - h) / delta;
+            let dhdv = (height(&coord_at(dpdv * delta)) - h) / delta;
+
+            let bumped = (dpdu + n * dhdu).cross(dpdv + n * dhdv);
+            if bumped.magnitude2() > 0.0 {
+                let bumped = bumped.normalize();
+                n = if bumped.dot(n) < 0.0 { -bumped } else { bumped };
+            }
+        }
+
+        // Tangent frame from the analytic uv parametrization of the primitive: the tangent follows u (Gram-Schmidt against n)
+        // and the bitangent is the cross product pointing up the image, the convention of OpenGL (Y+) normal maps
+        if let Some(map) = self.normal {
+            let tangent = dpdu - n * n.dot(dpdu);
+            if tangent.magnitude2() <= 0.0 { return n; }
+            let tangent = tangent.normalize();
+            let cross = n.cross(tangent);
+            let bitangent = if cross.dot(-dpdv) < 0.0 { -cross } else { cross };
+
+            let t = map.sample(&self.scaled(coord), 0.0) * 2.0 - vec3(1.0, 1.0, 1.0);
+            let mapped = tangent * t.x + bitangent * t.y + n * t.z;
+            if mapped.magnitude2() > 0.0 { n = mapped.normalize(); }
+        }
+
+        n
+    }
+}
+
+impl Default for MaterialTextures {
+    fn default() -> Self {
+        MaterialTextures::new()
+    }
+}
@@ -0,0 +1,293 @@
+// Order the tiles of the image are handed out in
+#[derive(Copy, Clone, PartialEq)]
+pub enum TileOrder {
+    // Row by row from the top left
+    Scanline,
+    // Outwards from the center, so the interesting part of the image shows up first
+    Spiral,
+    // Along a Hilbert curve over the next power of two square, consecutive tiles are neighbours when the grid is such a
+    // square and otherwise only mostly, the curve jumps where it leaves the grid
+    Hilbert,
+}
+
+impl TileOrder {
+    pub const ALL: [TileOrder; 3] = [TileOrder::Scanline, TileOrder::Spiral, TileOrder::Hilbert];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            TileOrder::Scanline => "Scanline",
+            TileOrder::Spiral => "Spiral",
+            TileOrder::Hilbert => "Hilbert",
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum RenderMode {
+    // Every frame adds samples to all tiles
+    Progressive,
+    // Every frame finishes the next tiles, one per thread, with all their samples
+    Bucket,
+}
+
+impl RenderMode {
+    pub const ALL: [RenderMode; 2] = [RenderMode::Progressive, RenderMode::Bucket];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RenderMode::Progressive => "Progressive",
+            RenderMode::Bucket => "Bucket",
+        }
+    }
+}
+
+// Rectangle of pixels, from (x0, y0) up to but not including (x1, y1)
+#[derive(Copy, Clone, PartialEq)]
+pub struct Tile {
+    pub x0: u32,
+    pub y0: u32,
+    pub x1: u32,
+    pub y1: u32,
+}
+
+impl Tile {
+    pub fn new(x0: u32, y0: u32, x1: u32, y1: u32) -> Tile {
+        Tile { x0: x0, y0: y0, x1: x1, y1: y1 }
+    }
+
+    // Indices of the pixels in the tile, row by row
+    pub fn pixels(&self, width: u32) -> impl Iterator<Item = usize> {
+        let tile = *self;
+        (tile.y0..tile.y1).flat_map(move |y| (tile.x0..tile.x1).map(move |x| (y * width + x) as usize))
+    }
+
+    pub fn pixel_count(&self) -> usize {
+        ((self.x1 - self.x0) * (self.y1 - self.y0)) as usize
+    }
+}
+
+// Splits the image into tiles and decides which of them are traced every frame. Every thread traces whole tiles,
+// so the pixels it touches stay close together in memory. A crop limits the render to part of the image,
+// pixels outside of it aren't traced at all, which also lets several machines share a render.
+pub struct TileScheduler {
+    pub mode: RenderMode,
+    pub order: TileOrder,
+    // Width and height of a tile in pixels
+    pub tile_size: u32,
+    // Samples every pixel takes in bucket mode
+    pub bucket_spp: u32,
+    // Part of the image to render, the whole image if None. Splats that land outside of it are dropped, BDPT scales
+    // the splats of its light subpaths up (see Scene::splat_scale), so the pixels inside still get all of their light.
+    pub crop: Option<Tile>,
+    // Bucket mode, index of the first tile that hasn't been traced yet
+    next_tile: usize,
+}
+
+impl TileScheduler {
+    pub fn new() -> TileScheduler {
+        TileScheduler {
+            mode: RenderMode::Progressive,
+            order: TileOrder::Spiral,
+            tile_size: 32,
+            bucket_spp: 64,
+            crop: None,
+            next_tile: 0,
+        }
+    }
+
+    // Starts handing out tiles from the first one again
+    pub fn reset(&mut self) {
+        self.next_tile = 0;
+    }
+
+    // Rendered part of a width x height image, the crop clamped to the image
+    pub fn region(&self, width: u32, height: u32) -> Tile {
+        match self.crop {
+            Some(crop) => {
+                let (x0, y0) = (crop.x0.min(width), crop.y0.min(height));
+                Tile::new(x0, y0, crop.x1.clamp(x0, width), crop.y1.clamp(y0, height))
+            }
+            None => Tile::new(0, 0, width, height)
+        }
+    }
+
+    // Every tile of the region, in order
+    pub fn tiles(&self, width: u32, height: u32) -> Vec<Tile> {
+        let region = self.region(width, height);
+        let size = self.tile_size.max(1);
+        let columns = (region.x1 - region.x0).div_ceil(size);
+        let rows = (region.y1 - region.y0).div_ceil(size);
+        if columns == 0 || rows == 0 { return Vec::new(); }
+
+        let coordinates = match self.order {
+            TileOrder::Scanline => (0..rows).flat_map(|y| (0..columns).map(move |x| (x, y))).collect(),
+            TileOrder::Spiral => TileScheduler::spiral(columns, rows),
+            TileOrder::Hilbert => {
+                let n = columns.max(rows).next_power_of_two();
+                let mut coordinates: Vec<(u32, u32)> = (0..rows).flat_map(|y| (0..columns).map(move |x| (x, y))).collect();
+                coordinates.sort_by_key(|&(x, y)| TileScheduler::hilbert_index(n, x, y));
+                coordinates
+            }
+        };
+
+        coordinates.into_iter().map(|(x, y)| {
+            let (x0, y0) = (region.x0 + x * size, region.y0 + y * size);
+            Tile::new(x0, y0, (x0 + size).min(region.x1), (y0 + size).min(region.y1))
+        }).collect()
+    }
+
+    // Tiles to trace this frame. In bucket mode these are the next tiles, one for every thread, and nothing once all are done.
+    // bucket is false for integrators that need every pixel traced each frame, they always render progressively.
+    pub fn next_tiles(&mut self, width: u32, height: u32, bucket: bool) -> Vec<Tile> {
+        let tiles = self.tiles(width, height);
+        if !bucket { return tiles; }
+
+        let start = self.next_tile.min(tiles.len());
+        self.next_tile = (start + rayon::current_num_threads()).min(tiles.len());
+        tiles[start..self.next_tile].to_vec()
+    }
+
+    // Tiles finished in bucket mode, and the number of tiles
+    pub fn progress(&self, width: u32, height: u32) -> (usize, usize) {
+        let count = self.tiles(width, height).len();
+        (self.next_tile.min(count), count)
+    }
+
+    // True once bucket mode traced every tile
+    pub fn finished(&self, width: u32, height: u32) -> bool {
+        let (done, count) = self.progress(width, height);
+        self.mode == RenderMode::Bucket && done == count
+    }
+
+    // Walks outwards from the center tile in growing squares, skipping the positions outside the grid
+    fn spiral(columns: u32, rows: u32) -> Vec<(u32, u32)> {
+        let count = (columns * rows) as usize;
+        let (mut x, mut y) = (((columns - 1) / 2) as i32, ((rows - 1) / 2) as i32);
+        let mut coordinates = vec![(x as u32, y as u32)];
+        let directions = [(1, 0), (0, 1), (-1, 0), (0, -1)];
+        let mut step = 1;
+        let mut direction = 0;
+        while coordinates.len() < count {
+            // Every step length is walked twice, turning after each
+            for _ in 0..2 {
+                let (dx, dy) = directions[direction];
+                for _ in 0..step {
+                    x += dx;
+                    y += dy;
+                    if x >= 0 && y >= 0 && x < columns as i32 && y < rows as i32 {
+                        coordinates.push((x as u32, y as u32));
+                    }
+                }
+                direction = (direction + 1) % 4;
+            }
+            step += 1;
+        }
+        coordinates
+    }
+
+    // Distance along the Hilbert curve through an n x n grid, n a power of two
+    fn hilbert_index(n: u32, mut x: u32, mut y: u32) -> u32 {
+        let mut d = 0;
+        let mut s = n / 2;
+        while s > 0 {
+            let rx = ((x & s) > 0) as u32;
+            let ry = ((y & s) > 0) as u32;
+            d += s * s * ((3 * rx) ^ ry);
+            // Rotates the quadrant, so the curve through it starts and ends next to its neighbours
+            if ry == 0 {
+                if rx == 1 {
+                    x = n - 1 - x;
+                    y = n - 1 - y;
+                }
+                std::mem::swap(&mut x, &mut y);
+            }
+            s /= 2;
+        }
+        d
+    }
+}
+
+impl Default for TileScheduler {
+    fn default() -> Self {
+        TileScheduler::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scheduler(order: TileOrder, crop: Option<Tile>) -> TileScheduler {
+        let mut scheduler = TileScheduler::new();
+        scheduler.order = order;
+        scheduler.crop = crop;
+        scheduler
+    }
+
+    #[test]
+    fn every_order_covers_every_pixel_once() {
+        let crops = [None, Some(Tile::new(13, 7, 150, 101)), Some(Tile::new(40, 50, 400, 300))];
+        for (width, height) in [(160, 120), (7 * 32 - 5, 3 * 32), (3 * 32, 7 * 32), (31, 200)] {
+            for crop in crops {
+                for order in TileOrder::ALL {
+                    let scheduler = scheduler(order, crop);
+                    let region = scheduler.region(width, height);
+                    let mut hits = vec![0; (width * height) as usize];
+                    for tile in scheduler.tiles(width, height) {
+                        assert!(tile.pixel_count() > 0);
+                        tile.pixels(width).for_each(|p| hits[p] += 1);
+                    }
+                    for y in 0..height {
+                        for x in 0..width {
+                            let inside = x >= region.x0 && x < region.x1 && y >= region.y0 && y < region.y1;
+                            assert_eq!(hits[(y * width + x) as usize], inside as u32, "{} {}x{} at ({}, {})", order.name(), width, height, x, y);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn spiral_starts_at_the_center() {
+        let tiles = scheduler(TileOrder::Spiral, None).tiles(7 * 32, 3 * 32);
+        assert!(tiles[0] == Tile::new(3 * 32, 32, 4 * 32, 2 * 32));
+    }
+
+    #[test]
+    fn hilbert_tiles_are_neighbours_on_power_of_two_grids() {
+        for n in [2, 4, 8] {
+            let tiles = scheduler(TileOrder::Hilbert, None).tiles(n * 32, n * 32);
+            assert_eq!(tiles.len(), (n * n) as usize);
+            for pair in tiles.windows(2) {
+                let dx = (pair[0].x0 as i32 - pair[1].x0 as i32).abs();
+                let dy = (pair[0].y0 as i32 - pair[1].y0 as i32).abs();
+                assert_eq!(dx + dy, 32);
+            }
+        }
+    }
+
+    #[test]
+    fn bucket_mode_hands_out_one_tile_per_thread_until_done() {
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(3).build().unwrap();
+        pool.install(|| {
+            let mut scheduler = scheduler(TileOrder::Scanline, None);
+            scheduler.mode = RenderMode::Bucket;
+            let all = scheduler.tiles(160, 120);
+            assert_eq!(all.len(), 20);
+
+            let mut handed_out = Vec::new();
+            while !scheduler.finished(160, 120) {
+                let tiles = scheduler.next_tiles(160, 120, true);
+                assert!(!tiles.is_empty() && tiles.len() <= 3);
+                handed_out.extend(tiles);
+            }
+            assert!(handed_out == all);
+            assert!(scheduler.next_tiles(160, 120, true).is_empty());
+            assert_eq!(scheduler.next_tiles(160, 120, false).len(), 20);
+
+            scheduler.reset();
+            assert_eq!(scheduler.progress(160, 120), (0, 20));
+        });
+    }
+}